@@ -0,0 +1,262 @@
+use crate::ctx::CompilerContext;
+use crate::error::{error_at, SourceError, SourceResult};
+use crate::hir::cfg::{BlockId, Cfg, Item};
+use crate::hir::{Expr, ExprKind, FunDecl, Stmt, StmtKind};
+
+/// Rejects reads of a variable declared without a value (`var x;`) that are not preceded by an
+/// assignment on every path from the declaration.
+///
+/// The bytecode compiler does not initialize such a variable, so a read could otherwise see the
+/// value another variable left in its slot. The analysis is a forward dataflow over the control
+/// flow graph of the function, tracking the variables that may still be unassigned. Code inside
+/// a loop expression and the right operand of `and` and `or` may not run, so their assignments
+/// do not count after them.
+pub fn check_definite_assignment(ctx: &CompilerContext, fun_decl: &FunDecl) -> SourceResult<()> {
+    if !has_unassigned_decl(&fun_decl.body) {
+        return Ok(());
+    }
+    let cfg = Cfg::build(fun_decl);
+    let preds = cfg.predecessors();
+    let order = cfg.reverse_postorder();
+    let local_count = fun_decl.locals.len();
+    // the variables that may be unassigned at the end of each block
+    let mut unassigned_out = vec![vec![false; local_count]; cfg.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &order {
+            let mut state = State {
+                ctx,
+                fun_decl,
+                unassigned: block_entry(&preds[block.0], &unassigned_out, local_count),
+                error: None,
+            };
+            for item in &cfg.blocks[block.0].items {
+                state.do_item(item);
+            }
+            if state.unassigned != unassigned_out[block.0] {
+                unassigned_out[block.0] = state.unassigned;
+                changed = true;
+            }
+        }
+    }
+    // the sets are final, report the first read in the order of the blocks
+    for &block in &order {
+        let mut state = State {
+            ctx,
+            fun_decl,
+            unassigned: block_entry(&preds[block.0], &unassigned_out, local_count),
+            error: None,
+        };
+        for item in &cfg.blocks[block.0].items {
+            state.do_item(item);
+        }
+        if let Some(error) = state.error {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+// A variable may be unassigned at the start of a block if it may be at the end of a predecessor
+fn block_entry(preds: &[BlockId], unassigned_out: &[Vec<bool>], local_count: usize) -> Vec<bool> {
+    let mut unassigned = vec![false; local_count];
+    for pred in preds {
+        for (u, pred_u) in unassigned.iter_mut().zip(&unassigned_out[pred.0]) {
+            *u |= *pred_u;
+        }
+    }
+    unassigned
+}
+
+fn has_unassigned_decl(stmts: &[Stmt]) -> bool {
+    stmts.iter().any(|stmt| match &stmt.kind {
+        StmtKind::VarDecl(_, None) => true,
+        StmtKind::Block(stmts) => has_unassigned_decl(stmts),
+        StmtKind::Defer(body) => has_unassigned_decl(std::slice::from_ref(body)),
+        StmtKind::If(_, then_body, else_body) => {
+            has_unassigned_decl(std::slice::from_ref(then_body))
+                || else_body
+                    .as_ref()
+                    .is_some_and(|s| has_unassigned_decl(std::slice::from_ref(s)))
+        }
+        StmtKind::While(_, _, body, _) => has_unassigned_decl(std::slice::from_ref(body)),
+        // loop expressions are blocks as well, declarations in them do not matter outside
+        _ => contains_loop_expr(stmt),
+    })
+}
+
+fn contains_loop_expr(stmt: &Stmt) -> bool {
+    fn expr_has(expr: &Expr) -> bool {
+        match &expr.kind {
+            ExprKind::While(..) => true,
+            ExprKind::Binary(_, _, left, right) | ExprKind::LogicalBinary(_, _, left, right) => {
+                expr_has(left) || expr_has(right)
+            }
+            ExprKind::GetItem(array, index) => expr_has(array) || expr_has(index),
+            ExprKind::GetSlice(array, start, end) => {
+                expr_has(array) || expr_has(start) || expr_has(end)
+            }
+            ExprKind::Call(callee, args) => expr_has(callee) || args.iter().any(expr_has),
+            ExprKind::Conditional(cond, then_expr, else_expr) => {
+                expr_has(cond) || expr_has(then_expr) || expr_has(else_expr)
+            }
+            ExprKind::MakeList(elements) => elements.iter().any(expr_has),
+            ExprKind::Try(expr) | ExprKind::Unary(_, _, expr) => expr_has(expr),
+            _ => false,
+        }
+    }
+    match &stmt.kind {
+        StmtKind::Break(_, Some(expr))
+        | StmtKind::Expr(expr)
+        | StmtKind::Return(expr)
+        | StmtKind::StoreGlobal(_, expr)
+        | StmtKind::StoreLocal(_, expr)
+        | StmtKind::Unpack(_, expr)
+        | StmtKind::VarDecl(_, Some(expr)) => expr_has(expr),
+        StmtKind::SetItem(array, index, value) => {
+            expr_has(array) || expr_has(index) || expr_has(value)
+        }
+        _ => false,
+    }
+}
+
+struct State<'a> {
+    ctx: &'a CompilerContext,
+    fun_decl: &'a FunDecl,
+    unassigned: Vec<bool>,
+    error: Option<SourceError>,
+}
+
+impl State<'_> {
+    fn do_item(&mut self, item: &Item) {
+        match item {
+            Item::Stmt(stmt) => self.do_stmt(stmt),
+            Item::Cond(cond) => self.do_expr(cond),
+        }
+    }
+
+    // Statements nested in loop expressions are not split into blocks, so this also follows
+    // their control flow, merging the branches of an `if`
+    fn do_stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Block(stmts) => stmts.iter().for_each(|s| self.do_stmt(s)),
+            StmtKind::Break(_, expr) => {
+                if let Some(expr) = expr {
+                    self.do_expr(expr);
+                }
+            }
+            StmtKind::Continue(_) => {}
+            StmtKind::Defer(body) => self.do_maybe(|state| state.do_stmt(body)),
+            StmtKind::Expr(expr) | StmtKind::Return(expr) | StmtKind::StoreGlobal(_, expr) => {
+                self.do_expr(expr)
+            }
+            StmtKind::If(cond, then_body, else_body) => {
+                self.do_expr(cond);
+                let before = self.unassigned.clone();
+                self.do_stmt(then_body);
+                let after_then = std::mem::replace(&mut self.unassigned, before);
+                if let Some(else_body) = else_body {
+                    self.do_stmt(else_body);
+                }
+                for (u, then_u) in self.unassigned.iter_mut().zip(after_then) {
+                    *u |= then_u;
+                }
+            }
+            StmtKind::SetItem(array, index, value) => {
+                self.do_expr(array);
+                self.do_expr(index);
+                self.do_expr(value);
+            }
+            StmtKind::StoreLocal(id, expr) | StmtKind::VarDecl(id, Some(expr)) => {
+                self.do_expr(expr);
+                self.unassigned[id.0] = false;
+            }
+            StmtKind::Unpack(ids, expr) => {
+                self.do_expr(expr);
+                for id in ids {
+                    self.unassigned[id.0] = false;
+                }
+            }
+            StmtKind::VarDecl(id, None) => self.unassigned[id.0] = true,
+            StmtKind::While(_, cond, body, update) => {
+                self.do_expr(cond);
+                self.do_maybe(|state| {
+                    state.do_stmt(body);
+                    if let Some(update) = update {
+                        state.do_stmt(update);
+                    }
+                });
+            }
+        }
+    }
+
+    fn do_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Binary(_, _, left, right) => {
+                self.do_expr(left);
+                self.do_expr(right);
+            }
+            ExprKind::Call(callee, args) => {
+                self.do_expr(callee);
+                args.iter().for_each(|arg| self.do_expr(arg));
+            }
+            ExprKind::Conditional(cond, then_expr, else_expr) => {
+                self.do_expr(cond);
+                let before = self.unassigned.clone();
+                self.do_expr(then_expr);
+                let after_then = std::mem::replace(&mut self.unassigned, before);
+                self.do_expr(else_expr);
+                for (u, then_u) in self.unassigned.iter_mut().zip(after_then) {
+                    *u |= then_u;
+                }
+            }
+            ExprKind::ConstBool(_)
+            | ExprKind::ConstFloat(_)
+            | ExprKind::ConstInt(_)
+            | ExprKind::ConstNull
+            | ExprKind::ConstString(_)
+            | ExprKind::LoadBuiltin(_)
+            | ExprKind::LoadGlobal(_) => {}
+            ExprKind::GetItem(array, index) => {
+                self.do_expr(array);
+                self.do_expr(index);
+            }
+            ExprKind::GetSlice(array, start, end) => {
+                self.do_expr(array);
+                self.do_expr(start);
+                self.do_expr(end);
+            }
+            ExprKind::LoadLocal(id) => {
+                if self.unassigned[id.0] && self.error.is_none() {
+                    let name = self.ctx.interner.resolve(self.fun_decl.locals[id.0].name);
+                    self.error = Some(error_at(
+                        expr.span,
+                        format!("variable {} may be used before it is assigned", name),
+                    ));
+                }
+            }
+            ExprKind::LogicalBinary(_, _, left, right) => {
+                self.do_expr(left);
+                self.do_maybe(|state| state.do_expr(right));
+            }
+            ExprKind::MakeList(elements) => elements.iter().for_each(|e| self.do_expr(e)),
+            // the expression may fail before its assignments
+            ExprKind::Try(expr) => self.do_maybe(|state| state.do_expr(expr)),
+            ExprKind::Unary(_, _, expr) => self.do_expr(expr),
+            ExprKind::While(_, cond, body) => {
+                self.do_expr(cond);
+                self.do_maybe(|state| state.do_stmt(body));
+            }
+        }
+    }
+
+    // Checks code that may not run, keeping only the variables it leaves unassigned
+    fn do_maybe(&mut self, f: impl FnOnce(&mut Self)) {
+        let before = self.unassigned.clone();
+        f(self);
+        for (u, before_u) in self.unassigned.iter_mut().zip(before) {
+            *u |= before_u;
+        }
+    }
+}
@@ -1,53 +1,299 @@
+mod assign;
 mod scope;
+mod strict;
 
 use crate::analyze::scope::{BlockScope, FunctionScope, GlobalScope, LocalScope, Lookup, Symbol};
 use crate::ast;
-use crate::ctx::CompilerContext;
-use crate::error::{err_at, SourceResult};
+use crate::ctx::{CompilerContext, Name, TEMP_NAME};
+use crate::error::{err_at, AttachErrSpan, SourceResult};
 use crate::hir;
-use crate::hir::{GlobalId, GlobalInfo, GlobalKind, LocalKind, LoopId};
+use crate::hir::{GlobalId, GlobalInfo, GlobalKind, LocalId, LocalKind, LoopId};
+use crate::module::ModuleId;
+use crate::src::Span;
+use natrix_runtime::value::{BinaryOp, Builtin, Function, UnaryOp, ValueType};
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 pub fn analyze(ctx: &CompilerContext, ast: &ast::Program) -> SourceResult<hir::Program> {
+    check_fun_decls(ctx, &ast.decls)?;
     let mut analyzer = Analyzer::new(ctx);
-    analyzer.do_program(ast)
+    analyzer.do_program(ast, &[])
+}
+
+/// Analyzes the functions of an interactive session, which see the top-level variables `vars` of
+/// the session as globals.
+pub fn analyze_session(
+    ctx: &CompilerContext,
+    ast: &ast::Program,
+    vars: &[Name],
+) -> SourceResult<hir::Program> {
+    check_fun_decls(ctx, &ast.decls)?;
+    let mut analyzer = Analyzer::new(ctx);
+    analyzer.do_program(ast, vars)
+}
+
+/// Checks the function declarations of a program, shared by the analysis and the AST interpreter
+/// so that both report the same diagnostics.
+///
+/// Functions of a module and parameters must have distinct names. A function named like a built-in function
+/// (or a native function) replaces it in the whole program, which is reported as a warning (an error in
+/// strict mode).
+pub fn check_fun_decls(ctx: &CompilerContext, decls: &[ast::FunDecl]) -> SourceResult<()> {
+    let mut seen: HashMap<(ModuleId, Name), Span> = HashMap::new();
+    for decl in decls {
+        if let Some(previous) = seen.insert((decl.module, decl.name), decl.name_span) {
+            let (line, column) = previous.start_pos(&ctx.sources);
+            return err_at(
+                decl.name_span,
+                format!(
+                    "function {} is already defined at {}:{}",
+                    ctx.interner.resolve(decl.name),
+                    line,
+                    column
+                ),
+            );
+        }
+        check_builtin_shadowing(ctx, decl.name, decl.name_span)?;
+        let name = ctx.interner.resolve(decl.name);
+        if is_builtin_name(ctx, name) {
+            ctx.warn(
+                decl.name_span,
+                format!("function {} replaces the built-in function {}", name, name),
+            );
+        }
+        for (i, param) in decl.params.iter().enumerate() {
+            if decl.params[..i].iter().any(|p| p.name == param.name) {
+                return err_at(
+                    param.name_span,
+                    format!(
+                        "duplicate parameter {} in function {}",
+                        ctx.interner.resolve(param.name),
+                        name
+                    ),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Strict mode does not allow declarations hiding a built-in function.
+fn check_builtin_shadowing(ctx: &CompilerContext, name: Name, name_span: Span) -> SourceResult<()> {
+    let name = ctx.interner.resolve(name);
+    if ctx.strict && is_builtin_name(ctx, name) {
+        err_at(
+            name_span,
+            format!("{} shadows a built-in function in strict mode", name),
+        )
+    } else {
+        Ok(())
+    }
+}
+
+// Reports calls of builtins that would fail at run time because of the number of arguments or the
+// type of an argument that is known without evaluating it.
+fn check_builtin_call(builtin: Builtin, args: &[hir::Expr], span: Span) -> SourceResult<()> {
+    Function::Builtin(builtin)
+        .check_args(args.len())
+        .err_at(span)?;
+    for (index, arg) in args.iter().enumerate() {
+        if let Some(accepted) = builtin.param_types(index)
+            && let Some(found) = known_type(arg)
+            && !accepted.contains(&found)
+        {
+            let accepted: Vec<String> = accepted.iter().map(|t| format!("{:?}", t)).collect();
+            return err_at(
+                arg.span,
+                format!(
+                    "{} expects {} as argument {}, found {:?}",
+                    builtin.name(),
+                    accepted.join(" or "),
+                    index + 1,
+                    found
+                ),
+            );
+        }
+    }
+    Ok(())
+}
+
+// Reads the file embedded by `embed(args)`, whose argument must be a string literal since the
+// file is read at compile time
+pub(crate) fn embed(ctx: &CompilerContext, args: &[ast::Expr], span: Span) -> SourceResult<String> {
+    Function::Builtin(Builtin::Embed)
+        .check_args(args.len())
+        .err_at(span)?;
+    match &args[0].kind {
+        ast::ExprKind::StringLiteral(path) => ctx.sources.read_embedded(args[0].span, path),
+        _ => err_at(args[0].span, "embed expects a string literal"),
+    }
+}
+
+fn known_type(expr: &hir::Expr) -> Option<ValueType> {
+    match expr.kind {
+        hir::ExprKind::ConstBool(_) => Some(ValueType::Bool),
+        hir::ExprKind::ConstFloat(_) => Some(ValueType::Float),
+        hir::ExprKind::ConstInt(_) => Some(ValueType::Int),
+        hir::ExprKind::ConstNull => Some(ValueType::Null),
+        hir::ExprKind::ConstString(_) => Some(ValueType::String),
+        hir::ExprKind::MakeList(_) => Some(ValueType::List),
+        hir::ExprKind::LoadBuiltin(_) | hir::ExprKind::LoadGlobal(_) => Some(ValueType::Function),
+        _ => None,
+    }
+}
+
+fn is_builtin_name(ctx: &CompilerContext, name: &str) -> bool {
+    Builtin::ALL.iter().any(|b| b.name() == name)
+        || ctx.natives().iter().any(|native| native.name() == name)
 }
 
 struct Analyzer<'a> {
     ctx: &'a CompilerContext,
-    global_scope: Rc<GlobalScope>,
+    global_scopes: Vec<Rc<GlobalScope>>, // the functions of each module, indexed by module
+    current_module: ModuleId,            // the module of the function being analyzed
+    native_base: usize,                  // the global of the first native function
     next_loop_id: usize,
+    loop_expr_depth: usize, // number of loop expressions enclosing the current statement
+    defer_depth: usize,     // number of defers enclosing the current statement
+    implicit_null: Vec<bool>, // per global function, whether the end of its body is reachable
+    global_calls: Vec<(GlobalId, usize, Span)>, // direct calls of globals: argument count, span
+    assigned_globals: HashSet<GlobalId>, // globals that may not hold their function
+}
+
+#[derive(Copy, Clone)]
+struct EnclosingLoop {
+    id: LoopId,
+    is_expr: bool, // loop expressions require a value in every break
 }
 
 impl<'a> Analyzer<'a> {
     fn new(ctx: &'a CompilerContext) -> Self {
         Self {
             ctx,
-            global_scope: GlobalScope::new(ctx),
+            global_scopes: (0..ctx.modules.count())
+                .map(|_| GlobalScope::new(ctx))
+                .collect(),
+            current_module: ModuleId::ROOT,
+            native_base: 0,
             next_loop_id: 0,
+            loop_expr_depth: 0,
+            defer_depth: 0,
+            implicit_null: Vec::new(),
+            global_calls: Vec::new(),
+            assigned_globals: HashSet::new(),
         }
     }
 
-    fn do_program(&mut self, ast: &ast::Program) -> SourceResult<hir::Program> {
+    fn do_program(&mut self, ast: &ast::Program, vars: &[Name]) -> SourceResult<hir::Program> {
         for (id, ast_decl) in ast.decls.iter().enumerate() {
-            self.global_scope
-                .declare(self.ctx, ast_decl.name, ast_decl.name_span, GlobalId(id))?;
+            self.global_scopes[ast_decl.module.0].declare(
+                self.ctx,
+                ast_decl.name,
+                ast_decl.name_span,
+                GlobalId(id),
+            )?;
+        }
+        // the exports of precompiled libraries follow the functions of the program
+        let mut imported = Vec::new();
+        for (index, library) in self.ctx.modules.libraries().iter().enumerate() {
+            for &(name, global) in &library.exports {
+                let id = GlobalId(ast.decls.len() + imported.len());
+                self.global_scopes[library.module.0].declare(self.ctx, name, library.span, id)?;
+                let param_count = library.bc.globals[global].unwrap_function().param_count();
+                imported.push(GlobalInfo::new(
+                    id,
+                    library.module,
+                    self.ctx.modules.global_name(library.module, name),
+                    library.span,
+                    GlobalKind::Imported(hir::ImportedFun::new(index, global, param_count)),
+                    false,
+                ));
+            }
+        }
+        // then the native functions, which every module sees
+        self.native_base = ast.decls.len() + imported.len();
+        for (index, native) in self.ctx.natives().iter().enumerate() {
+            imported.push(GlobalInfo::new(
+                GlobalId(self.native_base + index),
+                ModuleId::ROOT,
+                self.ctx.interner.lookup(native.name()).unwrap(),
+                ast.span,
+                GlobalKind::Native(native.clone()),
+                false,
+            ));
+        }
+        // and the variables of a session, whose values are only known when it runs
+        for &name in vars {
+            let id = GlobalId(ast.decls.len() + imported.len());
+            self.global_scopes[ModuleId::ROOT.0].declare(self.ctx, name, ast.span, id)?;
+            imported.push(GlobalInfo::new(
+                id,
+                ModuleId::ROOT,
+                name,
+                ast.span,
+                GlobalKind::Variable,
+                true,
+            ));
         }
         let mut globals = Vec::new();
         for (id, ast_decl) in ast.decls.iter().enumerate() {
             globals.push(GlobalInfo::new(
                 GlobalId(id),
-                ast_decl.name,
+                ast_decl.module,
+                self.ctx.modules.global_name(ast_decl.module, ast_decl.name),
                 ast_decl.name_span,
                 GlobalKind::Function(self.do_fun_decl(&ast_decl)?),
+                false,
             ));
         }
+        // nothing is known about the functions of a library or the host
+        self.implicit_null
+            .resize(globals.len() + imported.len(), false);
+        globals.append(&mut imported);
+        for id in &self.assigned_globals {
+            globals[id.0].assigned = true;
+        }
+        self.check_global_calls(&globals)?;
+        if self.ctx.strict {
+            strict::check_implicit_null_uses(self.ctx, &globals, &self.implicit_null)?;
+        }
         Ok(hir::Program::new(globals, ast.span))
     }
 
+    // Reports direct calls of global functions with the wrong number of arguments, unless the
+    // program assigns another value to the global
+    fn check_global_calls(&self, globals: &[GlobalInfo]) -> SourceResult<()> {
+        for &(id, arg_count, span) in &self.global_calls {
+            let global = &globals[id.0];
+            if let GlobalKind::Native(function) = &global.kind {
+                function.check_args(arg_count).err_at(span)?;
+                continue;
+            }
+            let param_count = global.param_count();
+            if arg_count != param_count && !global.assigned {
+                let (line, column) = global.name_span.start_pos(&self.ctx.sources);
+                return err_at(
+                    span,
+                    format!(
+                        "function {} expects {} argument{}, but {} were provided (declared at {}:{})",
+                        self.ctx.interner.resolve(global.name),
+                        param_count,
+                        if param_count == 1 { "" } else { "s" },
+                        arg_count,
+                        line,
+                        column
+                    ),
+                );
+            }
+        }
+        Ok(())
+    }
+
     fn do_fun_decl(&mut self, ast: &ast::FunDecl) -> SourceResult<hir::FunDecl> {
-        let function_scope = FunctionScope::new(self.global_scope.clone());
+        self.current_module = ast.module;
+        let function_scope = FunctionScope::new(self.global_scopes[ast.module.0].clone());
         for (i, param) in ast.params.iter().enumerate() {
+            check_builtin_shadowing(self.ctx, param.name, param.name_span)?;
             function_scope.declare(
                 self.ctx,
                 param.name,
@@ -56,6 +302,7 @@ impl<'a> Analyzer<'a> {
             )?;
         }
         let mut body = self.do_block(function_scope.clone(), None, &ast.body)?;
+        self.implicit_null.push(strict::completes_normally(&body));
         if !body
             .last()
             .is_some_and(|s| matches!(s.kind, hir::StmtKind::Return(_)))
@@ -66,17 +313,15 @@ impl<'a> Analyzer<'a> {
                 span,
             ));
         }
-        Ok(hir::FunDecl::new(
-            ast.params.len(),
-            function_scope.take_locals(),
-            body,
-        ))
+        let fun_decl = hir::FunDecl::new(ast.params.len(), function_scope.take_locals(), body);
+        assign::check_definite_assignment(self.ctx, &fun_decl)?;
+        Ok(fun_decl)
     }
 
     fn do_block(
         &mut self,
         scope: Rc<dyn LocalScope>,
-        enclosing_loop: Option<LoopId>,
+        enclosing_loop: Option<EnclosingLoop>,
         ast: &Vec<ast::Stmt>,
     ) -> SourceResult<Vec<hir::Stmt>> {
         let block_scope = BlockScope::new(scope);
@@ -90,7 +335,7 @@ impl<'a> Analyzer<'a> {
     fn do_stmt(
         &mut self,
         scope: &Rc<BlockScope>,
-        enclosing_loop: Option<LoopId>,
+        enclosing_loop: Option<EnclosingLoop>,
         ast: &ast::Stmt,
     ) -> SourceResult<hir::Stmt> {
         match &ast.kind {
@@ -108,13 +353,16 @@ impl<'a> Analyzer<'a> {
                     let symbol = scope.lookup(self.ctx, name, target.span)?;
                     let value = self.do_expr(scope, value)?;
                     match symbol {
-                        Symbol::Builtin(_) => {
+                        Symbol::Builtin(_) | Symbol::Native(_) => {
                             err_at(target.span, "built-in function cannot be assigned to")
                         }
-                        Symbol::Global(id) => Ok(hir::Stmt::new(
-                            hir::StmtKind::StoreGlobal(id, value),
-                            target.span,
-                        )),
+                        Symbol::Global(id) => {
+                            self.assigned_globals.insert(id);
+                            Ok(hir::Stmt::new(
+                                hir::StmtKind::StoreGlobal(id, value),
+                                target.span,
+                            ))
+                        }
                         Symbol::Local(id) => Ok(hir::Stmt::new(
                             hir::StmtKind::StoreLocal(id, value),
                             target.span,
@@ -126,24 +374,82 @@ impl<'a> Analyzer<'a> {
                 hir::StmtKind::Block(self.do_block(scope.clone(), enclosing_loop, stmts)?),
                 ast.span,
             )),
-            ast::StmtKind::Break => {
-                if let Some(loop_id) = enclosing_loop {
-                    Ok(hir::Stmt::new(hir::StmtKind::Break(loop_id), ast.span))
-                } else {
-                    err_at(ast.span, "break outside a loop")
+            ast::StmtKind::Break(expr) => match (enclosing_loop, expr) {
+                (None, _) => err_at(ast.span, "break outside a loop"),
+                (Some(enclosing), Some(expr)) if enclosing.is_expr => {
+                    let value = self.do_expr(scope, expr)?;
+                    Ok(hir::Stmt::new(
+                        hir::StmtKind::Break(enclosing.id, Some(value)),
+                        ast.span,
+                    ))
                 }
-            }
+                (Some(_), Some(_)) => {
+                    err_at(ast.span, "break with a value outside a loop expression")
+                }
+                (Some(enclosing), None) if enclosing.is_expr => {
+                    err_at(ast.span, "break without a value in a loop expression")
+                }
+                (Some(enclosing), None) => Ok(hir::Stmt::new(
+                    hir::StmtKind::Break(enclosing.id, None),
+                    ast.span,
+                )),
+            },
             ast::StmtKind::Continue => {
-                if let Some(loop_id) = enclosing_loop {
-                    Ok(hir::Stmt::new(hir::StmtKind::Continue(loop_id), ast.span))
+                if let Some(enclosing) = enclosing_loop {
+                    Ok(hir::Stmt::new(
+                        hir::StmtKind::Continue(enclosing.id),
+                        ast.span,
+                    ))
                 } else {
                     err_at(ast.span, "continue outside a loop")
                 }
             }
+            ast::StmtKind::Defer(body) => {
+                // the body runs after the block has been exited, so it cannot exit it again
+                self.defer_depth += 1;
+                let body = self.do_stmt(scope, None, body);
+                self.defer_depth -= 1;
+                Ok(hir::Stmt::new(
+                    hir::StmtKind::Defer(Box::new(body?)),
+                    ast.span,
+                ))
+            }
             ast::StmtKind::Expr(expr) => {
                 let expr = self.do_expr(scope, expr)?;
                 Ok(hir::Stmt::new(hir::StmtKind::Expr(expr), ast.span))
             }
+            ast::StmtKind::For {
+                init,
+                cond,
+                update,
+                body,
+            } => {
+                // the loop is a block of its own with the initialization followed by a while loop
+                let for_scope = BlockScope::new(scope.clone());
+                let mut stmts = init
+                    .iter()
+                    .map(|stmt| self.do_stmt(&for_scope, enclosing_loop, stmt))
+                    .collect::<SourceResult<Vec<hir::Stmt>>>()?;
+                let loop_id = self.new_loop_id();
+                let cond = match cond {
+                    Some(cond) => self.do_expr(&for_scope, cond)?,
+                    None => hir::Expr::new(hir::ExprKind::ConstBool(true), ast.span),
+                };
+                let update = match update {
+                    Some(update) => Some(Box::new(self.do_stmt(&for_scope, None, update)?)),
+                    None => None,
+                };
+                let enclosing = EnclosingLoop {
+                    id: loop_id,
+                    is_expr: false,
+                };
+                let body = self.do_stmt(&for_scope, Some(enclosing), body)?;
+                stmts.push(hir::Stmt::new(
+                    hir::StmtKind::While(loop_id, cond, Box::new(body), update),
+                    ast.span,
+                ));
+                Ok(hir::Stmt::new(hir::StmtKind::Block(stmts), ast.span))
+            }
             ast::StmtKind::If {
                 cond,
                 then_body,
@@ -161,6 +467,31 @@ impl<'a> Analyzer<'a> {
                     ast.span,
                 ))
             }
+            ast::StmtKind::CompileIf {
+                flag,
+                value,
+                then_body,
+                else_body,
+                ..
+            } => {
+                // the other branch is dropped without being analyzed, so it may refer to names
+                // that only exist when its flag is set
+                let branch = if self.ctx.flag_is_set(*flag, value.as_deref()) {
+                    Some(then_body)
+                } else {
+                    else_body.as_ref()
+                };
+                match branch {
+                    Some(stmt) => self.do_stmt(scope, enclosing_loop, stmt),
+                    None => Ok(hir::Stmt::new(hir::StmtKind::Block(Vec::new()), ast.span)),
+                }
+            }
+            ast::StmtKind::Return(_) if self.loop_expr_depth > 0 => {
+                err_at(ast.span, "return inside a loop expression")
+            }
+            ast::StmtKind::Return(_) if self.defer_depth > 0 => {
+                err_at(ast.span, "return inside a defer")
+            }
             ast::StmtKind::Return(expr) => {
                 let e = match expr {
                     Some(e) => self.do_expr(scope, e)?,
@@ -168,112 +499,377 @@ impl<'a> Analyzer<'a> {
                 };
                 Ok(hir::Stmt::new(hir::StmtKind::Return(e), ast.span))
             }
+            ast::StmtKind::Unpack { targets, value } => {
+                // the elements go to temporaries, so that the targets are only assigned once the
+                // number of elements has been checked
+                let value = self.do_expr(scope, value)?;
+                let name = self.ctx.interner.lookup(TEMP_NAME).unwrap();
+                let temps: Vec<LocalId> = targets
+                    .iter()
+                    .map(|target| scope.create_local(name, target.span, LocalKind::Temporary))
+                    .collect();
+                let mut stmts = vec![hir::Stmt::new(
+                    hir::StmtKind::Unpack(temps.clone(), value),
+                    ast.span,
+                )];
+                for (target, temp) in targets.iter().zip(temps) {
+                    let value = hir::Expr::new(hir::ExprKind::LoadLocal(temp), target.span);
+                    stmts.push(self.do_assign(scope, target, value)?);
+                }
+                Ok(hir::Stmt::new(hir::StmtKind::Block(stmts), ast.span))
+            }
             ast::StmtKind::VarDecl {
                 name,
                 name_span,
                 init,
             } => {
-                let value = self.do_expr(&scope, init)?;
+                let value = match init {
+                    Some(init) => Some(self.do_expr(&scope, init)?),
+                    None => None,
+                };
+                check_builtin_shadowing(self.ctx, *name, *name_span)?;
                 let id = scope.declare(self.ctx, *name, *name_span, LocalKind::LocalVariable)?;
                 Ok(hir::Stmt::new(hir::StmtKind::VarDecl(id, value), ast.span))
             }
             ast::StmtKind::While { cond, body } => {
-                let loop_id = LoopId(self.next_loop_id);
-                self.next_loop_id += 1;
+                let loop_id = self.new_loop_id();
                 let cond = self.do_expr(scope, cond)?;
-                let body = self.do_stmt(&scope, Some(loop_id), body)?;
+                let enclosing = EnclosingLoop {
+                    id: loop_id,
+                    is_expr: false,
+                };
+                let body = self.do_stmt(&scope, Some(enclosing), body)?;
                 Ok(hir::Stmt::new(
-                    hir::StmtKind::While(loop_id, cond, Box::new(body)),
+                    hir::StmtKind::While(loop_id, cond, Box::new(body), None),
                     ast.span,
                 ))
             }
         }
     }
 
-    fn do_expr(&mut self, scope: &Rc<BlockScope>, ast: &ast::Expr) -> SourceResult<hir::Expr> {
-        match &ast.kind {
-            ast::ExprKind::ArrayAccess { array, index } => {
+    // Assigns an already analyzed value to `target`
+    fn do_assign(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        target: &ast::AssignTarget,
+        value: hir::Expr,
+    ) -> SourceResult<hir::Stmt> {
+        match &target.kind {
+            ast::AssignTargetKind::ArrayAccess { array, index } => {
                 let array = self.do_expr(scope, array)?;
                 let index = self.do_expr(scope, index)?;
-                Ok(hir::Expr::new(
-                    hir::ExprKind::GetItem(Box::new(array), Box::new(index)),
-                    ast.span,
+                Ok(hir::Stmt::new(
+                    hir::StmtKind::SetItem(array, index, value),
+                    target.span,
                 ))
             }
+            ast::AssignTargetKind::Var(name) => match scope.lookup(self.ctx, name, target.span)? {
+                Symbol::Builtin(_) | Symbol::Native(_) => {
+                    err_at(target.span, "built-in function cannot be assigned to")
+                }
+                Symbol::Global(id) => {
+                    self.assigned_globals.insert(id);
+                    Ok(hir::Stmt::new(
+                        hir::StmtKind::StoreGlobal(id, value),
+                        target.span,
+                    ))
+                }
+                Symbol::Local(id) => Ok(hir::Stmt::new(
+                    hir::StmtKind::StoreLocal(id, value),
+                    target.span,
+                )),
+            },
+        }
+    }
+
+    // Expressions with operands are analyzed by methods of their own, so that a level of a deeply
+    // nested expression only takes the stack of its own kind (unoptimized builds give every arm of
+    // a match its own stack slots)
+    fn do_expr(&mut self, scope: &Rc<BlockScope>, ast: &ast::Expr) -> SourceResult<hir::Expr> {
+        match &ast.kind {
+            ast::ExprKind::ArrayAccess { array, index } => {
+                self.do_array_access(scope, array, index, ast.span)
+            }
             ast::ExprKind::Binary {
                 op,
                 op_span,
                 left,
                 right,
-            } => {
-                let left = self.do_expr(scope, left)?;
-                let right = self.do_expr(scope, right)?;
-                Ok(hir::Expr::new(
-                    hir::ExprKind::Binary(*op, *op_span, Box::new(left), Box::new(right)),
-                    ast.span,
-                ))
-            }
+            } => self.do_binary(scope, *op, *op_span, left, right, ast.span),
             ast::ExprKind::BoolLiteral(v) => {
                 Ok(hir::Expr::new(hir::ExprKind::ConstBool(*v), ast.span))
             }
-            ast::ExprKind::Call { callee, args } => {
-                let callee = self.do_expr(scope, callee)?;
-                let args = args
-                    .iter()
-                    .map(|arg| self.do_expr(scope, arg))
-                    .collect::<Result<Vec<_>, _>>()?;
-                Ok(hir::Expr::new(
-                    hir::ExprKind::Call(Box::new(callee), args),
-                    ast.span,
-                ))
-            }
+            ast::ExprKind::Call { callee, args } => self.do_call(scope, callee, args, ast.span),
+            ast::ExprKind::Conditional {
+                cond,
+                then_expr,
+                else_expr,
+            } => self.do_conditional(scope, cond, then_expr, else_expr, ast.span),
             ast::ExprKind::FloatLiteral(v) => {
                 Ok(hir::Expr::new(hir::ExprKind::ConstFloat(*v), ast.span))
             }
             ast::ExprKind::IntLiteral(v) => {
                 Ok(hir::Expr::new(hir::ExprKind::ConstInt(*v), ast.span))
             }
-            ast::ExprKind::ListLiteral(elements) => {
-                let elements = elements
-                    .iter()
-                    .map(|e| self.do_expr(scope, e))
-                    .collect::<Result<Vec<_>, _>>()?;
-                Ok(hir::Expr::new(hir::ExprKind::MakeList(elements), ast.span))
-            }
+            ast::ExprKind::ListLiteral(elements) => self.do_list(scope, elements, ast.span),
             ast::ExprKind::LogicalBinary {
                 and,
                 op_span,
                 left,
                 right,
-            } => {
-                let left = self.do_expr(scope, left)?;
-                let right = self.do_expr(scope, right)?;
-                Ok(hir::Expr::new(
-                    hir::ExprKind::LogicalBinary(*and, *op_span, Box::new(left), Box::new(right)),
-                    ast.span,
-                ))
-            }
+            } => self.do_logical_binary(scope, *and, *op_span, left, right, ast.span),
             ast::ExprKind::NullLiteral => Ok(hir::Expr::new(hir::ExprKind::ConstNull, ast.span)),
             ast::ExprKind::Paren(expr) => self.do_expr(scope, expr),
+            ast::ExprKind::Qualified {
+                module,
+                module_span,
+                name,
+            } => self.do_qualified(*module, *module_span, *name, ast.span),
+            ast::ExprKind::Slice { array, start, end } => {
+                self.do_slice(scope, array, start, end, ast.span)
+            }
             ast::ExprKind::StringLiteral(v) => Ok(hir::Expr::new(
                 hir::ExprKind::ConstString(v.clone()),
                 ast.span,
             )),
+            ast::ExprKind::Try(expr) => self.do_try(scope, expr, ast.span),
             ast::ExprKind::Unary { op, op_span, expr } => {
-                let expr = self.do_expr(scope, expr)?;
-                Ok(hir::Expr::new(
-                    hir::ExprKind::Unary(*op, *op_span, Box::new(expr)),
-                    ast.span,
-                ))
+                self.do_unary(scope, *op, *op_span, expr, ast.span)
             }
-            ast::ExprKind::Var(name) => Ok(hir::Expr::new(
-                match scope.lookup(self.ctx, name, ast.span)? {
-                    Symbol::Builtin(builtin) => hir::ExprKind::LoadBuiltin(builtin),
-                    Symbol::Global(id) => hir::ExprKind::LoadGlobal(id),
-                    Symbol::Local(id) => hir::ExprKind::LoadLocal(id),
-                },
-                ast.span,
-            )),
+            ast::ExprKind::Var(name) => self.do_var(scope, name, ast.span),
+            ast::ExprKind::While { cond, body } => self.do_while_expr(scope, cond, body, ast.span),
+        }
+    }
+
+    fn do_array_access(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        array: &ast::Expr,
+        index: &ast::Expr,
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let array = self.do_expr(scope, array)?;
+        let index = self.do_expr(scope, index)?;
+        Ok(hir::Expr::new(
+            hir::ExprKind::GetItem(Box::new(array), Box::new(index)),
+            span,
+        ))
+    }
+
+    fn do_binary(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        op: BinaryOp,
+        op_span: Span,
+        left: &ast::Expr,
+        right: &ast::Expr,
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let left = self.do_expr(scope, left)?;
+        let right = self.do_expr(scope, right)?;
+        Ok(hir::Expr::new(
+            hir::ExprKind::Binary(op, op_span, Box::new(left), Box::new(right)),
+            span,
+        ))
+    }
+
+    fn do_call(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        callee: &ast::Expr,
+        args: &[ast::Expr],
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let callee = self.do_expr(scope, callee)?;
+        if let hir::ExprKind::LoadBuiltin(Builtin::Embed) = callee.kind {
+            let content = embed(self.ctx, args, span)?;
+            return Ok(hir::Expr::new(
+                hir::ExprKind::ConstString(content.into()),
+                span,
+            ));
+        }
+        let args = args
+            .iter()
+            .map(|arg| self.do_expr(scope, arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        match callee.kind {
+            hir::ExprKind::LoadBuiltin(builtin) => check_builtin_call(builtin, &args, span)?,
+            hir::ExprKind::LoadGlobal(id) => self.global_calls.push((id, args.len(), span)),
+            _ => {}
         }
+        Ok(hir::Expr::new(
+            hir::ExprKind::Call(Box::new(callee), args),
+            span,
+        ))
+    }
+
+    fn do_conditional(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        cond: &ast::Expr,
+        then_expr: &ast::Expr,
+        else_expr: &ast::Expr,
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let cond = self.do_expr(scope, cond)?;
+        let then_expr = self.do_expr(scope, then_expr)?;
+        let else_expr = self.do_expr(scope, else_expr)?;
+        Ok(hir::Expr::new(
+            hir::ExprKind::Conditional(Box::new(cond), Box::new(then_expr), Box::new(else_expr)),
+            span,
+        ))
+    }
+
+    fn do_list(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        elements: &[ast::Expr],
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let elements = elements
+            .iter()
+            .map(|e| self.do_expr(scope, e))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(hir::Expr::new(hir::ExprKind::MakeList(elements), span))
+    }
+
+    fn do_logical_binary(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        and: bool,
+        op_span: Span,
+        left: &ast::Expr,
+        right: &ast::Expr,
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let left = self.do_expr(scope, left)?;
+        let right = self.do_expr(scope, right)?;
+        Ok(hir::Expr::new(
+            hir::ExprKind::LogicalBinary(and, op_span, Box::new(left), Box::new(right)),
+            span,
+        ))
+    }
+
+    fn do_qualified(
+        &self,
+        module: Name,
+        module_span: Span,
+        name: Name,
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let Some(target) = self.ctx.modules.resolve(self.current_module, module) else {
+            return err_at(
+                module_span,
+                format!(
+                    "no module named {} is imported",
+                    self.ctx.interner.resolve(module)
+                ),
+            );
+        };
+        // only the functions of the module itself, not the built-in functions it sees
+        match self.global_scopes[target.0].symbols().borrow().get(&name) {
+            Some(Symbol::Global(id)) => Ok(hir::Expr::new(hir::ExprKind::LoadGlobal(*id), span)),
+            _ => err_at(
+                span,
+                format!(
+                    "module {} has no function {}",
+                    self.ctx.interner.resolve(module),
+                    self.ctx.interner.resolve(name)
+                ),
+            ),
+        }
+    }
+
+    fn do_slice(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        array: &ast::Expr,
+        start: &Option<Box<ast::Expr>>,
+        end: &Option<Box<ast::Expr>>,
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let array = self.do_expr(scope, array)?;
+        let mut bound = |bound: &Option<Box<ast::Expr>>| match bound {
+            Some(bound) => self.do_expr(scope, bound),
+            None => Ok(hir::Expr::new(hir::ExprKind::ConstNull, span)),
+        };
+        let start = bound(start)?;
+        let end = bound(end)?;
+        Ok(hir::Expr::new(
+            hir::ExprKind::GetSlice(Box::new(array), Box::new(start), Box::new(end)),
+            span,
+        ))
+    }
+
+    fn do_try(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        expr: &ast::Expr,
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let expr = self.do_expr(scope, expr)?;
+        Ok(hir::Expr::new(hir::ExprKind::Try(Box::new(expr)), span))
+    }
+
+    fn do_unary(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        op: UnaryOp,
+        op_span: Span,
+        expr: &ast::Expr,
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let expr = self.do_expr(scope, expr)?;
+        Ok(hir::Expr::new(
+            hir::ExprKind::Unary(op, op_span, Box::new(expr)),
+            span,
+        ))
+    }
+
+    fn do_var(&self, scope: &Rc<BlockScope>, name: &Name, span: Span) -> SourceResult<hir::Expr> {
+        Ok(hir::Expr::new(
+            match scope.lookup(self.ctx, name, span)? {
+                // a predeclared value like `ARGS` is the result of calling its builtin
+                Symbol::Builtin(builtin) if builtin.is_value() => hir::ExprKind::Call(
+                    Box::new(hir::Expr::new(hir::ExprKind::LoadBuiltin(builtin), span)),
+                    Vec::new(),
+                ),
+                Symbol::Builtin(builtin) => hir::ExprKind::LoadBuiltin(builtin),
+                Symbol::Native(index) => {
+                    hir::ExprKind::LoadGlobal(GlobalId(self.native_base + index))
+                }
+                Symbol::Global(id) => hir::ExprKind::LoadGlobal(id),
+                Symbol::Local(id) => hir::ExprKind::LoadLocal(id),
+            },
+            span,
+        ))
+    }
+
+    fn do_while_expr(
+        &mut self,
+        scope: &Rc<BlockScope>,
+        cond: &ast::Expr,
+        body: &ast::Stmt,
+        span: Span,
+    ) -> SourceResult<hir::Expr> {
+        let loop_id = self.new_loop_id();
+        let cond = self.do_expr(scope, cond)?;
+        let enclosing = EnclosingLoop {
+            id: loop_id,
+            is_expr: true,
+        };
+        self.loop_expr_depth += 1;
+        let body = self.do_stmt(scope, Some(enclosing), body);
+        self.loop_expr_depth -= 1;
+        Ok(hir::Expr::new(
+            hir::ExprKind::While(loop_id, Box::new(cond), Box::new(body?)),
+            span,
+        ))
+    }
+
+    fn new_loop_id(&mut self) -> LoopId {
+        let loop_id = LoopId(self.next_loop_id);
+        self.next_loop_id += 1;
+        loop_id
     }
 }
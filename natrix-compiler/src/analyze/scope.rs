@@ -11,6 +11,7 @@ use std::rc::Rc;
 #[derive(Debug, Copy, Clone)]
 pub enum Symbol {
     Builtin(Builtin),
+    Native(usize), // index in `CompilerContext::natives`
     Global(GlobalId),
     Local(LocalId),
 }
@@ -19,15 +20,19 @@ pub trait Lookup {
     fn symbols(&self) -> &RefCell<HashMap<Name, Symbol>>;
     fn parent(&self) -> Option<&dyn Lookup>;
     fn lookup(&self, ctx: &CompilerContext, name: &Name, name_span: Span) -> SourceResult<Symbol> {
+        match self.find(name) {
+            Some(symbol) => Ok(symbol),
+            None => err_at(
+                name_span,
+                format!("undeclared variable {:?}", ctx.interner.resolve(*name)),
+            ),
+        }
+    }
+
+    fn find(&self, name: &Name) -> Option<Symbol> {
         match self.symbols().borrow().get(name) {
-            Some(symbol) => Ok(*symbol),
-            None => match self.parent() {
-                Some(parent) => parent.lookup(ctx, name, name_span),
-                None => err_at(
-                    name_span,
-                    format!("undeclared variable {:?}", ctx.interner.resolve(*name)),
-                ),
-            },
+            Some(symbol) => Some(*symbol),
+            None => self.parent().and_then(|parent| parent.find(name)),
         }
     }
 }
@@ -35,6 +40,13 @@ pub trait Lookup {
 pub trait LocalScope: Lookup {
     fn create_local(&self, name: Name, name_span: Span, kind: LocalKind) -> LocalId;
 
+    /// Span of the name and kind of a local created by `create_local`.
+    fn local_decl(&self, id: LocalId) -> (Span, LocalKind);
+
+    /// Declares a local in this scope, which must not declare `name` already.
+    ///
+    /// A local of an enclosing scope may be shadowed, the new local gets its own `LocalId` (and
+    /// slot), but a warning points to the shadowed declaration.
     fn declare(
         &self,
         ctx: &CompilerContext,
@@ -42,8 +54,30 @@ pub trait LocalScope: Lookup {
         name_span: Span,
         kind: LocalKind,
     ) -> SourceResult<LocalId> {
+        let shadowed = match self.parent().and_then(|parent| parent.find(&name)) {
+            Some(Symbol::Local(id)) => Some(self.local_decl(id)),
+            _ => None,
+        };
         match self.symbols().borrow_mut().entry(name) {
             Entry::Vacant(e) => {
+                if let Some((span, kind)) = shadowed {
+                    let (line, column) = span.start_pos(&ctx.sources);
+                    let kind = match kind {
+                        LocalKind::Parameter(_) => "parameter",
+                        LocalKind::LocalVariable => "variable",
+                        LocalKind::Temporary => unreachable!("temporaries have no name in scope"),
+                    };
+                    ctx.warn(
+                        name_span,
+                        format!(
+                            "{} shadows the {} declared at {}:{}",
+                            ctx.interner.resolve(name),
+                            kind,
+                            line,
+                            column
+                        ),
+                    );
+                }
                 let id = self.create_local(name, name_span, kind);
                 e.insert(Symbol::Local(id));
                 Ok(id)
@@ -72,6 +106,12 @@ impl BuiltinScope {
                 Symbol::Builtin(*builtin),
             );
         }
+        for (index, native) in ctx.natives().iter().enumerate() {
+            symbols.insert(
+                ctx.interner.lookup(native.name()).unwrap(),
+                Symbol::Native(index),
+            );
+        }
         Rc::new(BuiltinScope {
             symbols: RefCell::new(symbols),
         })
@@ -184,10 +224,19 @@ impl LocalScope for FunctionScope {
             .push(LocalInfo::new(id, name, name_span, kind));
         id
     }
+
+    fn local_decl(&self, id: LocalId) -> (Span, LocalKind) {
+        let local = &self.locals.borrow()[id.0];
+        (local.name_span, local.kind)
+    }
 }
 
 impl LocalScope for BlockScope {
     fn create_local(&self, name: Name, name_span: Span, kind: LocalKind) -> LocalId {
         self.parent.create_local(name, name_span, kind)
     }
+
+    fn local_decl(&self, id: LocalId) -> (Span, LocalKind) {
+        self.parent.local_decl(id)
+    }
 }
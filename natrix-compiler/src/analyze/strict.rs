@@ -0,0 +1,173 @@
+use crate::ctx::CompilerContext;
+use crate::error::{err_at, SourceResult};
+use crate::hir::{Expr, ExprKind, GlobalInfo, GlobalKind, LoopId, Stmt, StmtKind};
+
+/// Returns true if control can reach the end of `stmts`, i.e. the function would return an
+/// implicit null if `stmts` was its body.
+pub fn completes_normally(stmts: &[Stmt]) -> bool {
+    stmts.iter().all(stmt_completes_normally)
+}
+
+fn stmt_completes_normally(stmt: &Stmt) -> bool {
+    match &stmt.kind {
+        StmtKind::Block(stmts) => completes_normally(stmts),
+        StmtKind::Break(..) | StmtKind::Continue(_) | StmtKind::Return(_) => false,
+        StmtKind::If(_, then_body, Some(else_body)) => {
+            stmt_completes_normally(then_body) || stmt_completes_normally(else_body)
+        }
+        // an endless loop can only be left by a break (or a return)
+        StmtKind::While(id, cond, body, _) if matches!(cond.kind, ExprKind::ConstBool(true)) => {
+            breaks_from(body, *id)
+        }
+        _ => true,
+    }
+}
+
+fn breaks_from(stmt: &Stmt, id: LoopId) -> bool {
+    match &stmt.kind {
+        StmtKind::Block(stmts) => stmts.iter().any(|s| breaks_from(s, id)),
+        StmtKind::Break(loop_id, _) => *loop_id == id,
+        StmtKind::If(_, then_body, else_body) => {
+            breaks_from(then_body, id) || else_body.as_ref().is_some_and(|s| breaks_from(s, id))
+        }
+        StmtKind::While(_, _, body, _) => breaks_from(body, id),
+        _ => false,
+    }
+}
+
+/// Rejects uses of the result of calling a function that may return an implicit null.
+///
+/// `implicit_null[i]` tells whether the end of the body of global `i` is reachable. Only direct
+/// calls of global functions are checked, and a call whose result is discarded by an expression
+/// statement is not a use.
+pub fn check_implicit_null_uses(
+    ctx: &CompilerContext,
+    globals: &[GlobalInfo],
+    implicit_null: &[bool],
+) -> SourceResult<()> {
+    let checker = Checker {
+        ctx,
+        globals,
+        implicit_null,
+    };
+    for global in globals {
+        match &global.kind {
+            GlobalKind::Function(fun_decl) => checker.do_block(&fun_decl.body)?,
+            GlobalKind::Imported(_) | GlobalKind::Native(_) | GlobalKind::Variable => {}
+        }
+    }
+    Ok(())
+}
+
+struct Checker<'a> {
+    ctx: &'a CompilerContext,
+    globals: &'a [GlobalInfo],
+    implicit_null: &'a [bool],
+}
+
+impl Checker<'_> {
+    fn do_block(&self, stmts: &[Stmt]) -> SourceResult<()> {
+        stmts.iter().try_for_each(|stmt| self.do_stmt(stmt))
+    }
+
+    fn do_stmt(&self, stmt: &Stmt) -> SourceResult<()> {
+        match &stmt.kind {
+            StmtKind::Block(stmts) => self.do_block(stmts),
+            StmtKind::Break(_, expr) => expr.iter().try_for_each(|e| self.do_expr(e)),
+            StmtKind::Continue(_) => Ok(()),
+            StmtKind::Defer(body) => self.do_stmt(body),
+            StmtKind::Expr(expr) => self.do_discarded(expr),
+            StmtKind::If(cond, then_body, else_body) => {
+                self.do_expr(cond)?;
+                self.do_stmt(then_body)?;
+                else_body.iter().try_for_each(|s| self.do_stmt(s))
+            }
+            StmtKind::Return(expr)
+            | StmtKind::StoreGlobal(_, expr)
+            | StmtKind::StoreLocal(_, expr)
+            | StmtKind::Unpack(_, expr)
+            | StmtKind::VarDecl(_, Some(expr)) => self.do_expr(expr),
+            StmtKind::VarDecl(_, None) => Ok(()),
+            StmtKind::SetItem(array, index, value) => {
+                self.do_expr(array)?;
+                self.do_expr(index)?;
+                self.do_expr(value)
+            }
+            StmtKind::While(_, cond, body, update) => {
+                self.do_expr(cond)?;
+                self.do_stmt(body)?;
+                match update {
+                    Some(update) => self.do_stmt(update),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+
+    // Checks an expression whose result is discarded
+    fn do_discarded(&self, expr: &Expr) -> SourceResult<()> {
+        match &expr.kind {
+            ExprKind::Call(callee, args) => {
+                self.do_expr(callee)?;
+                args.iter().try_for_each(|arg| self.do_expr(arg))
+            }
+            // the result of the selected branch is discarded as well
+            ExprKind::Conditional(cond, then_expr, else_expr) => {
+                self.do_expr(cond)?;
+                self.do_discarded(then_expr)?;
+                self.do_discarded(else_expr)
+            }
+            _ => self.do_expr(expr),
+        }
+    }
+
+    fn do_expr(&self, expr: &Expr) -> SourceResult<()> {
+        match &expr.kind {
+            ExprKind::Binary(_, _, left, right)
+            | ExprKind::GetItem(left, right)
+            | ExprKind::LogicalBinary(_, _, left, right) => {
+                self.do_expr(left)?;
+                self.do_expr(right)
+            }
+            ExprKind::GetSlice(array, start, end) => {
+                self.do_expr(array)?;
+                self.do_expr(start)?;
+                self.do_expr(end)
+            }
+            ExprKind::Call(callee, args) => {
+                if let ExprKind::LoadGlobal(id) = callee.kind
+                    && self.implicit_null[id.0]
+                {
+                    return err_at(
+                        expr.span,
+                        format!(
+                            "the result of {} is used, but it may return an implicit null",
+                            self.ctx.interner.resolve(self.globals[id.0].name)
+                        ),
+                    );
+                }
+                self.do_expr(callee)?;
+                args.iter().try_for_each(|arg| self.do_expr(arg))
+            }
+            ExprKind::Conditional(cond, then_expr, else_expr) => {
+                self.do_expr(cond)?;
+                self.do_expr(then_expr)?;
+                self.do_expr(else_expr)
+            }
+            ExprKind::ConstBool(_)
+            | ExprKind::ConstFloat(_)
+            | ExprKind::ConstInt(_)
+            | ExprKind::ConstNull
+            | ExprKind::ConstString(_)
+            | ExprKind::LoadBuiltin(_)
+            | ExprKind::LoadGlobal(_)
+            | ExprKind::LoadLocal(_) => Ok(()),
+            ExprKind::MakeList(elements) => elements.iter().try_for_each(|e| self.do_expr(e)),
+            ExprKind::Try(expr) | ExprKind::Unary(_, _, expr) => self.do_expr(expr),
+            ExprKind::While(_, cond, body) => {
+                self.do_expr(cond)?;
+                self.do_stmt(body)
+            }
+        }
+    }
+}
@@ -1,8 +1,9 @@
 use crate::ast::{
-    AssignTarget, AssignTargetKind, Expr, ExprKind, FunDecl, Param, Program, Stmt, StmtKind,
+    AssignTarget, AssignTargetKind, Expr, ExprKind, FunDecl, Import, Param, Program, Stmt, StmtKind,
 };
 use crate::ctx::{CompilerContext, Name};
 use crate::src::Span;
+use crate::util::dot::tree_to_dot;
 use crate::util::tree::{def_formatter, impl_node_debug};
 use std::fmt::{self, Debug, Formatter};
 
@@ -10,9 +11,19 @@ def_formatter!(AstFormatter);
 
 impl_node_debug!(Program as program => ProgramDebug AstFormatter);
 
+impl Program {
+    /// Renders the AST in the DOT format of Graphviz, properties of a node as part of its label.
+    pub fn to_dot(&self, ctx: &CompilerContext) -> String {
+        tree_to_dot("ast", &format!("{:?}", self.debug_without_spans(ctx)))
+    }
+}
+
 impl<'a> Debug for ProgramDebug<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         self.fmt.header(f, "Program", self.program.span)?;
+        for i in self.program.imports.iter() {
+            self.fmt.import(f, i)?
+        }
         for d in self.program.decls.iter() {
             self.fmt.fun_decl(f, d)?
         }
@@ -20,12 +31,25 @@ impl<'a> Debug for ProgramDebug<'a> {
     }
 }
 
+impl_node_debug!(Import as import => ImportDebug AstFormatter);
+
+impl<'a> Debug for ImportDebug<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.fmt
+            .header_with_name(f, "Import", self.import.span, self.import.name)?;
+        self.fmt.property(f, "path", &self.import.path)
+    }
+}
+
 impl_node_debug!(FunDecl as fun_decl => FunDeclDebug AstFormatter);
 
 impl<'a> Debug for FunDeclDebug<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         self.fmt
             .header_with_name(f, "FunDecl", self.fun_decl.name_span, self.fun_decl.name)?;
+        if let Some(doc) = &self.fun_decl.doc {
+            self.fmt.property(f, "doc", doc)?;
+        }
         for p in self.fun_decl.params.iter() {
             self.fmt.param(f, p)?
         }
@@ -63,12 +87,60 @@ impl Debug for StmtDebug<'_> {
                 }
                 Ok(())
             }
-            StmtKind::Break => self.fmt.header(f, "Break", span),
+            StmtKind::Break(expr) => {
+                self.fmt.header(f, "Break", span)?;
+                if let Some(expr) = expr {
+                    self.fmt.expr(f, expr)
+                } else {
+                    Ok(())
+                }
+            }
+            StmtKind::CompileIf {
+                flag,
+                flag_span,
+                value,
+                then_body,
+                else_body,
+            } => {
+                self.fmt.header(f, "CompileIf", span)?;
+                self.fmt
+                    .property_name_with_span(f, "flag", *flag, *flag_span)?;
+                if let Some(value) = value {
+                    self.fmt.property(f, "value", value)?;
+                }
+                self.fmt.stmt(f, then_body)?;
+                if let Some(else_body) = else_body {
+                    self.fmt.stmt(f, else_body)?;
+                };
+                Ok(())
+            }
             StmtKind::Continue => self.fmt.header(f, "Continue", span),
+            StmtKind::Defer(body) => {
+                self.fmt.header(f, "Defer", span)?;
+                self.fmt.stmt(f, body)
+            }
             StmtKind::Expr(expr) => {
                 self.fmt.header(f, "Expr", span)?;
                 self.fmt.expr(f, expr)
             }
+            StmtKind::For {
+                init,
+                cond,
+                update,
+                body,
+            } => {
+                self.fmt.header(f, "For", span)?;
+                for stmt in init {
+                    self.fmt.stmt(f, stmt)?;
+                }
+                if let Some(cond) = cond {
+                    self.fmt.expr(f, cond)?;
+                }
+                if let Some(update) = update {
+                    self.fmt.stmt(f, update)?;
+                }
+                self.fmt.stmt(f, body)
+            }
             StmtKind::If {
                 cond,
                 then_body,
@@ -90,6 +162,13 @@ impl Debug for StmtDebug<'_> {
                     Ok(())
                 }
             }
+            StmtKind::Unpack { targets, value } => {
+                self.fmt.header(f, "Unpack", span)?;
+                for target in targets {
+                    self.fmt.assign_target(f, target)?;
+                }
+                self.fmt.expr(f, value)
+            }
             StmtKind::VarDecl {
                 name,
                 name_span,
@@ -98,7 +177,11 @@ impl Debug for StmtDebug<'_> {
                 self.fmt.header(f, "VarDecl", span)?;
                 self.fmt
                     .property_name_with_span(f, "name", *name, *name_span)?;
-                self.fmt.expr(f, init)
+                if let Some(init) = init {
+                    self.fmt.expr(f, init)
+                } else {
+                    Ok(())
+                }
             }
             StmtKind::While { cond, body } => {
                 self.fmt.header(f, "While", span)?;
@@ -142,6 +225,16 @@ impl Debug for ExprDebug<'_> {
                 }
                 Ok(())
             }
+            ExprKind::Conditional {
+                cond,
+                then_expr,
+                else_expr,
+            } => {
+                self.fmt.header(f, "Conditional", span)?;
+                self.fmt.expr(f, cond)?;
+                self.fmt.expr(f, then_expr)?;
+                self.fmt.expr(f, else_expr)
+            }
             ExprKind::FloatLiteral(value) => {
                 self.fmt.header_with_value(f, "FloatLiteral", span, value)
             }
@@ -169,15 +262,44 @@ impl Debug for ExprDebug<'_> {
                 self.fmt.header(f, "Paren", span)?;
                 self.fmt.expr(f, inner)
             }
+            ExprKind::Qualified {
+                module,
+                module_span,
+                name,
+            } => {
+                self.fmt.header_with_name(f, "Qualified", span, *name)?;
+                self.fmt
+                    .property_name_with_span(f, "module", *module, *module_span)
+            }
+            ExprKind::Slice { array, start, end } => {
+                self.fmt.header(f, "Slice", span)?;
+                self.fmt.expr(f, array)?;
+                for (name, bound) in [("start", start), ("end", end)] {
+                    match bound {
+                        Some(bound) => self.fmt.expr(f, bound)?,
+                        None => self.fmt.property(f, name, None::<()>)?,
+                    }
+                }
+                Ok(())
+            }
             ExprKind::StringLiteral(value) => {
                 self.fmt.header_with_value(f, "StringLiteral", span, value)
             }
+            ExprKind::Try(expr) => {
+                self.fmt.header(f, "Try", span)?;
+                self.fmt.expr(f, expr)
+            }
             ExprKind::Unary { op, op_span, expr } => {
                 self.fmt.header(f, "Unary", span)?;
                 self.fmt.property_with_span(f, "op", *op, *op_span)?;
                 self.fmt.expr(f, expr)
             }
             ExprKind::Var(name) => self.fmt.header_with_name(f, "Var", span, *name),
+            ExprKind::While { cond, body } => {
+                self.fmt.header(f, "While", span)?;
+                self.fmt.expr(f, cond)?;
+                self.fmt.stmt(f, body)
+            }
         }
     }
 }
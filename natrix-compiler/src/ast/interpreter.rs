@@ -1,27 +1,31 @@
+use crate::analyze::{check_fun_decls, embed};
 use crate::ast::{AssignTargetKind, Expr, ExprKind, FunDecl, Program, Stmt, StmtKind};
 use crate::ctx::{CompilerContext, Name};
-use crate::error::{err_at, AttachErrSpan, SourceResult};
+use crate::error::{err_at, error_at, AttachErrSpan, SourceResult};
+use crate::module::ModuleId;
+use crate::parser::ReplItem;
 use crate::src::Span;
 use natrix_runtime::ctx::RuntimeContext;
-use natrix_runtime::error::{nx_err, nx_error, NxResult};
-use natrix_runtime::value::{Builtin, Function, Value, ValueType};
+use natrix_runtime::error::{nx_err, nx_err_of, nx_error, ErrorKind, NxResult};
+use natrix_runtime::value::{BinaryOp, Builtin, Function, IntoValue, UnaryOp, Value, ValueType};
 use std::cell::RefCell;
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::Into;
 use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 enum StmtFlow {
-    Next,           // Normal execution continues
-    Return(Value),  // Early return from function
-    Break(Span),    // Exit innermost loop
-    Continue(Span), // Skip to next loop iteration
+    Next,                       // Normal execution continues
+    Return(Span, Value),        // Early return from function
+    Break(Span, Option<Value>), // Exit innermost loop (with the value of a loop expression)
+    Continue(Span),             // Skip to next loop iteration
 }
 
 struct Env {
     vars: RefCell<HashMap<Name, Value>>,
+    unassigned: RefCell<HashSet<Name>>, // declared by `var x;` and not assigned yet
     parent: Option<Rc<Env>>,
+    continues: bool, // same scope as the parent, see `Env::continuation`
 }
 
 impl Env {
@@ -33,23 +37,53 @@ impl Env {
                 Value::from_function(Rc::new(Function::Builtin(*builtin))),
             );
         }
+        for native in ctx.natives() {
+            vars.insert(
+                ctx.interner.lookup(native.name()).unwrap(),
+                Value::from_function(native.clone()),
+            );
+        }
         let env = Rc::new(Env {
             vars: RefCell::new(vars),
+            unassigned: RefCell::new(HashSet::new()),
             parent: None,
+            continues: false,
         });
-        // wrap builtin (read-only) scope in a global, writable scope
+        // wrap builtin and native (read-only) scope in a global, writable scope
         Env::new(env)
     }
 
     fn new(parent: Rc<Env>) -> Rc<Env> {
         Rc::new(Self {
             vars: RefCell::new(HashMap::new()),
+            unassigned: RefCell::new(HashSet::new()),
             parent: Some(parent),
+            continues: false,
         })
     }
 
+    // The rest of the scope of `env` after a defer, so that the deferred statement does not see
+    // the variables declared after it. Declarations still conflict with the ones of `env`.
+    fn continuation(env: Rc<Env>) -> Rc<Env> {
+        Rc::new(Self {
+            vars: RefCell::new(HashMap::new()),
+            unassigned: RefCell::new(HashSet::new()),
+            parent: Some(env),
+            continues: true,
+        })
+    }
+
+    fn declares(&self, name: Name) -> bool {
+        self.vars.borrow().contains_key(&name)
+            || self.continues && self.parent.as_ref().is_some_and(|p| p.declares(name))
+    }
+
     fn lookup(&self, ctx: &CompilerContext, name: &Name) -> NxResult<Value> {
         match self.vars.borrow().get(name).cloned() {
+            Some(_) if self.unassigned.borrow().contains(name) => nx_err(format!(
+                "variable {} is used before it is assigned",
+                ctx.interner.resolve(*name)
+            )),
             Some(val) => Ok(val),
             None => match &self.parent {
                 Some(parent) => parent.lookup(ctx, name),
@@ -62,16 +96,27 @@ impl Env {
     }
 
     fn declare(&self, ctx: &CompilerContext, name: Name, value: Value) -> NxResult<()> {
-        match self.vars.borrow_mut().entry(name) {
-            Entry::Vacant(e) => {
-                e.insert(value);
-                Ok(())
-            }
-            Entry::Occupied(_) => nx_err(format!(
+        if self.declares(name) {
+            return nx_err(format!(
                 "symbol {} already defined in this scope",
                 ctx.interner.resolve(name)
-            )),
+            ));
         }
+        self.vars.borrow_mut().insert(name, value);
+        Ok(())
+    }
+
+    // Declares `name` without a value, it cannot be read before it is assigned.
+    fn declare_unassigned(&self, ctx: &CompilerContext, name: Name) -> NxResult<()> {
+        self.declare(ctx, name, Value::NULL)?;
+        self.unassigned.borrow_mut().insert(name);
+        Ok(())
+    }
+
+    // Declares `name`, replacing any previous declaration in this scope.
+    fn define(&self, name: Name, value: Value) {
+        self.vars.borrow_mut().insert(name, value);
+        self.unassigned.borrow_mut().remove(&name);
     }
 
     fn assign(&self, ctx: &CompilerContext, name: Name, value: Value) -> NxResult<()> {
@@ -80,6 +125,7 @@ impl Env {
                 nx_err("built-in function cannot be assigned to")
             } else {
                 *slot = value;
+                self.unassigned.borrow_mut().remove(&name);
                 Ok(())
             }
         } else {
@@ -94,86 +140,323 @@ impl Env {
     }
 }
 
+/// Functions and variables that persist between the inputs of an interactive session.
+pub struct Session {
+    globals: Rc<Env>, // functions, and in the REPL also top-level variables
+    modules: HashMap<ModuleId, Rc<Env>>, // the functions of each imported module
+    fun_decls: Vec<Rc<FunDecl>>,
+}
+
+impl Session {
+    pub fn new(ctx: &CompilerContext) -> Self {
+        Self {
+            globals: Env::new_root(ctx),
+            modules: HashMap::new(),
+            fun_decls: Vec::new(),
+        }
+    }
+
+    // The scope of the functions of `module`
+    fn module_env(&self, module: ModuleId) -> Rc<Env> {
+        match module {
+            ModuleId::ROOT => self.globals.clone(),
+            _ => self.modules[&module].clone(),
+        }
+    }
+
+    /// Functions and top-level variables declared in the session, excluding builtins.
+    pub fn globals(&self) -> Vec<(Name, Value)> {
+        let vars = self.globals.vars.borrow();
+        vars.iter()
+            .map(|(name, value)| (*name, value.clone()))
+            .collect()
+    }
+
+    /// Names of all builtins, functions and top-level variables visible in the session.
+    pub fn names(&self) -> Vec<Name> {
+        let mut names = Vec::new();
+        let mut env = Some(&self.globals);
+        while let Some(e) = env {
+            names.extend(e.vars.borrow().keys().copied());
+            env = e.parent.as_ref();
+        }
+        names
+    }
+}
+
+/// Evaluates the syntax tree of a program directly.
+///
+/// Scripts only run to completion: the state of a script in progress is the Rust call stack of
+/// the recursive evaluation, which cannot be kept between calls. A host interleaving a script with
+/// its own work uses the `start` and `step` of the bytecode interpreter, whose state is data.
 pub struct Interpreter<'a> {
     ctx: &'a CompilerContext,
     rt: &'a mut RuntimeContext,
-    globals: Rc<Env>,
-    fun_decls: Vec<Rc<FunDecl>>,
+    session: Session,
+    module: ModuleId,      // the module of the function being run
+    warned: HashSet<Span>, // operators already reported by a warning
+    args: Vec<Value>,      // arguments of the calls being made, shared so calls don't allocate
+    tries: usize,          // `try` expressions being evaluated, which catch an error
+    decls_checked: bool,   // the analysis already checked the function declarations
 }
 
 impl<'a> Interpreter<'a> {
     pub fn new(ctx: &'a CompilerContext, rt: &'a mut RuntimeContext) -> Self {
-        let globals = Env::new_root(ctx);
+        Self::with_session(ctx, rt, Session::new(ctx))
+    }
+
+    pub fn with_session(
+        ctx: &'a CompilerContext,
+        rt: &'a mut RuntimeContext,
+        session: Session,
+    ) -> Self {
         Self {
             ctx,
             rt,
-            globals,
-            fun_decls: Vec::new(),
+            session,
+            module: ModuleId::ROOT,
+            warned: HashSet::new(),
+            args: Vec::new(),
+            tries: 0,
+            decls_checked: false,
         }
     }
 
+    /// Skips the checks of the function declarations, for a program that `analyze` already
+    /// checked, so that their warnings are only reported once.
+    pub fn skip_decl_checks(&mut self) {
+        self.decls_checked = true;
+    }
+
+    pub fn into_session(self) -> Session {
+        self.session
+    }
+
     pub fn run(&mut self, program: Program, args: Vec<Value>) -> SourceResult<Value> {
-        let main_name = self.ctx.interner.lookup("main");
-        let mut main_fun: Option<(Value, Span)> = None;
+        self.run_function(program, "main", args)
+    }
+
+    /// Like [`run`](Self::run), but calls the function `name` instead of `main`.
+    pub fn run_function(
+        &mut self,
+        program: Program,
+        name: &str,
+        args: Vec<Value>,
+    ) -> SourceResult<Value> {
+        if !self.decls_checked {
+            check_fun_decls(self.ctx, &program.decls)?;
+        }
+        let entry_name = self.ctx.interner.lookup(name);
+        let mut entry_fun: Option<(Value, Span)> = None;
         for decl in program.decls {
-            let index = self.fun_decls.len();
-            let fun_obj = Value::from_function(Rc::new(Function::UserDefined {
-                name: self.ctx.interner.resolve(decl.name).into(),
-                param_count: decl.params.len(),
-                max_slots: 0,
-                code_handle: index,
-            }));
-            if main_name == Some(decl.name) {
-                main_fun = Some((fun_obj.clone(), decl.name_span));
+            let fun_obj = self.function_object(&decl);
+            if entry_name == Some(decl.name) && decl.module == ModuleId::ROOT {
+                entry_fun = Some((fun_obj.clone(), decl.name_span));
+            }
+            if decl.module != ModuleId::ROOT {
+                self.session
+                    .modules
+                    .entry(decl.module)
+                    .or_insert_with(|| Env::new_root(self.ctx));
             }
-            self.globals
+            self.session
+                .module_env(decl.module)
                 .declare(self.ctx, decl.name, fun_obj)
                 .err_at(decl.name_span)?;
-            self.fun_decls.push(Rc::new(decl));
+            self.session.fun_decls.push(Rc::new(decl));
         }
-        match main_fun {
-            Some((fun_decl, span)) => self.dispatch(span, fun_decl, args),
-            None => err_at(program.span, "no main function defined"),
+        match entry_fun {
+            Some((fun_decl, span)) => {
+                let base = self.args.len();
+                self.args.extend(args);
+                // the entry point is called by the host, not from a call site
+                self.call(span, fun_decl, base).map_err(|mut err| {
+                    err.trace.pop();
+                    err
+                })
+            }
+            None if name == "main" => err_at(program.span, "no main function defined"),
+            None => err_at(program.span, format!("no function named {} defined", name)),
         }
     }
 
-    fn dispatch(&mut self, span: Span, callee: Value, args: Vec<Value>) -> SourceResult<Value> {
+    /// Executes interactive input in the session.
+    ///
+    /// Functions and top-level variables may be redeclared, replacing the previous declaration.
+    /// Returns the value of the last item if it is an expression statement, null otherwise.
+    pub fn exec(&mut self, items: Vec<ReplItem>) -> SourceResult<Value> {
+        let globals = self.session.globals.clone();
+        let mut result = Value::NULL;
+        for item in items {
+            result = Value::NULL;
+            match item {
+                ReplItem::FunDecl(decl) => {
+                    let fun_obj = self.function_object(&decl);
+                    self.session.globals.define(decl.name, fun_obj);
+                    self.session.fun_decls.push(Rc::new(decl));
+                }
+                ReplItem::Stmt(stmt) => match &stmt.kind {
+                    StmtKind::Expr(expr) => result = self.eval(&globals, expr)?,
+                    StmtKind::VarDecl { name, init, .. } => match init {
+                        Some(init) => {
+                            let value = self.eval(&globals, init)?;
+                            globals.define(*name, value);
+                        }
+                        None => {
+                            globals.define(*name, Value::NULL);
+                            globals.unassigned.borrow_mut().insert(*name);
+                        }
+                    },
+                    _ => match self.do_stmt(&globals, &stmt)? {
+                        StmtFlow::Next => {}
+                        StmtFlow::Return(span, _) => {
+                            return err_at(span, "return outside a function")
+                        }
+                        StmtFlow::Break(span, _) => return err_at(span, "break outside a loop"),
+                        StmtFlow::Continue(span) => {
+                            return err_at(span, "continue outside a loop");
+                        }
+                    },
+                },
+            }
+        }
+        Ok(result)
+    }
+
+    fn function_object(&self, decl: &FunDecl) -> Value {
+        Value::from_function(Rc::new(Function::UserDefined {
+            name: self
+                .ctx
+                .interner
+                .resolve(self.ctx.modules.global_name(decl.module, decl.name))
+                .into(),
+            param_count: decl.params.len(),
+            max_slots: 0,
+            code_handle: self.session.fun_decls.len(),
+            location: Some(decl.name_span.location(&self.ctx.sources).into()),
+        }))
+    }
+
+    /// Calls `callee` with the arguments pushed to `self.args` from `base` on, and removes them.
+    fn call(&mut self, span: Span, callee: Value, base: usize) -> SourceResult<Value> {
+        let result = self.dispatch(span, callee, base);
+        self.args.truncate(base);
+        result
+    }
+
+    fn dispatch(&mut self, span: Span, callee: Value, base: usize) -> SourceResult<Value> {
         if !callee.is_function() {
-            return err_at(span, format!("not a function: {}", callee));
+            return nx_err_of(ErrorKind::Type, format!("not a function: {}", callee)).err_at(span);
         }
         let fun_obj = callee.unwrap_function();
-        fun_obj.check_args(args.len()).err_at(span)?;
+        fun_obj.check_args(self.args.len() - base).err_at(span)?;
         match fun_obj.as_ref() {
-            Function::Builtin(builtin) => builtin.eval(self.rt, &args).err_at(span),
-            Function::UserDefined { code_handle, .. } => {
-                self.invoke(self.fun_decls.get(*code_handle).unwrap().clone(), args)
+            Function::Builtin(Builtin::TimeIt) => {
+                let started = self.rt.now();
+                let fun = self.args.pop().unwrap();
+                if !fun.is_function() {
+                    return nx_err_of(ErrorKind::Type, "time_it expects a function").err_at(span);
+                }
+                self.dispatch(span, fun, base)?;
+                Ok(Value::from_float(self.rt.now() - started))
+            }
+            Function::Builtin(builtin) => {
+                let value = builtin.eval(self.rt, &self.args[base..]).err_at(span)?;
+                self.rt.track_alloc(&value);
+                Ok(value)
             }
+            Function::Native { fun, .. } => {
+                let value = fun.call(self.rt, &self.args[base..]).err_at(span)?;
+                self.rt.track_alloc(&value);
+                Ok(value)
+            }
+            Function::UserDefined {
+                name, code_handle, ..
+            } => self
+                .invoke(self.session.fun_decls[*code_handle].clone(), base)
+                .map_err(|err| err.in_call(name, span)),
         }
     }
 
-    fn invoke(&mut self, fun_decl: Rc<FunDecl>, args: Vec<Value>) -> SourceResult<Value> {
-        let env = Env::new(self.globals.clone());
-        for (param, arg) in fun_decl.params.iter().zip(args) {
+    fn invoke(&mut self, fun_decl: Rc<FunDecl>, base: usize) -> SourceResult<Value> {
+        let env = Env::new(self.session.module_env(fun_decl.module));
+        for (param, arg) in fun_decl.params.iter().zip(self.args.drain(base..)) {
             env.declare(self.ctx, param.name, arg)
                 .err_at(param.name_span)?;
         }
-        match self.do_block(&env, &fun_decl.body)? {
+        let caller_module = std::mem::replace(&mut self.module, fun_decl.module);
+        let result = self.run_body(&env, &fun_decl);
+        self.module = caller_module;
+        result
+    }
+
+    fn run_body(&mut self, env: &Rc<Env>, fun_decl: &FunDecl) -> SourceResult<Value> {
+        self.safepoint(fun_decl.name_span)?;
+        match self.do_block(env, &fun_decl.body)? {
             StmtFlow::Next => Ok(Value::NULL),
-            StmtFlow::Return(value) => Ok(value),
-            StmtFlow::Break(span) => err_at(span, "break outside a loop"),
+            StmtFlow::Return(_, value) => Ok(value),
+            StmtFlow::Break(span, _) => err_at(span, "break outside a loop"),
             StmtFlow::Continue(span) => err_at(span, "continue outside a loop"),
         }
     }
 
+    // The variables of the function running in `env` for `locals()`, an inner declaration hides
+    // the outer ones of the same name
+    fn locals(&self, env: &Rc<Env>) -> Value {
+        let mut locals = HashMap::new();
+        let globals = self.session.module_env(self.module);
+        let mut scope = Some(env);
+        while let Some(env) = scope.filter(|env| !Rc::ptr_eq(env, &globals)) {
+            for (name, value) in env.vars.borrow().iter() {
+                locals
+                    .entry(self.ctx.interner.resolve(*name).to_string())
+                    .or_insert_with(|| value.clone());
+            }
+            scope = env.parent.as_ref();
+        }
+        locals.into_value()
+    }
+
+    // Calls the interrupt handler if the process was interrupted, at the same points as the
+    // bytecode interpreter: the start of a function and the end of an iteration of a loop
+    fn safepoint(&mut self, span: Span) -> SourceResult<()> {
+        if let Some(handler) = self.rt.take_interrupt() {
+            let base = self.args.len();
+            self.call(span, handler, base)?;
+        }
+        Ok(())
+    }
+
     fn do_block(&mut self, env: &Rc<Env>, stmts: &Vec<Stmt>) -> SourceResult<StmtFlow> {
-        let inner_env = Env::new(env.clone());
+        let mut inner_env = Env::new(env.clone());
+        // cleanup list, run on any exit except an error that no `try` catches
+        let mut deferred = Vec::new();
+        let mut result = Ok(StmtFlow::Next);
         for stmt in stmts {
-            let flow = self.do_stmt(&inner_env, stmt)?;
-            if !matches!(flow, StmtFlow::Next) {
-                return Ok(flow);
+            if let StmtKind::Defer(body) = &stmt.kind {
+                deferred.push((body, inner_env.clone()));
+                inner_env = Env::continuation(inner_env);
+                continue;
+            }
+            result = self.do_stmt(&inner_env, stmt);
+            if !matches!(result, Ok(StmtFlow::Next)) {
+                break;
+            }
+        }
+        for (body, env) in deferred.into_iter().rev() {
+            if result.is_err() && self.tries == 0 {
+                break;
+            }
+            // an error of a deferred statement replaces the one being unwound
+            match self.do_stmt(&env, body) {
+                Ok(StmtFlow::Next) => {}
+                Ok(StmtFlow::Return(span, _)) => return err_at(span, "return inside a defer"),
+                Ok(StmtFlow::Break(span, _)) => return err_at(span, "break outside a loop"),
+                Ok(StmtFlow::Continue(span)) => return err_at(span, "continue outside a loop"),
+                Err(err) => result = Err(err),
             }
         }
-        Ok(StmtFlow::Next)
+        result
     }
 
     fn do_stmt(&mut self, env: &Rc<Env>, stmt: &Stmt) -> SourceResult<StmtFlow> {
@@ -194,12 +477,49 @@ impl<'a> Interpreter<'a> {
                 Ok(StmtFlow::Next)
             }
             StmtKind::Block(stmts) => self.do_block(env, &stmts),
-            StmtKind::Break => Ok(StmtFlow::Break(stmt.span)),
+            StmtKind::Break(expr) => {
+                let value = match expr {
+                    Some(expr) => Some(self.eval(env, expr)?),
+                    None => None,
+                };
+                Ok(StmtFlow::Break(stmt.span, value))
+            }
             StmtKind::Continue => Ok(StmtFlow::Continue(stmt.span)),
+            StmtKind::Defer(_) => unreachable!("defer outside a block"),
             StmtKind::Expr(expr) => {
                 self.eval(env, expr)?;
                 Ok(StmtFlow::Next)
             }
+            StmtKind::For {
+                init,
+                cond,
+                update,
+                body,
+            } => {
+                let env = Env::new(env.clone());
+                for stmt in init {
+                    self.do_stmt(&env, stmt)?;
+                }
+                while match cond {
+                    Some(cond) => self.eval_bool(&env, cond)?,
+                    None => true,
+                } {
+                    match self.do_stmt(&env, body)? {
+                        StmtFlow::Next => {}
+                        StmtFlow::Break(_, None) => break,
+                        StmtFlow::Break(span, Some(_)) => {
+                            return err_at(span, "break with a value outside a loop expression");
+                        }
+                        StmtFlow::Continue(_) => {}
+                        flow @ StmtFlow::Return(..) => return Ok(flow),
+                    }
+                    if let Some(update) = update {
+                        self.do_stmt(&env, update)?;
+                    }
+                    self.safepoint(stmt.span)?;
+                }
+                Ok(StmtFlow::Next)
+            }
             StmtKind::If {
                 cond,
                 then_body,
@@ -213,102 +533,372 @@ impl<'a> Interpreter<'a> {
                     Ok(StmtFlow::Next)
                 }
             }
+            StmtKind::CompileIf {
+                flag,
+                value,
+                then_body,
+                else_body,
+                ..
+            } => {
+                if self.ctx.flag_is_set(*flag, value.as_deref()) {
+                    self.do_stmt(env, then_body)
+                } else if let Some(else_body) = else_body {
+                    self.do_stmt(env, else_body)
+                } else {
+                    Ok(StmtFlow::Next)
+                }
+            }
             StmtKind::Return(expr) => {
                 let value = match expr {
                     Some(expr) => self.eval(env, expr)?,
                     None => Value::NULL,
                 };
-                Ok(StmtFlow::Return(value))
+                Ok(StmtFlow::Return(stmt.span, value))
+            }
+            StmtKind::Unpack { targets, value } => {
+                let values = self
+                    .eval(env, value)?
+                    .unpack(targets.len())
+                    .err_at(value.span)?;
+                for (target, val) in targets.iter().zip(values) {
+                    match &target.kind {
+                        AssignTargetKind::Var(name) => {
+                            env.assign(self.ctx, *name, val).err_at(target.span)?;
+                        }
+                        AssignTargetKind::ArrayAccess { array, index } => {
+                            let array = self.eval(env, array)?;
+                            let index = self.eval(env, index)?;
+                            array.set_item(index, val).err_at(target.span)?;
+                        }
+                    }
+                }
+                Ok(StmtFlow::Next)
             }
             StmtKind::VarDecl {
                 name,
                 name_span,
                 init,
             } => {
-                let val = self.eval(env, init)?;
-                env.declare(self.ctx, *name, val).err_at(*name_span)?;
+                match init {
+                    Some(init) => {
+                        let val = self.eval(env, init)?;
+                        env.declare(self.ctx, *name, val).err_at(*name_span)?;
+                    }
+                    None => env.declare_unassigned(self.ctx, *name).err_at(*name_span)?,
+                }
                 Ok(StmtFlow::Next)
             }
             StmtKind::While { cond, body } => {
                 while self.eval_bool(env, cond)? {
                     match self.do_stmt(&env, body)? {
                         StmtFlow::Next => {}
-                        StmtFlow::Break(_) => break,
-                        StmtFlow::Continue(_) => continue,
-                        StmtFlow::Return(value) => return Ok(StmtFlow::Return(value)),
+                        StmtFlow::Break(_, None) => break,
+                        StmtFlow::Break(span, Some(_)) => {
+                            return err_at(span, "break with a value outside a loop expression");
+                        }
+                        StmtFlow::Continue(_) => {}
+                        flow @ StmtFlow::Return(..) => return Ok(flow),
                     }
+                    self.safepoint(stmt.span)?;
                 }
                 Ok(StmtFlow::Next)
             }
         }
     }
 
+    // Expressions with operands are evaluated by methods of their own, so that a level of a deeply
+    // nested expression only takes the stack of its own kind (unoptimized builds give every arm of
+    // a match its own stack slots)
     fn eval(&mut self, env: &Rc<Env>, expr: &Expr) -> SourceResult<Value> {
         match &expr.kind {
             ExprKind::ArrayAccess { array, index } => {
-                let array = self.eval(env, array)?;
-                let index = self.eval(env, index)?;
-                array.get_item(index).err_at(expr.span)
+                self.eval_array_access(env, array, index, expr.span)
             }
             ExprKind::Binary {
                 op,
                 op_span,
                 left,
                 right,
-            } => {
-                let left = self.eval(env, left)?;
-                let right = self.eval(env, right)?;
-                op.eval(&left, &right).err_at(*op_span)
-            }
+            } => self.eval_binary(env, *op, *op_span, left, right),
             ExprKind::BoolLiteral(value) => Ok(Value::from_bool(*value)),
-            ExprKind::Call { callee, args } => {
-                let callee = self.eval(env, callee)?;
-                let mut arg_values = Vec::with_capacity(args.len());
-                for arg in args {
-                    arg_values.push(self.eval(env, arg)?);
-                }
-                self.dispatch(expr.span, callee, arg_values)
-            }
+            ExprKind::Call { callee, args } => self.eval_call(env, callee, args, expr.span),
+            ExprKind::Conditional {
+                cond,
+                then_expr,
+                else_expr,
+            } => self.eval_conditional(env, cond, then_expr, else_expr),
             ExprKind::FloatLiteral(value) => Ok(Value::from_float(*value)),
             ExprKind::IntLiteral(value) => Ok(Value::from_int(*value)),
-            ExprKind::ListLiteral(exprs) => {
-                let mut values = Vec::with_capacity(exprs.len());
-                for expr in exprs {
-                    values.push(self.eval(env, expr)?);
-                }
-                Ok(Value::from_list(Rc::new(RefCell::new(values))))
-            }
+            ExprKind::ListLiteral(exprs) => self.eval_list(env, exprs),
             ExprKind::LogicalBinary {
                 and,
                 op_span: _,
                 left,
                 right,
-            } => {
-                if *and {
-                    Ok(Value::from_bool(
-                        self.eval_bool(env, left)? && self.eval_bool(env, right)?,
-                    ))
-                } else {
-                    Ok(Value::from_bool(
-                        self.eval_bool(env, left)? || self.eval_bool(env, right)?,
-                    ))
-                }
-            }
+            } => self.eval_logical_binary(env, *and, left, right),
             ExprKind::NullLiteral => Ok(Value::NULL),
             ExprKind::Paren(inner) => self.eval(env, inner),
+            ExprKind::Qualified {
+                module,
+                module_span,
+                name,
+            } => self.eval_qualified(*module, *module_span, *name, expr.span),
+            ExprKind::Slice { array, start, end } => {
+                self.eval_slice(env, array, start, end, expr.span)
+            }
             ExprKind::StringLiteral(value) => Ok(Value::from_string(value.clone())),
-            ExprKind::Unary { op, op_span, expr } => {
-                let val = self.eval(env, expr)?;
-                op.eval(&val).err_at(*op_span)
+            ExprKind::Try(inner) => self.eval_try(env, inner),
+            ExprKind::Unary { op, op_span, expr } => self.eval_unary(env, *op, *op_span, expr),
+            ExprKind::Var(name) => self.eval_var(env, name, expr.span),
+            ExprKind::While { cond, body } => self.eval_while(env, cond, body, expr.span),
+        }
+    }
+
+    fn eval_array_access(
+        &mut self,
+        env: &Rc<Env>,
+        array: &Expr,
+        index: &Expr,
+        span: Span,
+    ) -> SourceResult<Value> {
+        let array = self.eval(env, array)?;
+        let index = self.eval(env, index)?;
+        let item = array.get_item(index).err_at(span)?;
+        self.rt.track_alloc(&item);
+        Ok(item)
+    }
+
+    fn eval_binary(
+        &mut self,
+        env: &Rc<Env>,
+        op: BinaryOp,
+        op_span: Span,
+        left: &Expr,
+        right: &Expr,
+    ) -> SourceResult<Value> {
+        let left = self.eval(env, left)?;
+        let right = self.eval(env, right)?;
+        if self.rt.is_strict() {
+            op.check_strict(&left, &right).err_at(op_span)?;
+        } else if self.rt.warns_numeric_eq()
+            && let Some(message) = op.numeric_eq_warning(&left, &right)
+            && self.warned.insert(op_span)
+        {
+            let warning = error_at(op_span, message);
+            let text = warning.display_as_warning(&self.ctx.sources).to_string();
+            self.rt.warn(&text);
+        }
+        let value = op.eval(&left, &right).err_at(op_span)?;
+        self.rt.track_alloc(&value);
+        Ok(value)
+    }
+
+    fn eval_call(
+        &mut self,
+        env: &Rc<Env>,
+        callee: &Expr,
+        args: &[Expr],
+        span: Span,
+    ) -> SourceResult<Value> {
+        let direct = matches!(callee.kind,
+            ExprKind::Var(name) if self.ctx.interner.resolve(name) == Builtin::Embed.name());
+        let callee = self.eval(env, callee)?;
+        // like in the compiler, only `embed(...)` itself reads the file
+        if direct
+            && callee.is_function()
+            && let Function::Builtin(Builtin::Embed) = callee.unwrap_function().as_ref()
+        {
+            let content = embed(self.ctx, args, span)?;
+            return Ok(Value::from_string(content.into()));
+        }
+        if args.is_empty()
+            && callee.is_function()
+            && let Function::Builtin(Builtin::Locals) = callee.unwrap_function().as_ref()
+        {
+            // like in bytecode, where the names of the slots are only kept with debug info
+            if !self.ctx.debug_info {
+                return nx_err("locals needs debug info, see --debug").err_at(span);
+            }
+            let value = self.locals(env);
+            self.rt.track_alloc(&value);
+            return Ok(value);
+        }
+        let base = self.args.len();
+        for arg in args {
+            match self.eval(env, arg) {
+                Ok(value) => self.args.push(value),
+                Err(err) => {
+                    self.args.truncate(base);
+                    return Err(err);
+                }
+            }
+        }
+        self.call(span, callee, base)
+    }
+
+    fn eval_conditional(
+        &mut self,
+        env: &Rc<Env>,
+        cond: &Expr,
+        then_expr: &Expr,
+        else_expr: &Expr,
+    ) -> SourceResult<Value> {
+        if self.eval_bool(env, cond)? {
+            self.eval(env, then_expr)
+        } else {
+            self.eval(env, else_expr)
+        }
+    }
+
+    fn eval_list(&mut self, env: &Rc<Env>, exprs: &[Expr]) -> SourceResult<Value> {
+        let mut values = Vec::with_capacity(exprs.len());
+        for expr in exprs {
+            values.push(self.eval(env, expr)?);
+        }
+        let list = Value::from_list(Rc::new(values));
+        self.rt.track_alloc(&list);
+        Ok(list)
+    }
+
+    fn eval_logical_binary(
+        &mut self,
+        env: &Rc<Env>,
+        and: bool,
+        left: &Expr,
+        right: &Expr,
+    ) -> SourceResult<Value> {
+        if and {
+            Ok(Value::from_bool(
+                self.eval_bool(env, left)? && self.eval_bool(env, right)?,
+            ))
+        } else {
+            Ok(Value::from_bool(
+                self.eval_bool(env, left)? || self.eval_bool(env, right)?,
+            ))
+        }
+    }
+
+    fn eval_qualified(
+        &mut self,
+        module: Name,
+        module_span: Span,
+        name: Name,
+        span: Span,
+    ) -> SourceResult<Value> {
+        let Some(target) = self.ctx.modules.resolve(self.module, module) else {
+            return err_at(
+                module_span,
+                format!(
+                    "no module named {} is imported",
+                    self.ctx.interner.resolve(module)
+                ),
+            );
+        };
+        if self.ctx.modules.is_library(target) {
+            return err_at(
+                span,
+                format!(
+                    "module {} is a precompiled library, which only runs as bytecode",
+                    self.ctx.interner.resolve(module)
+                ),
+            );
+        }
+        let module_env = self.session.module_env(target);
+        if !module_env.declares(name) {
+            return err_at(
+                span,
+                format!(
+                    "module {} has no function {}",
+                    self.ctx.interner.resolve(module),
+                    self.ctx.interner.resolve(name)
+                ),
+            );
+        }
+        module_env.lookup(self.ctx, &name).err_at(span)
+    }
+
+    fn eval_slice(
+        &mut self,
+        env: &Rc<Env>,
+        array: &Expr,
+        start: &Option<Box<Expr>>,
+        end: &Option<Box<Expr>>,
+        span: Span,
+    ) -> SourceResult<Value> {
+        let array = self.eval(env, array)?;
+        let start = match start {
+            Some(start) => self.eval(env, start)?,
+            None => Value::NULL,
+        };
+        let end = match end {
+            Some(end) => self.eval(env, end)?,
+            None => Value::NULL,
+        };
+        let slice = array.get_slice(start, end).err_at(span)?;
+        self.rt.track_alloc(&slice);
+        Ok(slice)
+    }
+
+    // calls interrupted by the error have already released their arguments
+    fn eval_try(&mut self, env: &Rc<Env>, inner: &Expr) -> SourceResult<Value> {
+        self.tries += 1;
+        let result = self.eval(env, inner);
+        self.tries -= 1;
+        Ok(result.unwrap_or(Value::NULL))
+    }
+
+    fn eval_unary(
+        &mut self,
+        env: &Rc<Env>,
+        op: UnaryOp,
+        op_span: Span,
+        expr: &Expr,
+    ) -> SourceResult<Value> {
+        let val = self.eval(env, expr)?;
+        if self.rt.is_strict() {
+            op.check_strict(&val).err_at(op_span)?;
+        }
+        op.eval(&val).err_at(op_span)
+    }
+
+    fn eval_var(&mut self, env: &Rc<Env>, name: &Name, span: Span) -> SourceResult<Value> {
+        let value = env.lookup(self.ctx, name).err_at(span)?;
+        if value.is_function()
+            && let Function::Builtin(builtin) = value.unwrap_function().as_ref()
+            && builtin.is_value()
+        {
+            return builtin.eval(self.rt, &[]).err_at(span);
+        }
+        Ok(value)
+    }
+
+    fn eval_while(
+        &mut self,
+        env: &Rc<Env>,
+        cond: &Expr,
+        body: &Stmt,
+        span: Span,
+    ) -> SourceResult<Value> {
+        while self.eval_bool(env, cond)? {
+            match self.do_stmt(&env, body)? {
+                StmtFlow::Next | StmtFlow::Continue(_) => {}
+                StmtFlow::Break(_, Some(value)) => return Ok(value),
+                StmtFlow::Break(span, None) => {
+                    return err_at(span, "break without a value in a loop expression");
+                }
+                StmtFlow::Return(span, _) => {
+                    return err_at(span, "return inside a loop expression");
+                }
             }
-            ExprKind::Var(name) => env.lookup(self.ctx, name).err_at(expr.span),
+            self.safepoint(span)?;
         }
+        Ok(Value::NULL)
     }
 
     fn eval_bool(&mut self, env: &Rc<Env>, expr: &Expr) -> SourceResult<bool> {
         let value = self.eval(env, expr)?;
         if value.get_type() != ValueType::Bool {
-            err_at(expr.span, "expected a boolean value")
+            nx_err_of(ErrorKind::Type, "expected a boolean value").err_at(expr.span)
         } else {
             Ok(value.unwrap_bool())
         }
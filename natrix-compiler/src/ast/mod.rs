@@ -1,24 +1,36 @@
 use crate::ctx::Name;
+use crate::module::ModuleId;
 use crate::src::Span;
 use crate::util::tree::def_node;
-pub use interpreter::Interpreter;
+pub use interpreter::{Interpreter, Session};
 use natrix_runtime::value::{BinaryOp, UnaryOp};
 use std::rc::Rc;
 
 mod debug;
 mod interpreter;
+mod remap;
 
 def_node!(Program {
+    imports: Vec<Import>,
     decls: Vec<FunDecl>,
     span: Span,
 });
 
+// `import "path";` or `import name;`, which imports the file `name.nx`
+def_node!(Import {
+    path: Box<str>,
+    name: Name, // the name of the file without its extension
+    span: Span,
+});
+
 def_node!(FunDecl {
+    doc: Option<Box<str>>,
     name: Name,
     name_span: Span,
     params: Vec<Param>,
     body: Vec<Stmt>,
     body_span: Span,
+    module: ModuleId, // set when the module is imported, see `module::parse_program`
 });
 
 def_node!(Param {
@@ -57,6 +69,12 @@ pub enum ExprKind {
         callee: Box<Expr>,
         args: Vec<Expr>,
     },
+    // `cond ? then_expr : else_expr` - only the selected branch is evaluated
+    Conditional {
+        cond: Box<Expr>,
+        then_expr: Box<Expr>,
+        else_expr: Box<Expr>,
+    },
     FloatLiteral(f64),
     IntLiteral(i64),
     ListLiteral(Vec<Expr>),
@@ -68,13 +86,31 @@ pub enum ExprKind {
     },
     NullLiteral,
     Paren(Box<Expr>),
+    // `module.name` - a function of an imported module
+    Qualified {
+        module: Name,
+        module_span: Span,
+        name: Name,
+    },
+    // `array[start:end]` - a missing bound is the start or the end
+    Slice {
+        array: Box<Expr>,
+        start: Option<Box<Expr>>,
+        end: Option<Box<Expr>>,
+    },
     StringLiteral(Rc<str>),
+    // `try expr` - null instead of the runtime error of `expr`
+    Try(Box<Expr>),
     Unary {
         op: UnaryOp,
         op_span: Span,
         expr: Box<Expr>,
     },
     Var(Name),
+    While {
+        cond: Box<Expr>,
+        body: Box<Stmt>,
+    },
 }
 
 pub enum AssignTargetKind {
@@ -88,19 +124,44 @@ pub enum StmtKind {
         value: Expr,
     },
     Block(Vec<Stmt>),
-    Break,
+    Break(Option<Expr>),
+    // `@if (flag) ... else ...` or `@if (flag == "value") ...` - only the branch selected by the
+    // flags defined on the command line is compiled
+    CompileIf {
+        flag: Name,
+        flag_span: Span,
+        value: Option<Rc<str>>,
+        then_body: Box<Stmt>,
+        else_body: Option<Box<Stmt>>,
+    },
     Continue,
+    // `defer stmt;` - runs the statement when the enclosing block is exited, in reverse order of
+    // the defers of the block
+    Defer(Box<Stmt>),
     Expr(Expr),
+    // `for (init; cond; update) body` - the declarations of `init` are only visible in the loop,
+    // and `update` also runs when the body continues
+    For {
+        init: Vec<Stmt>,
+        cond: Option<Expr>,
+        update: Option<Box<Stmt>>,
+        body: Box<Stmt>,
+    },
     If {
         cond: Expr,
         then_body: Box<Stmt>,
         else_body: Option<Box<Stmt>>,
     },
     Return(Option<Expr>),
+    // `a, b = value;` - the value is evaluated first, then the targets are assigned left to right
+    Unpack {
+        targets: Vec<AssignTarget>,
+        value: Expr,
+    },
     VarDecl {
         name: Name,
         name_span: Span,
-        init: Expr,
+        init: Option<Expr>, // None for `var x;`, which must be assigned before it is read
     },
     While {
         cond: Expr,
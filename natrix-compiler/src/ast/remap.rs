@@ -0,0 +1,153 @@
+// Moves an AST parsed with another interner to the interner of the context: its names are
+// translated and its string literals replaced by those of the context, so that the program is
+// the same as if it had been parsed with the context. See `module::parse_program`.
+
+use crate::ast::{AssignTarget, AssignTargetKind, Expr, ExprKind, Program, Stmt, StmtKind};
+use crate::ctx::{Interner, NameMap};
+use std::rc::Rc;
+
+struct Remap<'a> {
+    names: &'a NameMap,
+    interner: &'a mut Interner,
+}
+
+impl Program {
+    pub(crate) fn remap(&mut self, names: &NameMap, interner: &mut Interner) {
+        let mut remap = Remap { names, interner };
+        for import in &mut self.imports {
+            import.name = names.get(import.name);
+        }
+        for decl in &mut self.decls {
+            decl.name = names.get(decl.name);
+            for param in &mut decl.params {
+                param.name = names.get(param.name);
+            }
+            decl.body.iter_mut().for_each(|stmt| remap.stmt(stmt));
+        }
+    }
+}
+
+impl Remap<'_> {
+    fn literal(&mut self, literal: &mut Rc<str>) {
+        *literal = self.interner.intern_literal(literal);
+    }
+
+    fn target(&mut self, target: &mut AssignTarget) {
+        match &mut target.kind {
+            AssignTargetKind::ArrayAccess { array, index } => {
+                self.expr(array);
+                self.expr(index);
+            }
+            AssignTargetKind::Var(name) => *name = self.names.get(*name),
+        }
+    }
+
+    fn stmt(&mut self, stmt: &mut Stmt) {
+        match &mut stmt.kind {
+            StmtKind::Assign { target, value } => {
+                self.target(target);
+                self.expr(value);
+            }
+            StmtKind::Block(stmts) => stmts.iter_mut().for_each(|stmt| self.stmt(stmt)),
+            StmtKind::Break(value) | StmtKind::Return(value) => {
+                value.iter_mut().for_each(|value| self.expr(value));
+            }
+            StmtKind::CompileIf {
+                flag,
+                value,
+                then_body,
+                else_body,
+                ..
+            } => {
+                *flag = self.names.get(*flag);
+                value.iter_mut().for_each(|value| self.literal(value));
+                self.stmt(then_body);
+                else_body.iter_mut().for_each(|body| self.stmt(body));
+            }
+            StmtKind::Continue => {}
+            StmtKind::Defer(body) => self.stmt(body),
+            StmtKind::Expr(expr) => self.expr(expr),
+            StmtKind::For {
+                init,
+                cond,
+                update,
+                body,
+            } => {
+                init.iter_mut().for_each(|stmt| self.stmt(stmt));
+                cond.iter_mut().for_each(|cond| self.expr(cond));
+                update.iter_mut().for_each(|update| self.stmt(update));
+                self.stmt(body);
+            }
+            StmtKind::If {
+                cond,
+                then_body,
+                else_body,
+            } => {
+                self.expr(cond);
+                self.stmt(then_body);
+                else_body.iter_mut().for_each(|body| self.stmt(body));
+            }
+            StmtKind::Unpack { targets, value } => {
+                targets.iter_mut().for_each(|target| self.target(target));
+                self.expr(value);
+            }
+            StmtKind::VarDecl { name, init, .. } => {
+                *name = self.names.get(*name);
+                init.iter_mut().for_each(|init| self.expr(init));
+            }
+            StmtKind::While { cond, body } => {
+                self.expr(cond);
+                self.stmt(body);
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::ArrayAccess { array, index } => {
+                self.expr(array);
+                self.expr(index);
+            }
+            ExprKind::Binary { left, right, .. } | ExprKind::LogicalBinary { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::BoolLiteral(_)
+            | ExprKind::FloatLiteral(_)
+            | ExprKind::IntLiteral(_)
+            | ExprKind::NullLiteral => {}
+            ExprKind::Call { callee, args } => {
+                self.expr(callee);
+                args.iter_mut().for_each(|arg| self.expr(arg));
+            }
+            ExprKind::Conditional {
+                cond,
+                then_expr,
+                else_expr,
+            } => {
+                self.expr(cond);
+                self.expr(then_expr);
+                self.expr(else_expr);
+            }
+            ExprKind::ListLiteral(elements) => elements.iter_mut().for_each(|e| self.expr(e)),
+            ExprKind::Paren(inner) | ExprKind::Try(inner) | ExprKind::Unary { expr: inner, .. } => {
+                self.expr(inner)
+            }
+            ExprKind::Qualified { module, name, .. } => {
+                *module = self.names.get(*module);
+                *name = self.names.get(*name);
+            }
+            ExprKind::Slice { array, start, end } => {
+                self.expr(array);
+                start.iter_mut().for_each(|start| self.expr(start));
+                end.iter_mut().for_each(|end| self.expr(end));
+            }
+            ExprKind::StringLiteral(value) => self.literal(value),
+            ExprKind::Var(name) => *name = self.names.get(*name),
+            ExprKind::While { cond, body } => {
+                self.expr(cond);
+                self.stmt(body);
+            }
+        }
+    }
+}
@@ -1,10 +1,10 @@
 use crate::src::Span;
-use natrix_runtime::bc::Opcode;
-use natrix_runtime::leb128::{encode_sleb128, encode_uleb128};
+use natrix_runtime::bc::{Immediate, Opcode};
+use natrix_runtime::leb128::{encode_sleb128, encode_sleb128_padded, encode_uleb128, sleb128_len};
 use std::fmt::{Debug, Display};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct Label(usize);
+pub struct Label(pub(crate) usize);
 
 impl Display for Label {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -16,15 +16,17 @@ impl Display for Label {
 pub enum InsKind {
     Add,
     Call(usize),
+    DeferBegin(Label),
+    DeferEnd,
     Div,
     Eq,
     Ge,
     GetItem,
+    GetSlice,
     Gt,
     JFalse(Label),
     Jmp(Label),
     JTrue(Label),
-    LabelDef(Label),
     Le,
     Load0,
     LoadBuiltin(usize),
@@ -50,9 +52,11 @@ pub enum InsKind {
     StoreGlobal(usize),
     StoreLocal(usize),
     Sub,
+    TryBegin(Label),
+    TryEnd,
+    Unpack(usize),
 }
 
-#[allow(dead_code)]
 pub struct Ins {
     pub kind: InsKind,
     pub span: Span,
@@ -67,15 +71,17 @@ impl Ins {
         match self.kind {
             InsKind::Add => (Opcode::Add, Immediates::None),
             InsKind::Call(arg_count) => (Opcode::Call, Immediates::Usize(arg_count)),
+            InsKind::DeferBegin(label) => (Opcode::DeferBegin, Immediates::Label(label)),
+            InsKind::DeferEnd => (Opcode::DeferEnd, Immediates::None),
             InsKind::Div => (Opcode::Div, Immediates::None),
             InsKind::Eq => (Opcode::Eq, Immediates::None),
             InsKind::Ge => (Opcode::Ge, Immediates::None),
             InsKind::GetItem => (Opcode::GetItem, Immediates::None),
+            InsKind::GetSlice => (Opcode::GetSlice, Immediates::None),
             InsKind::Gt => (Opcode::Gt, Immediates::None),
             InsKind::JFalse(label) => (Opcode::JFalse, Immediates::Label(label)),
             InsKind::Jmp(label) => (Opcode::Jmp, Immediates::Label(label)),
             InsKind::JTrue(label) => (Opcode::JTrue, Immediates::Label(label)),
-            InsKind::LabelDef(_) => unreachable!(),
             InsKind::Le => (Opcode::Le, Immediates::None),
             InsKind::Load0 => (Opcode::Load0, Immediates::None),
             InsKind::LoadBuiltin(i) => (Opcode::LoadBuiltin, Immediates::Usize(i)),
@@ -101,36 +107,68 @@ impl Ins {
             InsKind::StoreGlobal(i) => (Opcode::StoreGlobal, Immediates::Usize(i)),
             InsKind::StoreLocal(i) => (Opcode::StoreLocal, Immediates::Usize(i)),
             InsKind::Sub => (Opcode::Sub, Immediates::None),
+            InsKind::TryBegin(label) => (Opcode::TryBegin, Immediates::Label(label)),
+            InsKind::TryEnd => (Opcode::TryEnd, Immediates::None),
+            InsKind::Unpack(i) => (Opcode::Unpack, Immediates::Usize(i)),
         }
     }
 }
 
+impl InsKind {
+    // Whether the instruction ends a basic block
+    fn is_terminator(self) -> bool {
+        matches!(
+            self,
+            InsKind::JFalse(_) | InsKind::Jmp(_) | InsKind::JTrue(_) | InsKind::Ret
+        )
+    }
+}
+
 impl Debug for Ins {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let InsKind::LabelDef(label) = self.kind {
-            write!(f, "{}:", label)
-        } else {
-            let (opcode, immediates) = self.encoding();
-            write!(f, "  {}", opcode.name())?;
-            match immediates {
-                Immediates::None => Ok(()),
-                Immediates::Usize(i) => write!(f, " {}", i),
-                Immediates::I64(i) => write!(f, " {}", i),
-                Immediates::Label(label) => write!(f, " {}", label),
-            }
+        let (opcode, immediates) = self.encoding();
+        write!(f, "  {}", opcode.name())?;
+        match immediates {
+            Immediates::None => Ok(()),
+            Immediates::Usize(i) => write!(f, " {}", i),
+            Immediates::I64(i) => write!(f, " {}", i),
+            Immediates::Label(label) => write!(f, " {}", label),
         }
     }
 }
 
-pub struct BytecodeBuilder {
+/// A straight-line sequence of instructions, entered at its start through one of its labels or
+/// from the previous block, and left at its end. Only the last instruction can jump or return,
+/// a conditional jump falls through to the next block.
+pub struct BasicBlock {
+    pub labels: Vec<(Label, Span)>,
     pub ins: Vec<Ins>,
+}
+
+impl BasicBlock {
+    fn new() -> Self {
+        Self {
+            labels: Vec::new(),
+            ins: Vec::new(),
+        }
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.ins.last().is_some_and(|ins| ins.kind.is_terminator())
+    }
+}
+
+/// Collects the instructions of a function as basic blocks, in the order in which they are
+/// encoded to flat bytecode.
+pub struct BytecodeBuilder {
+    pub blocks: Vec<BasicBlock>,
     label_count: usize,
 }
 
 impl BytecodeBuilder {
     pub fn new() -> Self {
         Self {
-            ins: Vec::new(),
+            blocks: vec![BasicBlock::new()],
             label_count: 0,
         }
     }
@@ -141,27 +179,74 @@ impl BytecodeBuilder {
         label
     }
 
+    /// Places `label` before the next instruction, which starts a new block unless the current
+    /// one is still empty.
     pub fn define_label(&mut self, span: Span, label: Label) {
         assert!(
-            !self.ins.iter().any(|i| i.kind == InsKind::LabelDef(label)),
+            !self
+                .blocks
+                .iter()
+                .any(|block| block.labels.iter().any(|(l, _)| *l == label)),
             "label {:?} already defined",
             label
         );
-        self.ins.push(Ins::new(InsKind::LabelDef(label), span));
+        if !self.current().ins.is_empty() {
+            self.blocks.push(BasicBlock::new());
+        }
+        self.current().labels.push((label, span));
     }
 
+    /// Appends an instruction to the current block, or to a new one if the current block ends
+    /// with a jump or return.
     pub fn append(&mut self, span: Span, ins_kind: InsKind) {
-        assert!(!matches!(ins_kind, InsKind::LabelDef(_)));
-        self.ins.push(Ins::new(ins_kind, span));
+        if self.current().is_terminated() {
+            self.blocks.push(BasicBlock::new());
+        }
+        self.current().ins.push(Ins::new(ins_kind, span));
+    }
+
+    fn current(&mut self) -> &mut BasicBlock {
+        self.blocks.last_mut().unwrap()
     }
 
-    pub fn encode(&self) -> Vec<u8> {
-        let (_, mut label_offsets) = self.encode_pass(|_, _| 0);
-        loop {
-            let (code, new_label_offsets) =
-                self.encode_pass(|from, to_label| label_offsets[to_label.0] as i64 - from as i64);
+    // All instructions in encoding order
+    fn instructions(&self) -> impl Iterator<Item = &Ins> {
+        self.blocks.iter().flat_map(|block| &block.ins)
+    }
+
+    /// Encodes the instructions, returning the code, the span of each encoded instruction (keyed
+    /// by its offset within the code) and the offset of each label (indexed by the label).
+    ///
+    /// Jump offsets are relative and SLEB128 encoded, so the size of a jump depends on the
+    /// distance to its label, which in turn depends on the size of the jumps in between. The code
+    /// is therefore encoded repeatedly, each pass using the label offsets of the previous one,
+    /// until the offsets no longer change. A jump never gets narrower than in a previous pass
+    /// (shorter offsets are padded), so the sizes and label offsets only grow. Since an offset
+    /// takes at most 10 bytes, this converges after at most 10 passes per jump, however far the
+    /// jumps go.
+    pub fn encode(&self) -> (Vec<u8>, Vec<(usize, Span)>, Vec<usize>) {
+        let mut widths = vec![1; self.instructions().count()]; // operand size of each jump
+        let (_, mut label_offsets, _) = self.encode_pass(&mut widths, |_, _| 0);
+        let jump_count = self
+            .instructions()
+            .filter(|ins| {
+                matches!(
+                    ins.kind,
+                    InsKind::JFalse(_)
+                        | InsKind::Jmp(_)
+                        | InsKind::JTrue(_)
+                        | InsKind::TryBegin(_)
+                        | InsKind::DeferBegin(_)
+                )
+            })
+            .count();
+        for _ in 0..=10 * jump_count {
+            let (code, new_label_offsets, spans) = self
+                .encode_pass(&mut widths, |from, to_label| {
+                    label_offsets[to_label.0] as i64 - from as i64
+                });
             if new_label_offsets == label_offsets {
-                return code;
+                return (code, spans, label_offsets);
             }
             assert!(
                 new_label_offsets
@@ -172,36 +257,59 @@ impl BytecodeBuilder {
             );
             label_offsets = new_label_offsets;
         }
+        panic!("encoding of jumps did not converge");
     }
 
-    fn encode_pass<F: Fn(usize, Label) -> i64>(&self, calc_delta: F) -> (Vec<u8>, Vec<usize>) {
+    fn encode_pass<F: Fn(usize, Label) -> i64>(
+        &self,
+        widths: &mut [usize],
+        calc_delta: F,
+    ) -> (Vec<u8>, Vec<usize>, Vec<(usize, Span)>) {
         let mut label_offsets = Vec::new();
         label_offsets.resize(self.label_count, 0);
         let mut code = Vec::new();
-        for ins in self.ins.iter() {
-            if let InsKind::LabelDef(label) = ins.kind {
+        let mut spans = Vec::new();
+        let mut index = 0;
+        for block in &self.blocks {
+            for (label, _) in &block.labels {
                 label_offsets[label.0] = code.len();
-            } else {
+            }
+            for ins in &block.ins {
                 let (opcode, immediates) = ins.encoding();
+                spans.push((code.len(), ins.span));
                 code.push(opcode.as_u8());
-                match immediates {
-                    Immediates::None => {}
-                    Immediates::Usize(i) => encode_uleb128(i, |b| code.push(b)),
-                    Immediates::I64(i) => encode_sleb128(i, |b| code.push(b)),
-                    Immediates::Label(label) => {
-                        encode_sleb128(calc_delta(code.len() - 1, label), |b| code.push(b));
+                match (opcode.immediate(), immediates) {
+                    (Immediate::None, Immediates::None) => {}
+                    (Immediate::Uleb, Immediates::Usize(i)) => encode_uleb128(i, |b| code.push(b)),
+                    (Immediate::Sleb, Immediates::I64(i)) => encode_sleb128(i, |b| code.push(b)),
+                    (Immediate::Label, Immediates::Label(label)) => {
+                        let delta = calc_delta(code.len() - 1, label);
+                        widths[index] = widths[index].max(sleb128_len(delta));
+                        encode_sleb128_padded(delta, widths[index], |b| code.push(b));
                     }
+                    (immediate, immediates) => panic!(
+                        "{} takes an immediate of kind {:?}, not {:?}",
+                        opcode.name(),
+                        immediate,
+                        immediates
+                    ),
                 }
+                index += 1;
             }
         }
-        (code, label_offsets)
+        (code, label_offsets, spans)
     }
 }
 
 impl Debug for BytecodeBuilder {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for ins in &self.ins {
-            write!(f, "{:?}\n", ins)?;
+        for block in &self.blocks {
+            for (label, _) in &block.labels {
+                write!(f, "{}:\n", label)?;
+            }
+            for ins in &block.ins {
+                write!(f, "{:?}\n", ins)?;
+            }
         }
         Ok(())
     }
@@ -214,3 +322,95 @@ enum Immediates {
     I64(i64),
     Label(Label),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use natrix_runtime::leb128::decode_sleb128;
+
+    // Returns the target of the jump at `offset`
+    fn jump_target(code: &[u8], offset: usize) -> usize {
+        let mut ip = offset + 1;
+        let delta = decode_sleb128(|| {
+            ip += 1;
+            code[ip - 1]
+        });
+        (offset as i64 + delta) as usize
+    }
+
+    #[test]
+    fn test_basic_blocks() {
+        let mut bb = BytecodeBuilder::new();
+        let l_else = bb.new_label();
+        let l_end = bb.new_label();
+        bb.append(Span::DUMMY, InsKind::PushTrue);
+        bb.append(Span::DUMMY, InsKind::JFalse(l_else));
+        bb.append(Span::DUMMY, InsKind::Push1);
+        bb.append(Span::DUMMY, InsKind::Ret);
+        bb.append(Span::DUMMY, InsKind::Push0); // unreachable, but still encoded
+        bb.define_label(Span::DUMMY, l_else);
+        bb.define_label(Span::DUMMY, l_end);
+        bb.append(Span::DUMMY, InsKind::PushNull);
+        bb.append(Span::DUMMY, InsKind::Ret);
+
+        let shape: Vec<(usize, usize)> = bb
+            .blocks
+            .iter()
+            .map(|block| (block.labels.len(), block.ins.len()))
+            .collect();
+        assert_eq!(shape, [(0, 2), (0, 2), (0, 1), (2, 2)]);
+        let (code, _, label_offsets) = bb.encode();
+        assert_eq!(code.len(), 8);
+        assert_eq!(label_offsets, [6, 6]);
+        assert_eq!(jump_target(&code, 1), 6);
+    }
+
+    #[test]
+    fn test_long_jumps() {
+        // a loop whose body is several kilobytes, jumping forward out of it and back to its head
+        let mut bb = BytecodeBuilder::new();
+        let l_head = bb.new_label();
+        let l_exit = bb.new_label();
+        bb.define_label(Span::DUMMY, l_head);
+        bb.append(Span::DUMMY, InsKind::PushTrue);
+        bb.append(Span::DUMMY, InsKind::JFalse(l_exit));
+        for _ in 0..5000 {
+            bb.append(Span::DUMMY, InsKind::PushInt(1000));
+            bb.append(Span::DUMMY, InsKind::Pop);
+        }
+        bb.append(Span::DUMMY, InsKind::Jmp(l_head));
+        bb.define_label(Span::DUMMY, l_exit);
+        bb.append(Span::DUMMY, InsKind::Ret);
+
+        let (code, spans, _) = bb.encode();
+        let exit = code.len() - 1;
+        assert_eq!(code[exit], Opcode::Ret.as_u8());
+        assert_eq!(jump_target(&code, 1), exit);
+        let (back_jump, _) = spans[spans.len() - 2];
+        assert_eq!(code[back_jump], Opcode::Jmp.as_u8());
+        assert_eq!(jump_target(&code, back_jump), 0);
+    }
+
+    #[test]
+    fn test_jumps_at_width_boundaries() {
+        // distances around the point where an offset needs a second byte
+        for padding in 55..75 {
+            let mut bb = BytecodeBuilder::new();
+            let l_start = bb.new_label();
+            let l_end = bb.new_label();
+            bb.define_label(Span::DUMMY, l_start);
+            bb.append(Span::DUMMY, InsKind::Jmp(l_end));
+            for _ in 0..padding {
+                bb.append(Span::DUMMY, InsKind::Pop);
+            }
+            bb.append(Span::DUMMY, InsKind::Jmp(l_start));
+            bb.define_label(Span::DUMMY, l_end);
+            bb.append(Span::DUMMY, InsKind::Ret);
+
+            let (code, spans, _) = bb.encode();
+            assert_eq!(jump_target(&code, 0), code.len() - 1, "padding {}", padding);
+            let (back_jump, _) = spans[spans.len() - 2];
+            assert_eq!(jump_target(&code, back_jump), 0, "padding {}", padding);
+        }
+    }
+}
@@ -1,21 +1,103 @@
 use crate::bc::builder::{BytecodeBuilder, InsKind, Label};
+use crate::bc::link::link_library;
 use crate::ctx::CompilerContext;
 use crate::error::{err_at, SourceResult};
-use crate::hir::{Expr, ExprKind, FunDecl, GlobalKind, LocalKind, LoopId, Program, Stmt, StmtKind};
-use natrix_runtime::bc::Bytecode;
+use crate::hir::callgraph::CallGraph;
+use crate::hir::{
+    Expr, ExprKind, FunDecl, GlobalKind, LocalId, LocalKind, LoopId, Program, Stmt, StmtKind,
+};
+use crate::module::ModuleId;
+use crate::src::Span;
+use natrix_runtime::bc::{Bytecode, Library, LocalName};
 use natrix_runtime::value::{BinaryOp, Function, UnaryOp, Value};
 use std::cmp::max;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::ops::Range;
 use std::rc::Rc;
 
+/// Compiles a program with `main`. If `ctx.prune` is set, functions unreachable from `main` in the
+/// call graph are left out and their globals are null.
 pub fn compile(ctx: &CompilerContext, program: &Program) -> SourceResult<Bytecode> {
+    let main = program
+        .globals
+        .iter()
+        .find(|global| ctx.interner.resolve(global.name) == "main");
+    let reachable = match main {
+        Some(main) if ctx.prune => Some(CallGraph::build(program).reachable_from(main.id)),
+        _ => None,
+    };
+    match compile_globals(ctx, program, reachable.as_deref()) {
+        (bc, Some(main_index)) => {
+            let bc = Bytecode { main_index, ..bc };
+            #[cfg(debug_assertions)]
+            if let Err(err) = bc.verify(natrix_runtime::bc::Opcode::ALL.len()) {
+                panic!("compiled invalid bytecode: {}", err.message);
+            }
+            Ok(bc)
+        }
+        (_, None) => err_at(program.span, "no main function defined"),
+    }
+}
+
+/// Compiles the functions of a program that does not need to define `main`.
+///
+/// The result is meant for inspection (e.g. disassembly) rather than running, `main_index` is
+/// zero if there is no `main` function.
+pub fn compile_functions(ctx: &CompilerContext, program: &Program) -> Bytecode {
+    compile_globals(ctx, program, None).0
+}
+
+/// Compiles a library: all its functions, like `compile_functions`, and an export table of the
+/// functions of the root module, by which the programs importing the library call them.
+pub fn compile_library(ctx: &CompilerContext, program: &Program) -> Library {
+    let bc = compile_functions(ctx, program);
+    let exports = program
+        .globals
+        .iter()
+        .filter(|global| global.module == ModuleId::ROOT)
+        .filter(|global| matches!(global.kind, GlobalKind::Function(_)))
+        .map(|global| (ctx.interner.resolve(global.name).into(), global.id.0))
+        .collect();
+    Library { bc, exports }
+}
+
+// Compiles the globals marked in `reachable`, or all of them, and links the libraries
+fn compile_globals(
+    ctx: &CompilerContext,
+    program: &Program,
+    reachable: Option<&[bool]>,
+) -> (Bytecode, Option<usize>) {
     let mut code = Vec::new();
+    let mut line_table = Vec::new();
     let mut cp: ConstantPool = ConstantPool::new();
     let mut globals = Vec::new();
     let mut main_index: Option<usize> = None;
+    let mut local_names = Vec::new();
+
+    // the globals of the libraries follow those of the program, whose imported functions are
+    // loaded from the globals of their library and are left null. Native functions are held by
+    // their own global.
+    let mut library_bases = Vec::new();
+    let mut next_base = program.globals.len();
+    for library in ctx.modules.libraries() {
+        library_bases.push(next_base);
+        next_base += library.bc.globals.len();
+    }
+    let global_indices: Vec<usize> = program
+        .globals
+        .iter()
+        .map(|global| match &global.kind {
+            GlobalKind::Function(_) | GlobalKind::Native(_) | GlobalKind::Variable => global.id.0,
+            GlobalKind::Imported(imported) => library_bases[imported.library] + imported.global,
+        })
+        .collect();
 
     for (index, global) in program.globals.iter().enumerate() {
+        if reachable.is_some_and(|reachable| !reachable[index]) {
+            globals.push(Value::NULL);
+            continue;
+        }
         match &global.kind {
             GlobalKind::Function(fun_decl) => {
                 let name = ctx.interner.resolve(global.name);
@@ -23,46 +105,151 @@ pub fn compile(ctx: &CompilerContext, program: &Program) -> SourceResult<Bytecod
                     main_index = Some(index);
                 }
                 let code_handle = code.len();
-                let (mut f_code, max_slots) = do_function(&mut cp, fun_decl);
+                let (mut f_code, f_spans, max_slots, live_ranges) =
+                    do_function(&mut cp, &global_indices, fun_decl, ctx.debug_info);
                 code.append(&mut f_code);
+                local_names.extend(
+                    live_ranges
+                        .into_iter()
+                        .filter(|(id, ..)| fun_decl.locals[id.0].kind != LocalKind::Temporary)
+                        .map(|(id, slot, live)| LocalName {
+                            name: ctx.interner.resolve(fun_decl.locals[id.0].name).into(),
+                            slot,
+                            live: code_handle + live.start..code_handle + live.end,
+                        }),
+                );
+                line_table.extend(
+                    f_spans
+                        .into_iter()
+                        .map(|(offset, span)| (code_handle + offset, span.to_code_span())),
+                );
                 globals.push(Value::from_function(Rc::new(Function::UserDefined {
                     name: name.into(),
                     param_count: fun_decl.param_count,
                     max_slots,
                     code_handle,
+                    location: Some(global.name_span.location(&ctx.sources).into()),
                 })));
             }
+            GlobalKind::Imported(_) | GlobalKind::Variable => globals.push(Value::NULL),
+            GlobalKind::Native(function) => globals.push(Value::from_function(function.clone())),
         }
     }
-    match main_index {
-        Some(main_index) => Ok(Bytecode {
-            code,
-            constants: cp.constants,
-            globals,
-            main_index,
-        }),
-        None => err_at(program.span, "no main function defined"),
+    let mut bc = Bytecode {
+        code,
+        constants: Vec::new(),
+        line_table,
+        globals,
+        main_index: main_index.unwrap_or(0),
+        local_names: ctx.debug_info.then_some(local_names),
+    };
+    for (library, base) in ctx.modules.libraries().iter().zip(library_bases) {
+        let prefix = ctx
+            .modules
+            .name(library.module)
+            .expect("a library is imported");
+        let linked = link_library(&mut bc, &mut cp, library, ctx.interner.resolve(prefix));
+        debug_assert_eq!(linked, base);
     }
+    bc.constants = cp.constants;
+    (bc, main_index)
 }
 
-fn do_function(cp: &mut ConstantPool, fun_decl: &FunDecl) -> (Vec<u8>, usize) {
-    let mut local_slots = Vec::new();
-    local_slots.resize(fun_decl.locals.len(), 0);
+// The slot of each variable and the code range in which it holds the variable
+type VariableRanges = Vec<(LocalId, usize, Range<usize>)>;
+
+// Also returns the slot and the code range of each variable if `debug_info` is set.
+fn do_function(
+    cp: &mut ConstantPool,
+    global_indices: &[usize],
+    fun_decl: &FunDecl,
+    debug_info: bool,
+) -> (Vec<u8>, Vec<(usize, Span)>, usize, VariableRanges) {
     for i in 0..fun_decl.param_count {
         assert_eq!(fun_decl.locals[i].kind, LocalKind::Parameter(i));
-        local_slots[i] = i;
     }
 
     let mut c = FunctionCompiler {
-        used_slots: fun_decl.param_count,
-        max_slots: fun_decl.param_count,
-        local_slots,
+        slots: SlotAllocator::new(fun_decl.param_count, fun_decl.locals.len()),
         loop_labels: HashMap::new(),
+        defers: Vec::new(),
         bb: BytecodeBuilder::new(),
         cp,
+        global_indices,
+        live_ranges: debug_info.then(Vec::new),
     };
     c.do_block(&fun_decl.body);
-    (c.bb.encode(), c.max_slots)
+    let (code, spans, label_offsets) = c.bb.encode();
+    let mut live_ranges: VariableRanges = Vec::new();
+    if let Some(ranges) = c.live_ranges {
+        // parameters are live for the whole call
+        live_ranges.extend((0..fun_decl.param_count).map(|i| (LocalId(i), i, 0..code.len())));
+        live_ranges.extend(ranges.into_iter().map(|range| {
+            let end = range.end.expect("variable live after its block");
+            (
+                range.id,
+                range.slot,
+                label_offsets[range.start.0]..label_offsets[end.0],
+            )
+        }));
+    }
+    (code, spans, c.slots.max_slots, live_ranges)
+}
+
+/// Assigns the slots of a call frame to the locals of a function.
+///
+/// Parameters occupy the first slots for the whole call. A variable is live from its declaration
+/// to the end of the enclosing block and gets the lowest slot not used by a live local, so the
+/// variables of disjoint blocks share slots. Every access checks that the slot is still owned by
+/// the accessed local, i.e. that it cannot read a value left behind by another variable.
+struct SlotAllocator {
+    owners: Vec<LocalId>,            // the local owning each slot in use
+    block_starts: Vec<usize>,        // slots in use at the start of each enclosing block
+    local_slots: Vec<Option<usize>>, // indexed by LocalId, None until declared
+    max_slots: usize,
+}
+
+impl SlotAllocator {
+    fn new(param_count: usize, local_count: usize) -> Self {
+        let mut local_slots = vec![None; local_count];
+        for (i, slot) in local_slots.iter_mut().take(param_count).enumerate() {
+            *slot = Some(i);
+        }
+        Self {
+            owners: (0..param_count).map(LocalId).collect(),
+            block_starts: Vec::new(),
+            local_slots,
+            max_slots: param_count,
+        }
+    }
+
+    fn enter_block(&mut self) {
+        self.block_starts.push(self.owners.len());
+    }
+
+    // Frees the slots of the variables declared in the block.
+    fn exit_block(&mut self) {
+        let start = self.block_starts.pop().expect("no block to exit");
+        for id in self.owners.drain(start..) {
+            self.local_slots[id.0] = None;
+        }
+    }
+
+    fn declare(&mut self, id: LocalId) -> usize {
+        assert!(self.local_slots[id.0].is_none(), "{:?} declared twice", id);
+        let slot = self.owners.len();
+        self.owners.push(id);
+        self.local_slots[id.0] = Some(slot);
+        self.max_slots = max(self.max_slots, self.owners.len());
+        slot
+    }
+
+    fn slot(&self, id: LocalId) -> usize {
+        match self.local_slots[id.0] {
+            Some(slot) if self.owners[slot] == id => slot,
+            _ => panic!("{:?} accessed outside of its block", id),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -71,7 +258,7 @@ enum ConstantKey {
     String(Rc<str>),
 }
 
-struct ConstantPool {
+pub(super) struct ConstantPool {
     constants: Vec<Value>,
     map: HashMap<ConstantKey, usize>,
 }
@@ -97,6 +284,18 @@ impl ConstantPool {
         }
     }
 
+    // A constant of another bytecode, only floats and strings are shared
+    pub(super) fn add_constant(&mut self, value: &Value) -> usize {
+        if value.is_string() {
+            self.add_string(&value.unwrap_string())
+        } else if value.is_float() {
+            self.add_float(value.unwrap_float())
+        } else {
+            self.constants.push(value.clone());
+            self.constants.len() - 1
+        }
+    }
+
     fn add_string(&mut self, value: &Rc<str>) -> usize {
         let key = ConstantKey::String(value.clone());
         match self.map.entry(key) {
@@ -111,35 +310,116 @@ impl ConstantPool {
     }
 }
 
+// The code of a variable from its declaration to the end of its block, between two labels.
+struct LiveRange {
+    id: LocalId,
+    slot: usize,
+    start: Label,
+    end: Option<Label>, // None until the block is compiled
+}
+
 struct FunctionCompiler<'a> {
-    used_slots: usize,
-    max_slots: usize,
-    local_slots: Vec<usize>,                      // indexed by LocalId
-    loop_labels: HashMap<LoopId, (Label, Label)>, // break target, continue target
+    slots: SlotAllocator,
+    loop_labels: HashMap<LoopId, (Label, Label, usize)>, // break and continue targets, defer depth
+    defers: Vec<Vec<&'a Stmt>>, // deferred statements of each enclosing block
     bb: BytecodeBuilder,
     cp: &'a mut ConstantPool,
+    global_indices: &'a [usize], // the index in the bytecode of each global of the program
+    live_ranges: Option<Vec<LiveRange>>, // only tracked for debug info
 }
 
 impl<'a> FunctionCompiler<'a> {
-    fn do_block(&mut self, stmts: &[Stmt]) {
-        let saved_slots = self.used_slots;
+    fn do_block(&mut self, stmts: &'a [Stmt]) {
+        let first_range = self.live_ranges.as_ref().map_or(0, Vec::len);
+        self.slots.enter_block();
+        self.defers.push(Vec::new());
         for stmt in stmts {
             self.do_stmt(&stmt);
         }
-        self.used_slots = saved_slots;
+        let jumps = stmts.last().is_some_and(|stmt| {
+            matches!(
+                stmt.kind,
+                StmtKind::Break(..) | StmtKind::Continue(_) | StmtKind::Return(_)
+            )
+        });
+        if !jumps {
+            self.emit_defers(self.defers.len() - 1);
+        }
+        self.defers.pop();
+        self.slots.exit_block();
+        if let Some(last) = stmts.last() {
+            self.end_live_ranges(first_range, last.span.tail());
+        }
+    }
+
+    // Starts the live range of a variable just stored to `slot`.
+    fn start_live_range(&mut self, span: Span, id: LocalId, slot: usize) {
+        if let Some(ranges) = &mut self.live_ranges {
+            let start = self.bb.new_label();
+            self.bb.define_label(span, start);
+            ranges.push(LiveRange {
+                id,
+                slot,
+                start,
+                end: None,
+            });
+        }
+    }
+
+    // Ends the live ranges of the variables of a block, which start at index `first`. The ranges
+    // of nested blocks have already ended.
+    fn end_live_ranges(&mut self, first: usize, span: Span) {
+        if let Some(ranges) = &mut self.live_ranges {
+            let end = self.bb.new_label();
+            self.bb.define_label(span, end);
+            for range in &mut ranges[first..] {
+                range.end.get_or_insert(end);
+            }
+        }
+    }
+
+    // Emits the deferred statements of the blocks from `depth` on, before a jump out of them. The
+    // code is duplicated at every exit, innermost block first and the latest defer first, each
+    // removing the handler installed by its defer.
+    fn emit_defers(&mut self, depth: usize) {
+        for block in (depth..self.defers.len()).rev() {
+            for i in (0..self.defers[block].len()).rev() {
+                let body = self.defers[block][i];
+                self.bb.append(body.span, InsKind::TryEnd);
+                self.do_stmt(body);
+            }
+        }
     }
 
-    fn do_stmt(&mut self, stmt: &Stmt) {
+    fn do_stmt(&mut self, stmt: &'a Stmt) {
         match &stmt.kind {
             StmtKind::Block(stmts) => self.do_block(&stmts),
-            StmtKind::Break(loop_id) => {
-                let (l_break, _continue) = self.loop_labels[loop_id];
+            StmtKind::Break(loop_id, expr) => {
+                // the deferred statements leave the value of the break on the stack
+                if let Some(expr) = expr {
+                    self.do_expr(expr);
+                }
+                let (l_break, _continue, depth) = self.loop_labels[loop_id];
+                self.emit_defers(depth);
                 self.bb.append(stmt.span, InsKind::Jmp(l_break));
             }
             StmtKind::Continue(loop_id) => {
-                let (_break, l_continue) = self.loop_labels[loop_id];
+                let (_break, l_continue, depth) = self.loop_labels[loop_id];
+                self.emit_defers(depth);
                 self.bb.append(stmt.span, InsKind::Jmp(l_continue));
             }
+            StmtKind::Defer(body) => {
+                // an error unwinding to a `try` runs another copy of the statement at l_cleanup
+                let l_cleanup = self.bb.new_label();
+                let l_next = self.bb.new_label();
+                self.bb.append(stmt.span, InsKind::DeferBegin(l_cleanup));
+                self.bb.append(stmt.span, InsKind::Jmp(l_next));
+                self.bb.define_label(body.span, l_cleanup);
+                self.do_stmt(body);
+                self.bb.append(stmt.span, InsKind::DeferEnd);
+                self.bb.define_label(stmt.span.tail(), l_next);
+                self.defers.last_mut().unwrap().push(body);
+            }
             StmtKind::Expr(expr) => {
                 self.do_expr(&expr);
                 self.bb.append(stmt.span, InsKind::Pop);
@@ -167,6 +447,7 @@ impl<'a> FunctionCompiler<'a> {
             }
             StmtKind::Return(expr) => {
                 self.do_expr(&expr);
+                self.emit_defers(0);
                 self.bb.append(stmt.span, InsKind::Ret)
             }
             StmtKind::SetItem(array, index, value) => {
@@ -177,37 +458,64 @@ impl<'a> FunctionCompiler<'a> {
             }
             StmtKind::StoreGlobal(id, expr) => {
                 self.do_expr(&expr);
-                self.bb.append(stmt.span, InsKind::StoreGlobal(id.0))
+                self.bb
+                    .append(stmt.span, InsKind::StoreGlobal(self.global_indices[id.0]))
             }
             StmtKind::StoreLocal(id, expr) => {
                 self.do_expr(&expr);
                 self.bb
-                    .append(stmt.span, InsKind::StoreLocal(self.local_slots[id.0]))
+                    .append(stmt.span, InsKind::StoreLocal(self.slots.slot(*id)))
             }
-            StmtKind::VarDecl(id, expr) => {
-                let slot = self.used_slots;
-                self.local_slots[id.0] = slot;
-                self.used_slots += 1;
-                self.max_slots = max(self.max_slots, self.used_slots);
+            StmtKind::Unpack(ids, expr) => {
                 self.do_expr(&expr);
-                self.bb.append(stmt.span, InsKind::StoreLocal(slot))
+                // the first element ends up on top of the stack
+                self.bb.append(stmt.span, InsKind::Unpack(ids.len()));
+                for id in ids {
+                    let slot = self.slots.declare(*id);
+                    self.bb.append(stmt.span, InsKind::StoreLocal(slot));
+                    self.start_live_range(stmt.span, *id, slot);
+                }
             }
-            StmtKind::While(loop_id, cond, body) => {
+            StmtKind::VarDecl(id, expr) => {
+                let slot = self.slots.declare(*id);
+                match expr {
+                    Some(expr) => self.do_expr(expr),
+                    // the analysis makes sure that the program assigns the variable before
+                    // reading it, but locals() would show what another variable left in the slot
+                    None if self.live_ranges.is_some() => {
+                        self.bb.append(stmt.span, InsKind::PushNull)
+                    }
+                    None => return,
+                }
+                self.bb.append(stmt.span, InsKind::StoreLocal(slot));
+                self.start_live_range(stmt.span, *id, slot);
+            }
+            StmtKind::While(loop_id, cond, body, update) => {
                 let l_head = self.bb.new_label();
                 let l_body = self.bb.new_label();
                 let l_exit = self.bb.new_label();
-                self.loop_labels.insert(*loop_id, (l_exit, l_head));
+                // a continue runs the update of a `for` loop before the condition
+                let l_next = match update {
+                    Some(_) => self.bb.new_label(),
+                    None => l_head,
+                };
+                self.loop_labels
+                    .insert(*loop_id, (l_exit, l_next, self.defers.len()));
                 self.bb.define_label(stmt.span, l_head);
                 self.do_cond(cond, l_body, l_exit, false);
                 self.bb.define_label(body.span, l_body);
                 self.do_stmt(&body);
+                if let Some(update) = update {
+                    self.bb.define_label(update.span, l_next);
+                    self.do_stmt(update);
+                }
                 self.bb.append(stmt.span, InsKind::Jmp(l_head));
                 self.bb.define_label(body.span.tail(), l_exit);
             }
         }
     }
 
-    fn do_expr(&mut self, expr: &Expr) {
+    fn do_expr(&mut self, expr: &'a Expr) {
         match &expr.kind {
             ExprKind::Binary(op, op_span, left, right) => {
                 self.do_expr(&left);
@@ -229,7 +537,19 @@ impl<'a> FunctionCompiler<'a> {
             ExprKind::Call(callee, args) => {
                 self.do_expr(&callee);
                 args.iter().for_each(|e| self.do_expr(&e));
-                self.bb.append(callee.span, InsKind::Call(args.len()))
+                self.bb.append(expr.span, InsKind::Call(args.len()))
+            }
+            ExprKind::Conditional(cond, then_expr, else_expr) => {
+                let l_then = self.bb.new_label();
+                let l_else = self.bb.new_label();
+                let l_end = self.bb.new_label();
+                self.do_cond(cond, l_then, l_else, false);
+                self.bb.define_label(then_expr.span, l_then);
+                self.do_expr(then_expr);
+                self.bb.append(then_expr.span, InsKind::Jmp(l_end));
+                self.bb.define_label(else_expr.span, l_else);
+                self.do_expr(else_expr);
+                self.bb.define_label(expr.span.tail(), l_end);
             }
             ExprKind::ConstBool(v) if *v => self.bb.append(expr.span, InsKind::PushTrue),
             ExprKind::ConstBool(_) => self.bb.append(expr.span, InsKind::PushFalse),
@@ -248,18 +568,22 @@ impl<'a> FunctionCompiler<'a> {
                 self.do_expr(&index);
                 self.bb.append(expr.span, InsKind::GetItem)
             }
+            ExprKind::GetSlice(array, start, end) => {
+                self.do_expr(array);
+                self.do_expr(start);
+                self.do_expr(end);
+                self.bb.append(expr.span, InsKind::GetSlice)
+            }
             ExprKind::LoadBuiltin(builtin) => self
                 .bb
                 .append(expr.span, InsKind::LoadBuiltin(builtin.index())),
-            ExprKind::LoadGlobal(id) => self.bb.append(expr.span, InsKind::LoadGlobal(id.0)),
-            ExprKind::LoadLocal(id) => {
-                if id.0 == 0 {
-                    self.bb.append(expr.span, InsKind::Load0)
-                } else {
-                    self.bb
-                        .append(expr.span, InsKind::LoadLocal(self.local_slots[id.0]))
-                }
-            }
+            ExprKind::LoadGlobal(id) => self
+                .bb
+                .append(expr.span, InsKind::LoadGlobal(self.global_indices[id.0])),
+            ExprKind::LoadLocal(id) => match self.slots.slot(*id) {
+                0 => self.bb.append(expr.span, InsKind::Load0),
+                slot => self.bb.append(expr.span, InsKind::LoadLocal(slot)),
+            },
             ExprKind::LogicalBinary(_, op_span, _, _) => {
                 let l_true = self.bb.new_label();
                 let l_false = self.bb.new_label();
@@ -276,6 +600,14 @@ impl<'a> FunctionCompiler<'a> {
                 elements.iter().for_each(|e| self.do_expr(&e));
                 self.bb.append(expr.span, InsKind::MakeList(elements.len()))
             }
+            ExprKind::Try(inner) => {
+                // an error in between unwinds to l_end with null in place of the value
+                let l_end = self.bb.new_label();
+                self.bb.append(expr.span, InsKind::TryBegin(l_end));
+                self.do_expr(inner);
+                self.bb.append(expr.span, InsKind::TryEnd);
+                self.bb.define_label(expr.span.tail(), l_end);
+            }
             ExprKind::Unary(op, op_span, expr) => {
                 self.do_expr(&expr);
                 match op {
@@ -283,13 +615,30 @@ impl<'a> FunctionCompiler<'a> {
                     UnaryOp::Not => self.bb.append(*op_span, InsKind::Not),
                 }
             }
+            ExprKind::While(loop_id, cond, body) => {
+                // breaks jump to l_exit with their value on the stack, normal exit pushes null
+                let l_head = self.bb.new_label();
+                let l_body = self.bb.new_label();
+                let l_done = self.bb.new_label();
+                let l_exit = self.bb.new_label();
+                self.loop_labels
+                    .insert(*loop_id, (l_exit, l_head, self.defers.len()));
+                self.bb.define_label(expr.span, l_head);
+                self.do_cond(cond, l_body, l_done, false);
+                self.bb.define_label(body.span, l_body);
+                self.do_stmt(&body);
+                self.bb.append(expr.span, InsKind::Jmp(l_head));
+                self.bb.define_label(body.span.tail(), l_done);
+                self.bb.append(body.span.tail(), InsKind::PushNull);
+                self.bb.define_label(body.span.tail(), l_exit);
+            }
         }
     }
 
     // requirements:
     // - if `expr` evaluates to `negate`, jump to the l_false label, otherwise jump to the l_true label
     // - l_true will be placed right after the code generated by this function
-    fn do_cond(&mut self, expr: &Expr, l_true: Label, l_false: Label, negate: bool) {
+    fn do_cond(&mut self, expr: &'a Expr, l_true: Label, l_false: Label, negate: bool) {
         match &expr.kind {
             ExprKind::Unary(op, _op_span, expr) if *op == UnaryOp::Not => {
                 self.do_cond(expr, l_true, l_false, !negate)
@@ -314,6 +663,18 @@ impl<'a> FunctionCompiler<'a> {
                 self.bb.define_label(*op_span, l_rhs);
                 self.do_cond(right, l_true, l_false, negate)
             }
+            // both branches are conditions jumping to the same labels, the then branch jumps
+            // over the else branch if it does not jump to l_false
+            ExprKind::Conditional(cond, then_expr, else_expr) => {
+                let l_then = self.bb.new_label();
+                let l_else = self.bb.new_label();
+                self.do_cond(cond, l_then, l_else, false);
+                self.bb.define_label(then_expr.span, l_then);
+                self.do_cond(then_expr, l_true, l_false, negate);
+                self.bb.append(then_expr.span, InsKind::Jmp(l_true));
+                self.bb.define_label(else_expr.span, l_else);
+                self.do_cond(else_expr, l_true, l_false, negate)
+            }
             _ => {
                 self.do_expr(&expr);
                 if negate {
@@ -325,3 +686,98 @@ impl<'a> FunctionCompiler<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::analyze;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_constants_shared_by_functions() {
+        let mut ctx = CompilerContext::default();
+        let source_id = ctx.sources.add_from_string(
+            r#"fun f() { return ["a", 1.5, "b"]; }
+               fun main() { return [f(), "a", 1.5, "a" "b", 0.0]; }"#,
+        );
+        let ast = parse(&mut ctx, source_id).unwrap();
+        let program = analyze(&ctx, &ast).unwrap();
+        let bc = compile(&ctx, &program).unwrap();
+        let constants: Vec<String> = bc.constants.iter().map(|c| format!("{}", c)).collect();
+        assert_eq!(constants, ["a", "1.5", "b", "ab", "0.0"]);
+        assert_eq!(bc.stats().constant_loads, 7);
+    }
+
+    #[test]
+    fn test_local_names() {
+        let source = "fun main(a) { var b = a; { var c = b; } return locals(); }";
+        for debug_info in [false, true] {
+            let mut ctx = CompilerContext::default();
+            ctx.debug_info = debug_info;
+            let source_id = ctx.sources.add_from_string(source);
+            let ast = parse(&mut ctx, source_id).unwrap();
+            let program = analyze(&ctx, &ast).unwrap();
+            let bc = compile(&ctx, &program).unwrap();
+            let Some(local_names) = bc.local_names else {
+                assert!(!debug_info);
+                continue;
+            };
+            let names: Vec<&str> = local_names.iter().map(|l| l.name.as_ref()).collect();
+            assert_eq!(names, ["a", "b", "c"]);
+            let (a, b, c) = (&local_names[0], &local_names[1], &local_names[2]);
+            assert_eq!(a.live, 0..bc.code.len());
+            assert!(a.live.start < b.live.start && b.live.start < c.live.start);
+            assert!(c.live.end < b.live.end && b.live.end <= a.live.end);
+        }
+    }
+
+    #[test]
+    fn test_unreachable_functions_left_out() {
+        let source = "fun main() { f(); print(time_it(g)); }\n\
+                      fun f() { print(1); }\n\
+                      fun g() {}\n\
+                      fun unused() { f(); }";
+        for prune in [false, true] {
+            let mut ctx = CompilerContext::default();
+            ctx.prune = prune;
+            let source_id = ctx.sources.add_from_string(source);
+            let ast = parse(&mut ctx, source_id).unwrap();
+            let program = analyze(&ctx, &ast).unwrap();
+            let bc = compile(&ctx, &program).unwrap();
+            assert!(bc.function_index("g").is_some());
+            assert_eq!(bc.function_index("unused").is_some(), !prune);
+            assert_eq!(bc.globals[3].is_null(), prune);
+        }
+    }
+
+    #[test]
+    fn test_disjoint_blocks_share_slots() {
+        let mut slots = SlotAllocator::new(1, 5);
+        slots.enter_block();
+        assert_eq!(slots.declare(LocalId(1)), 1);
+        slots.enter_block();
+        assert_eq!(slots.declare(LocalId(2)), 2);
+        slots.exit_block();
+        slots.enter_block();
+        assert_eq!(slots.declare(LocalId(3)), 2);
+        assert_eq!(slots.slot(LocalId(1)), 1);
+        slots.exit_block();
+        assert_eq!(slots.declare(LocalId(4)), 2);
+        slots.exit_block();
+        assert_eq!(slots.slot(LocalId(0)), 0);
+        assert_eq!(slots.max_slots, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "LocalId(2) accessed outside of its block")]
+    fn test_access_after_block_panics() {
+        let mut slots = SlotAllocator::new(0, 3);
+        slots.enter_block();
+        slots.declare(LocalId(0));
+        slots.enter_block();
+        slots.declare(LocalId(2));
+        slots.exit_block();
+        slots.declare(LocalId(1));
+        slots.slot(LocalId(2));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::bc::builder::{BytecodeBuilder, InsKind, Label};
+use crate::bc::compiler::ConstantPool;
+use crate::module::LinkedLibrary;
+use crate::src::Span;
+use natrix_runtime::bc::{Bytecode, Immediate, LocalName, Opcode};
+use natrix_runtime::leb128::{decode_sleb128, decode_uleb128};
+use natrix_runtime::value::{Function, Value};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::rc::Rc;
+
+/// Appends a precompiled library to the bytecode of a program: its globals after the globals of
+/// `bc`, the code of its functions after the code of `bc` and its constants to the pool. Returns
+/// the index of the first global of the library in `bc`.
+///
+/// The functions are named `prefix.name`, and their instructions get the span of the import of
+/// the library, since its sources are not available. Each function is decoded and encoded again,
+/// because the indices of globals and constants change, and with them the size of instructions
+/// and the offsets of the jumps over them.
+pub(super) fn link_library(
+    bc: &mut Bytecode,
+    cp: &mut ConstantPool,
+    library: &LinkedLibrary,
+    prefix: &str,
+) -> usize {
+    let global_base = bc.globals.len();
+    for global in &library.bc.globals {
+        let function = global.is_function().then(|| global.unwrap_function());
+        let Some(Function::UserDefined {
+            name,
+            param_count,
+            max_slots,
+            code_handle,
+            location,
+        }) = function.as_deref()
+        else {
+            bc.globals.push(global.clone());
+            continue;
+        };
+        let range = library.bc.function_range(*code_handle);
+        let (code, spans, offsets) =
+            relocate(&library.bc, range.clone(), global_base, cp, library.span);
+        let start = bc.code.len();
+        bc.code.extend(code);
+        bc.line_table.extend(
+            spans
+                .into_iter()
+                .map(|(offset, span)| (start + offset, span.to_code_span())),
+        );
+        if let Some(local_names) = &mut bc.local_names {
+            let locals = library.bc.local_names.iter().flatten();
+            local_names.extend(
+                locals
+                    .filter(|local| range.contains(&local.live.start))
+                    .filter_map(|local| {
+                        Some(LocalName {
+                            name: local.name.clone(),
+                            slot: local.slot,
+                            live: start + offsets.get(&local.live.start)?
+                                ..start + offsets.get(&local.live.end)?,
+                        })
+                    }),
+            );
+        }
+        bc.globals
+            .push(Value::from_function(Rc::new(Function::UserDefined {
+                name: format!("{}.{}", prefix, name).into(),
+                param_count: *param_count,
+                max_slots: *max_slots,
+                code_handle: start,
+                location: location.clone(),
+            })));
+    }
+    global_base
+}
+
+// The code of a function, the span of each instruction and the new offset of old offsets
+type Relocated = (Vec<u8>, Vec<(usize, Span)>, HashMap<usize, usize>);
+
+// Encodes the function in `range` of `library` again, for globals starting at `global_base` and
+// the constants of `cp`. Also returns the new offset, relative to the start of the function, of
+// each old offset that a jump or the live range of a local refers to.
+fn relocate(
+    library: &Bytecode,
+    range: Range<usize>,
+    global_base: usize,
+    cp: &mut ConstantPool,
+    span: Span,
+) -> Relocated {
+    let instructions = decode(&library.code, range.clone());
+    let mut bb = BytecodeBuilder::new();
+
+    // a label at each offset referred to, where an instruction starts or the function ends
+    let starts: HashSet<usize> = instructions
+        .iter()
+        .map(|(offset, ..)| *offset)
+        .chain([range.end])
+        .collect();
+    let targets = instructions
+        .iter()
+        .filter(|(_, opcode, _)| matches!(opcode.immediate(), Immediate::Label))
+        .map(|&(offset, _, operand)| (offset as i64 + operand) as usize);
+    let bounds = library
+        .local_names
+        .iter()
+        .flatten()
+        .filter(|local| range.contains(&local.live.start))
+        .flat_map(|local| [local.live.start, local.live.end]);
+    let mut labels: HashMap<usize, Label> = HashMap::new();
+    for offset in targets.chain(bounds) {
+        if starts.contains(&offset) && !labels.contains_key(&offset) {
+            labels.insert(offset, bb.new_label());
+        }
+    }
+
+    for &(offset, opcode, operand) in &instructions {
+        if let Some(label) = labels.get(&offset) {
+            bb.define_label(span, *label);
+        }
+        let index = operand as usize;
+        let target = || labels[&((offset as i64 + operand) as usize)];
+        let kind = match opcode {
+            Opcode::Push0 => InsKind::Push0,
+            Opcode::Push1 => InsKind::Push1,
+            Opcode::PushNull => InsKind::PushNull,
+            Opcode::PushFalse => InsKind::PushFalse,
+            Opcode::PushTrue => InsKind::PushTrue,
+            Opcode::PushInt => InsKind::PushInt(operand),
+            Opcode::PushConst => InsKind::PushConst(cp.add_constant(&library.constants[index])),
+            Opcode::Add => InsKind::Add,
+            Opcode::Sub => InsKind::Sub,
+            Opcode::Mul => InsKind::Mul,
+            Opcode::Div => InsKind::Div,
+            Opcode::Mod => InsKind::Mod,
+            Opcode::Eq => InsKind::Eq,
+            Opcode::Ne => InsKind::Ne,
+            Opcode::Lt => InsKind::Lt,
+            Opcode::Le => InsKind::Le,
+            Opcode::Gt => InsKind::Gt,
+            Opcode::Ge => InsKind::Ge,
+            Opcode::Neg => InsKind::Neg,
+            Opcode::Not => InsKind::Not,
+            Opcode::Load0 => InsKind::Load0,
+            Opcode::LoadLocal => InsKind::LoadLocal(index),
+            Opcode::StoreLocal => InsKind::StoreLocal(index),
+            Opcode::LoadGlobal => InsKind::LoadGlobal(global_base + index),
+            Opcode::StoreGlobal => InsKind::StoreGlobal(global_base + index),
+            Opcode::LoadBuiltin => InsKind::LoadBuiltin(index),
+            Opcode::MakeList => InsKind::MakeList(index),
+            Opcode::GetItem => InsKind::GetItem,
+            Opcode::SetItem => InsKind::SetItem,
+            Opcode::Jmp => InsKind::Jmp(target()),
+            Opcode::JFalse => InsKind::JFalse(target()),
+            Opcode::JTrue => InsKind::JTrue(target()),
+            Opcode::Call => InsKind::Call(index),
+            Opcode::Ret => InsKind::Ret,
+            Opcode::Pop => InsKind::Pop,
+            Opcode::Unpack => InsKind::Unpack(index),
+            Opcode::GetSlice => InsKind::GetSlice,
+            Opcode::TryBegin => InsKind::TryBegin(target()),
+            Opcode::TryEnd => InsKind::TryEnd,
+            Opcode::DeferBegin => InsKind::DeferBegin(target()),
+            Opcode::DeferEnd => InsKind::DeferEnd,
+        };
+        bb.append(span, kind);
+    }
+    if let Some(label) = labels.get(&range.end) {
+        bb.define_label(span, *label);
+    }
+
+    let (code, spans, label_offsets) = bb.encode();
+    let offsets = labels
+        .into_iter()
+        .map(|(offset, label)| (offset, label_offsets[label.0]))
+        .collect();
+    (code, spans, offsets)
+}
+
+// The offset, opcode and operand (zero if it has none) of each instruction in `range`, which
+// the library was verified to contain
+fn decode(code: &[u8], range: Range<usize>) -> Vec<(usize, Opcode, i64)> {
+    let mut instructions = Vec::new();
+    let mut ip = range.start;
+    while ip < range.end {
+        let offset = ip;
+        let opcode = Opcode::from_u8(code[ip]).expect("invalid opcode");
+        ip += 1;
+        let fetch = || {
+            let byte = code[ip];
+            ip += 1;
+            byte
+        };
+        let operand = match opcode.immediate() {
+            Immediate::None => 0,
+            Immediate::Uleb => decode_uleb128(fetch) as i64,
+            Immediate::Sleb | Immediate::Label => decode_sleb128(fetch),
+        };
+        instructions.push((offset, opcode, operand));
+    }
+    instructions
+}
@@ -1,2 +1,3 @@
 mod builder;
 pub mod compiler;
+mod link;
@@ -0,0 +1,126 @@
+use crate::ctx::CompilerContext;
+use crate::src::Span;
+use natrix_runtime::bc::{Bytecode, Coverage, Opcode};
+use natrix_runtime::value::Function;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Renders the coverage of a bytecode run as an lcov tracefile.
+///
+/// Line counts are the maximum execution count of the instructions starting on that line.
+/// Every conditional jump contributes two branches - the jump being taken and falling through.
+pub fn lcov_report(ctx: &CompilerContext, bc: &Bytecode, coverage: &Coverage) -> String {
+    let mut out = String::new();
+    for (source_index, file) in collect(ctx, bc, coverage) {
+        let source = ctx.sources.iter().nth(source_index).unwrap();
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", source.name()).unwrap();
+        for (line, name, _) in file.functions.iter() {
+            writeln!(out, "FN:{},{}", line, name).unwrap();
+        }
+        for (_, name, count) in file.functions.iter() {
+            writeln!(out, "FNDA:{},{}", count, name).unwrap();
+        }
+        writeln!(out, "FNF:{}", file.functions.len()).unwrap();
+        let fn_hit = file.functions.iter().filter(|(_, _, c)| *c > 0).count();
+        writeln!(out, "FNH:{}", fn_hit).unwrap();
+        let mut block = 0;
+        let mut prev_line = 0;
+        let mut branches_hit = 0;
+        for (line, hits, taken) in file.branches.iter() {
+            block = if *line == prev_line { block + 1 } else { 0 };
+            prev_line = *line;
+            if *hits == 0 {
+                writeln!(out, "BRDA:{},{},0,-", line, block).unwrap();
+                writeln!(out, "BRDA:{},{},1,-", line, block).unwrap();
+            } else {
+                writeln!(out, "BRDA:{},{},0,{}", line, block, taken).unwrap();
+                writeln!(out, "BRDA:{},{},1,{}", line, block, hits - taken).unwrap();
+                branches_hit += (*taken > 0) as usize + (hits - taken > 0) as usize;
+            }
+        }
+        writeln!(out, "BRF:{}", file.branches.len() * 2).unwrap();
+        writeln!(out, "BRH:{}", branches_hit).unwrap();
+        for (line, count) in file.lines.iter() {
+            writeln!(out, "DA:{},{}", line, count).unwrap();
+        }
+        writeln!(out, "LF:{}", file.lines.len()).unwrap();
+        let lines_hit = file.lines.values().filter(|c| **c > 0).count();
+        writeln!(out, "LH:{}", lines_hit).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+    }
+    out
+}
+
+/// Renders the sources annotated with line execution counts in the style of gcov.
+///
+/// Lines without code are marked with `-`, lines with code that never executed with `#####`.
+pub fn annotate(ctx: &CompilerContext, bc: &Bytecode, coverage: &Coverage) -> String {
+    let mut out = String::new();
+    for (source_index, file) in collect(ctx, bc, coverage) {
+        let source = ctx.sources.iter().nth(source_index).unwrap();
+        writeln!(out, "{:>9}:{:>5}:Source:{}", "-", 0, source.name()).unwrap();
+        for (i, text) in source.content().lines().enumerate() {
+            let count = match file.lines.get(&(i + 1)) {
+                None => "-".to_string(),
+                Some(0) => "#####".to_string(),
+                Some(count) => count.to_string(),
+            };
+            writeln!(out, "{:>9}:{:>5}:{}", count, i + 1, text).unwrap();
+        }
+    }
+    out
+}
+
+#[derive(Default)]
+struct FileCoverage {
+    lines: BTreeMap<usize, u64>,            // line -> execution count
+    branches: Vec<(usize, u64, u64)>,       // line, executions, jumps taken
+    functions: Vec<(usize, Box<str>, u64)>, // line, name, call count
+}
+
+// Groups the execution counts by source file and line, using the line table of the bytecode.
+fn collect(
+    ctx: &CompilerContext,
+    bc: &Bytecode,
+    coverage: &Coverage,
+) -> BTreeMap<usize, FileCoverage> {
+    let hits = |offset: usize| coverage.hits.get(offset).copied().unwrap_or(0);
+    let line_of = |code_span| {
+        Span::from_code_span(&ctx.sources, code_span)
+            .start_pos(&ctx.sources)
+            .0
+    };
+    let mut files: BTreeMap<usize, FileCoverage> = BTreeMap::new();
+    for (offset, code_span) in bc.line_table.iter() {
+        let line = line_of(*code_span);
+        let file = files.entry(code_span.source_index).or_default();
+        // instructions with an empty span are generated at the end of a statement, such as the
+        // jump over an else branch or the implicit return, they make no line executable
+        if code_span.start < code_span.end {
+            let count = file.lines.entry(line).or_insert(0);
+            *count = (*count).max(hits(*offset));
+        }
+        if matches!(
+            Opcode::from_u8(bc.code[*offset]),
+            Some(Opcode::JFalse | Opcode::JTrue)
+        ) {
+            let taken = coverage.taken.get(*offset).copied().unwrap_or(0);
+            file.branches.push((line, hits(*offset), taken));
+        }
+    }
+    for global in bc.globals.iter() {
+        if global.is_function()
+            && let Function::UserDefined {
+                name, code_handle, ..
+            } = global.unwrap_function().as_ref()
+            && let Some(code_span) = bc.span_at(*code_handle)
+        {
+            let line = line_of(code_span);
+            let file = files.entry(code_span.source_index).or_default();
+            file.functions
+                .push((line, name.clone(), hits(*code_handle)));
+        }
+    }
+    files
+}
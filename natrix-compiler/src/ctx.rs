@@ -1,26 +1,92 @@
-use crate::src::Sources;
-use crate::token_type::{TokenType, KEYWORDS};
-use natrix_runtime::value::Builtin;
-use std::collections::HashMap;
+use crate::error::{error_at, SourceError};
+use crate::module::Modules;
+use crate::src::{Sources, Span};
+use crate::token_type::{Edition, TokenType, KEYWORDS};
+use natrix_runtime::ctx::RuntimeContext;
+use natrix_runtime::value::{Builtin, Function};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::num::NonZeroU32;
+use std::rc::Rc;
+
+/// Name of the locals introduced by the analysis, which no identifier can refer to.
+pub const TEMP_NAME: &str = "(temp)";
 
 /// Compiler context containing shared infrastructure used throughout the compilation pipeline.
 pub struct CompilerContext {
     pub sources: Sources,
     pub interner: Interner,
+    pub edition: Edition,
+    pub strict: bool, // enables the extra checks of --strict in the analysis
+    pub defines: HashMap<String, String>, // flags for `@if`, from --define name=value
+    pub debug_info: bool, // keeps the names of locals in the bytecode, from --debug
+    pub prune: bool, // leaves functions unreachable from main out of the bytecode, unless --keep-all
+    pub jobs: usize, // threads parsing the files of a program, from --jobs
+    pub modules: Modules, // the imported modules, see `module::parse_program`
+    natives: Vec<Rc<Function>>, // see `declare_natives`
+    warnings: RefCell<Vec<SourceError>>,
 }
 
 impl CompilerContext {
     pub fn new() -> Self {
+        Self::with_edition(Edition::default())
+    }
+
+    pub fn with_edition(edition: Edition) -> Self {
         let mut interner = Interner::new();
         for builtin in Builtin::ALL {
             interner.intern(builtin.name());
         }
+        interner.intern(TEMP_NAME);
         Self {
             sources: Sources::new(),
             interner,
+            edition,
+            strict: false,
+            defines: HashMap::new(),
+            debug_info: false,
+            prune: false,
+            jobs: 1,
+            modules: Modules::new(),
+            natives: Vec::new(),
+            warnings: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Whether the condition of `@if (flag)` or `@if (flag == "value")` holds. A flag without a
+    /// value is set if it is defined as anything but `false` or `0`.
+    pub fn flag_is_set(&self, flag: Name, value: Option<&str>) -> bool {
+        match (self.defines.get(self.interner.resolve(flag)), value) {
+            (Some(defined), Some(value)) => defined == value,
+            (Some(defined), None) => defined != "false" && defined != "0",
+            (None, _) => false,
         }
     }
+
+    /// Makes the native functions registered with `rt` known to the analysis, so that programs can
+    /// call them by name like built-in functions. The compiled program must run with `rt`, or a
+    /// context with the same natives.
+    pub fn declare_natives(&mut self, rt: &RuntimeContext) {
+        for native in rt.natives() {
+            self.interner.intern(native.name());
+        }
+        self.natives = rt.natives().to_vec();
+    }
+
+    /// The functions declared by `declare_natives`.
+    pub fn natives(&self) -> &[Rc<Function>] {
+        &self.natives
+    }
+
+    /// Records a diagnostic that does not stop the compilation.
+    pub fn warn(&self, span: Span, message: impl Into<Box<str>>) {
+        self.warnings.borrow_mut().push(error_at(span, message));
+    }
+
+    /// Returns the warnings recorded since the last call, in the order they were reported.
+    pub fn take_warnings(&self) -> Vec<SourceError> {
+        self.warnings.take()
+    }
 }
 
 impl Default for CompilerContext {
@@ -43,6 +109,7 @@ pub struct Interner {
     // This is safe and simple, but wastes memory. A future optimization could use unsafe
     // code to store raw pointers into `strings` as HashMap keys, eliminating the duplication.
     map: HashMap<Box<str>, Name>,
+    literals: HashSet<Rc<str>>, // string literals, see `intern_literal`
 }
 
 impl Interner {
@@ -50,6 +117,7 @@ impl Interner {
         let mut interner = Self {
             strings: Vec::new(),
             map: HashMap::new(),
+            literals: HashSet::new(),
         };
         for &(kw, _) in KEYWORDS {
             interner.intern(kw);
@@ -77,24 +145,87 @@ impl Interner {
         sym
     }
 
+    /// Returns a shared copy of the string literal `s`, so that identical literals anywhere in
+    /// the program are one allocation in the AST, the HIR and the constant pool.
+    pub fn intern_literal(&mut self, s: &str) -> Rc<str> {
+        if let Some(literal) = self.literals.get(s) {
+            return literal.clone();
+        }
+        let literal: Rc<str> = s.into();
+        self.literals.insert(literal.clone());
+        literal
+    }
+
     pub fn resolve(&self, sym: Name) -> &str {
         &self.strings[sym.0.get() as usize - 1]
     }
 
-    pub fn resolve_keyword(&self, name: Name) -> Option<TokenType> {
+    /// Returns the keyword token type of `name` if it is a keyword in `edition`.
+    pub fn resolve_keyword(&self, name: Name, edition: Edition) -> Option<TokenType> {
         let idx = name.0.get() as usize - 1;
-        KEYWORDS.get(idx).map(|&(_, tt)| tt)
+        KEYWORDS
+            .get(idx)
+            .map(|&(_, tt)| tt)
+            .filter(|tt| tt.edition() <= edition)
     }
 
     pub fn lookup(&self, name: &str) -> Option<Name> {
         self.map.get(name).copied()
     }
+
+    /// Interns the strings of `other` in the order `other` interned them, so that the names are
+    /// the same as if its input had been interned here, and returns the translation of its
+    /// names.
+    pub fn merge(&mut self, other: &Interner) -> NameMap {
+        NameMap(other.strings.iter().map(|s| self.intern(s)).collect())
+    }
+}
+
+/// The names of an interner merged into another one, see [`Interner::merge`].
+pub struct NameMap(Vec<Name>);
+
+impl NameMap {
+    pub fn get(&self, name: Name) -> Name {
+        self.0[name.0.get() as usize - 1]
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_intern_literal() {
+        let mut interner = Interner::new();
+        let first = interner.intern_literal("hello");
+        let second = interner.intern_literal("hello");
+        assert!(Rc::ptr_eq(&first, &second));
+        assert_eq!(&*interner.intern_literal("world"), "world");
+        // literals are not names
+        assert_eq!(interner.lookup("hello"), None);
+    }
+
+    #[test]
+    fn test_flag_is_set() {
+        let mut ctx = CompilerContext::new();
+        ctx.defines.insert("debug".into(), "true".into());
+        ctx.defines.insert("verbose".into(), "0".into());
+        ctx.defines.insert("target".into(), "wasm".into());
+        let mut flag = |name| ctx.interner.intern(name);
+        let (debug, verbose, target, other) = (
+            flag("debug"),
+            flag("verbose"),
+            flag("target"),
+            flag("other"),
+        );
+        assert!(ctx.flag_is_set(debug, None));
+        assert!(!ctx.flag_is_set(verbose, None));
+        assert!(ctx.flag_is_set(verbose, Some("0")));
+        assert!(ctx.flag_is_set(target, Some("wasm")));
+        assert!(!ctx.flag_is_set(target, Some("x86")));
+        assert!(!ctx.flag_is_set(other, None));
+    }
+
     #[test]
     fn test_intern_basic() {
         let mut interner = Interner::new();
@@ -136,6 +267,21 @@ mod tests {
         assert_eq!(interner.resolve(sym2), "🦀");
     }
 
+    #[test]
+    fn test_merge() {
+        let mut interner = Interner::new();
+        let shared = interner.intern("shared");
+        let mut other = Interner::new();
+        let own = other.intern("own");
+        let other_shared = other.intern("shared");
+        let names = interner.merge(&other);
+        assert_eq!(names.get(other_shared), shared);
+        assert_eq!(interner.resolve(names.get(own)), "own");
+        // keywords have the same names in every interner
+        let kw = other.intern("while");
+        assert_eq!(interner.merge(&other).get(kw), interner.intern("while"));
+    }
+
     #[test]
     fn test_name_size_optimization() {
         // Name should be 4 bytes (u32)
@@ -143,4 +289,20 @@ mod tests {
         // Option<Name> should also be 4 bytes due to NonZeroU32 optimization
         assert_eq!(size_of::<Option<Name>>(), 4);
     }
+
+    #[test]
+    fn test_resolve_keyword_by_edition() {
+        let mut interner = Interner::new();
+        let name = interner.intern("match");
+        assert_eq!(interner.resolve_keyword(name, Edition::E1), None);
+        assert_eq!(
+            interner.resolve_keyword(name, Edition::E2),
+            Some(TokenType::KwMatch)
+        );
+        let name = interner.intern("while");
+        assert_eq!(
+            interner.resolve_keyword(name, Edition::E1),
+            Some(TokenType::KwWhile)
+        );
+    }
 }
@@ -0,0 +1,58 @@
+use crate::ast::{FunDecl, Program};
+use crate::ctx::CompilerContext;
+use natrix_runtime::value::Builtin;
+use std::fmt::Write;
+
+/// Renders the signatures and doc comments of all functions in `program` as Markdown.
+pub fn to_markdown(ctx: &CompilerContext, title: &str, program: &Program) -> String {
+    let mut out = String::new();
+    writeln!(out, "# {}", title).unwrap();
+    for decl in program.decls.iter() {
+        fun_decl(ctx, &mut out, decl);
+    }
+    out
+}
+
+/// Renders the signature and description of every builtin as Markdown, for `natrix doc --builtins`.
+pub fn builtins_markdown() -> String {
+    let mut out = String::new();
+    writeln!(out, "# Builtins").unwrap();
+    for builtin in Builtin::ALL {
+        writeln!(out).unwrap();
+        writeln!(out, "## {}", builtin.name()).unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "```").unwrap();
+        writeln!(out, "{}", builtin.signature()).unwrap();
+        writeln!(out, "```").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "{}", builtin.description()).unwrap();
+    }
+    out
+}
+
+fn fun_decl(ctx: &CompilerContext, out: &mut String, decl: &FunDecl) {
+    let name = ctx.interner.resolve(decl.name);
+    let params: Vec<&str> = decl
+        .params
+        .iter()
+        .map(|p| ctx.interner.resolve(p.name))
+        .collect();
+    writeln!(out).unwrap();
+    writeln!(out, "## {}", name).unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "```").unwrap();
+    writeln!(out, "fun {}({})", name, params.join(", ")).unwrap();
+    writeln!(out, "```").unwrap();
+    if let Some(doc) = &decl.doc {
+        writeln!(out).unwrap();
+        writeln!(out, "{}", doc).unwrap();
+    }
+    if !params.is_empty() {
+        writeln!(out).unwrap();
+        writeln!(out, "**Parameters:**").unwrap();
+        writeln!(out).unwrap();
+        for param in params {
+            writeln!(out, "- `{}`", param).unwrap();
+        }
+    }
+}
@@ -1,32 +1,81 @@
 use crate::src::{Sources, Span};
-use natrix_runtime::error::{NxError, NxResult};
+use natrix_runtime::bc::CodeSpan;
+use natrix_runtime::error::{ErrorKind, NxError, NxResult};
 use std::fmt::{Debug, Display};
 
 pub type SourceResult<T> = Result<T, SourceError>;
 
 #[derive(Debug)]
 pub struct SourceError {
+    pub kind: ErrorKind, // `Other` for all errors found before running
     pub message: Box<str>,
     pub span: Span,
+    /// The calls in progress when a runtime error occurred, innermost first: the name of the
+    /// called function and the location of the call.
+    pub trace: Vec<(Box<str>, Span)>,
 }
 
 impl SourceError {
     pub fn display_with<'a>(&'a self, sources: &'a Sources) -> ErrorDisplay<'a> {
-        ErrorDisplay::new(sources, &self.message, Some(self.span))
+        ErrorDisplay {
+            trace: &self.trace,
+            ..ErrorDisplay::new(sources, &self.message, Some(self.span))
+        }
+    }
+
+    /// Records that the error occurred in a call of `function` at `span`, as it propagates out
+    /// of the call.
+    pub fn in_call(mut self, function: &str, span: Span) -> Self {
+        self.trace.push((function.into(), span));
+        self
+    }
+
+    /// Like `display_with`, but reports the diagnostic as a warning.
+    pub fn display_as_warning<'a>(&'a self, sources: &'a Sources) -> ErrorDisplay<'a> {
+        ErrorDisplay {
+            severity: "warning",
+            ..self.display_with(sources)
+        }
     }
 }
 
 pub trait AttachErrSpan {
     type Output;
     fn err_at(self, span: Span) -> Self::Output;
+
+    /// Like `err_at`, but prefers the location of the failing instruction recorded by the
+    /// bytecode interpreter, which must have run code compiled from `sources`.
+    fn err_at_bc(self, sources: &Sources, span: Span) -> Self::Output;
 }
 
 impl AttachErrSpan for NxError {
     type Output = SourceError;
     fn err_at(self, span: Span) -> SourceError {
         SourceError {
+            kind: self.kind,
             message: self.message,
             span,
+            trace: Vec::new(),
+        }
+    }
+
+    fn err_at_bc(self, sources: &Sources, span: Span) -> SourceError {
+        let span = match self.span {
+            Some(code_span) => Span::from_code_span(sources, code_span),
+            None => span,
+        };
+        // calls without a location can only come from bytecode compiled without a line table
+        let trace = self
+            .trace
+            .iter()
+            .filter_map(|(function, code_span)| {
+                let span = Span::from_code_span(sources, (*code_span)?);
+                Some((function.clone(), span))
+            })
+            .collect();
+        SourceError {
+            trace,
+            ..self.err_at(span)
         }
     }
 }
@@ -36,20 +85,28 @@ impl<T> AttachErrSpan for NxResult<T> {
     fn err_at(self, span: Span) -> SourceResult<T> {
         self.map_err(|e| e.err_at(span))
     }
+
+    fn err_at_bc(self, sources: &Sources, span: Span) -> SourceResult<T> {
+        self.map_err(|e| e.err_at_bc(sources, span))
+    }
 }
 
 pub struct ErrorDisplay<'a> {
     sources: &'a Sources,
+    severity: &'static str,
     message: &'a str,
     span: Option<Span>,
+    trace: &'a [(Box<str>, Span)],
 }
 
 impl<'a> ErrorDisplay<'a> {
     fn new(sources: &'a Sources, message: &'a str, span: Option<Span>) -> Self {
         Self {
             sources,
+            severity: "error",
             message,
             span,
+            trace: &[],
         }
     }
 }
@@ -63,25 +120,44 @@ impl Display for ErrorDisplay<'_> {
             let text = src.get_line(sline);
             write!(
                 f,
-                "{}:{}:{}: error: {}",
+                "{}:{}:{}: {}: {}",
                 src.name(),
                 sline,
                 scol,
+                self.severity,
                 self.message
             )?;
             if !text.trim().is_empty() {
                 let cnt: usize = if eline == sline {
-                    if ecol <= scol { 1 } else { ecol - scol }
+                    if ecol <= scol {
+                        1
+                    } else {
+                        ecol - scol
+                    }
                 } else {
                     text.chars().count() - scol + 1
                 };
-                write!(f, "\n{}\n{}{}", text, " ".repeat(scol - 1), "^".repeat(cnt))
-            } else {
-                Ok(())
+                write!(f, "\n{}\n{}{}", text, " ".repeat(scol - 1), "^".repeat(cnt))?;
             }
         } else {
-            write!(f, "error: {}", self.message)
+            write!(f, "{}: {}", self.severity, self.message)?;
         }
+        // a run of frames that print the same, e.g. of a deep recursion, is shown once
+        let frames: Vec<_> = self
+            .trace
+            .iter()
+            .map(|(function, span)| (function, span.location(self.sources)))
+            .collect();
+        for run in frames.chunk_by(|a, b| a == b) {
+            let (function, location) = &run[0];
+            write!(f, "\n  in {}, called from {}", function, location)?;
+            match run.len() {
+                1 => {}
+                2 => write!(f, "\n  in {}, called from {}", function, location)?,
+                n => write!(f, "\n  ... repeated {} times", n - 1)?,
+            }
+        }
+        Ok(())
     }
 }
 
@@ -91,7 +167,16 @@ pub fn err_at<T>(span: Span, message: impl Into<Box<str>>) -> SourceResult<T> {
 
 pub fn error_at(span: Span, message: impl Into<Box<str>>) -> SourceError {
     SourceError {
+        kind: ErrorKind::Other,
         message: message.into(),
         span,
+        trace: Vec::new(),
     }
 }
+
+/// Formats a warning of the bytecode interpreter like those of the analysis, at the instruction
+/// raising it, see `Interpreter::set_warning_format`. The bytecode must be compiled from `sources`.
+pub fn format_bc_warning(sources: &Sources, message: &str, code_span: CodeSpan) -> String {
+    let warning = error_at(Span::from_code_span(sources, code_span), message);
+    warning.display_as_warning(sources).to_string()
+}
@@ -0,0 +1,238 @@
+use crate::ctx::CompilerContext;
+use crate::hir::{Expr, ExprKind, GlobalId, GlobalKind, Program, Stmt, StmtKind};
+use std::fmt::Write;
+
+/// How a function refers to another one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    /// The function is called by name, e.g. `f(1)`.
+    Call,
+    /// The function is used as a value, e.g. passed to `time_it` or stored in a list, and may be
+    /// called wherever the value ends up.
+    Reference,
+}
+
+/// The functions each function of a program refers to.
+///
+/// A function is only known to be unused if no reachable function refers to it at all, since a
+/// function used as a value can be called by any code that gets hold of the value.
+#[derive(Debug)]
+pub struct CallGraph {
+    /// The functions referred to by each global, indexed by global, in the order of their ids.
+    /// A function both called and used as a value has a single `Call` edge.
+    pub edges: Vec<Vec<(GlobalId, EdgeKind)>>,
+}
+
+impl CallGraph {
+    pub fn build(program: &Program) -> Self {
+        let edges = program
+            .globals
+            .iter()
+            .map(|global| {
+                // the functions of a library call each other within its bytecode
+                let GlobalKind::Function(fun_decl) = &global.kind else {
+                    return Vec::new();
+                };
+                let mut edges = Vec::new();
+                fun_decl.body.iter().for_each(|s| do_stmt(s, &mut edges));
+                edges.sort_by_key(|(id, kind)| (id.0, *kind));
+                edges.dedup_by_key(|(id, _)| *id);
+                edges
+            })
+            .collect();
+        CallGraph { edges }
+    }
+
+    /// Whether each global can be reached from `root` along the edges, indexed by global.
+    pub fn reachable_from(&self, root: GlobalId) -> Vec<bool> {
+        let mut reachable = vec![false; self.edges.len()];
+        reachable[root.0] = true;
+        let mut stack = vec![root];
+        while let Some(id) = stack.pop() {
+            for (callee, _) in &self.edges[id.0] {
+                if !reachable[callee.0] {
+                    reachable[callee.0] = true;
+                    stack.push(*callee);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Renders the graph in the DOT format of Graphviz. References are dashed, and functions
+    /// unreachable from `main` are gray.
+    pub fn to_dot(&self, ctx: &CompilerContext, program: &Program) -> String {
+        let name = |id: GlobalId| ctx.interner.resolve(program.globals[id.0].name);
+        let main = program
+            .globals
+            .iter()
+            .find(|g| ctx.interner.resolve(g.name) == "main");
+        let reachable = main.map(|main| self.reachable_from(main.id));
+        let mut out = String::from("digraph calls {\n");
+        for global in &program.globals {
+            write!(out, "    \"{}\"", name(global.id)).unwrap();
+            if reachable.as_ref().is_some_and(|r| !r[global.id.0]) {
+                out.push_str(" [color=gray, fontcolor=gray]");
+            }
+            out.push_str(";\n");
+        }
+        for (index, edges) in self.edges.iter().enumerate() {
+            for (callee, kind) in edges {
+                write!(
+                    out,
+                    "    \"{}\" -> \"{}\"",
+                    name(GlobalId(index)),
+                    name(*callee)
+                )
+                .unwrap();
+                if *kind == EdgeKind::Reference {
+                    out.push_str(" [style=dashed]");
+                }
+                out.push_str(";\n");
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn do_stmt(stmt: &Stmt, edges: &mut Vec<(GlobalId, EdgeKind)>) {
+    match &stmt.kind {
+        StmtKind::Block(stmts) => stmts.iter().for_each(|s| do_stmt(s, edges)),
+        StmtKind::Break(_, expr) | StmtKind::VarDecl(_, expr) => {
+            if let Some(expr) = expr {
+                do_expr(expr, edges);
+            }
+        }
+        StmtKind::Continue(_) => {}
+        StmtKind::Defer(body) => do_stmt(body, edges),
+        StmtKind::Expr(expr)
+        | StmtKind::Return(expr)
+        | StmtKind::StoreGlobal(_, expr)
+        | StmtKind::StoreLocal(_, expr)
+        | StmtKind::Unpack(_, expr) => do_expr(expr, edges),
+        StmtKind::If(cond, then_body, else_body) => {
+            do_expr(cond, edges);
+            do_stmt(then_body, edges);
+            if let Some(else_body) = else_body {
+                do_stmt(else_body, edges);
+            }
+        }
+        StmtKind::SetItem(array, index, value) => {
+            do_expr(array, edges);
+            do_expr(index, edges);
+            do_expr(value, edges);
+        }
+        StmtKind::While(_, cond, body, update) => {
+            do_expr(cond, edges);
+            do_stmt(body, edges);
+            if let Some(update) = update {
+                do_stmt(update, edges);
+            }
+        }
+    }
+}
+
+fn do_expr(expr: &Expr, edges: &mut Vec<(GlobalId, EdgeKind)>) {
+    match &expr.kind {
+        ExprKind::Binary(_, _, left, right) | ExprKind::LogicalBinary(_, _, left, right) => {
+            do_expr(left, edges);
+            do_expr(right, edges);
+        }
+        ExprKind::Call(callee, args) => {
+            match callee.kind {
+                ExprKind::LoadGlobal(id) => edges.push((id, EdgeKind::Call)),
+                _ => do_expr(callee, edges),
+            }
+            args.iter().for_each(|arg| do_expr(arg, edges));
+        }
+        ExprKind::Conditional(cond, then_expr, else_expr) => {
+            do_expr(cond, edges);
+            do_expr(then_expr, edges);
+            do_expr(else_expr, edges);
+        }
+        ExprKind::ConstBool(_)
+        | ExprKind::ConstFloat(_)
+        | ExprKind::ConstInt(_)
+        | ExprKind::ConstNull
+        | ExprKind::ConstString(_)
+        | ExprKind::LoadBuiltin(_)
+        | ExprKind::LoadLocal(_) => {}
+        ExprKind::GetItem(array, index) => {
+            do_expr(array, edges);
+            do_expr(index, edges);
+        }
+        ExprKind::GetSlice(array, start, end) => {
+            do_expr(array, edges);
+            do_expr(start, edges);
+            do_expr(end, edges);
+        }
+        ExprKind::LoadGlobal(id) => edges.push((*id, EdgeKind::Reference)),
+        ExprKind::MakeList(elements) => elements.iter().for_each(|e| do_expr(e, edges)),
+        ExprKind::Try(expr) | ExprKind::Unary(_, _, expr) => do_expr(expr, edges),
+        ExprKind::While(_, cond, body) => {
+            do_expr(cond, edges);
+            do_stmt(body, edges);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::analyze;
+    use crate::parser::parse;
+
+    fn call_graph(source: &str) -> (CompilerContext, Program, CallGraph) {
+        let mut ctx = CompilerContext::default();
+        let source_id = ctx.sources.add_from_string(source);
+        let ast = parse(&mut ctx, source_id).unwrap();
+        let program = analyze(&ctx, &ast).unwrap();
+        let graph = CallGraph::build(&program);
+        (ctx, program, graph)
+    }
+
+    #[test]
+    fn test_calls_and_references() {
+        let (_, _, graph) = call_graph(
+            "fun main() { f(); f(); print(time_it(g)); }\n\
+             fun f() { return f(); }\n\
+             fun g() { return h; }\n\
+             fun h() {}\n\
+             fun unused() { f(); }",
+        );
+        assert_eq!(
+            graph.edges,
+            [
+                vec![
+                    (GlobalId(1), EdgeKind::Call),
+                    (GlobalId(2), EdgeKind::Reference)
+                ],
+                vec![(GlobalId(1), EdgeKind::Call)],
+                vec![(GlobalId(3), EdgeKind::Reference)],
+                vec![],
+                vec![(GlobalId(1), EdgeKind::Call)],
+            ]
+        );
+        assert_eq!(
+            graph.reachable_from(GlobalId(0)),
+            [true, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let (ctx, program, graph) = call_graph(
+            "fun main() { f(g); }\n\
+             fun f(x) {}\n\
+             fun g() {}\n\
+             fun unused() { main(); }",
+        );
+        assert_eq!(
+            graph.to_dot(&ctx, &program),
+            "digraph calls {\n    \"main\";\n    \"f\";\n    \"g\";\n    \
+             \"unused\" [color=gray, fontcolor=gray];\n    \"main\" -> \"f\";\n    \
+             \"main\" -> \"g\" [style=dashed];\n    \"unused\" -> \"main\";\n}\n"
+        );
+    }
+}
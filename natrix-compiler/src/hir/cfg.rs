@@ -0,0 +1,513 @@
+use crate::ctx::CompilerContext;
+use crate::hir::{Expr, ExprKind, FunDecl, GlobalKind, LoopId, Program, Stmt, StmtKind};
+use crate::util::dot::label;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BlockId(pub usize);
+
+/// What a basic block executes, in order.
+#[derive(Debug, Copy, Clone)]
+pub enum Item<'a> {
+    /// A statement without control flow of its own. Loop expressions are part of the statement
+    /// they appear in, their breaks and continues do not leave it.
+    Stmt(&'a Stmt),
+    /// The condition of an `if` or `while`, the last item of a block with two successors: the
+    /// one taken if it is true and the one taken if it is false, which a condition that is
+    /// always true does not have.
+    Cond(&'a Expr),
+}
+
+#[derive(Debug, Default)]
+pub struct BasicBlock<'a> {
+    pub items: Vec<Item<'a>>,
+    pub succs: Vec<BlockId>,
+}
+
+/// The control-flow graph of a function, with statements as the smallest unit.
+///
+/// Deferred statements are copied to every exit of their block, like the bytecode compiler does,
+/// so they appear once per path out of the block. Every path ends in the empty exit block, code
+/// after a `return`, `break` or `continue` is in blocks without predecessors.
+#[derive(Debug)]
+pub struct Cfg<'a> {
+    pub blocks: Vec<BasicBlock<'a>>,
+    pub entry: BlockId,
+    pub exit: BlockId,
+}
+
+impl<'a> Cfg<'a> {
+    pub fn build(fun_decl: &'a FunDecl) -> Self {
+        let mut builder = Builder {
+            blocks: vec![BasicBlock::default(), BasicBlock::default()],
+            current: BlockId(0),
+            exit: BlockId(1),
+            loops: HashMap::new(),
+            defers: Vec::new(),
+        };
+        builder.do_block(&fun_decl.body);
+        // the function returns null at its end, unless the end is the empty block left by a
+        // jump
+        let end = builder.current;
+        if builder.blocks[end.0].items.is_empty()
+            && builder.predecessor_count(end) == 0
+            && end.0 == builder.blocks.len() - 1
+        {
+            builder.blocks.pop();
+        } else {
+            builder.blocks[end.0].succs.push(builder.exit);
+        }
+        Cfg {
+            blocks: builder.blocks,
+            entry: BlockId(0),
+            exit: BlockId(1),
+        }
+    }
+
+    /// The predecessors of each block, indexed by block.
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (index, block) in self.blocks.iter().enumerate() {
+            for succ in &block.succs {
+                preds[succ.0].push(BlockId(index));
+            }
+        }
+        preds
+    }
+
+    /// The blocks reachable from the entry in reverse postorder, i.e. every block comes before
+    /// its successors except along the back edges of loops.
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = Vec::new();
+        // explicit stack of blocks and the index of their next successor to visit
+        let mut stack = vec![(self.entry, 0)];
+        visited[self.entry.0] = true;
+        while let Some((block, next)) = stack.pop() {
+            match self.blocks[block.0].succs.get(next) {
+                Some(&succ) => {
+                    stack.push((block, next + 1));
+                    if !visited[succ.0] {
+                        visited[succ.0] = true;
+                        stack.push((succ, 0));
+                    }
+                }
+                None => order.push(block),
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// The immediate dominator of each block, indexed by block: the last block other than
+    /// itself that every path from the entry to it goes through. `None` for the entry and the
+    /// unreachable blocks.
+    ///
+    /// Uses the iterative algorithm of Cooper, Harvey and Kennedy, which converges in a few
+    /// passes over the structured control flow of natrix.
+    pub fn dominators(&self) -> Vec<Option<BlockId>> {
+        let order = self.reverse_postorder();
+        let mut position = vec![usize::MAX; self.blocks.len()];
+        for (i, block) in order.iter().enumerate() {
+            position[block.0] = i;
+        }
+        let preds = self.predecessors();
+        let mut idom: Vec<Option<BlockId>> = vec![None; self.blocks.len()];
+        idom[self.entry.0] = Some(self.entry);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &order[1..] {
+                let mut new_idom: Option<BlockId> = None;
+                for &pred in &preds[block.0] {
+                    if idom[pred.0].is_none() {
+                        continue; // not processed yet, or unreachable
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(other) => intersect(&idom, &position, pred, other),
+                    });
+                }
+                if new_idom != idom[block.0] {
+                    idom[block.0] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        idom[self.entry.0] = None;
+        idom
+    }
+
+    /// Whether every path from the entry to `b` goes through `a`, given the result of
+    /// `dominators`. A block dominates itself.
+    pub fn dominates(idom: &[Option<BlockId>], a: BlockId, b: BlockId) -> bool {
+        let mut block = Some(b);
+        while let Some(current) = block {
+            if current == a {
+                return true;
+            }
+            block = idom[current.0];
+        }
+        false
+    }
+}
+
+/// Renders the control-flow graphs of the functions of a program in the DOT format of Graphviz,
+/// one cluster per function. Blocks show the first line of the source of their items, the edges
+/// out of a condition are labeled with its value, and unreachable blocks are gray.
+pub fn to_dot(ctx: &CompilerContext, program: &Program) -> String {
+    let mut out = String::from("digraph cfg {\n    node [shape=box];\n");
+    for global in &program.globals {
+        let GlobalKind::Function(fun_decl) = &global.kind else {
+            continue;
+        };
+        let cfg = Cfg::build(fun_decl);
+        let mut reachable = vec![false; cfg.blocks.len()];
+        for block in cfg.reverse_postorder() {
+            reachable[block.0] = true;
+        }
+        let node = |block: BlockId| format!("f{}b{}", global.id.0, block.0);
+        writeln!(out, "    subgraph cluster_{} {{", global.id.0).unwrap();
+        let name = ctx.interner.resolve(global.name);
+        writeln!(out, "        label=\"{}\";", name).unwrap();
+        for (index, block) in cfg.blocks.iter().enumerate() {
+            let id = BlockId(index);
+            let title = match id {
+                _ if id == cfg.entry => "entry".to_string(),
+                _ if id == cfg.exit => "exit".to_string(),
+                _ => format!("block {}", index),
+            };
+            let lines = block.items.iter().map(|item| item_text(ctx, item));
+            let lines: Vec<&str> = std::iter::once(title.as_str()).chain(lines).collect();
+            write!(out, "        {} [label={}", node(id), label(lines)).unwrap();
+            if !reachable[index] {
+                out.push_str(", color=gray, fontcolor=gray");
+            }
+            out.push_str("];\n");
+        }
+        for (index, block) in cfg.blocks.iter().enumerate() {
+            let cond = matches!(block.items.last(), Some(Item::Cond(_)));
+            for (i, succ) in block.succs.iter().enumerate() {
+                write!(out, "        {} -> {}", node(BlockId(index)), node(*succ)).unwrap();
+                if cond {
+                    out.push_str(if i == 0 {
+                        " [label=true]"
+                    } else {
+                        " [label=false]"
+                    });
+                }
+                out.push_str(";\n");
+            }
+        }
+        out.push_str("    }\n");
+    }
+    out.push_str("}\n");
+    out
+}
+
+// The first line of the source of an item, trimmed
+fn item_text<'a>(ctx: &'a CompilerContext, item: &Item) -> &'a str {
+    let span = match item {
+        // the span of an assignment is that of its target
+        Item::Stmt(Stmt {
+            kind: StmtKind::StoreGlobal(_, value) | StmtKind::StoreLocal(_, value),
+            span,
+        }) if value.span.source_id() == span.source_id() && value.span.end() > span.end() => {
+            span.extend_to(value.span)
+        }
+        // the implicit return at the end of a function
+        Item::Stmt(Stmt {
+            kind: StmtKind::Return(_),
+            span,
+        }) if span.start() == span.end() => return "return",
+        Item::Stmt(stmt) => stmt.span,
+        Item::Cond(cond) => cond.span,
+    };
+    let content = ctx.sources.get_by_id(span.source_id()).content();
+    let text = content[span.start()..span.end()].lines().next();
+    text.unwrap_or_default().trim()
+}
+
+// The common dominator of two blocks, walking up the dominator tree from the one that comes
+// later in reverse postorder
+fn intersect(
+    idom: &[Option<BlockId>],
+    position: &[usize],
+    mut a: BlockId,
+    mut b: BlockId,
+) -> BlockId {
+    while a != b {
+        while position[a.0] > position[b.0] {
+            a = idom[a.0].unwrap();
+        }
+        while position[b.0] > position[a.0] {
+            b = idom[b.0].unwrap();
+        }
+    }
+    a
+}
+
+struct Builder<'a> {
+    blocks: Vec<BasicBlock<'a>>,
+    current: BlockId,
+    exit: BlockId,
+    loops: HashMap<LoopId, (BlockId, BlockId, usize)>, // continue and break targets, defer depth
+    defers: Vec<Vec<&'a Stmt>>, // deferred statements of each enclosing block
+}
+
+impl<'a> Builder<'a> {
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push(BasicBlock::default());
+        BlockId(self.blocks.len() - 1)
+    }
+
+    // Ends the current block with an edge to `target` and continues in a new block, which has no
+    // predecessors unless one is added
+    fn jump(&mut self, target: BlockId) {
+        self.blocks[self.current.0].succs.push(target);
+        self.current = self.new_block();
+    }
+
+    // Ends the current block with a condition and continues in `then_block` (for the caller to
+    // fill in). A condition that is always true has no edge to `else_block`, so that the code
+    // after `while (true)` is only reached by a break.
+    fn branch(&mut self, cond: &'a Expr, then_block: BlockId, else_block: BlockId) {
+        let block = &mut self.blocks[self.current.0];
+        block.items.push(Item::Cond(cond));
+        block.succs.push(then_block);
+        if !matches!(cond.kind, ExprKind::ConstBool(true)) {
+            block.succs.push(else_block);
+        }
+        self.current = then_block;
+    }
+
+    // Ends the current block with an edge to `next`, which becomes the current block
+    fn fall_into(&mut self, next: BlockId) {
+        self.blocks[self.current.0].succs.push(next);
+        self.current = next;
+    }
+
+    fn predecessor_count(&self, block: BlockId) -> usize {
+        self.blocks
+            .iter()
+            .filter(|b| b.succs.contains(&block))
+            .count()
+    }
+
+    fn push(&mut self, stmt: &'a Stmt) {
+        self.blocks[self.current.0].items.push(Item::Stmt(stmt));
+    }
+
+    fn do_block(&mut self, stmts: &'a [Stmt]) {
+        self.defers.push(Vec::new());
+        for stmt in stmts {
+            self.do_stmt(stmt);
+        }
+        let jumps = stmts.last().is_some_and(|stmt| {
+            matches!(
+                stmt.kind,
+                StmtKind::Break(..) | StmtKind::Continue(_) | StmtKind::Return(_)
+            )
+        });
+        if !jumps {
+            self.do_defers(self.defers.len() - 1);
+        }
+        self.defers.pop();
+    }
+
+    // The deferred statements of the blocks from `depth` on, innermost block and latest defer
+    // first, as run by a jump out of them
+    fn do_defers(&mut self, depth: usize) {
+        for block in (depth..self.defers.len()).rev() {
+            for i in (0..self.defers[block].len()).rev() {
+                self.do_stmt(self.defers[block][i]);
+            }
+        }
+    }
+
+    fn do_stmt(&mut self, stmt: &'a Stmt) {
+        match &stmt.kind {
+            StmtKind::Block(stmts) => self.do_block(stmts),
+            StmtKind::Break(loop_id, _) => {
+                self.push(stmt);
+                let (_continue, l_break, depth) = self.loops[loop_id];
+                self.do_defers(depth);
+                self.jump(l_break);
+            }
+            StmtKind::Continue(loop_id) => {
+                let (l_continue, _break, depth) = self.loops[loop_id];
+                self.do_defers(depth);
+                self.jump(l_continue);
+            }
+            StmtKind::Defer(body) => self.defers.last_mut().unwrap().push(body),
+            StmtKind::If(cond, then_body, else_body) => {
+                let then_block = self.new_block();
+                let else_block = self.new_block();
+                self.branch(cond, then_block, else_block);
+                self.do_stmt(then_body);
+                match else_body {
+                    Some(else_body) => {
+                        let end = self.new_block();
+                        self.fall_into(end);
+                        self.current = else_block;
+                        self.do_stmt(else_body);
+                        self.fall_into(end);
+                    }
+                    None => self.fall_into(else_block),
+                }
+            }
+            StmtKind::Return(_) => {
+                self.push(stmt);
+                self.do_defers(0);
+                self.jump(self.exit);
+            }
+            StmtKind::While(loop_id, cond, body, update) => {
+                let head = self.new_block();
+                let body_block = self.new_block();
+                let exit = self.new_block();
+                // a continue runs the update of a `for` loop before the condition
+                let next = match update {
+                    Some(_) => self.new_block(),
+                    None => head,
+                };
+                self.loops.insert(*loop_id, (next, exit, self.defers.len()));
+                self.fall_into(head);
+                self.branch(cond, body_block, exit);
+                self.do_stmt(body);
+                if let Some(update) = update {
+                    self.fall_into(next);
+                    self.do_stmt(update);
+                }
+                self.fall_into(head);
+                self.current = exit;
+            }
+            StmtKind::Expr(_)
+            | StmtKind::SetItem(..)
+            | StmtKind::StoreGlobal(..)
+            | StmtKind::StoreLocal(..)
+            | StmtKind::Unpack(..)
+            | StmtKind::VarDecl(..) => self.push(stmt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::analyze;
+    use crate::ctx::CompilerContext;
+    use crate::hir::GlobalKind;
+    use crate::parser::parse;
+    use crate::token_type::Edition;
+
+    fn with_cfg(source: &str, check: impl FnOnce(&Cfg)) {
+        let mut ctx = CompilerContext::with_edition(Edition::E2);
+        let source_id = ctx.sources.add_from_string(source);
+        let ast = parse(&mut ctx, source_id).unwrap();
+        let program = analyze(&ctx, &ast).unwrap();
+        let GlobalKind::Function(fun_decl) = &program.globals[0].kind else {
+            panic!("expected a function");
+        };
+        check(&Cfg::build(fun_decl));
+    }
+
+    // The block whose first item is the statement starting with `text` in the source
+    fn block_at(cfg: &Cfg, source: &str, text: &str) -> BlockId {
+        let pos = source.find(text).unwrap();
+        let index = cfg.blocks.iter().position(|block| {
+            matches!(block.items.first(), Some(Item::Stmt(stmt)) if stmt.span.start() == pos)
+        });
+        BlockId(index.unwrap_or_else(|| panic!("no block starts with {:?}", text)))
+    }
+
+    #[test]
+    fn test_if_else_diamond() {
+        let source = "fun main(c) { var x = 0; if (c) { x = 1; } else { x = 2; } return x; }";
+        with_cfg(source, |cfg| {
+            let (then_block, else_block, join) = (
+                block_at(cfg, source, "x = 1"),
+                block_at(cfg, source, "x = 2"),
+                block_at(cfg, source, "return"),
+            );
+            let preds = cfg.predecessors();
+            assert_eq!(preds[join.0], [then_block, else_block]);
+            let idom = cfg.dominators();
+            assert_eq!(idom[then_block.0], Some(cfg.entry));
+            assert_eq!(idom[join.0], Some(cfg.entry));
+            assert!(!Cfg::dominates(&idom, then_block, join));
+            assert!(Cfg::dominates(&idom, join, cfg.exit));
+        });
+    }
+
+    #[test]
+    fn test_loop_and_unreachable_code() {
+        let source = "fun main(n) { while (n > 0) { n = n - 1; if (n == 5) { break; } } \
+                      return n; n = 1; }";
+        with_cfg(source, |cfg| {
+            let body = block_at(cfg, source, "n = n - 1");
+            let after = block_at(cfg, source, "return");
+            let dead = block_at(cfg, source, "n = 1");
+            let preds = cfg.predecessors();
+            // the loop exit is reached from the condition and from the break
+            assert_eq!(preds[after.0].len(), 2);
+            assert!(preds[dead.0].is_empty());
+            let order = cfg.reverse_postorder();
+            assert!(!order.contains(&dead));
+            let idom = cfg.dominators();
+            assert_eq!(idom[dead.0], None);
+            // the loop head dominates the body and the code after the loop
+            let head = idom[body.0].unwrap();
+            assert!(Cfg::dominates(&idom, head, after));
+            assert!(preds[head.0].contains(&cfg.entry));
+        });
+    }
+
+    #[test]
+    fn test_infinite_loop_left_by_break() {
+        let source = "fun main() { var x; while (true) { x = 3; break; } print(x); }";
+        with_cfg(source, |cfg| {
+            let body = block_at(cfg, source, "x = 3");
+            let after = block_at(cfg, source, "print");
+            // only the break leaves the loop, the condition has no false edge
+            assert_eq!(cfg.predecessors()[after.0], [body]);
+        });
+    }
+
+    #[test]
+    fn test_defers_at_every_exit() {
+        let source = "fun main(c) { defer print(1); if (c) { return 0; } return 1; }";
+        with_cfg(source, |cfg| {
+            let deferred = cfg
+                .blocks
+                .iter()
+                .flat_map(|block| &block.items)
+                .filter(|item| {
+                    matches!(item, Item::Stmt(stmt) if stmt.span.start() == source.find("print").unwrap())
+                })
+                .count();
+            assert_eq!(deferred, 2);
+            assert_eq!(cfg.predecessors()[cfg.exit.0].len(), 2);
+        });
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let mut ctx = CompilerContext::default();
+        let source_id = ctx
+            .sources
+            .add_from_string("fun main(c) { var x = 1; if (c) { x = 2; } return x; x = 3; }");
+        let ast = parse(&mut ctx, source_id).unwrap();
+        let program = analyze(&ctx, &ast).unwrap();
+        assert_eq!(
+            to_dot(&ctx, &program),
+            "digraph cfg {\n    node [shape=box];\n    subgraph cluster_0 {\n        \
+             label=\"main\";\n        f0b0 [label=\"entry\\lvar x = 1;\\lc\\l\"];\n        \
+             f0b1 [label=\"exit\\l\"];\n        f0b2 [label=\"block 2\\lx = 2\\l\"];\n        \
+             f0b3 [label=\"block 3\\lreturn x;\\l\"];\n        \
+             f0b4 [label=\"block 4\\lx = 3\\lreturn\\l\", color=gray, fontcolor=gray];\n        \
+             f0b0 -> f0b2 [label=true];\n        f0b0 -> f0b3 [label=false];\n        \
+             f0b2 -> f0b3;\n        f0b3 -> f0b1;\n        f0b4 -> f0b1;\n    }\n}\n"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use crate::hir::{Expr, ExprKind, GlobalId, GlobalInfo, GlobalKind, LoopId, Stmt, StmtKind};
+use natrix_runtime::value::Value;
+use std::rc::Rc;
+
+// Limits on the work done to evaluate one call, so that folding stays cheap and terminates
+const MAX_STEPS: usize = 10_000;
+const MAX_DEPTH: usize = 64;
+
+/// Evaluates calls of user functions with constant arguments at compile time.
+///
+/// Evaluation gives up (returns `None`) as soon as the function does something that cannot be
+/// done at compile time: calling a builtin with side effects, failing with a runtime error,
+/// breaking a rule of strict mode or exceeding the step or call depth budget. Such calls are left
+/// to the runtime, so folding never changes the behavior of a program.
+pub struct ConstEvaluator<'a> {
+    globals: &'a [GlobalInfo],
+    unavailable: GlobalId, // the function being folded, its body is not in `globals`
+    steps: usize,
+    depth: usize,
+}
+
+enum Flow {
+    Normal,
+    Break(LoopId, Value),
+    Continue(LoopId),
+    Return(Value),
+}
+
+impl<'a> ConstEvaluator<'a> {
+    pub fn new(globals: &'a [GlobalInfo], unavailable: GlobalId) -> Self {
+        Self {
+            globals,
+            unavailable,
+            steps: 0,
+            depth: 0,
+        }
+    }
+
+    /// Returns the result of calling global `id` with `args`, if it can be computed.
+    pub fn call(&mut self, id: GlobalId, args: Vec<Value>) -> Option<Value> {
+        self.steps = 0;
+        self.do_call(id, args)
+    }
+
+    /// Whether global `id` holds its function during the whole run, which is what calls of it
+    /// are evaluated with.
+    pub fn is_constant(&self, id: GlobalId) -> bool {
+        !self.globals[id.0].assigned
+    }
+
+    fn do_call(&mut self, id: GlobalId, args: Vec<Value>) -> Option<Value> {
+        if id == self.unavailable || !self.is_constant(id) || self.depth == MAX_DEPTH {
+            return None;
+        }
+        // the functions of a library have no HIR to evaluate
+        let GlobalKind::Function(fun_decl) = &self.globals[id.0].kind else {
+            return None;
+        };
+        if args.len() != fun_decl.param_count {
+            return None;
+        }
+        let mut frame = args;
+        frame.resize(fun_decl.locals.len(), Value::NULL);
+        self.depth += 1;
+        let flow = self.do_block(&mut frame, &fun_decl.body);
+        self.depth -= 1;
+        match flow? {
+            Flow::Normal => Some(Value::NULL),
+            Flow::Return(value) => Some(value),
+            Flow::Break(..) | Flow::Continue(_) => unreachable!(),
+        }
+    }
+
+    fn step(&mut self) -> Option<()> {
+        self.steps += 1;
+        (self.steps <= MAX_STEPS).then_some(())
+    }
+
+    fn do_block(&mut self, frame: &mut [Value], stmts: &[Stmt]) -> Option<Flow> {
+        let mut deferred = Vec::new();
+        let mut flow = Flow::Normal;
+        for stmt in stmts {
+            if let StmtKind::Defer(body) = &stmt.kind {
+                deferred.push(body);
+                continue;
+            }
+            flow = self.do_stmt(frame, stmt)?;
+            if !matches!(flow, Flow::Normal) {
+                break;
+            }
+        }
+        // the analysis guarantees that deferred statements complete normally
+        for body in deferred.into_iter().rev() {
+            self.do_stmt(frame, body)?;
+        }
+        Some(flow)
+    }
+
+    fn do_stmt(&mut self, frame: &mut [Value], stmt: &Stmt) -> Option<Flow> {
+        self.step()?;
+        match &stmt.kind {
+            StmtKind::Block(stmts) => self.do_block(frame, stmts),
+            StmtKind::Break(id, expr) => {
+                let value = match expr {
+                    Some(expr) => self.do_expr(frame, expr)?,
+                    None => Value::NULL,
+                };
+                Some(Flow::Break(*id, value))
+            }
+            StmtKind::Continue(id) => Some(Flow::Continue(*id)),
+            StmtKind::Defer(_) => unreachable!("defer outside a block"),
+            StmtKind::Expr(expr) => {
+                self.do_expr(frame, expr)?;
+                Some(Flow::Normal)
+            }
+            StmtKind::If(cond, then_body, else_body) => {
+                if self.do_bool_expr(frame, cond)? {
+                    self.do_stmt(frame, then_body)
+                } else if let Some(else_body) = else_body {
+                    self.do_stmt(frame, else_body)
+                } else {
+                    Some(Flow::Normal)
+                }
+            }
+            StmtKind::Return(expr) => Some(Flow::Return(self.do_expr(frame, expr)?)),
+            StmtKind::SetItem(array, index, value) => {
+                let array = self.do_expr(frame, array)?;
+                let index = self.do_expr(frame, index)?;
+                let value = self.do_expr(frame, value)?;
+                array.set_item(index, value).ok()?;
+                Some(Flow::Normal)
+            }
+            StmtKind::StoreGlobal(..) => None,
+            StmtKind::Unpack(ids, expr) => {
+                let values = self.do_expr(frame, expr)?.unpack(ids.len()).ok()?;
+                for (id, value) in ids.iter().zip(values) {
+                    frame[id.0] = value;
+                }
+                Some(Flow::Normal)
+            }
+            StmtKind::StoreLocal(id, expr) | StmtKind::VarDecl(id, Some(expr)) => {
+                frame[id.0] = self.do_expr(frame, expr)?;
+                Some(Flow::Normal)
+            }
+            StmtKind::VarDecl(_, None) => Some(Flow::Normal),
+            StmtKind::While(id, cond, body, update) => match self.do_loop(
+                frame,
+                *id,
+                cond,
+                body,
+                update.as_deref(),
+            )? {
+                Flow::Break(..) => Some(Flow::Normal),
+                flow => Some(flow),
+            },
+        }
+    }
+
+    // Returns `Flow::Normal` if the condition became false, the break of this loop or a return
+    fn do_loop(
+        &mut self,
+        frame: &mut [Value],
+        id: LoopId,
+        cond: &Expr,
+        body: &Stmt,
+        update: Option<&Stmt>,
+    ) -> Option<Flow> {
+        while self.do_bool_expr(frame, cond)? {
+            match self.do_stmt(frame, body)? {
+                Flow::Normal => {}
+                Flow::Continue(loop_id) if loop_id == id => {}
+                Flow::Break(loop_id, value) if loop_id == id => {
+                    return Some(Flow::Break(loop_id, value))
+                }
+                flow => return Some(flow),
+            }
+            if let Some(update) = update {
+                self.do_stmt(frame, update)?;
+            }
+        }
+        Some(Flow::Normal)
+    }
+
+    fn do_expr(&mut self, frame: &mut [Value], expr: &Expr) -> Option<Value> {
+        self.step()?;
+        match &expr.kind {
+            ExprKind::Binary(op, _, left, right) => {
+                let left = self.do_expr(frame, left)?;
+                let right = self.do_expr(frame, right)?;
+                op.check_strict(&left, &right).ok()?;
+                op.eval(&left, &right).ok()
+            }
+            ExprKind::Call(callee, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.do_expr(frame, arg))
+                    .collect::<Option<Vec<_>>>()?;
+                match callee.kind {
+                    ExprKind::LoadBuiltin(builtin) if args.len() == builtin.param_count() => {
+                        builtin.eval_const(&args).ok()?
+                    }
+                    ExprKind::LoadGlobal(id) => self.do_call(id, args),
+                    _ => None,
+                }
+            }
+            ExprKind::Conditional(cond, then_expr, else_expr) => {
+                if self.do_bool_expr(frame, cond)? {
+                    self.do_expr(frame, then_expr)
+                } else {
+                    self.do_expr(frame, else_expr)
+                }
+            }
+            ExprKind::ConstBool(v) => Some(Value::from_bool(*v)),
+            ExprKind::ConstFloat(v) => Some(Value::from_float(*v)),
+            ExprKind::ConstInt(v) => Some(Value::from_int(*v)),
+            ExprKind::ConstNull => Some(Value::NULL),
+            ExprKind::ConstString(v) => Some(Value::from_string(v.clone())),
+            ExprKind::GetItem(array, index) => {
+                let array = self.do_expr(frame, array)?;
+                let index = self.do_expr(frame, index)?;
+                array.get_item(index).ok()
+            }
+            ExprKind::GetSlice(array, start, end) => {
+                let array = self.do_expr(frame, array)?;
+                let start = self.do_expr(frame, start)?;
+                let end = self.do_expr(frame, end)?;
+                array.get_slice(start, end).ok()
+            }
+            // functions as values are only available at run time
+            ExprKind::LoadBuiltin(_) | ExprKind::LoadGlobal(_) => None,
+            ExprKind::LoadLocal(id) => Some(frame[id.0].clone()),
+            ExprKind::LogicalBinary(and, _, left, right) => {
+                let left = self.do_bool_expr(frame, left)?;
+                if left != *and {
+                    Some(Value::from_bool(left))
+                } else {
+                    self.do_bool_expr(frame, right).map(Value::from_bool)
+                }
+            }
+            ExprKind::MakeList(elements) => {
+                let elements = elements
+                    .iter()
+                    .map(|e| self.do_expr(frame, e))
+                    .collect::<Option<Vec<_>>>()?;
+                Some(Value::from_list(Rc::new(elements)))
+            }
+            // a failure gives up like any other, which leaves the call to the runtime
+            ExprKind::Try(expr) => self.do_expr(frame, expr),
+            ExprKind::Unary(op, _, arg) => {
+                let arg = self.do_expr(frame, arg)?;
+                op.check_strict(&arg).ok()?;
+                op.eval(&arg).ok()
+            }
+            ExprKind::While(id, cond, body) => match self.do_loop(frame, *id, cond, body, None)? {
+                Flow::Normal => Some(Value::NULL),
+                Flow::Break(_, value) => Some(value),
+                Flow::Continue(_) | Flow::Return(_) => None,
+            },
+        }
+    }
+
+    fn do_bool_expr(&mut self, frame: &mut [Value], expr: &Expr) -> Option<bool> {
+        let value = self.do_expr(frame, expr)?;
+        value.is_bool().then(|| value.unwrap_bool())
+    }
+}
@@ -31,6 +31,21 @@ impl<'a> Debug for GlobalInfoDebug<'a> {
         write!(f, "\n")?;
         match &self.global.kind {
             GlobalKind::Function(function) => self.fmt.function(f, function),
+            GlobalKind::Imported(imported) => write!(
+                f,
+                "{}Imported: library {}, global {}, params {}\n",
+                self.fmt.indented().indent_str(),
+                imported.library,
+                imported.global,
+                imported.param_count
+            ),
+            GlobalKind::Native(function) => write!(
+                f,
+                "{}Native: params {}\n",
+                self.fmt.indented().indent_str(),
+                function.param_count()
+            ),
+            GlobalKind::Variable => write!(f, "{}Variable\n", self.fmt.indented().indent_str()),
         }
     }
 }
@@ -60,6 +75,7 @@ impl<'a> Debug for LocalInfoDebug<'a> {
         match &self.local.kind {
             LocalKind::Parameter(index) => write!(f, "Param#{:?}", index)?,
             LocalKind::LocalVariable => write!(f, "LocalVariable")?,
+            LocalKind::Temporary => write!(f, "Temporary")?,
         }
         self.fmt.span(f, self.local.name_span)?;
         write!(f, "\n")
@@ -79,8 +95,19 @@ impl<'a> Debug for StmtDebug<'a> {
                 }
                 Ok(())
             }
-            StmtKind::Break(id) => self.fmt.header_with_value(f, "Break", span, id),
+            StmtKind::Break(id, expr) => {
+                self.fmt.header_with_value(f, "Break", span, id)?;
+                if let Some(expr) = expr {
+                    self.fmt.expr(f, expr)
+                } else {
+                    Ok(())
+                }
+            }
             StmtKind::Continue(id) => self.fmt.header_with_value(f, "Continue", span, id),
+            StmtKind::Defer(body) => {
+                self.fmt.header(f, "Defer", span)?;
+                self.fmt.stmt(f, body)
+            }
             StmtKind::Expr(expr) => {
                 self.fmt.header(f, "Expr", span)?;
                 self.fmt.expr(f, expr)
@@ -112,14 +139,26 @@ impl<'a> Debug for StmtDebug<'a> {
                 self.fmt.header_with_value(f, "StoreLocal", span, id)?;
                 self.fmt.expr(f, value)
             }
+            StmtKind::Unpack(ids, value) => {
+                self.fmt.header_with_value(f, "Unpack", span, ids)?;
+                self.fmt.expr(f, value)
+            }
             StmtKind::VarDecl(id, value) => {
                 self.fmt.header_with_value(f, "VarDecl", span, id)?;
-                self.fmt.expr(f, value)
+                if let Some(value) = value {
+                    self.fmt.expr(f, value)
+                } else {
+                    Ok(())
+                }
             }
-            StmtKind::While(id, cond, body) => {
+            StmtKind::While(id, cond, body, update) => {
                 self.fmt.header_with_value(f, "While", span, id)?;
                 self.fmt.expr(f, cond)?;
-                self.fmt.stmt(f, body)
+                self.fmt.stmt(f, body)?;
+                if let Some(update) = update {
+                    self.fmt.stmt(f, update)?;
+                }
+                Ok(())
             }
         }
     }
@@ -144,6 +183,12 @@ impl<'a> Debug for ExprDebug<'a> {
                 }
                 Ok(())
             }
+            ExprKind::Conditional(cond, then_expr, else_expr) => {
+                self.fmt.header(f, "Conditional", span)?;
+                self.fmt.expr(f, cond)?;
+                self.fmt.expr(f, then_expr)?;
+                self.fmt.expr(f, else_expr)
+            }
             ExprKind::ConstBool(value) => self.fmt.header_with_value(f, "ConstBool", span, value),
             ExprKind::ConstFloat(value) => self.fmt.header_with_value(f, "ConstFloat", span, value),
             ExprKind::ConstInt(value) => self.fmt.header_with_value(f, "ConstInt", span, value),
@@ -156,6 +201,12 @@ impl<'a> Debug for ExprDebug<'a> {
                 self.fmt.expr(f, array)?;
                 self.fmt.expr(f, index)
             }
+            ExprKind::GetSlice(array, start, end) => {
+                self.fmt.header(f, "GetSlice", span)?;
+                self.fmt.expr(f, array)?;
+                self.fmt.expr(f, start)?;
+                self.fmt.expr(f, end)
+            }
             ExprKind::LoadBuiltin(builtin) => {
                 self.fmt.header_with_value(f, "LoadBuiltin", span, builtin)
             }
@@ -174,10 +225,19 @@ impl<'a> Debug for ExprDebug<'a> {
                 }
                 Ok(())
             }
+            ExprKind::Try(expr) => {
+                self.fmt.header(f, "Try", span)?;
+                self.fmt.expr(f, expr)
+            }
             ExprKind::Unary(op, op_span, expr) => {
                 self.fmt.header_with_value(f, "Unary", *op_span, *op)?;
                 self.fmt.expr(f, expr)
             }
+            ExprKind::While(id, cond, body) => {
+                self.fmt.header_with_value(f, "While", span, id)?;
+                self.fmt.expr(f, cond)?;
+                self.fmt.stmt(f, body)
+            }
         }
     }
 }
@@ -0,0 +1,193 @@
+use crate::ctx::CompilerContext;
+use crate::hir::{Expr, ExprKind, FunDecl, GlobalKind, Program, Stmt, StmtKind};
+use crate::src::Span;
+use std::fmt::Write;
+
+/// Describes each construct of the program that has no HIR node of its own: `for` loops,
+/// `unless`, `guard`, with-blocks and assignments to several targets. Each one is shown with its source lines
+/// and the HIR it was lowered to, before any pass runs, followed by the locals it declares.
+///
+/// `else if` needs no entry, it is an `if` in the `else` branch of another.
+pub fn explain_desugaring(ctx: &CompilerContext, program: &Program) -> String {
+    let mut out = String::new();
+    for global in &program.globals {
+        let GlobalKind::Function(fun_decl) = &global.kind else {
+            continue;
+        };
+        let mut explainer = Explainer {
+            ctx,
+            fun_decl,
+            out: &mut out,
+        };
+        fun_decl.body.iter().for_each(|s| explainer.do_stmt(s));
+    }
+    out
+}
+
+// The name of the construct that `stmt` was lowered from, if it is not written as is
+fn construct(ctx: &CompilerContext, stmt: &Stmt) -> Option<&'static str> {
+    match (&stmt.kind, leading_word(ctx, stmt.span)) {
+        (StmtKind::Block(_), "for") => Some("for loop"),
+        (StmtKind::Block(_), "with") => Some("with-block"),
+        (StmtKind::Block(stmts), _) => match stmts.first() {
+            Some(Stmt {
+                kind: StmtKind::Unpack(..),
+                ..
+            }) => Some("multiple assignment"),
+            _ => None,
+        },
+        (StmtKind::If(..), "unless") => Some("unless"),
+        (StmtKind::If(..), "guard") => Some("guard"),
+        _ => None,
+    }
+}
+
+// The identifier or keyword the source of a span starts with
+fn leading_word(ctx: &CompilerContext, span: Span) -> &str {
+    let content = ctx.sources.get_by_id(span.source_id()).content();
+    let text = &content[span.start()..span.end()];
+    let end = text
+        .find(|c: char| !c.is_alphanumeric() && c != '_')
+        .unwrap_or(text.len());
+    &text[..end]
+}
+
+struct Explainer<'a> {
+    ctx: &'a CompilerContext,
+    fun_decl: &'a FunDecl,
+    out: &'a mut String,
+}
+
+impl Explainer<'_> {
+    fn explain(&mut self, name: &str, stmt: &Stmt) {
+        let sources = &self.ctx.sources;
+        let source = sources.get_by_id(stmt.span.source_id());
+        let (first, _) = stmt.span.start_pos(sources);
+        let (last, _) = stmt.span.end_pos(sources);
+        writeln!(self.out, "{}: {}", stmt.span.location(sources), name).unwrap();
+        for line in first..=last {
+            writeln!(self.out, "  {:>4} | {}", line, source.get_line(line)).unwrap();
+        }
+        writeln!(self.out, "  is lowered to:").unwrap();
+        let hir = format!("{:?}", stmt.debug_without_spans(self.ctx));
+        for line in hir.lines() {
+            writeln!(self.out, "    {}", line).unwrap();
+        }
+        let locals: Vec<String> = self
+            .fun_decl
+            .locals
+            .iter()
+            .filter(|local| stmt.span.contains(local.name_span))
+            .map(|local| format!("{:?} {:?}", local.id, self.ctx.interner.resolve(local.name)))
+            .collect();
+        if !locals.is_empty() {
+            writeln!(self.out, "  declaring {}", locals.join(", ")).unwrap();
+        }
+        writeln!(self.out).unwrap();
+    }
+
+    fn do_stmt(&mut self, stmt: &Stmt) {
+        if let Some(name) = construct(self.ctx, stmt) {
+            self.explain(name, stmt);
+        }
+        match &stmt.kind {
+            StmtKind::Block(stmts) => stmts.iter().for_each(|s| self.do_stmt(s)),
+            StmtKind::Break(_, expr) | StmtKind::VarDecl(_, expr) => {
+                if let Some(expr) = expr {
+                    self.do_expr(expr);
+                }
+            }
+            StmtKind::Continue(_) => {}
+            StmtKind::Defer(body) => self.do_stmt(body),
+            StmtKind::Expr(expr)
+            | StmtKind::Return(expr)
+            | StmtKind::StoreGlobal(_, expr)
+            | StmtKind::StoreLocal(_, expr)
+            | StmtKind::Unpack(_, expr) => self.do_expr(expr),
+            StmtKind::If(cond, then_body, else_body) => {
+                self.do_expr(cond);
+                self.do_stmt(then_body);
+                if let Some(else_body) = else_body {
+                    self.do_stmt(else_body);
+                }
+            }
+            StmtKind::SetItem(array, index, value) => {
+                self.do_expr(array);
+                self.do_expr(index);
+                self.do_expr(value);
+            }
+            StmtKind::While(_, cond, body, update) => {
+                self.do_expr(cond);
+                self.do_stmt(body);
+                if let Some(update) = update {
+                    self.do_stmt(update);
+                }
+            }
+        }
+    }
+
+    // Only loop expressions contain statements, but they can be nested anywhere
+    fn do_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Binary(_, _, left, right)
+            | ExprKind::GetItem(left, right)
+            | ExprKind::LogicalBinary(_, _, left, right) => {
+                self.do_expr(left);
+                self.do_expr(right);
+            }
+            ExprKind::Call(callee, args) => {
+                self.do_expr(callee);
+                args.iter().for_each(|arg| self.do_expr(arg));
+            }
+            ExprKind::Conditional(first, second, third)
+            | ExprKind::GetSlice(first, second, third) => {
+                self.do_expr(first);
+                self.do_expr(second);
+                self.do_expr(third);
+            }
+            ExprKind::ConstBool(_)
+            | ExprKind::ConstFloat(_)
+            | ExprKind::ConstInt(_)
+            | ExprKind::ConstNull
+            | ExprKind::ConstString(_)
+            | ExprKind::LoadBuiltin(_)
+            | ExprKind::LoadGlobal(_)
+            | ExprKind::LoadLocal(_) => {}
+            ExprKind::MakeList(elements) => elements.iter().for_each(|e| self.do_expr(e)),
+            ExprKind::Try(expr) | ExprKind::Unary(_, _, expr) => self.do_expr(expr),
+            ExprKind::While(_, cond, body) => {
+                self.do_expr(cond);
+                self.do_stmt(body);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::analyze;
+    use crate::parser::parse;
+    use crate::token_type::Edition;
+
+    #[test]
+    fn test_explain_desugaring() {
+        let mut ctx = CompilerContext::with_edition(Edition::E2);
+        let source_id = ctx.sources.add_from_string(
+            "fun main(a) {\n    \
+                 unless (a) return;\n    \
+                 if (a) {} else if (!a) {}\n    \
+                 format(a);\n\
+             }\n\
+             fun format(a) {}",
+        );
+        let ast = parse(&mut ctx, source_id).unwrap();
+        let program = analyze(&ctx, &ast).unwrap();
+        assert_eq!(
+            explain_desugaring(&ctx, &program),
+            "<string>:2: unless\n     2 |     unless (a) return;\n  is lowered to:\n    If\n      \
+             Unary(Not)\n        LoadLocal(LocalId(0))\n      Return\n        \
+             ConstNull\n\n"
+        );
+    }
+}
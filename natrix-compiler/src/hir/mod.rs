@@ -1,10 +1,16 @@
+pub mod callgraph;
+pub mod cfg;
+mod const_eval;
 mod debug;
+pub mod desugar;
 pub mod opt;
+pub mod pass;
 
 use crate::ctx::Name;
+use crate::module::ModuleId;
 use crate::src::Span;
 use crate::util::tree::def_node;
-use natrix_runtime::value::{BinaryOp, Builtin, UnaryOp};
+use natrix_runtime::value::{BinaryOp, Builtin, Function, UnaryOp};
 use std::rc::Rc;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -23,15 +29,37 @@ def_node!(Program {
 
 def_node!(GlobalInfo {
     id: GlobalId,
+    module: ModuleId, // the module declaring or importing it
     name: Name,
     name_span: Span,
     kind: GlobalKind,
+    assigned: bool, // the program stores another value in it, so it may not hold its function
 });
 
 pub enum GlobalKind {
     Function(FunDecl),
+    Imported(ImportedFun), // a function of a precompiled library, see `Modules::libraries`
+    Native(Rc<Function>),  // a function of the host, see `CompilerContext::natives`
+    Variable,              // a top-level variable of an interactive session, see `analyze_session`
 }
 
+impl GlobalInfo {
+    pub fn param_count(&self) -> usize {
+        match &self.kind {
+            GlobalKind::Function(fun_decl) => fun_decl.param_count,
+            GlobalKind::Imported(imported) => imported.param_count,
+            GlobalKind::Native(function) => function.param_count(),
+            GlobalKind::Variable => 0,
+        }
+    }
+}
+
+def_node!(ImportedFun {
+    library: usize, // index in `Modules::libraries`
+    global: usize,  // the global holding the function in the bytecode of the library
+    param_count: usize,
+});
+
 def_node!(FunDecl {
     param_count: usize,
     locals: Vec<LocalInfo>, // invariant - first param_count elements are LocalKind::Parameter
@@ -49,6 +77,7 @@ def_node!(LocalInfo {
 pub enum LocalKind {
     Parameter(usize),
     LocalVariable,
+    Temporary, // introduced by the analysis, has no declaration in the source
 }
 
 def_node!(Stmt {
@@ -58,16 +87,19 @@ def_node!(Stmt {
 
 pub enum StmtKind {
     Block(Vec<Stmt>),
-    Break(LoopId),
+    Break(LoopId, Option<Expr>),
     Continue(LoopId),
+    Defer(Box<Stmt>), // runs the statement before any exit from the enclosing block
     Expr(Expr),
     If(Expr, Box<Stmt>, Option<Box<Stmt>>),
     Return(Expr),
     SetItem(Expr, Expr, Expr),
     StoreGlobal(GlobalId, Expr),
     StoreLocal(LocalId, Expr),
-    VarDecl(LocalId, Expr),
-    While(LoopId, Expr, Box<Stmt>),
+    Unpack(Vec<LocalId>, Expr), // declares the locals and stores the elements of a list in them
+    VarDecl(LocalId, Option<Expr>), // None if the local is assigned later
+    While(LoopId, Expr, Box<Stmt>, Option<Box<Stmt>>), // the update of a `for` loop runs after
+                                // the body and when it continues
 }
 
 def_node!(Expr {
@@ -78,16 +110,20 @@ def_node!(Expr {
 pub enum ExprKind {
     Binary(BinaryOp, Span, Box<Expr>, Box<Expr>),
     Call(Box<Expr>, Vec<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
     ConstBool(bool),
     ConstFloat(f64),
     ConstInt(i64),
     ConstNull,
     ConstString(Rc<str>),
     GetItem(Box<Expr>, Box<Expr>),
+    GetSlice(Box<Expr>, Box<Expr>, Box<Expr>),
     LoadBuiltin(Builtin),
     LoadGlobal(GlobalId),
     LoadLocal(LocalId),
     LogicalBinary(bool, Span, Box<Expr>, Box<Expr>),
     MakeList(Vec<Expr>),
+    Try(Box<Expr>), // null if the expression fails
     Unary(UnaryOp, Span, Box<Expr>),
+    While(LoopId, Box<Expr>, Box<Stmt>),
 }
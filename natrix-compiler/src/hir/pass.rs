@@ -0,0 +1,191 @@
+use crate::ctx::CompilerContext;
+use crate::error::SourceResult;
+use crate::hir::opt::{eliminate_dead_code, eliminate_dead_stores, fold_constants};
+use crate::hir::Program;
+use std::time::{Duration, Instant};
+
+/// A transformation of the HIR between the analysis and the bytecode compiler.
+pub trait Pass {
+    /// Short name used to select the pass on the command line, e.g. `fold-constants`.
+    fn name(&self) -> &'static str;
+
+    /// One line description printed by `--print-passes`.
+    fn description(&self) -> &'static str;
+
+    fn run(&self, ctx: &CompilerContext, program: &mut Program) -> SourceResult<()>;
+}
+
+pub struct FoldConstants;
+
+impl Pass for FoldConstants {
+    fn name(&self) -> &'static str {
+        "fold-constants"
+    }
+
+    fn description(&self) -> &'static str {
+        "evaluate operators and calls of pure functions with constant operands at compile time"
+    }
+
+    fn run(&self, ctx: &CompilerContext, program: &mut Program) -> SourceResult<()> {
+        fold_constants(ctx, program);
+        Ok(())
+    }
+}
+
+pub struct DeadCode;
+
+impl Pass for DeadCode {
+    fn name(&self) -> &'static str {
+        "dead-code"
+    }
+
+    fn description(&self) -> &'static str {
+        "remove statements that can never execute"
+    }
+
+    fn run(&self, _ctx: &CompilerContext, program: &mut Program) -> SourceResult<()> {
+        eliminate_dead_code(program);
+        Ok(())
+    }
+}
+
+pub struct DeadStores;
+
+impl Pass for DeadStores {
+    fn name(&self) -> &'static str {
+        "dead-stores"
+    }
+
+    fn description(&self) -> &'static str {
+        "remove assignments to local variables whose value is never read"
+    }
+
+    fn run(&self, _ctx: &CompilerContext, program: &mut Program) -> SourceResult<()> {
+        eliminate_dead_stores(program);
+        Ok(())
+    }
+}
+
+/// Ordered list of passes, each of which can be disabled by name.
+pub struct PassManager {
+    passes: Vec<(Box<dyn Pass>, bool)>, // the pass and whether it is enabled
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self { passes: Vec::new() }
+    }
+
+    /// The passes used by `natrix` unless disabled on the command line.
+    pub fn with_default_passes() -> Self {
+        let mut manager = Self::new();
+        manager.register(FoldConstants);
+        manager.register(DeadCode);
+        manager.register(DeadStores);
+        manager
+    }
+
+    /// Appends `pass`, it runs after all passes registered before it.
+    pub fn register(&mut self, pass: impl Pass + 'static) {
+        self.passes.push((Box::new(pass), true));
+    }
+
+    pub fn disable(&mut self, name: &str) -> Result<(), String> {
+        match self.passes.iter_mut().find(|(pass, _)| pass.name() == name) {
+            Some((_, enabled)) => {
+                *enabled = false;
+                Ok(())
+            }
+            None => Err(format!("unknown pass {:?}, see --print-passes", name)),
+        }
+    }
+
+    /// The registered passes in order, with whether they are enabled.
+    pub fn passes(&self) -> impl Iterator<Item = (&dyn Pass, bool)> {
+        self.passes
+            .iter()
+            .map(|(pass, enabled)| (pass.as_ref(), *enabled))
+    }
+
+    pub fn run(&self, ctx: &CompilerContext, program: &mut Program) -> SourceResult<()> {
+        for (pass, _) in self.passes.iter().filter(|(_, enabled)| *enabled) {
+            pass.run(ctx, program)?;
+        }
+        Ok(())
+    }
+
+    /// Like `run`, but also returns the name of each pass that ran with the time it took.
+    pub fn run_timed(
+        &self,
+        ctx: &CompilerContext,
+        program: &mut Program,
+    ) -> SourceResult<Vec<(&'static str, Duration)>> {
+        let mut times = Vec::new();
+        for (pass, _) in self.passes.iter().filter(|(_, enabled)| *enabled) {
+            let start = Instant::now();
+            pass.run(ctx, program)?;
+            times.push((pass.name(), start.elapsed()));
+        }
+        Ok(times)
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::analyze;
+    use crate::parser::parse;
+
+    fn optimized_main(source: &str, disabled: &[&str]) -> String {
+        let mut ctx = CompilerContext::default();
+        let source_id = ctx.sources.add_from_string(source);
+        let ast = parse(&mut ctx, source_id).unwrap();
+        let mut program = analyze(&ctx, &ast).unwrap();
+        let mut manager = PassManager::with_default_passes();
+        for name in disabled {
+            manager.disable(name).unwrap();
+        }
+        manager.run(&ctx, &mut program).unwrap();
+        format!("{:?}", program.globals[0].debug_with(&ctx))
+    }
+
+    #[test]
+    fn test_default_passes() {
+        let source = r#"fun main() { if (1 < 2) { print("a"); } else { print("b"); } return 1; print("c"); }"#;
+        let dump = optimized_main(source, &[]);
+        assert!(!dump.contains("If"), "{}", dump);
+        assert!(dump.contains(r#"ConstString("a")"#), "{}", dump);
+        assert!(!dump.contains(r#"ConstString("b")"#), "{}", dump);
+        assert!(!dump.contains(r#"ConstString("c")"#), "{}", dump);
+
+        // without folding the condition is not constant, so the branches are kept
+        let dump = optimized_main(source, &["fold-constants"]);
+        assert!(dump.contains(r#"ConstString("b")"#), "{}", dump);
+        assert!(!dump.contains(r#"ConstString("c")"#), "{}", dump);
+    }
+
+    #[test]
+    fn test_disable_unknown_pass() {
+        let mut manager = PassManager::with_default_passes();
+        assert!(manager.disable("inline").is_err());
+        manager.disable("dead-code").unwrap();
+        let enabled: Vec<_> = manager
+            .passes()
+            .map(|(pass, enabled)| (pass.name(), enabled))
+            .collect();
+        assert_eq!(
+            enabled,
+            [
+                ("fold-constants", true),
+                ("dead-code", false),
+                ("dead-stores", true)
+            ]
+        );
+    }
+}
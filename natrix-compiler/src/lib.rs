@@ -1,10 +1,15 @@
 pub mod analyze;
 pub mod ast;
 pub mod bc;
+pub mod coverage;
 pub mod ctx;
+pub mod doc;
 pub mod error;
 pub mod hir;
+pub mod module;
 pub mod parser;
+pub mod refactor;
+pub mod repl;
 pub mod src;
 pub mod token;
 pub mod token_type;
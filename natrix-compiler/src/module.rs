@@ -0,0 +1,469 @@
+// Programs split over several files with `import`.
+//
+// The files given on the command line form the root module. Each imported file is another
+// module, parsed once however often it is imported, and its functions live in a namespace of
+// their own: they see the functions of their module and the built-in functions, and reach those
+// of the modules they import as `module.name`.
+//
+// A module can also be a precompiled library, a `.nxlib` file whose bytecode is linked into the
+// bytecode of the program. Its functions are only known by the export table of the library.
+
+use crate::ast::{Import, Program};
+use crate::ctx::{CompilerContext, Interner, Name};
+use crate::error::{err_at, SourceResult};
+use crate::parser::{parse, parse_source, ParseResult};
+use crate::src::{Source, SourceId, Span};
+use natrix_runtime::bc::{Bytecode, Library};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{thread, vec};
+
+/// The extension of precompiled libraries, which `import` reads instead of parsing them.
+pub const LIBRARY_EXTENSION: &str = "nxlib";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ModuleId(pub usize);
+
+impl ModuleId {
+    /// The module of the files the program was started with.
+    pub const ROOT: ModuleId = ModuleId(0);
+}
+
+struct Module {
+    name: Option<Name>, // the name it is imported as, `None` for the root
+    imports: HashMap<Name, ModuleId>, // the modules it imports, by name
+    qualified: HashMap<Name, Name>, // the names of its functions as seen from other modules
+}
+
+impl Module {
+    fn new(name: Option<Name>) -> Self {
+        Module {
+            name,
+            imports: HashMap::new(),
+            qualified: HashMap::new(),
+        }
+    }
+}
+
+/// A precompiled library imported by the program.
+pub struct LinkedLibrary {
+    pub module: ModuleId,
+    pub span: Span, // of the import that read it
+    pub bc: Bytecode,
+    pub exports: Vec<(Name, usize)>, // the name of each exported function and its global in `bc`
+}
+
+/// The modules of a program, filled by `parse_program`.
+pub struct Modules {
+    modules: Vec<Module>,
+    libraries: Vec<LinkedLibrary>,
+    by_path: HashMap<PathBuf, ModuleId>, // canonical path of each file read so far
+    packages: HashMap<String, PathBuf>,  // the file read by `import name;`, see `add_package`
+    search_dirs: Vec<PathBuf>,           // see `add_search_dir`
+}
+
+impl Modules {
+    pub fn new() -> Self {
+        Modules {
+            modules: vec![Module::new(None)],
+            libraries: Vec::new(),
+            by_path: HashMap::new(),
+            packages: HashMap::new(),
+            search_dirs: Vec::new(),
+        }
+    }
+
+    /// Makes the imports named `name` read `entry`, the entry of a package the program depends
+    /// on, unless the importing file has a file of that name next to it.
+    pub fn add_package(&mut self, name: &str, entry: PathBuf) {
+        self.packages.insert(name.to_string(), entry);
+    }
+
+    /// Adds a directory searched for the imports that are neither next to the importing file
+    /// nor a package.
+    pub fn add_search_dir(&mut self, dir: PathBuf) {
+        self.search_dirs.push(dir);
+    }
+
+    // The file read by `import`, or the path next to the importing file if it is nowhere. A
+    // precompiled library of the same name is found where the source is not.
+    fn locate(&self, ctx: &CompilerContext, import: &ImportRef) -> PathBuf {
+        let relative = ctx.sources.relative_path(import.span, &import.path);
+        if let Some(path) = existing(&relative) {
+            return path;
+        }
+        if let Some(entry) = self.packages.get(ctx.interner.resolve(import.name)) {
+            return entry.clone();
+        }
+        self.search_dirs
+            .iter()
+            .find_map(|dir| existing(&dir.join(&*import.path)))
+            .unwrap_or(relative)
+    }
+
+    /// The number of modules, including the root.
+    pub fn count(&self) -> usize {
+        self.modules.len()
+    }
+
+    /// The name `module` is imported as, `None` for the root.
+    pub fn name(&self, module: ModuleId) -> Option<Name> {
+        self.modules[module.0].name
+    }
+
+    /// The precompiled libraries of the program, in the order they were imported.
+    pub fn libraries(&self) -> &[LinkedLibrary] {
+        &self.libraries
+    }
+
+    /// Whether `module` is a precompiled library rather than parsed from source.
+    pub fn is_library(&self, module: ModuleId) -> bool {
+        self.libraries
+            .iter()
+            .any(|library| library.module == module)
+    }
+
+    /// The module imported as `name` by `from`, if any.
+    pub fn resolve(&self, from: ModuleId, name: Name) -> Option<ModuleId> {
+        self.modules[from.0].imports.get(&name).copied()
+    }
+
+    /// The name of the function `name` of `module` in the whole program, e.g. in error messages
+    /// and the bytecode: `module.name`, or just `name` in the root module.
+    pub fn global_name(&self, module: ModuleId, name: Name) -> Name {
+        self.modules[module.0]
+            .qualified
+            .get(&name)
+            .copied()
+            .unwrap_or(name)
+    }
+}
+
+// `path` if it exists, or else the library of the same name
+fn existing(path: &Path) -> Option<PathBuf> {
+    let library = path.with_extension(LIBRARY_EXTENSION);
+    [path.to_path_buf(), library]
+        .into_iter()
+        .find(|path| path.exists())
+}
+
+impl Default for Modules {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parses the root module from `source_ids` and every module it imports, directly or not, into
+/// a single program whose declarations are tagged with their module.
+///
+/// An import is resolved relative to the file containing it, then among the packages and search
+/// directories of `ctx.modules`. Each file is read once, a module imported again, also under a
+/// cycle of imports, refers to the same declarations. A `.nxlib` file is read as a precompiled
+/// library, which contributes no declarations, only the names of its exports.
+///
+/// The files are parsed on `ctx.jobs` threads: the root files together, then the files imported
+/// by the modules parsed so far, level by level. The results are merged in the order a single
+/// thread would have parsed them, so the program does not depend on the number of threads.
+pub fn parse_program(ctx: &mut CompilerContext, source_ids: &[SourceId]) -> SourceResult<Program> {
+    let mut parsed = ParsedFiles::new(ctx, source_ids.to_vec());
+    let mut program = parsed.next(ctx)?;
+    for _ in &source_ids[1..] {
+        let mut other = parsed.next(ctx)?;
+        program.imports.append(&mut other.imports);
+        program.decls.append(&mut other.decls);
+    }
+    for source_id in source_ids {
+        if let Some(path) = ctx.sources.get_by_id(*source_id).path()
+            && let Ok(path) = fs::canonicalize(path)
+        {
+            ctx.modules.by_path.insert(path, ModuleId::ROOT);
+        }
+    }
+
+    // the imports still to resolve and the module containing each of them
+    let mut pending: Vec<(ModuleId, ImportRef)> = program
+        .imports
+        .iter()
+        .map(|import| (ModuleId::ROOT, ImportRef::from(import)))
+        .collect();
+    while !pending.is_empty() {
+        // the files of the modules found by this level are read first and parsed together, an
+        // error stops the level after the imports before it
+        let mut resolved = Vec::new();
+        let mut failed = None;
+        for (from, import) in pending.drain(..) {
+            match resolve_import(ctx, &import) {
+                Ok(module) => resolved.push((from, import, module)),
+                Err(err) => {
+                    failed = Some(err);
+                    break;
+                }
+            }
+        }
+        let source_ids: Vec<SourceId> = resolved
+            .iter()
+            .filter_map(|(_, _, module)| match module {
+                Resolved::Source(_, source_id) => Some(*source_id),
+                _ => None,
+            })
+            .collect();
+        let mut parsed = ParsedFiles::new(ctx, source_ids);
+        for (from, import, module) in resolved {
+            let id = match module {
+                Resolved::Known(id) => id,
+                Resolved::Library(id, library) => {
+                    let prefix = ctx.interner.resolve(import.name).to_owned();
+                    let mut exports = Vec::new();
+                    for (name, global) in library.exports {
+                        let qualified = ctx.interner.intern(&format!("{}.{}", prefix, name));
+                        let name = ctx.interner.intern(&name);
+                        ctx.modules.modules[id.0].qualified.insert(name, qualified);
+                        exports.push((name, global));
+                    }
+                    ctx.modules.libraries.push(LinkedLibrary {
+                        module: id,
+                        span: import.span,
+                        bc: library.bc,
+                        exports,
+                    });
+                    id
+                }
+                Resolved::Source(id, _) => {
+                    let mut module = parsed.next(ctx)?;
+                    let prefix = ctx.interner.resolve(import.name).to_owned();
+                    for decl in &mut module.decls {
+                        decl.module = id;
+                        let qualified = format!("{}.{}", prefix, ctx.interner.resolve(decl.name));
+                        let qualified = ctx.interner.intern(&qualified);
+                        ctx.modules.modules[id.0]
+                            .qualified
+                            .insert(decl.name, qualified);
+                    }
+                    pending.extend(
+                        module
+                            .imports
+                            .iter()
+                            .map(|import| (id, ImportRef::from(import))),
+                    );
+                    program.decls.append(&mut module.decls);
+                    id
+                }
+            };
+            match ctx.modules.modules[from.0].imports.entry(import.name) {
+                Entry::Vacant(e) => {
+                    e.insert(id);
+                }
+                Entry::Occupied(e) if *e.get() == id => {}
+                Entry::Occupied(_) => {
+                    return err_at(
+                        import.span,
+                        format!(
+                            "another module named {} is already imported",
+                            ctx.interner.resolve(import.name)
+                        ),
+                    );
+                }
+            }
+        }
+        if let Some(err) = failed {
+            return Err(err);
+        }
+    }
+    Ok(program)
+}
+
+// The module an import refers to, with a new module read but not parsed yet
+enum Resolved {
+    Known(ModuleId),
+    Library(ModuleId, Library),
+    Source(ModuleId, SourceId),
+}
+
+// Finds the file of `import` and reads it if it is not a module yet
+fn resolve_import(ctx: &mut CompilerContext, import: &ImportRef) -> SourceResult<Resolved> {
+    let path = ctx.modules.locate(ctx, import);
+    let cannot_import = |err: &dyn Display| format!("cannot import {:?}: {}", &*import.path, err);
+    let canonical = match fs::canonicalize(&path) {
+        Ok(canonical) => canonical,
+        Err(err) => return err_at(import.span, cannot_import(&err)),
+    };
+    if let Some(&id) = ctx.modules.by_path.get(&canonical) {
+        return Ok(Resolved::Known(id));
+    }
+    let id = ModuleId(ctx.modules.count());
+    if path.extension().is_some_and(|ext| ext == LIBRARY_EXTENSION) {
+        let library = match fs::read(&path) {
+            Ok(bytes) => Library::deserialize(&bytes).map_err(|err| err.message),
+            Err(err) => Err(err.to_string().into()),
+        };
+        let library = match library {
+            Ok(library) => library,
+            Err(err) => return err_at(import.span, cannot_import(&err)),
+        };
+        ctx.modules.modules.push(Module::new(Some(import.name)));
+        ctx.modules.by_path.insert(canonical, id);
+        Ok(Resolved::Library(id, library))
+    } else {
+        let source_id = match ctx.sources.add_from_file(&path) {
+            Ok(source_id) => source_id,
+            Err(err) => return err_at(import.span, cannot_import(&err)),
+        };
+        ctx.modules.modules.push(Module::new(Some(import.name)));
+        ctx.modules.by_path.insert(canonical, id);
+        Ok(Resolved::Source(id, source_id))
+    }
+}
+
+// The AST of a file parsed on a worker thread, with the interner of its names. Its string
+// literals are `Rc`s shared only with that interner, so the two can move to another thread
+// together.
+struct Parsed(ParseResult<(Program, Interner)>);
+
+// SAFETY: the `Rc`s are only reachable through the value, which is moved as a whole
+unsafe impl Send for Parsed {}
+
+// Files parsed in order by `next`: when it is called on the current thread, or beforehand on
+// up to `ctx.jobs` threads, each with an interner of its own. `next` then moves the result to
+// the interner of the context, so that the names are interned in the same order either way.
+struct ParsedFiles {
+    source_ids: vec::IntoIter<SourceId>,
+    parsed: Option<vec::IntoIter<Parsed>>, // `None` when parsing on the current thread
+}
+
+impl ParsedFiles {
+    fn new(ctx: &CompilerContext, source_ids: Vec<SourceId>) -> Self {
+        let jobs = ctx.jobs.min(source_ids.len());
+        let parsed = (jobs > 1).then(|| Self::parse_in_parallel(ctx, &source_ids, jobs));
+        ParsedFiles {
+            source_ids: source_ids.into_iter(),
+            parsed: parsed.map(Vec::into_iter),
+        }
+    }
+
+    fn parse_in_parallel(
+        ctx: &CompilerContext,
+        source_ids: &[SourceId],
+        jobs: usize,
+    ) -> Vec<Parsed> {
+        let sources: Vec<&Source> = source_ids
+            .iter()
+            .map(|id| ctx.sources.get_by_id(*id))
+            .collect();
+        let edition = ctx.edition;
+        let next = AtomicUsize::new(0);
+        let mut parsed: Vec<(usize, Parsed)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut parsed = Vec::new();
+                        loop {
+                            let index = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(source) = sources.get(index) else {
+                                return parsed;
+                            };
+                            let mut interner = Interner::new();
+                            let program = parse_source(source, &mut interner, edition);
+                            parsed.push((index, Parsed(program.map(|p| (p, interner)))));
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+        parsed.sort_by_key(|(index, _)| *index);
+        parsed.into_iter().map(|(_, parsed)| parsed).collect()
+    }
+
+    fn next(&mut self, ctx: &mut CompilerContext) -> ParseResult<Program> {
+        let source_id = self.source_ids.next().unwrap();
+        let Some(parsed) = &mut self.parsed else {
+            return parse(ctx, source_id);
+        };
+        let Parsed(result) = parsed.next().unwrap();
+        let (mut program, interner) = result?;
+        let names = ctx.interner.merge(&interner);
+        program.remap(&names, &mut ctx.interner);
+        Ok(program)
+    }
+}
+
+// The parts of an `Import` needed to resolve it, the AST of the root keeps its own
+struct ImportRef {
+    path: Box<str>,
+    name: Name,
+    span: Span,
+}
+
+impl From<&Import> for ImportRef {
+    fn from(import: &Import) -> Self {
+        ImportRef {
+            path: import.path.clone(),
+            name: import.name,
+            span: import.span,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token_type::Edition;
+
+    #[test]
+    fn test_unknown_module() {
+        let mut ctx = CompilerContext::with_edition(Edition::E2);
+        let source_id = ctx
+            .sources
+            .add_from_string("import \"no/such/file.nx\";\nfun main() {}");
+        let error = parse_program(&mut ctx, &[source_id]).unwrap_err();
+        assert!(
+            error
+                .message
+                .starts_with("cannot import \"no/such/file.nx\": "),
+            "{}",
+            error.message
+        );
+        assert_eq!(ctx.modules.count(), 1);
+    }
+
+    #[test]
+    fn test_packages_and_search_dirs() {
+        let mut ctx = CompilerContext::with_edition(Edition::E2);
+        ctx.modules
+            .add_package("geometry", PathBuf::from("../tests/lib/geometry.nx"));
+        ctx.modules.add_search_dir(PathBuf::from("../tests/lib"));
+        let source_id = ctx
+            .sources
+            .add_from_string("import geometry;\nimport \"shapes.nx\";\nfun main() {}");
+        let program = parse_program(&mut ctx, &[source_id]).unwrap();
+        // geometry imports shapes next to it, which is the file found in the search directory
+        assert_eq!(ctx.modules.count(), 3);
+        let names: Vec<_> = program
+            .decls
+            .iter()
+            .map(|decl| {
+                ctx.interner
+                    .resolve(ctx.modules.global_name(decl.module, decl.name))
+            })
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "main",
+                "geometry.area",
+                "geometry.report",
+                "geometry.check",
+                "shapes.square",
+                "shapes.describe",
+                "shapes.fail"
+            ]
+        );
+    }
+}
@@ -1,46 +1,161 @@
 use crate::ast::{
-    AssignTarget, AssignTargetKind, Expr, ExprKind, FunDecl, Param, Program, Stmt, StmtKind,
+    AssignTarget, AssignTargetKind, Expr, ExprKind, FunDecl, Import, Param, Program, Stmt, StmtKind,
 };
-use crate::ctx::CompilerContext;
-use crate::error::{SourceError, SourceResult};
-use crate::src::{SourceId, Span};
+use crate::ctx::{CompilerContext, Interner};
+use crate::error::{err_at, error_at, SourceError, SourceResult};
+use crate::module::ModuleId;
+use crate::src::{Source, SourceId, Span};
 use crate::token::{Token, TokenType, Tokenizer};
+use crate::token_type::Edition;
 use natrix_runtime::value::{BinaryOp, UnaryOp};
+use std::path::Path;
 use std::str::FromStr;
 
 pub type ParseResult<T> = SourceResult<T>;
 
+// Maximum nesting of expressions and statements. The parser and the passes after it recurse on
+// the Rust stack, deeper input is rejected instead of overflowing it.
+const MAX_NESTING_DEPTH: usize = 200;
+
+// Maximum number of operators in the left-associative chains around an expression, like the
+// terms of `a + b + c`. Only the passes after the parser recurse on each of them, with less
+// stack than the parser needs for a nested construct, so long chains are allowed.
+const MAX_CHAIN_DEPTH: usize = 1000;
+
+const ASSIGNMENT_IN_COND: &str = "expected a condition, not an assignment (did you mean `==`?)";
+
 pub fn parse(ctx: &mut CompilerContext, source_id: SourceId) -> ParseResult<Program> {
-    let mut parser = Parser::new(ctx, source_id)?;
+    let source = ctx.sources.get_by_id(source_id);
+    parse_source(source, &mut ctx.interner, ctx.edition)
+}
+
+/// Parses `source` like [`parse`], interning its names with `interner`, which lets files be
+/// parsed on other threads, see `module::parse_program`.
+pub fn parse_source(
+    source: &Source,
+    interner: &mut Interner,
+    edition: Edition,
+) -> ParseResult<Program> {
+    let tokenizer = Tokenizer::with_interner(source, interner, edition);
+    let mut parser = Parser::with_tokenizer(tokenizer)?;
+    let mut imports = Vec::new();
     let mut fun_decls = Vec::new();
     while parser.tt() != TokenType::Eof {
-        fun_decls.push(parser.fun_decl()?);
+        match parser.tt() {
+            TokenType::KwImport => imports.push(parser.import()?),
+            // flags select statements, not declarations
+            TokenType::At => return parser.err("@if must be inside a function"),
+            _ => fun_decls.push(parser.fun_decl()?),
+        }
+    }
+    Ok(Program::new(imports, fun_decls, parser.span()))
+}
+
+/// A top-level entry of interactive input.
+pub enum ReplItem {
+    FunDecl(FunDecl),
+    Stmt(Stmt),
+}
+
+/// Parses a line of REPL input, which may mix function declarations with statements.
+///
+/// The semicolon after the last expression statement may be omitted.
+pub fn parse_repl_input(
+    ctx: &mut CompilerContext,
+    source_id: SourceId,
+) -> ParseResult<Vec<ReplItem>> {
+    let mut parser = Parser::new(ctx, source_id)?;
+    parser.repl = true;
+    let mut items = Vec::new();
+    while parser.tt() != TokenType::Eof {
+        items.push(match parser.tt() {
+            TokenType::KwFun => ReplItem::FunDecl(parser.fun_decl()?),
+            TokenType::KwVar => {
+                items.extend(parser.var_decl()?.into_iter().map(ReplItem::Stmt));
+                continue;
+            }
+            _ => ReplItem::Stmt(parser.stmt()?),
+        });
     }
-    Ok(Program::new(fun_decls, parser.span()))
+    Ok(items)
 }
 
 struct Parser<'a> {
     tokenizer: Tokenizer<'a>,
     current_token: Token,
+    doc_comments: Vec<String>, // doc comment lines immediately preceding current_token
+    peeked: Option<(Token, Vec<String>)>, // the token after current_token and its doc comments
+    repl: bool,                // allows omitting the semicolon of a trailing expression statement
+    depth: usize,              // number of expressions and statements being parsed
+    chain_depth: usize,        // number of operators of chains being parsed, see `chained`
 }
 
 impl<'a> Parser<'a> {
     fn new(ctx: &'a mut CompilerContext, source_id: SourceId) -> SourceResult<Self> {
-        let mut tokenizer = Tokenizer::new(ctx, source_id);
-        let current_token = tokenizer.next_token()?;
+        Self::with_tokenizer(Tokenizer::new(ctx, source_id))
+    }
+
+    fn with_tokenizer(mut tokenizer: Tokenizer<'a>) -> SourceResult<Self> {
+        let mut doc_comments = Vec::new();
+        let current_token = Self::next_token(&mut tokenizer, &mut doc_comments)?;
         Ok(Parser {
             tokenizer,
             current_token,
+            doc_comments,
+            peeked: None,
+            repl: false,
+            depth: 0,
+            chain_depth: 0,
         })
     }
 
     fn fun_decl(&mut self) -> SourceResult<FunDecl> {
+        let doc = self.take_doc();
         self.expect(TokenType::KwFun)?;
         let name_span = self.span();
         let name = self.expect(TokenType::Identifier)?.name.unwrap();
         let params = self.params()?;
         let (body, body_span) = self.block()?;
-        Ok(FunDecl::new(name, name_span, params, body, body_span))
+        Ok(FunDecl::new(
+            doc,
+            name,
+            name_span,
+            params,
+            body,
+            body_span,
+            ModuleId::ROOT,
+        ))
+    }
+
+    // `import "path";` or `import name;`, the module is named after the file
+    fn import(&mut self) -> ParseResult<Import> {
+        let start_span = self.expect(TokenType::KwImport)?.span;
+        let (path, name) = match self.tt() {
+            TokenType::Identifier => {
+                let path = format!("{}.nx", self.lexeme());
+                (path, self.consume()?.name.unwrap())
+            }
+            TokenType::StringLiteral => {
+                let path = decode_string_literal(self.lexeme());
+                let stem = Path::new(&path).file_stem().and_then(|stem| stem.to_str());
+                let Some(name) = stem.and_then(|stem| self.tokenizer.intern_identifier(stem))
+                else {
+                    return self.err(format!(
+                        "cannot import {:?}, the name of the file is not a valid module name",
+                        path
+                    ));
+                };
+                self.consume()?;
+                (path, name)
+            }
+            tt => return self.err(format!("expected module name or path, not {:?}", tt)),
+        };
+        let end_span = self.expect(TokenType::Semicolon)?.span;
+        Ok(Import::new(
+            path.into(),
+            name,
+            start_span.extend_to(end_span),
+        ))
     }
 
     fn params(&mut self) -> SourceResult<Vec<Param>> {
@@ -50,6 +165,9 @@ impl<'a> Parser<'a> {
             params.push(self.param()?);
             while self.tt() == TokenType::Comma {
                 self.consume()?;
+                if self.tt() == TokenType::RParen {
+                    break;
+                }
                 params.push(self.param()?);
             }
         };
@@ -70,7 +188,9 @@ impl<'a> Parser<'a> {
         let start_span = self.expect(TokenType::LBrace)?.span;
         while self.tt() != TokenType::RBrace {
             if self.tt() == TokenType::KwVar {
-                stmts.push(self.var_decl()?);
+                stmts.extend(self.var_decl()?);
+            } else if self.tt() == TokenType::KwDefer {
+                stmts.push(self.defer()?);
             } else {
                 stmts.push(self.stmt()?);
             }
@@ -79,25 +199,57 @@ impl<'a> Parser<'a> {
         Ok((stmts, start_span.extend_to(end_span)))
     }
 
-    fn var_decl(&mut self) -> ParseResult<Stmt> {
-        let start_span = self.expect(TokenType::KwVar)?.span;
-        let name_token = self.expect(TokenType::Identifier)?;
-        //         match(Kind.COLON);
-        //         TypeNode type = type();
-        self.expect(TokenType::Assign)?;
-        let init = self.expr()?;
+    // Each name of `var a = 1, b;` becomes its own declaration, the first one spanning the `var`
+    // keyword and the last one the semicolon
+    fn var_decl(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut start_span = self.expect(TokenType::KwVar)?.span;
+        let mut decls = Vec::new();
+        loop {
+            let name_token = self.expect(TokenType::Identifier)?;
+            //         match(Kind.COLON);
+            //         TypeNode type = type();
+            let init = if matches!(self.tt(), TokenType::Semicolon | TokenType::Comma) {
+                None
+            } else {
+                self.expect(TokenType::Assign)?;
+                Some(self.expr()?)
+            };
+            let end_span = init.as_ref().map_or(name_token.span, |init| init.span);
+            decls.push(Stmt::new(
+                StmtKind::VarDecl {
+                    name: name_token.name.unwrap(),
+                    name_span: name_token.span,
+                    init,
+                },
+                start_span.extend_to(end_span),
+            ));
+            if self.tt() != TokenType::Comma {
+                break;
+            }
+            self.consume()?;
+            start_span = self.span();
+        }
         let end_span = self.expect(TokenType::Semicolon)?.span;
-        Ok(Stmt::new(
-            StmtKind::VarDecl {
-                name: name_token.name.unwrap(),
-                name_span: name_token.span,
-                init,
-            },
-            start_span.extend_to(end_span),
-        ))
+        let last = decls.last_mut().unwrap();
+        last.span = last.span.extend_to(end_span);
+        Ok(decls)
+    }
+
+    fn defer(&mut self) -> ParseResult<Stmt> {
+        let start_span = self.expect(TokenType::KwDefer)?.span;
+        if self.tt() == TokenType::KwVar {
+            return self.err("a variable declaration cannot be deferred");
+        }
+        let body = self.stmt()?;
+        let span = start_span.extend_to(body.span);
+        Ok(Stmt::new(StmtKind::Defer(Box::new(body)), span))
     }
 
     fn stmt(&mut self) -> ParseResult<Stmt> {
+        self.nested("statement", Self::stmt_inner)
+    }
+
+    fn stmt_inner(&mut self) -> ParseResult<Stmt> {
         match self.tt() {
             TokenType::LBrace => {
                 let (stmts, span) = self.block()?;
@@ -105,19 +257,59 @@ impl<'a> Parser<'a> {
             }
             TokenType::KwBreak => {
                 let span = self.consume()?.span;
+                let expr = if self.tt() != TokenType::Semicolon {
+                    Some(self.expr()?)
+                } else {
+                    None
+                };
                 let span = span.extend_to(self.expect(TokenType::Semicolon)?.span);
-                Ok(Stmt::new(StmtKind::Break, span))
+                Ok(Stmt::new(StmtKind::Break(expr), span))
+            }
+            TokenType::At => {
+                let start_span = self.consume()?.span;
+                self.expect(TokenType::KwIf)?;
+                self.expect(TokenType::LParen)?;
+                let flag_token = self.expect(TokenType::Identifier)?;
+                let value = if self.tt() == TokenType::Eq {
+                    self.consume()?;
+                    if self.tt() != TokenType::StringLiteral {
+                        return self.err("expected a string to compare the flag with");
+                    }
+                    let value = decode_string_literal(self.lexeme());
+                    self.consume()?;
+                    Some(self.tokenizer.intern_literal(&value))
+                } else {
+                    None
+                };
+                self.expect(TokenType::RParen)?;
+                let then_body = self.stmt()?;
+                let else_body = if self.tt() == TokenType::KwElse {
+                    self.consume()?;
+                    Some(self.stmt()?)
+                } else {
+                    None
+                };
+                let span = start_span.extend_to(else_body.as_ref().unwrap_or(&then_body).span);
+                Ok(Stmt::new(
+                    StmtKind::CompileIf {
+                        flag: flag_token.name.unwrap(),
+                        flag_span: flag_token.span,
+                        value,
+                        then_body: Box::new(then_body),
+                        else_body: else_body.map(Box::new),
+                    },
+                    span,
+                ))
             }
             TokenType::KwContinue => {
                 let span = self.consume()?.span;
                 let span = span.extend_to(self.expect(TokenType::Semicolon)?.span);
                 Ok(Stmt::new(StmtKind::Continue, span))
             }
+            TokenType::KwDefer => self.err("defer must be directly inside a block"),
             TokenType::KwIf => {
                 let start_span = self.consume()?.span;
-                self.expect(TokenType::LParen)?;
-                let cond = self.expr()?;
-                self.expect(TokenType::RParen)?;
+                let cond = self.cond()?;
                 let then_body = self.stmt()?;
                 let else_body = if self.tt() == TokenType::KwElse {
                     self.consume()?;
@@ -135,6 +327,28 @@ impl<'a> Parser<'a> {
                     span,
                 ))
             }
+            TokenType::KwGuard => {
+                // guard (cond) else body  =>  if (!cond) body
+                let kw_span = self.consume()?.span;
+                let cond = self.negated_cond(kw_span)?;
+                self.expect(TokenType::KwElse)?;
+                let else_body = self.stmt()?;
+                if !exits_scope(&else_body) {
+                    return err_at(
+                        else_body.span,
+                        "guard body must end with return, break or continue",
+                    );
+                }
+                let span = kw_span.extend_to(else_body.span);
+                Ok(Stmt::new(
+                    StmtKind::If {
+                        cond,
+                        then_body: Box::new(else_body),
+                        else_body: None,
+                    },
+                    span,
+                ))
+            }
             TokenType::KwReturn => {
                 let start_span = self.consume()?.span;
                 let expr = if self.tt() != TokenType::Semicolon {
@@ -148,13 +362,25 @@ impl<'a> Parser<'a> {
                     start_span.extend_to(end_span),
                 ))
             }
+            TokenType::KwUnless => {
+                // unless (cond) body  =>  if (!cond) body
+                let kw_span = self.consume()?.span;
+                let cond = self.negated_cond(kw_span)?;
+                let then_body = self.stmt()?;
+                let span = kw_span.extend_to(then_body.span);
+                Ok(Stmt::new(
+                    StmtKind::If {
+                        cond,
+                        then_body: Box::new(then_body),
+                        else_body: None,
+                    },
+                    span,
+                ))
+            }
+            TokenType::KwFor => self.for_loop(),
+            TokenType::KwWith => self.with_block(),
             TokenType::KwWhile => {
-                let start_span = self.consume()?.span;
-                self.expect(TokenType::LParen)?;
-                let cond = self.expr()?;
-                self.expect(TokenType::RParen)?;
-                let body = self.stmt()?;
-                let span = start_span.extend_to(body.span);
+                let (cond, body, span) = self.while_loop()?;
                 Ok(Stmt::new(
                     StmtKind::While {
                         cond,
@@ -164,42 +390,214 @@ impl<'a> Parser<'a> {
                 ))
             }
             _ => {
-                let expr = self.expr()?;
-                if self.tt() == TokenType::Assign {
-                    let target = match expr.kind {
-                        ExprKind::Var(name) => {
-                            AssignTarget::new(AssignTargetKind::Var(name), expr.span)
-                        }
-                        ExprKind::ArrayAccess { array, index } => AssignTarget::new(
-                            AssignTargetKind::ArrayAccess { array, index },
-                            expr.span,
-                        ),
-                        _ => {
-                            return self.err("expected lvalue on the left side of assignment");
-                        }
-                    };
-                    self.consume()?;
-                    let value = self.expr()?;
+                let stmt = self.simple_stmt()?;
+                if !self.repl
+                    || self.tt() != TokenType::Eof
+                    || !matches!(stmt.kind, StmtKind::Expr(_))
+                {
                     self.expect(TokenType::Semicolon)?;
-                    let span = target.span.extend_to(value.span);
-                    Ok(Stmt::new(StmtKind::Assign { target, value }, span))
-                } else {
-                    self.expect(TokenType::Semicolon)?;
-                    let span = expr.span;
-                    Ok(Stmt::new(StmtKind::Expr(expr), span))
                 }
+                Ok(stmt)
+            }
+        }
+    }
+
+    // Parses an expression statement or an assignment, without the semicolon, which the update
+    // of a `for` loop does not have
+    fn simple_stmt(&mut self) -> ParseResult<Stmt> {
+        let expr = self.expr()?;
+        if self.tt() == TokenType::Comma {
+            let mut targets = vec![self.assign_target(expr)?];
+            while self.tt() == TokenType::Comma {
+                self.consume()?;
+                let expr = self.expr()?;
+                targets.push(self.assign_target(expr)?);
+            }
+            self.expect(TokenType::Assign)?;
+            let value = self.expr()?;
+            let span = targets[0].span.extend_to(value.span);
+            Ok(Stmt::new(StmtKind::Unpack { targets, value }, span))
+        } else if self.tt() == TokenType::Assign {
+            let target = self.assign_target(expr)?;
+            self.consume()?;
+            let value = self.expr()?;
+            let span = target.span.extend_to(value.span);
+            Ok(Stmt::new(StmtKind::Assign { target, value }, span))
+        } else {
+            let span = expr.span;
+            Ok(Stmt::new(StmtKind::Expr(expr), span))
+        }
+    }
+
+    // with (var f = open(path)) body  =>  { var f = open(path); defer close(f); body }
+    fn with_block(&mut self) -> ParseResult<Stmt> {
+        let start_span = self.expect(TokenType::KwWith)?.span;
+        self.expect(TokenType::LParen)?;
+        let var_span = self.expect(TokenType::KwVar)?.span;
+        let name_token = self.expect(TokenType::Identifier)?;
+        let name = name_token.name.unwrap();
+        self.expect(TokenType::Assign)?;
+        let init = self.expr()?;
+        self.expect(TokenType::RParen)?;
+        let body = self.stmt()?;
+        let span = start_span.extend_to(body.span);
+        let decl = Stmt::new(
+            StmtKind::VarDecl {
+                name,
+                name_span: name_token.span,
+                init: Some(init),
+            },
+            var_span.extend_to(name_token.span),
+        );
+        let close = self.tokenizer.intern_identifier("close").unwrap();
+        let call = Expr::new(
+            ExprKind::Call {
+                callee: Box::new(Expr::new(ExprKind::Var(close), start_span)),
+                args: vec![Expr::new(ExprKind::Var(name), name_token.span)],
+            },
+            start_span,
+        );
+        let defer = Stmt::new(
+            StmtKind::Defer(Box::new(Stmt::new(StmtKind::Expr(call), start_span))),
+            start_span,
+        );
+        Ok(Stmt::new(StmtKind::Block(vec![decl, defer, body]), span))
+    }
+
+    fn for_loop(&mut self) -> ParseResult<Stmt> {
+        let start_span = self.expect(TokenType::KwFor)?.span;
+        self.expect(TokenType::LParen)?;
+        let init = match self.tt() {
+            TokenType::KwVar => self.var_decl()?,
+            TokenType::Semicolon => {
+                self.consume()?;
+                Vec::new()
             }
+            _ => {
+                let stmt = self.simple_stmt()?;
+                self.expect(TokenType::Semicolon)?;
+                vec![stmt]
+            }
+        };
+        let cond = if self.tt() == TokenType::Semicolon {
+            None
+        } else {
+            let cond = self.expr()?;
+            if self.tt() == TokenType::Assign {
+                return self.err(ASSIGNMENT_IN_COND);
+            }
+            Some(cond)
+        };
+        self.expect(TokenType::Semicolon)?;
+        let update = if self.tt() == TokenType::RParen {
+            None
+        } else {
+            Some(Box::new(self.simple_stmt()?))
+        };
+        self.expect(TokenType::RParen)?;
+        let body = self.stmt()?;
+        let span = start_span.extend_to(body.span);
+        Ok(Stmt::new(
+            StmtKind::For {
+                init,
+                cond,
+                update,
+                body: Box::new(body),
+            },
+            span,
+        ))
+    }
+
+    fn assign_target(&self, expr: Expr) -> ParseResult<AssignTarget> {
+        match expr.kind {
+            ExprKind::Var(name) => Ok(AssignTarget::new(AssignTargetKind::Var(name), expr.span)),
+            ExprKind::ArrayAccess { array, index } => Ok(AssignTarget::new(
+                AssignTargetKind::ArrayAccess { array, index },
+                expr.span,
+            )),
+            ExprKind::Paren(_) => err_at(
+                expr.span,
+                "parenthesized expressions are not assignable (remove the parentheses)",
+            ),
+            ExprKind::Slice { .. } => err_at(expr.span, "slices are not assignable"),
+            ExprKind::Qualified { .. } => err_at(
+                expr.span,
+                "functions of other modules cannot be assigned to",
+            ),
+            _ => self.err("expected lvalue on the left side of assignment"),
+        }
+    }
+
+    // Parses the parenthesized condition of if, unless, guard and while. An assignment in its
+    // place is most likely a mistyped comparison and gets a targeted error.
+    fn cond(&mut self) -> ParseResult<Expr> {
+        // `if x = 1` - the parentheses are missing as well, but the `=` is the more useful hint
+        if self.tt() == TokenType::Identifier && self.peek()?.tt == TokenType::Assign {
+            return err_at(self.peek()?.span, ASSIGNMENT_IN_COND);
+        }
+        self.expect(TokenType::LParen)?;
+        let cond = self.expr()?;
+        if self.tt() == TokenType::Assign {
+            return self.err(ASSIGNMENT_IN_COND);
         }
+        self.expect(TokenType::RParen)?;
+        Ok(cond)
+    }
+
+    // Parses a parenthesized condition and wraps it in a logical negation attributed to the
+    // keyword of the construct being desugared.
+    fn negated_cond(&mut self, kw_span: Span) -> ParseResult<Expr> {
+        let cond = self.cond()?;
+        let span = cond.span;
+        Ok(Expr::new(
+            ExprKind::Unary {
+                op: UnaryOp::Not,
+                op_span: kw_span,
+                expr: Box::new(cond),
+            },
+            span,
+        ))
+    }
+
+    fn while_loop(&mut self) -> ParseResult<(Expr, Stmt, Span)> {
+        let start_span = self.expect(TokenType::KwWhile)?.span;
+        let cond = self.cond()?;
+        let body = self.stmt()?;
+        let span = start_span.extend_to(body.span);
+        Ok((cond, body, span))
     }
 
     fn expr(&mut self) -> ParseResult<Expr> {
-        self.logic_or()
+        self.nested("expression", Self::conditional)
+    }
+
+    // `cond ? a : b`, right-associative, so `a ? b : c ? d : e` is `a ? b : (c ? d : e)`
+    fn conditional(&mut self) -> ParseResult<Expr> {
+        let cond = self.logic_or()?;
+        if self.tt() != TokenType::Question {
+            return Ok(cond);
+        }
+        self.consume()?;
+        let then_expr = self.expr()?;
+        self.expect(TokenType::Colon)?;
+        let else_expr = self.expr()?;
+        let span = cond.span.extend_to(else_expr.span);
+        Ok(Expr::new(
+            ExprKind::Conditional {
+                cond: Box::new(cond),
+                then_expr: Box::new(then_expr),
+                else_expr: Box::new(else_expr),
+            },
+            span,
+        ))
     }
 
     fn logic_or(&mut self) -> ParseResult<Expr> {
+        let chain_depth = self.chain_depth;
         let mut left = self.logic_and()?;
         while self.tt() == TokenType::Or {
             let op_span = self.consume()?.span;
+            self.chained()?;
             let right = self.logic_and()?;
             let span = left.span.extend_to(right.span);
             left = Expr::new(
@@ -212,13 +610,16 @@ impl<'a> Parser<'a> {
                 span,
             )
         }
+        self.chain_depth = chain_depth;
         Ok(left)
     }
 
     fn logic_and(&mut self) -> ParseResult<Expr> {
+        let chain_depth = self.chain_depth;
         let mut left = self.equality()?;
         while self.tt() == TokenType::And {
             let op_span = self.consume()?.span;
+            self.chained()?;
             let right = self.equality()?;
             let span = left.span.extend_to(right.span);
             left = Expr::new(
@@ -231,18 +632,21 @@ impl<'a> Parser<'a> {
                 span,
             )
         }
+        self.chain_depth = chain_depth;
         Ok(left)
     }
 
     fn equality(&mut self) -> ParseResult<Expr> {
+        let chain_depth = self.chain_depth;
         let mut left = self.comparison()?;
         loop {
             let op = match self.tt() {
                 TokenType::Eq => BinaryOp::Eq,
                 TokenType::Ne => BinaryOp::Ne,
-                _ => return Ok(left),
+                _ => break,
             };
             let op_span = self.consume()?.span;
+            self.chained()?;
             let right = self.comparison()?;
             let span = left.span.extend_to(right.span);
             left = Expr::new(
@@ -255,9 +659,12 @@ impl<'a> Parser<'a> {
                 span,
             )
         }
+        self.chain_depth = chain_depth;
+        Ok(left)
     }
 
     fn comparison(&mut self) -> ParseResult<Expr> {
+        let chain_depth = self.chain_depth;
         let mut left = self.additive()?;
         loop {
             let op = match self.tt() {
@@ -265,9 +672,10 @@ impl<'a> Parser<'a> {
                 TokenType::Le => BinaryOp::Le,
                 TokenType::Gt => BinaryOp::Gt,
                 TokenType::Ge => BinaryOp::Ge,
-                _ => return Ok(left),
+                _ => break,
             };
             let op_span = self.consume()?.span;
+            self.chained()?;
             let right = self.additive()?;
             let span = left.span.extend_to(right.span);
             left = Expr::new(
@@ -280,17 +688,21 @@ impl<'a> Parser<'a> {
                 span,
             )
         }
+        self.chain_depth = chain_depth;
+        Ok(left)
     }
 
     fn additive(&mut self) -> ParseResult<Expr> {
+        let chain_depth = self.chain_depth;
         let mut left = self.multiplicative()?;
         loop {
             let op = match self.tt() {
                 TokenType::Plus => BinaryOp::Add,
                 TokenType::Minus => BinaryOp::Sub,
-                _ => return Ok(left),
+                _ => break,
             };
             let op_span = self.consume()?.span;
+            self.chained()?;
             let right = self.multiplicative()?;
             let span = left.span.extend_to(right.span);
             left = Expr::new(
@@ -303,18 +715,22 @@ impl<'a> Parser<'a> {
                 span,
             )
         }
+        self.chain_depth = chain_depth;
+        Ok(left)
     }
 
     fn multiplicative(&mut self) -> ParseResult<Expr> {
+        let chain_depth = self.chain_depth;
         let mut left = self.unary()?;
         loop {
             let op = match self.tt() {
                 TokenType::Star => BinaryOp::Mul,
                 TokenType::Slash => BinaryOp::Div,
                 TokenType::Percent => BinaryOp::Mod,
-                _ => return Ok(left),
+                _ => break,
             };
             let op_span = self.consume()?.span;
+            self.chained()?;
             let right = self.unary()?;
             let span = left.span.extend_to(right.span);
             left = Expr::new(
@@ -327,16 +743,26 @@ impl<'a> Parser<'a> {
                 span,
             )
         }
+        self.chain_depth = chain_depth;
+        Ok(left)
     }
 
+    // `try` covers the whole expression to its right, so `try a / b` also catches the division
+    // and `1 + try a / b` adds the result of the `try`
     fn unary(&mut self) -> ParseResult<Expr> {
         let op = match self.tt() {
             TokenType::Bang => UnaryOp::Not,
             TokenType::Minus => UnaryOp::Neg,
+            TokenType::KwTry => {
+                let kw_span = self.consume()?.span;
+                let expr = self.expr()?;
+                let span = kw_span.extend_to(expr.span);
+                return Ok(Expr::new(ExprKind::Try(Box::new(expr)), span));
+            }
             _ => return self.postfix(),
         };
         let op_span = self.consume()?.span;
-        let expr = self.unary()?;
+        let expr = self.nested("expression", Self::unary)?;
         let span = op_span.extend_to(expr.span);
         Ok(Expr::new(
             ExprKind::Unary {
@@ -348,28 +774,51 @@ impl<'a> Parser<'a> {
         ))
     }
 
+    // Calls and indexing apply left to right to the result of any primary expression, so that
+    // `f()(x)`, `fs[0](x)` and `(f)(x)` chain like in most languages
     fn postfix(&mut self) -> ParseResult<Expr> {
+        let chain_depth = self.chain_depth;
         let mut expr = self.primary()?;
         loop {
             match self.tt() {
                 TokenType::LBracket => {
                     self.consume()?;
-                    let index = self.expr()?;
-                    let span = expr.span.extend_to(self.expect(TokenType::RBracket)?.span);
-                    expr = Expr::new(
-                        ExprKind::ArrayAccess {
+                    self.chained()?;
+                    let expr_span = expr.span;
+                    let start = if self.tt() == TokenType::Colon {
+                        None
+                    } else {
+                        Some(Box::new(self.expr()?))
+                    };
+                    let kind = match start {
+                        Some(index) if self.tt() != TokenType::Colon => ExprKind::ArrayAccess {
                             array: Box::new(expr),
-                            index: Box::new(index),
+                            index,
                         },
-                        span,
-                    );
+                        start => {
+                            self.expect(TokenType::Colon)?;
+                            let end = if self.tt() == TokenType::RBracket {
+                                None
+                            } else {
+                                Some(Box::new(self.expr()?))
+                            };
+                            ExprKind::Slice {
+                                array: Box::new(expr),
+                                start,
+                                end,
+                            }
+                        }
+                    };
+                    let span = expr_span.extend_to(self.expect(TokenType::RBracket)?.span);
+                    expr = Expr::new(kind, span);
                 }
                 TokenType::LParen => {
                     self.consume()?;
+                    self.chained()?;
                     let args = if self.tt() == TokenType::RParen {
                         Vec::new()
                     } else {
-                        self.expr_list()?
+                        self.expr_list(TokenType::RParen)?
                     };
                     let span = expr.span.extend_to(self.expect(TokenType::RParen)?.span);
                     expr = Expr::new(
@@ -380,9 +829,11 @@ impl<'a> Parser<'a> {
                         span,
                     );
                 }
-                _ => return Ok(expr),
+                _ => break,
             }
         }
+        self.chain_depth = chain_depth;
+        Ok(expr)
     }
 
     fn primary(&mut self) -> ParseResult<Expr> {
@@ -405,17 +856,22 @@ impl<'a> Parser<'a> {
             )),
             TokenType::KwNull => Ok(Expr::new(ExprKind::NullLiteral, self.consume()?.span)),
             TokenType::StringLiteral => {
-                let span = self.span();
-                let value = decode_string_literal(self.lexeme());
-                self.consume()?;
-                Ok(Expr::new(ExprKind::StringLiteral(value.into()), span))
+                // adjacent literals are one string, e.g. to split a long string over lines
+                let mut span = self.span();
+                let mut value = String::new();
+                while self.tt() == TokenType::StringLiteral {
+                    value.push_str(&decode_string_literal(self.lexeme()));
+                    span = span.extend_to(self.consume()?.span);
+                }
+                let value = self.tokenizer.intern_literal(&value);
+                Ok(Expr::new(ExprKind::StringLiteral(value), span))
             }
             TokenType::LBracket => {
                 let start_span = self.consume()?.span;
                 let values = if self.tt() == TokenType::RBracket {
                     Vec::new()
                 } else {
-                    self.expr_list()?
+                    self.expr_list(TokenType::RBracket)?
                 };
                 let span = start_span.extend_to(self.expect(TokenType::RBracket)?.span);
                 Ok(Expr::new(ExprKind::ListLiteral(values), span))
@@ -429,22 +885,76 @@ impl<'a> Parser<'a> {
             TokenType::Identifier => {
                 let name_span = self.span();
                 let name = self.consume()?.name.unwrap();
-                Ok(Expr::new(ExprKind::Var(name), name_span))
+                if self.tt() != TokenType::Dot {
+                    return Ok(Expr::new(ExprKind::Var(name), name_span));
+                }
+                self.consume()?;
+                let span = name_span.extend_to(self.span());
+                let member = self.expect(TokenType::Identifier)?.name.unwrap();
+                Ok(Expr::new(
+                    ExprKind::Qualified {
+                        module: name,
+                        module_span: name_span,
+                        name: member,
+                    },
+                    span,
+                ))
+            }
+            TokenType::KwWhile => {
+                let (cond, body, span) = self.while_loop()?;
+                Ok(Expr::new(
+                    ExprKind::While {
+                        cond: Box::new(cond),
+                        body: Box::new(body),
+                    },
+                    span,
+                ))
             }
             tt => self.err(format!("expected expression, not {:?}", tt)),
         }
     }
 
-    fn expr_list(&mut self) -> ParseResult<Vec<Expr>> {
+    // A comma-separated list of expressions, optionally followed by a trailing comma before `end`
+    fn expr_list(&mut self, end: TokenType) -> ParseResult<Vec<Expr>> {
         let mut values = Vec::new();
         values.push(self.expr()?);
         while self.tt() == TokenType::Comma {
             self.consume()?;
+            if self.tt() == end {
+                break;
+            }
             values.push(self.expr()?);
         }
         Ok(values)
     }
 
+    // Parses a construct that may contain itself, failing if too many are already open
+    fn nested<T>(
+        &mut self,
+        what: &str,
+        parse: impl FnOnce(&mut Self) -> ParseResult<T>,
+    ) -> ParseResult<T> {
+        if self.depth == MAX_NESTING_DEPTH {
+            return self.err(format!("{} too deeply nested", what));
+        }
+        let chain_depth = self.chain_depth;
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        self.chain_depth = chain_depth; // drops the operators of chains left by an error
+        result
+    }
+
+    // Counts an operator of a left-associative chain, since each one puts the tree of `a + b + c`
+    // or `f(x)(y)` one level deeper. The caller restores the count after the chain.
+    fn chained(&mut self) -> ParseResult<()> {
+        if self.chain_depth == MAX_CHAIN_DEPTH {
+            return self.err("expression chain too long");
+        }
+        self.chain_depth += 1;
+        Ok(())
+    }
+
     fn expect(&mut self, tt: TokenType) -> SourceResult<Token> {
         if self.tt() == tt {
             self.consume()
@@ -455,10 +965,59 @@ impl<'a> Parser<'a> {
 
     fn consume(&mut self) -> SourceResult<Token> {
         let token = self.current_token;
-        self.current_token = self.tokenizer.next_token()?;
+        self.current_token = match self.peeked.take() {
+            Some((next, doc_comments)) => {
+                self.doc_comments = doc_comments;
+                next
+            }
+            None => {
+                self.doc_comments.clear();
+                Self::next_token(&mut self.tokenizer, &mut self.doc_comments)?
+            }
+        };
         Ok(token)
     }
 
+    // The token after the current one, without consuming anything
+    fn peek(&mut self) -> SourceResult<Token> {
+        if self.peeked.is_none() {
+            let mut doc_comments = Vec::new();
+            let next = Self::next_token(&mut self.tokenizer, &mut doc_comments)?;
+            self.peeked = Some((next, doc_comments));
+        }
+        Ok(self.peeked.as_ref().unwrap().0)
+    }
+
+    // Doc comments are not part of the grammar - they are collected here and picked up by the
+    // declaration that follows them (or silently dropped if there is none).
+    fn next_token(
+        tokenizer: &mut Tokenizer<'a>,
+        doc_comments: &mut Vec<String>,
+    ) -> SourceResult<Token> {
+        loop {
+            let token = tokenizer.next_token()?;
+            if token.tt.is_reserved() {
+                return err_at(
+                    token.span,
+                    format!("{} is a reserved keyword", tokenizer.lexeme(&token)),
+                );
+            }
+            if token.tt != TokenType::DocComment {
+                return Ok(token);
+            }
+            let text = tokenizer.lexeme(&token)[3..].trim_end();
+            doc_comments.push(text.strip_prefix(' ').unwrap_or(text).to_owned());
+        }
+    }
+
+    fn take_doc(&mut self) -> Option<Box<str>> {
+        if self.doc_comments.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.doc_comments).join("\n").into())
+        }
+    }
+
     fn tt(&self) -> TokenType {
         self.current_token.tt
     }
@@ -476,10 +1035,21 @@ impl<'a> Parser<'a> {
     }
 
     fn error(&self, message: impl Into<Box<str>>) -> SourceError {
-        SourceError {
-            message: message.into(),
-            span: self.current_token.span,
-        }
+        error_at(self.current_token.span, message)
+    }
+}
+
+// Checks whether control never falls through the end of `stmt`.
+fn exits_scope(stmt: &Stmt) -> bool {
+    match &stmt.kind {
+        StmtKind::Block(stmts) => stmts.last().is_some_and(exits_scope),
+        StmtKind::Break(_) | StmtKind::Continue | StmtKind::Return(_) => true,
+        StmtKind::If {
+            then_body,
+            else_body: Some(else_body),
+            ..
+        } => exits_scope(then_body) && exits_scope(else_body),
+        _ => false,
     }
 }
 
@@ -508,3 +1078,116 @@ fn decode_string_literal(lexeme: &str) -> String {
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token_type::Edition;
+
+    fn parse_main(body: &str) -> ParseResult<Program> {
+        let mut ctx = CompilerContext::default();
+        let source_id = ctx
+            .sources
+            .add_from_string(&format!("fun main() {{ {} }}", body));
+        parse(&mut ctx, source_id)
+    }
+
+    // Wraps `inner` in `depth` levels of randomly chosen constructs
+    fn nest(depth: usize, seed: &mut u64, inner: &str) -> String {
+        let mut prefix = String::new();
+        let mut suffix = String::new();
+        for _ in 0..depth {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            let (open, close) = match *seed % 6 {
+                0 => ("(", ")"),
+                1 => ("-", ""),
+                2 => ("!", ""),
+                3 => ("[", "]"),
+                4 => ("f(", ")"),
+                _ => ("a[", "]"),
+            };
+            prefix.push_str(open);
+            suffix.insert_str(0, close);
+        }
+        format!("{}{}{}", prefix, inner, suffix)
+    }
+
+    #[test]
+    fn test_nesting_depth() {
+        // the limit is chosen for the 8 MiB stack of the main thread, test threads get less
+        let test = || {
+            let mut seed = 0x2545_f491_4f6c_dd1d;
+            for _ in 0..20 {
+                let shallow = nest(100, &mut seed, "1");
+                assert!(parse_main(&format!("return {};", shallow)).is_ok());
+
+                let deep = nest(10_000, &mut seed, "1");
+                let err = parse_main(&format!("return {};", deep)).unwrap_err();
+                assert_eq!(err.message.as_ref(), "expression too deeply nested");
+            }
+
+            let blocks = format!("{}{}", "{".repeat(10_000), "}".repeat(10_000));
+            let err = parse_main(&blocks).unwrap_err();
+            assert_eq!(err.message.as_ref(), "statement too deeply nested");
+            // the condition of the innermost if is the first construct over the limit
+            let ifs = format!("{};", "if (x) ".repeat(10_000));
+            let err = parse_main(&ifs).unwrap_err();
+            assert_eq!(err.message.as_ref(), "expression too deeply nested");
+
+            // left-associative chains get deeper with each operator, but have a limit of their own
+            let chain = |op: &str, n: usize| format!("print({});", vec!["x"; n].join(op));
+            for op in [" + ", " * ", " < ", " == ", " && ", " || "] {
+                assert!(parse_main(&chain(op, 1000)).is_ok());
+                let err = parse_main(&chain(op, 1002)).unwrap_err();
+                assert_eq!(err.message.as_ref(), "expression chain too long");
+            }
+            let calls = format!("f{};", "(x)".repeat(2000));
+            let err = parse_main(&calls).unwrap_err();
+            assert_eq!(err.message.as_ref(), "expression chain too long");
+            let mixed = format!("print({});", vec!["x * x"; 500].join(" + "));
+            assert!(parse_main(&mixed).is_ok());
+            // the operators of the chains around a nested expression add up
+            let nested = format!("print({}x{});", "x + (".repeat(150), ")".repeat(150));
+            assert!(parse_main(&nested).is_ok());
+            let level = format!("{}(", "x + ".repeat(7));
+            let long = format!("print({}x{});", level.repeat(150), ")".repeat(150));
+            let err = parse_main(&long).unwrap_err();
+            assert_eq!(err.message.as_ref(), "expression chain too long");
+        };
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(test)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn test_import() {
+        let mut ctx = CompilerContext::with_edition(Edition::E2);
+        let source_id = ctx
+            .sources
+            .add_from_string("import util;\nimport \"lib/geometry.nx\";\nimport \"my-lib.nx\";");
+        let err = parse(&mut ctx, source_id).unwrap_err();
+        assert_eq!(
+            err.message.as_ref(),
+            "cannot import \"my-lib.nx\", the name of the file is not a valid module name"
+        );
+
+        let source_id = ctx
+            .sources
+            .add_from_string("import util;\nimport \"lib/geometry.nx\";");
+        let program = parse(&mut ctx, source_id).unwrap();
+        let imports: Vec<_> = program
+            .imports
+            .iter()
+            .map(|import| (&*import.path, ctx.interner.resolve(import.name)))
+            .collect();
+        assert_eq!(
+            imports,
+            [("util.nx", "util"), ("lib/geometry.nx", "geometry")]
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use crate::analyze::analyze;
+use crate::ast;
+use crate::ctx::CompilerContext;
+use crate::error::{err_at, SourceResult};
+use crate::hir::{
+    Expr, ExprKind, GlobalId, GlobalKind, LocalId, LocalKind, Program, Stmt, StmtKind,
+};
+use crate::parser::parse;
+use crate::src::{Source, SourceId, Span};
+use crate::token_type::is_identifier;
+
+/// Replacement of the source text covered by `span` with `new_text`.
+#[derive(Debug, Clone)]
+pub struct TextEdit {
+    pub span: Span,
+    pub new_text: Box<str>,
+}
+
+/// Applies the edits that belong to `source` and returns the resulting text.
+///
+/// Edits targeting other sources are ignored. Edits must not overlap.
+pub fn apply_edits(source: &Source, edits: &[TextEdit]) -> String {
+    let mut edits: Vec<&TextEdit> = edits
+        .iter()
+        .filter(|e| e.span.source_id() == source.id())
+        .collect();
+    edits.sort_by_key(|e| e.span.start());
+    let content = source.content();
+    let mut result = String::with_capacity(content.len());
+    let mut offset = 0;
+    for edit in edits {
+        result.push_str(&content[offset..edit.span.start()]);
+        result.push_str(&edit.new_text);
+        offset = edit.span.end();
+    }
+    result.push_str(&content[offset..]);
+    result
+}
+
+/// Computes the edits needed to rename the global or local symbol referenced at `old_span`.
+///
+/// Symbols are resolved by `analyze`, so every declaration and reference of the symbol is
+/// renamed while unrelated symbols with the same name (e.g. shadowed locals) are left alone.
+/// The rename is rejected if the new name would clash with an existing declaration or if any
+/// reference would resolve to a different symbol afterwards.
+pub fn rename(
+    ctx: &CompilerContext,
+    program: &ast::Program,
+    old_span: Span,
+    new_name: &str,
+) -> SourceResult<Vec<TextEdit>> {
+    if !is_identifier(new_name, ctx.edition) {
+        return err_at(
+            old_span,
+            format!("{:?} is not a valid identifier", new_name),
+        );
+    }
+    let occurrences = collect_occurrences(&analyze(ctx, program)?);
+    let target = match occurrences.iter().find(|(span, _)| span.contains(old_span)) {
+        Some((_, symbol)) => *symbol,
+        None => return err_at(old_span, "no renameable symbol at this location"),
+    };
+    let edits: Vec<TextEdit> = occurrences
+        .iter()
+        .filter(|(_, symbol)| *symbol == target)
+        .map(|(span, _)| TextEdit {
+            span: *span,
+            new_text: new_name.into(),
+        })
+        .collect();
+    verify(ctx, program, &occurrences, &edits, old_span, new_name)?;
+    Ok(edits)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum SymbolRef {
+    Global(GlobalId),
+    Local(GlobalId, LocalId),
+}
+
+// Re-analyzes the renamed sources in a scratch context and checks that every occurrence still
+// resolves to the same symbol. Renaming only changes identifiers, so the HIR of both programs has
+// the same shape and the occurrence lists can be compared element by element.
+fn verify(
+    ctx: &CompilerContext,
+    program: &ast::Program,
+    occurrences: &[(Span, SymbolRef)],
+    edits: &[TextEdit],
+    old_span: Span,
+    new_name: &str,
+) -> SourceResult<()> {
+    let mut source_ids: Vec<SourceId> = Vec::new();
+    for decl in &program.decls {
+        if !source_ids.contains(&decl.name_span.source_id()) {
+            source_ids.push(decl.name_span.source_id());
+        }
+    }
+    let mut scratch = CompilerContext::with_edition(ctx.edition);
+    let mut decls = Vec::new();
+    for source_id in source_ids {
+        let text = apply_edits(ctx.sources.get_by_id(source_id), edits);
+        let scratch_id = scratch.sources.add_from_string(&text);
+        match parse(&mut scratch, scratch_id) {
+            Ok(mut renamed) => decls.append(&mut renamed.decls),
+            Err(e) => {
+                return err_at(
+                    old_span,
+                    format!("cannot rename to {}: {}", new_name, e.message),
+                );
+            }
+        }
+    }
+    let renamed = ast::Program::new(Vec::new(), decls, program.span);
+    let renamed_occurrences = match analyze(&scratch, &renamed) {
+        Ok(hir) => collect_occurrences(&hir),
+        Err(e) => {
+            return err_at(
+                old_span,
+                format!("cannot rename to {}: {}", new_name, e.message),
+            );
+        }
+    };
+    if occurrences.len() != renamed_occurrences.len()
+        || occurrences
+            .iter()
+            .zip(renamed_occurrences.iter())
+            .any(|((_, a), (_, b))| a != b)
+    {
+        return err_at(
+            old_span,
+            format!(
+                "cannot rename to {}: the name would refer to a different symbol",
+                new_name
+            ),
+        );
+    }
+    Ok(())
+}
+
+fn collect_occurrences(program: &Program) -> Vec<(Span, SymbolRef)> {
+    let mut collector = Collector {
+        occurrences: Vec::new(),
+        function: GlobalId(0),
+        temporaries: Vec::new(),
+    };
+    for global in &program.globals {
+        collector
+            .occurrences
+            .push((global.name_span, SymbolRef::Global(global.id)));
+    }
+    for global in &program.globals {
+        collector.function = global.id;
+        match &global.kind {
+            GlobalKind::Function(fun_decl) => {
+                collector.temporaries.clear();
+                for local in &fun_decl.locals {
+                    if local.kind == LocalKind::Temporary {
+                        collector.temporaries.push(local.id);
+                    } else {
+                        collector
+                            .occurrences
+                            .push((local.name_span, SymbolRef::Local(global.id, local.id)));
+                    }
+                }
+                collector.do_block(&fun_decl.body);
+            }
+            GlobalKind::Imported(_) | GlobalKind::Native(_) | GlobalKind::Variable => {}
+        }
+    }
+    collector.occurrences
+}
+
+struct Collector {
+    occurrences: Vec<(Span, SymbolRef)>,
+    function: GlobalId,
+    temporaries: Vec<LocalId>, // locals of the function that do not appear in the source
+}
+
+impl Collector {
+    fn do_block(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.do_stmt(stmt);
+        }
+    }
+
+    fn do_stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Block(stmts) => self.do_block(stmts),
+            StmtKind::Break(_, expr) => {
+                if let Some(expr) = expr {
+                    self.do_expr(expr);
+                }
+            }
+            StmtKind::Continue(_) => {}
+            StmtKind::Defer(body) => self.do_stmt(body),
+            StmtKind::Expr(expr)
+            | StmtKind::Return(expr)
+            | StmtKind::Unpack(_, expr)
+            | StmtKind::VarDecl(_, Some(expr)) => self.do_expr(expr),
+            StmtKind::VarDecl(_, None) => {}
+            StmtKind::If(cond, then_body, else_body) => {
+                self.do_expr(cond);
+                self.do_stmt(then_body);
+                if let Some(else_body) = else_body {
+                    self.do_stmt(else_body);
+                }
+            }
+            StmtKind::SetItem(array, index, value) => {
+                self.do_expr(array);
+                self.do_expr(index);
+                self.do_expr(value);
+            }
+            StmtKind::StoreGlobal(id, expr) => {
+                self.occurrences.push((stmt.span, SymbolRef::Global(*id)));
+                self.do_expr(expr);
+            }
+            StmtKind::StoreLocal(id, expr) => {
+                self.occurrences
+                    .push((stmt.span, SymbolRef::Local(self.function, *id)));
+                self.do_expr(expr);
+            }
+            StmtKind::While(_, cond, body, update) => {
+                self.do_expr(cond);
+                self.do_stmt(body);
+                if let Some(update) = update {
+                    self.do_stmt(update);
+                }
+            }
+        }
+    }
+
+    fn do_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Binary(_, _, left, right)
+            | ExprKind::GetItem(left, right)
+            | ExprKind::LogicalBinary(_, _, left, right) => {
+                self.do_expr(left);
+                self.do_expr(right);
+            }
+            ExprKind::GetSlice(array, start, end) => {
+                self.do_expr(array);
+                self.do_expr(start);
+                self.do_expr(end);
+            }
+            ExprKind::Call(callee, args) => {
+                self.do_expr(callee);
+                args.iter().for_each(|arg| self.do_expr(arg));
+            }
+            ExprKind::Conditional(cond, then_expr, else_expr) => {
+                self.do_expr(cond);
+                self.do_expr(then_expr);
+                self.do_expr(else_expr);
+            }
+            ExprKind::ConstBool(_)
+            | ExprKind::ConstFloat(_)
+            | ExprKind::ConstInt(_)
+            | ExprKind::ConstNull
+            | ExprKind::ConstString(_)
+            | ExprKind::LoadBuiltin(_) => {}
+            ExprKind::LoadGlobal(id) => {
+                self.occurrences.push((expr.span, SymbolRef::Global(*id)));
+            }
+            ExprKind::LoadLocal(id) => {
+                if !self.temporaries.contains(id) {
+                    self.occurrences
+                        .push((expr.span, SymbolRef::Local(self.function, *id)));
+                }
+            }
+            ExprKind::MakeList(elements) => elements.iter().for_each(|e| self.do_expr(e)),
+            ExprKind::Try(expr) | ExprKind::Unary(_, _, expr) => self.do_expr(expr),
+            ExprKind::While(_, cond, body) => {
+                self.do_expr(cond);
+                self.do_stmt(body);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token::{TokenType, Tokenizer};
+
+    // Renames the `nth` (zero-based) token whose lexeme is `old_name`.
+    fn rename_nth(
+        source: &str,
+        old_name: &str,
+        nth: usize,
+        new_name: &str,
+    ) -> Result<String, String> {
+        let mut ctx = CompilerContext::new();
+        let source_id = ctx.sources.add_from_string(source);
+        let mut spans = Vec::new();
+        let mut tokenizer = Tokenizer::new(&mut ctx, source_id);
+        loop {
+            let token = tokenizer.next_token().unwrap();
+            if token.tt == TokenType::Eof {
+                break;
+            }
+            if tokenizer.lexeme(&token) == old_name {
+                spans.push(token.span);
+            }
+        }
+        let program = parse(&mut ctx, source_id).unwrap();
+        match rename(&ctx, &program, spans[nth], new_name) {
+            Ok(edits) => Ok(apply_edits(ctx.sources.get_by_id(source_id), &edits)),
+            Err(e) => Err(e.message.into()),
+        }
+    }
+
+    #[test]
+    fn test_rename_local_respects_shadowing() {
+        let source = "fun main() { var x = 1; { var x = 2; print(x); } print(x); x = 3; }";
+        assert_eq!(
+            rename_nth(source, "x", 4, "y").unwrap(),
+            "fun main() { var y = 1; { var x = 2; print(x); } print(y); y = 3; }"
+        );
+        assert_eq!(
+            rename_nth(source, "x", 2, "y").unwrap(),
+            "fun main() { var x = 1; { var y = 2; print(y); } print(x); x = 3; }"
+        );
+    }
+
+    #[test]
+    fn test_rename_parameter() {
+        let source = "fun f(a, b) { return a + b; } fun main() { print(f(1, 2)); }";
+        assert_eq!(
+            rename_nth(source, "a", 1, "first").unwrap(),
+            "fun f(first, b) { return first + b; } fun main() { print(f(1, 2)); }"
+        );
+    }
+
+    #[test]
+    fn test_rename_unpack_target() {
+        let source = "fun main() { var a = 1; var b = 2; a, b = [b, a]; print(a); }";
+        assert_eq!(
+            rename_nth(source, "a", 1, "x").unwrap(),
+            "fun main() { var x = 1; var b = 2; x, b = [b, x]; print(x); }"
+        );
+    }
+
+    #[test]
+    fn test_rename_global() {
+        let source = "fun f() { return f; } fun main() { f = main; f(); }";
+        assert_eq!(
+            rename_nth(source, "f", 0, "g").unwrap(),
+            "fun g() { return g; } fun main() { g = main; g(); }"
+        );
+    }
+
+    #[test]
+    fn test_rename_duplicate() {
+        let source = "fun main() { var a = 1; var b = 2; }";
+        assert_eq!(
+            rename_nth(source, "a", 0, "b").unwrap_err(),
+            "cannot rename to b: symbol b already defined in this scope"
+        );
+    }
+
+    #[test]
+    fn test_rename_capture() {
+        let source = "fun f() {} fun main() { var x = 1; f(); }";
+        assert_eq!(
+            rename_nth(source, "x", 0, "f").unwrap_err(),
+            "cannot rename to f: the name would refer to a different symbol"
+        );
+        let source = "fun main() { var x = 1; print(x); }";
+        assert_eq!(
+            rename_nth(source, "x", 0, "print").unwrap_err(),
+            "cannot rename to print: the name would refer to a different symbol"
+        );
+    }
+
+    #[test]
+    fn test_rename_invalid() {
+        let source = "fun main() { var x = 1; print(x); }";
+        assert_eq!(
+            rename_nth(source, "x", 0, "while").unwrap_err(),
+            "\"while\" is not a valid identifier"
+        );
+        assert_eq!(
+            rename_nth(source, "x", 0, "1x").unwrap_err(),
+            "\"1x\" is not a valid identifier"
+        );
+        assert_eq!(
+            rename_nth(source, "print", 0, "p").unwrap_err(),
+            "no renameable symbol at this location"
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use crate::analyze::analyze_session;
+use crate::ast::{self, FunDecl, Interpreter, Session};
+use crate::bc::compiler::compile_functions;
+use crate::ctx::{CompilerContext, Name};
+use crate::error::SourceResult;
+use crate::parser::{parse_repl_input, ReplItem};
+use crate::src::SourceId;
+use crate::token_type::{Edition, KEYWORDS};
+use natrix_runtime::ctx::RuntimeContext;
+use natrix_runtime::value::{Function, Value};
+
+const HELP: &str = "\
+:type EXPR       print the type of the value of EXPR
+:dump-ast INPUT  print the syntax tree of INPUT
+:dump-bc NAME    print the bytecode of function NAME
+:globals         list the functions and variables of the session
+:reset           forget all functions and variables
+:load FILE       execute the declarations and statements in FILE
+:help            print this help";
+
+/// Interactive session evaluating one input at a time with the AST interpreter.
+///
+/// Every input is added to `ctx.sources` so that errors can be reported against it.
+/// Inputs starting with `:` are commands for inspecting the session, see `:help`.
+pub struct Repl {
+    pub ctx: CompilerContext,
+    session: Option<Session>,
+    decl_sources: Vec<SourceId>, // inputs declaring functions, re-parsed by :dump-bc
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self::with_edition(Edition::default())
+    }
+
+    pub fn with_edition(edition: Edition) -> Self {
+        let ctx = CompilerContext::with_edition(edition);
+        let session = Some(Session::new(&ctx));
+        Self {
+            ctx,
+            session,
+            decl_sources: Vec::new(),
+        }
+    }
+
+    /// Parses and executes `input`, returning the value of a trailing expression statement.
+    ///
+    /// The output of commands is written to `rt`.
+    pub fn eval(&mut self, rt: &mut RuntimeContext, input: &str) -> SourceResult<Value> {
+        if let Some(command) = input.trim_start().strip_prefix(':') {
+            self.command(rt, command.trim_end())?;
+            return Ok(Value::NULL);
+        }
+        let source_id = self.ctx.sources.add_from_string(input);
+        self.exec(rt, source_id)
+    }
+
+    fn exec(&mut self, rt: &mut RuntimeContext, source_id: SourceId) -> SourceResult<Value> {
+        let items = parse_repl_input(&mut self.ctx, source_id)?;
+        if items
+            .iter()
+            .any(|item| matches!(item, ReplItem::FunDecl(_)))
+        {
+            self.decl_sources.push(source_id);
+        }
+        let session = self.session.take().unwrap();
+        let mut interpreter = Interpreter::with_session(&self.ctx, rt, session);
+        let result = interpreter.exec(items);
+        self.session = Some(interpreter.into_session());
+        result
+    }
+
+    fn command(&mut self, rt: &mut RuntimeContext, command: &str) -> SourceResult<()> {
+        let (name, arg) = match command.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+        match name {
+            "type" => {
+                let value = self.eval(rt, arg)?;
+                rt.write(&value.get_type().to_string());
+            }
+            "dump-ast" => {
+                let source_id = self.ctx.sources.add_from_string(arg);
+                for item in parse_repl_input(&mut self.ctx, source_id)? {
+                    let dump = match &item {
+                        ReplItem::FunDecl(decl) => format!("{:?}", decl.debug_with(&self.ctx)),
+                        ReplItem::Stmt(stmt) => format!("{:?}", stmt.debug_with(&self.ctx)),
+                    };
+                    rt.write(dump.trim_end());
+                }
+            }
+            "dump-bc" => match self.dump_bc(arg)? {
+                Some(dump) => rt.write(dump.trim_end()),
+                None => rt.write(&format!("error: no function named {:?}", arg)),
+            },
+            "globals" => {
+                let mut globals: Vec<(&str, Value)> = self
+                    .session
+                    .as_ref()
+                    .unwrap()
+                    .globals()
+                    .into_iter()
+                    .map(|(name, value)| (self.ctx.interner.resolve(name), value))
+                    .collect();
+                globals.sort_by_key(|(name, _)| *name);
+                for (name, value) in globals {
+                    rt.write(&format!("{} = {}", name, value));
+                }
+            }
+            "reset" => {
+                self.session = Some(Session::new(&self.ctx));
+                self.decl_sources.clear();
+            }
+            "load" => match self.ctx.sources.add_from_file(arg) {
+                Ok(source_id) => {
+                    self.exec(rt, source_id)?;
+                }
+                Err(err) => rt.write(&format!("error: cannot load {}: {}", arg, err)),
+            },
+            "help" => rt.write(HELP),
+            _ => rt.write(&format!("error: unknown command :{}, try :help", name)),
+        }
+        Ok(())
+    }
+
+    // Compiles the functions declared so far (the latest declaration of each name wins), which see
+    // the variables of the session as globals, and disassembles the one called `name`.
+    fn dump_bc(&mut self, name: &str) -> SourceResult<Option<String>> {
+        let mut decls: Vec<FunDecl> = Vec::new();
+        for source_id in self.decl_sources.clone() {
+            for item in parse_repl_input(&mut self.ctx, source_id)? {
+                if let ReplItem::FunDecl(decl) = item {
+                    decls.retain(|d| d.name != decl.name);
+                    decls.push(decl);
+                }
+            }
+        }
+        let Some(span) = decls.first().map(|decl| decl.name_span) else {
+            return Ok(None);
+        };
+        let decl_count = decls.len();
+        let vars: Vec<Name> = self
+            .session
+            .as_ref()
+            .unwrap()
+            .globals()
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| decls.iter().all(|decl| decl.name != *name))
+            .collect();
+        let hir = analyze_session(
+            &self.ctx,
+            &ast::Program::new(Vec::new(), decls, span),
+            &vars,
+        )?;
+        let bc = compile_functions(&self.ctx, &hir);
+        // the declared functions come first, followed by the natives and the variables
+        for global in bc.globals.iter().take(decl_count) {
+            if let Function::UserDefined {
+                name: fun_name,
+                code_handle,
+                ..
+            } = global.unwrap_function().as_ref()
+                && fun_name.as_ref() == name
+            {
+                return Ok(Some(bc.disassemble(bc.function_range(*code_handle))));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Keywords, builtins, functions and variables starting with `prefix`, sorted and deduplicated.
+    pub fn completions(&self, prefix: &str) -> Vec<String> {
+        let session = self.session.as_ref().unwrap();
+        let mut names: Vec<String> = KEYWORDS
+            .iter()
+            .filter(|&&(_, tt)| tt.edition() <= self.ctx.edition)
+            .map(|&(kw, _)| kw)
+            .chain(
+                session
+                    .names()
+                    .into_iter()
+                    .map(|name| self.ctx.interner.resolve(name)),
+            )
+            .filter(|name| name.starts_with(prefix))
+            .map(String::from)
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns true if `input` has unclosed brackets, i.e. it continues on the next line.
+pub fn is_incomplete(input: &str) -> bool {
+    let mut depth = 0i32;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|&c| c != '\n').is_some() {},
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' | '\n' => break,
+                        '\\' => {
+                            chars.next();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(repl: &mut Repl, input: &str) -> String {
+        let mut rt = RuntimeContext::new();
+        match repl.eval(&mut rt, input) {
+            Ok(value) => value.to_string(),
+            Err(e) => e.message.into(),
+        }
+    }
+
+    #[test]
+    fn test_session_persists() {
+        let mut repl = Repl::new();
+        assert_eq!(eval(&mut repl, "var x = 20;"), "null");
+        assert_eq!(eval(&mut repl, "fun f(a) { return a + 1; }"), "null");
+        assert_eq!(eval(&mut repl, "f(x) * 2"), "42");
+        assert_eq!(eval(&mut repl, "var x = 1; x = x + 1; x"), "2");
+        assert_eq!(eval(&mut repl, "fun f(a) { return a; }"), "null");
+        assert_eq!(eval(&mut repl, "f(x)"), "2");
+        assert_eq!(eval(&mut repl, "break;"), "break outside a loop");
+    }
+
+    #[test]
+    fn test_completions() {
+        let mut repl = Repl::new();
+        eval(&mut repl, "var where = 1; fun whirl() {}");
+        assert_eq!(repl.completions("wh"), ["where", "while", "whirl"]);
+        assert!(repl.completions("pri").contains(&"print".to_string()));
+        assert!(repl.completions("zzz").is_empty());
+    }
+
+    #[test]
+    fn test_edition() {
+        let mut repl = Repl::new();
+        assert_eq!(eval(&mut repl, "var match = 1; match + 1"), "2");
+        let mut repl = Repl::with_edition(Edition::E2);
+        assert_eq!(
+            eval(&mut repl, "var match = 1;"),
+            "match is a reserved keyword"
+        );
+        assert!(repl.completions("ma").contains(&"match".to_string()));
+    }
+
+    #[test]
+    fn test_is_incomplete() {
+        assert!(is_incomplete("fun f() {"));
+        assert!(is_incomplete("print([1,"));
+        assert!(!is_incomplete("fun f() { return \"{\"; }"));
+        assert!(!is_incomplete("1 + 2 // (comment"));
+        assert!(!is_incomplete("}"));
+    }
+}
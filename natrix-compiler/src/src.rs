@@ -1,47 +1,99 @@
+use crate::error::{err_at, SourceResult};
+use natrix_runtime::bc::CodeSpan;
+use std::cell::RefCell;
 use std::fmt::Debug;
 use std::fs;
 use std::io;
 use std::num::NonZeroUsize;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SourceId(NonZeroUsize);
 
 #[derive(Default)]
 pub struct Sources {
     sources: Vec<Source>,
+    embedded: RefCell<Vec<PathBuf>>, // files read by `read_embedded`
 }
 
 impl Sources {
     pub fn new() -> Self {
-        Self { sources: vec![] }
+        Self::default()
     }
 
     pub fn add_from_string(&mut self, content: &str) -> SourceId {
+        self.add_from_named_string("<string>", content)
+    }
+
+    /// Adds `content` under `name`, which is used in error messages instead of `<string>`.
+    pub fn add_from_named_string(&mut self, name: &str, content: &str) -> SourceId {
         let id = SourceId(NonZeroUsize::new(self.sources.len() + 1).unwrap());
-        let source = Source::new(id, "<string>".to_owned(), content.to_owned());
+        let source = Source::new(id, name.to_owned(), content.to_owned());
         self.sources.push(source);
         id
     }
 
+    /// Adds `content` read from the file `path` under `name`, so that errors show `name` while
+    /// paths in the source are resolved relative to the file, see `relative_path`.
+    pub fn add_from_named_string_at(&mut self, name: &str, content: &str, path: &Path) -> SourceId {
+        let id = self.add_from_named_string(name, content);
+        self.sources[id.0.get() - 1].path = Some(path.to_path_buf());
+        id
+    }
+
     pub fn add_from_file(&mut self, path: impl AsRef<Path>) -> io::Result<SourceId> {
         let path = path.as_ref();
         let content = fs::read_to_string(path)?;
         let name = path.display().to_string();
         let id = SourceId(NonZeroUsize::new(self.sources.len() + 1).unwrap());
-        let source = Source::new(id, name, content);
+        let mut source = Source::new(id, name, content);
+        source.path = Some(path.to_path_buf());
         self.sources.push(source);
         Ok(id)
     }
 
+    /// Resolves `path` relative to the directory of the source file containing `span`, or the
+    /// current directory for sources not read from a file.
+    pub fn relative_path(&self, span: Span, path: &str) -> PathBuf {
+        let dir = self
+            .get_by_id(span.source_id())
+            .path
+            .as_deref()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new(""));
+        dir.join(path)
+    }
+
+    /// Reads the file `path` for `embed`, see `relative_path`. Errors are reported at `span`.
+    pub fn read_embedded(&self, span: Span, path: &str) -> SourceResult<String> {
+        let full_path = self.relative_path(span, path);
+        match fs::read_to_string(&full_path) {
+            Ok(content) => {
+                self.embedded.borrow_mut().push(full_path);
+                Ok(content)
+            }
+            Err(err) => err_at(span, format!("cannot embed {:?}: {}", path, err)),
+        }
+    }
+
+    /// The files read for `embed` so far, in the order they were read.
+    pub fn embedded_files(&self) -> Vec<PathBuf> {
+        self.embedded.borrow().clone()
+    }
+
     pub fn get_by_id(&self, id: SourceId) -> &Source {
         &self.sources[id.0.get() - 1]
     }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Source> {
+        self.sources.iter()
+    }
 }
 
 pub struct Source {
     id: SourceId,
     name: String,
+    path: Option<PathBuf>, // only for sources read from a file
     content: String,
     line_starts: Vec<usize>,
 }
@@ -60,15 +112,25 @@ impl Source {
         Source {
             id,
             name,
+            path: None,
             content,
             line_starts,
         }
     }
 
+    pub fn id(&self) -> SourceId {
+        self.id
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// The file the source was read from, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
     pub fn content(&self) -> &str {
         &self.content
     }
@@ -99,7 +161,7 @@ impl Source {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Span {
     source_id: SourceId,
     start: usize,
@@ -128,6 +190,21 @@ impl Span {
         self.source_id
     }
 
+    /// Byte offset of the start of the span within its source.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Byte offset just past the end of the span within its source.
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Returns true if `other` lies entirely within this span.
+    pub fn contains(&self, other: Span) -> bool {
+        self.source_id == other.source_id && self.start <= other.start && other.end <= self.end
+    }
+
     pub fn start_pos(&self, sources: &Sources) -> (usize, usize) {
         sources.get_by_id(self.source_id).offset_to_pos(self.start)
     }
@@ -136,6 +213,12 @@ impl Span {
         sources.get_by_id(self.source_id).offset_to_pos(self.end)
     }
 
+    /// The source name and line of the start of the span, like `demos/sieve.nx:3`.
+    pub fn location(&self, sources: &Sources) -> String {
+        let (line, _) = self.start_pos(sources);
+        format!("{}:{}", sources.get_by_id(self.source_id).name(), line)
+    }
+
     pub fn extend_to(&self, end: Span) -> Span {
         assert_eq!(self.source_id, end.source_id);
         assert!(self.start <= end.end);
@@ -154,6 +237,22 @@ impl Span {
         }
     }
 
+    /// Converts the span to the representation stored in bytecode.
+    pub fn to_code_span(&self) -> CodeSpan {
+        CodeSpan {
+            source_index: self.source_id.0.get() - 1,
+            start: self.start,
+            end: self.end,
+        }
+    }
+
+    /// Converts a span stored in bytecode back to a `Span`. The bytecode must have been compiled
+    /// from the given sources.
+    pub fn from_code_span(sources: &Sources, code_span: CodeSpan) -> Span {
+        let source = &sources.sources[code_span.source_index];
+        Span::new(source, code_span.start, code_span.end)
+    }
+
     pub fn debug_with<'a>(&'a self, sources: &'a Sources) -> SpanDebug<'a> {
         SpanDebug::with_sources(&self, sources)
     }
@@ -253,6 +352,21 @@ impl<'a> Cursor<'a> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_read_embedded() {
+        let dir = std::env::temp_dir().join(format!("natrix-embed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("main.nx"), "fun main() {}").unwrap();
+        fs::write(dir.join("data.txt"), "hello\n").unwrap();
+        let mut sources = Sources::new();
+        let sid = sources.add_from_file(dir.join("main.nx")).unwrap();
+        let span = Span::new(sources.get_by_id(sid), 0, 3);
+        assert_eq!(sources.read_embedded(span, "data.txt").unwrap(), "hello\n");
+        let err = sources.read_embedded(span, "missing.txt").unwrap_err();
+        assert!(err.message.starts_with("cannot embed \"missing.txt\": "));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_coords_no_trailing_nl() {
         let mut sources = Sources::new();
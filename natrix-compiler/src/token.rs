@@ -1,7 +1,9 @@
 use crate::ctx::{CompilerContext, Interner, Name};
-use crate::error::{SourceError, SourceResult};
-use crate::src::{Cursor, SourceId, Span};
+use crate::error::{error_at, SourceError, SourceResult};
+use crate::src::{Cursor, Source, SourceId, Span};
+use crate::token_type::Edition;
 pub use crate::token_type::TokenType;
+use std::rc::Rc;
 
 #[derive(Debug, Copy, Clone)]
 pub struct Token {
@@ -13,13 +15,29 @@ pub struct Token {
 pub struct Tokenizer<'a> {
     cursor: Cursor<'a>,
     interner: &'a mut Interner,
+    edition: Edition,
 }
 
 impl<'a> Tokenizer<'a> {
     pub fn new(ctx: &'a mut CompilerContext, source_id: SourceId) -> Tokenizer<'a> {
+        Self::with_interner(
+            ctx.sources.get_by_id(source_id),
+            &mut ctx.interner,
+            ctx.edition,
+        )
+    }
+
+    /// Tokenizes `source` with names interned by `interner`, which need not be the interner of
+    /// the context, e.g. for a file parsed on another thread.
+    pub fn with_interner(
+        source: &'a Source,
+        interner: &'a mut Interner,
+        edition: Edition,
+    ) -> Tokenizer<'a> {
         Tokenizer {
-            cursor: Cursor::new(ctx.sources.get_by_id(source_id)),
-            interner: &mut ctx.interner,
+            cursor: Cursor::new(source),
+            interner,
+            edition,
         }
     }
 
@@ -37,7 +55,7 @@ impl<'a> Tokenizer<'a> {
                 return Ok(Token {
                     tt: self
                         .interner
-                        .resolve_keyword(name)
+                        .resolve_keyword(name, self.edition)
                         .unwrap_or(TokenType::Identifier),
                     span,
                     name: Some(name),
@@ -51,6 +69,29 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    /// Interns a name that is not written as an identifier, like the name of a module imported
+    /// by its path, or returns `None` if it could not be written as one.
+    pub fn intern_identifier(&mut self, name: &str) -> Option<Name> {
+        let mut chars = name.chars();
+        if !chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return None;
+        }
+        let name = self.interner.intern(name);
+        match self.interner.resolve_keyword(name, self.edition) {
+            Some(_) => None,
+            None => Some(name),
+        }
+    }
+
+    /// Interns the value of a string literal, see `Interner::intern_literal`.
+    pub fn intern_literal(&mut self, value: &str) -> Rc<str> {
+        self.interner.intern_literal(value)
+    }
+
     pub fn lexeme(&self, token: &Token) -> &str {
         self.cursor.lexeme(token.span)
     }
@@ -74,12 +115,19 @@ impl<'a> Tokenizer<'a> {
                     while self.cursor.peek() != Some('\n') && self.cursor.peek() != None {
                         self.cursor.advance();
                     }
-                    Ok(TokenType::Comment)
+                    // `///` starts a doc comment, but `////` and longer are ordinary comments
+                    let lexeme = self.cursor.lexeme(self.cursor.span_from_mark());
+                    if lexeme.starts_with("///") && !lexeme.starts_with("////") {
+                        Ok(TokenType::DocComment)
+                    } else {
+                        Ok(TokenType::Comment)
+                    }
                 } else {
                     Ok(TokenType::Slash)
                 }
             }
             Some('%') => Ok(TokenType::Percent),
+            Some('@') => Ok(TokenType::At),
             Some('=') => self.two_char_symbol('=', TokenType::Assign, TokenType::Eq),
             Some('!') => self.two_char_symbol('=', TokenType::Bang, TokenType::Ne),
             Some('>') => self.two_char_symbol('=', TokenType::Gt, TokenType::Ge),
@@ -101,6 +149,9 @@ impl<'a> Tokenizer<'a> {
                 }
             }
             Some(',') => Ok(TokenType::Comma),
+            Some('.') => Ok(TokenType::Dot),
+            Some(':') => Ok(TokenType::Colon),
+            Some('?') => Ok(TokenType::Question),
             Some(';') => Ok(TokenType::Semicolon),
             Some('"') => self.do_string_literal(),
             Some(c) => self.err(format!("unexpected character {:?}", c)),
@@ -198,9 +249,6 @@ impl<'a> Tokenizer<'a> {
     }
 
     fn error(&self, message: impl Into<Box<str>>) -> SourceError {
-        SourceError {
-            message: message.into(),
-            span: self.cursor.span_from_mark(),
-        }
+        error_at(self.cursor.span_from_mark(), message)
     }
 }
@@ -1,19 +1,31 @@
+use std::str::FromStr;
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum TokenType {
     Eof,
     Whitespace,
     Comment,
+    DocComment,
     KwBreak,
+    KwClass,
     KwContinue,
+    KwDefer,
     KwElse,
     KwFalse,
+    KwFor,
     KwFun,
+    KwGuard,
     KwIf,
+    KwImport,
+    KwMatch,
     KwNull,
     KwReturn,
     KwTrue,
+    KwTry,
+    KwUnless,
     KwVar,
     KwWhile,
+    KwWith,
     Identifier,
     IntLiteral,
     FloatLiteral,
@@ -39,20 +51,97 @@ pub enum TokenType {
     Ge,
     Bang,
     Comma,
+    Dot,
+    Colon,
+    Question,
     Semicolon,
     Assign,
+    At,
 }
 
 pub const KEYWORDS: &[(&str, TokenType)] = &[
     ("break", TokenType::KwBreak),
+    ("class", TokenType::KwClass),
     ("continue", TokenType::KwContinue),
+    ("defer", TokenType::KwDefer),
     ("else", TokenType::KwElse),
     ("false", TokenType::KwFalse),
+    ("for", TokenType::KwFor),
     ("fun", TokenType::KwFun),
+    ("guard", TokenType::KwGuard),
     ("if", TokenType::KwIf),
+    ("import", TokenType::KwImport),
+    ("match", TokenType::KwMatch),
     ("null", TokenType::KwNull),
     ("return", TokenType::KwReturn),
     ("true", TokenType::KwTrue),
+    ("try", TokenType::KwTry),
+    ("unless", TokenType::KwUnless),
     ("var", TokenType::KwVar),
     ("while", TokenType::KwWhile),
+    ("with", TokenType::KwWith),
 ];
+
+/// Version of the language, determining the set of keywords.
+///
+/// Keywords introduced by a later edition remain ordinary identifiers in earlier ones, so that
+/// existing programs using them as names keep working.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Edition {
+    #[default]
+    E1,
+    E2, // adds defer, for, guard, import, try, unless and with, reserves class and match
+}
+
+impl FromStr for Edition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(Edition::E1),
+            "2" => Ok(Edition::E2),
+            _ => Err(format!("unknown edition {:?}", s)),
+        }
+    }
+}
+
+impl TokenType {
+    /// The first edition in which the keyword is recognized.
+    pub fn edition(self) -> Edition {
+        match self {
+            TokenType::KwClass
+            | TokenType::KwDefer
+            | TokenType::KwFor
+            | TokenType::KwGuard
+            | TokenType::KwImport
+            | TokenType::KwMatch
+            | TokenType::KwTry
+            | TokenType::KwUnless
+            | TokenType::KwWith => Edition::E2,
+            _ => Edition::E1,
+        }
+    }
+
+    /// Keywords that are reserved for future use and not part of the grammar yet.
+    pub fn is_reserved(self) -> bool {
+        matches!(self, TokenType::KwClass | TokenType::KwMatch)
+    }
+}
+
+/// Returns true if `name` is a keyword in `edition`.
+pub fn is_keyword(name: &str, edition: Edition) -> bool {
+    KEYWORDS
+        .iter()
+        .any(|&(kw, tt)| kw == name && tt.edition() <= edition)
+}
+
+/// Returns true if `name` is an identifier in `edition`: a letter or underscore followed by
+/// letters, digits and underscores, and not a keyword.
+pub fn is_identifier(name: &str, edition: Edition) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !is_keyword(name, edition)
+}
@@ -0,0 +1,87 @@
+// Helpers for rendering graphs in the DOT format of Graphviz.
+
+use std::fmt::Write;
+
+/// Returns `lines` as a quoted DOT label, each line left-aligned.
+pub fn label<'a>(lines: impl IntoIterator<Item = &'a str>) -> String {
+    let mut out = String::from("\"");
+    for line in lines {
+        for c in line.chars() {
+            match c {
+                '"' | '\\' => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                c => out.push(c),
+            }
+        }
+        out.push_str("\\l");
+    }
+    out.push('"');
+    out
+}
+
+/// Renders the debug output of an AST or HIR node as a DOT graph called `name`, with an edge
+/// from each node to its children.
+///
+/// The output is expected without spans, one node per line indented by two spaces per level.
+/// Lines starting with a lowercase letter are properties, they are added to the label of the
+/// node they belong to.
+pub fn tree_to_dot(name: &str, tree: &str) -> String {
+    let mut labels: Vec<Vec<&str>> = Vec::new();
+    let mut edges = Vec::new();
+    // the indentation and the index of the nodes enclosing the current line
+    let mut parents: Vec<(usize, usize)> = Vec::new();
+    for line in tree.lines() {
+        let text = line.trim_start();
+        let indent = line.len() - text.len();
+        while parents.last().is_some_and(|&(i, _)| i >= indent) {
+            parents.pop();
+        }
+        if text.starts_with(|c: char| c.is_ascii_lowercase()) {
+            if let Some(&(_, node)) = parents.last() {
+                labels[node].push(text);
+            }
+        } else if !text.is_empty() {
+            if let Some(&(_, parent)) = parents.last() {
+                edges.push((parent, labels.len()));
+            }
+            parents.push((indent, labels.len()));
+            labels.push(vec![text]);
+        }
+    }
+    let mut out = format!("digraph {} {{\n    node [shape=box];\n", name);
+    for (index, lines) in labels.into_iter().enumerate() {
+        writeln!(out, "    n{} [label={}];", index, label(lines)).unwrap();
+    }
+    for (parent, child) in edges {
+        writeln!(out, "    n{} -> n{};", parent, child).unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_label() {
+        assert_eq!(label(["a \"b\"", "c\\d"]), "\"a \\\"b\\\"\\lc\\\\d\\l\"");
+    }
+
+    #[test]
+    fn test_tree_to_dot() {
+        let tree = "Program\n  FunDecl(\"f\")\n    Expr\n      Binary\n        op: Add\n        \
+                    IntLiteral(1)\n        IntLiteral(2)\n    Return\n";
+        assert_eq!(
+            tree_to_dot("ast", tree),
+            "digraph ast {\n    node [shape=box];\n    n0 [label=\"Program\\l\"];\n    \
+             n1 [label=\"FunDecl(\\\"f\\\")\\l\"];\n    n2 [label=\"Expr\\l\"];\n    \
+             n3 [label=\"Binary\\lop: Add\\l\"];\n    n4 [label=\"IntLiteral(1)\\l\"];\n    \
+             n5 [label=\"IntLiteral(2)\\l\"];\n    n6 [label=\"Return\\l\"];\n    \
+             n0 -> n1;\n    n1 -> n2;\n    n2 -> n3;\n    n3 -> n4;\n    n3 -> n5;\n    \
+             n1 -> n6;\n}\n"
+        );
+    }
+}
@@ -6,6 +6,7 @@ macro_rules! def_formatter {
         pub struct $name<'a> {
             ctx: Option<&'a CompilerContext>,
             indent: usize,
+            spans: bool,
         }
 
         impl<'a> $name<'a> {
@@ -13,6 +14,7 @@ macro_rules! def_formatter {
                 Self {
                     ctx: None,
                     indent: 0,
+                    spans: true,
                 }
             }
 
@@ -20,6 +22,16 @@ macro_rules! def_formatter {
                 Self {
                     ctx: Some(ctx),
                     indent: 0,
+                    spans: true,
+                }
+            }
+
+            #[allow(dead_code)]
+            pub fn without_spans(ctx: &'a CompilerContext) -> Self {
+                Self {
+                    ctx: Some(ctx),
+                    indent: 0,
+                    spans: false,
                 }
             }
 
@@ -27,10 +39,14 @@ macro_rules! def_formatter {
                 Self {
                     ctx: self.ctx,
                     indent: self.indent + 2,
+                    spans: self.spans,
                 }
             }
 
             fn span(&self, f: &mut Formatter<'_>, span: Span) -> fmt::Result {
+                if !self.spans {
+                    return Ok(());
+                }
                 write!(f, " ")?;
                 if let Some(ctx) = self.ctx {
                     span.debug_with(&ctx.sources).fmt(f)
@@ -88,6 +104,16 @@ macro_rules! def_formatter {
                 self.end_header(f, span)
             }
 
+            #[allow(dead_code)]
+            pub fn property<T: Debug>(
+                &self,
+                f: &mut Formatter<'_>,
+                name: &str,
+                value: T,
+            ) -> fmt::Result {
+                writeln!(f, "{}  {}: {:?}", self.indent_str(), name, value)
+            }
+
             #[allow(dead_code)]
             pub fn property_with_span<T: Debug>(
                 &self,
@@ -163,6 +189,14 @@ macro_rules! impl_node_debug {
             pub fn debug_with<'a>(&'a self, ctx: &'a CompilerContext) -> impl Debug + 'a {
                 $debug_name::with_context(self, ctx)
             }
+
+            #[allow(dead_code)]
+            pub fn debug_without_spans<'a>(&'a self, ctx: &'a CompilerContext) -> impl Debug + 'a {
+                $debug_name {
+                    fmt: $formatter_name::without_spans(ctx),
+                    $field: self,
+                }
+            }
         }
 
         impl Debug for $name {
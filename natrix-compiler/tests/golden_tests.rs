@@ -1,20 +1,86 @@
 use natrix_compiler::analyze::analyze;
 use natrix_compiler::ast::Interpreter as AstInterpreter;
-use natrix_compiler::bc::compiler::compile;
+use natrix_compiler::bc::compiler::{compile, compile_library};
+use natrix_compiler::coverage::{annotate, lcov_report};
 use natrix_compiler::ctx::CompilerContext;
-use natrix_compiler::error::SourceResult;
+use natrix_compiler::doc::to_markdown;
+use natrix_compiler::error::{format_bc_warning, AttachErrSpan, SourceResult};
+use natrix_compiler::hir::opt::fold_constants;
+use natrix_compiler::hir::pass::PassManager;
+use natrix_compiler::module::parse_program;
 use natrix_compiler::parser::parse;
-use natrix_compiler::src::SourceId;
+use natrix_compiler::repl::{is_incomplete, Repl};
+use natrix_compiler::src::{SourceId, Span};
 use natrix_compiler::token::{TokenType, Tokenizer};
+use natrix_compiler::token_type::Edition;
 use natrix_runtime::bc::{Bytecode, Interpreter as BcInterpreter};
-use natrix_runtime::ctx::RuntimeContext;
+use natrix_runtime::ctx::{Capability, Interrupts, RuntimeContext};
+use natrix_runtime::error::{nx_err, NxError, NxResult};
+use natrix_runtime::value::{FromValue, IntoValue, Value};
+use std::collections::HashMap;
 use std::fmt::Write;
+use std::io;
 use std::path::Path;
-use test_utils::{datatest_stable, run_golden_test, run_golden_test_variant};
+use std::sync::Arc;
+use test_utils::{assert_golden, datatest_stable, run_golden_test, run_golden_test_variant};
+
+// Captures the output and replaces the clock, random seed, environment, files and stdin by fixed
+// values, so that the expected output does not depend on when and where the tests run. The files
+// are held in memory, so scripts may read and write them. The process is interrupted once before
+// the script starts, so the first safepoint after a script registers a handler calls it. The
+// natives of `test_natives` are registered, but only the programs of that test declare them.
+fn test_runtime() -> RuntimeContext {
+    let mut rt = RuntimeContext::with_capture();
+    rt.grant(Capability::Read);
+    rt.grant(Capability::Write);
+    rt.set_fixed_clock(1_000_000.0, 0.25);
+    rt.set_seed(42);
+    rt.set_env(HashMap::new());
+    rt.set_files(HashMap::from([(
+        "lines.txt".to_string(),
+        "first\nsecond\r\n\nlast".to_string(),
+    )]));
+    rt.set_input(Box::new(io::empty()));
+    let interrupts = Arc::new(Interrupts::new());
+    interrupts.raise();
+    rt.set_interrupts(interrupts);
+    rt.register_native("host_add", 2, |_, args| {
+        Ok((i64::from_value(&args[0])? + i64::from_value(&args[1])?).into_value())
+    });
+    rt.register_native("host_greet", 1, |rt, args| {
+        rt.write(&format!("hello from the host, {}", args[0]));
+        Ok(Value::NULL)
+    });
+    rt.register_native("host_fail", 0, |_, _| -> NxResult<Value> {
+        nx_err("the host refused")
+    });
+    rt
+}
+
+// The value of a directive `// NAME: VALUE` in the comments at the start of a test
+fn directive<'a>(input: &'a str, name: &str) -> Option<&'a str> {
+    input
+        .lines()
+        .map_while(|line| line.strip_prefix("// "))
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(": "))
+}
+
+// A test with the directive `// edition: N` is compiled in that edition, the others in the
+// default one. `// define: NAME[=VALUE]` sets a flag for `@if`, like `--define`.
+fn context_for(input: &str) -> CompilerContext {
+    let edition =
+        directive(input, "edition").map_or(Edition::default(), |edition| edition.parse().unwrap());
+    let mut ctx = CompilerContext::with_edition(edition);
+    if let Some(define) = directive(input, "define") {
+        let (name, value) = define.split_once('=').unwrap_or((define, "true"));
+        ctx.defines.insert(name.into(), value.into());
+    }
+    ctx
+}
 
 fn test_tokenizer(path: &Path) -> test_utils::TestResult {
     run_golden_test(path, |input| {
-        let mut ctx = CompilerContext::default();
+        let mut ctx = context_for(input);
         let source_id = ctx.sources.add_from_string(input);
         let mut tokenizer = Tokenizer::new(&mut ctx, source_id);
         let mut result = String::new();
@@ -37,7 +103,7 @@ fn test_tokenizer(path: &Path) -> test_utils::TestResult {
 
 fn test_parser(path: &Path) -> test_utils::TestResult {
     run_golden_test(path, |input| {
-        let mut ctx = CompilerContext::default();
+        let mut ctx = context_for(input);
         let source_id = ctx.sources.add_from_string(input);
         match parse(&mut ctx, source_id) {
             Ok(ast) => format!("{:?}", ast.debug_with(&ctx)),
@@ -46,9 +112,50 @@ fn test_parser(path: &Path) -> test_utils::TestResult {
     })
 }
 
+fn test_doc(path: &Path) -> test_utils::TestResult {
+    run_golden_test(path, |input| {
+        let mut ctx = context_for(input);
+        let source_id = ctx.sources.add_from_string(input);
+        match parse(&mut ctx, source_id) {
+            Ok(ast) => to_markdown(&ctx, ctx.sources.get_by_id(source_id).name(), &ast),
+            Err(error) => format!("{}", error.display_with(&ctx.sources)),
+        }
+    })
+}
+
 fn test_ast_interpreter(path: &Path) -> test_utils::TestResult {
     run_golden_test_variant(path, "ast", |input| {
-        let mut ctx = CompilerContext::default();
+        let mut ctx = context_for(input);
+        let source_id = ctx
+            .sources
+            .add_from_named_string_at("<string>", input, path);
+        run_ast(ctx, source_id)
+    })
+}
+
+fn run_ast(mut ctx: CompilerContext, source_id: SourceId) -> String {
+    ctx.debug_info = true; // for locals()
+    let program = match parse_program(&mut ctx, &[source_id]) {
+        Ok(program) => program,
+        Err(error) => {
+            return format!("{}", error.display_with(&ctx.sources));
+        }
+    };
+    let mut rt = test_runtime();
+    let mut interpreter = AstInterpreter::new(&mut ctx, &mut rt);
+    let result = interpreter.run(program, vec![]);
+    let mut output = rt.take_output();
+    if let Err(error) = result {
+        writeln!(output, "{}", error.display_with(&ctx.sources)).unwrap();
+    }
+    output
+}
+
+// Prints the warnings of the analysis, followed by the output of `natrix --ast --warn-numeric-eq`
+// (the interpreter repeats the declaration checks, so its own compile time warnings are ignored).
+fn test_warnings(path: &Path) -> test_utils::TestResult {
+    run_golden_test(path, |input| {
+        let mut ctx = context_for(input);
         let source_id = ctx.sources.add_from_string(input);
         let program = match parse(&mut ctx, source_id) {
             Ok(program) => program,
@@ -56,8 +163,82 @@ fn test_ast_interpreter(path: &Path) -> test_utils::TestResult {
                 return format!("{}", error.display_with(&ctx.sources));
             }
         };
-        let mut rt = RuntimeContext::with_capture();
-        let mut interpreter = AstInterpreter::new(&mut ctx, &mut rt);
+        // the warnings of folding are reported along with those of the analysis
+        match analyze(&ctx, &program) {
+            Ok(mut hir) => fold_constants(&ctx, &mut hir),
+            Err(error) => return format!("{}", error.display_with(&ctx.sources)),
+        }
+        let mut output = String::new();
+        for warning in ctx.take_warnings() {
+            writeln!(output, "{}", warning.display_as_warning(&ctx.sources)).unwrap();
+        }
+        let mut rt = test_runtime();
+        rt.set_warn_numeric_eq(true);
+        let mut interpreter = AstInterpreter::new(&ctx, &mut rt);
+        let result = interpreter.run(program, vec![]);
+        let run_output = rt.take_output();
+        output.push_str(&run_output);
+        if let Err(error) = result {
+            writeln!(output, "{}", error.display_with(&ctx.sources)).unwrap();
+        }
+        // the bytecode interpreter warns at the same locations, given no passes, which would
+        // report the warnings of constant comparisons before running
+        let mut ctx = context_for(input);
+        let source_id = ctx.sources.add_from_string(input);
+        if let Ok(bc) = compile_to_bc(&mut ctx, source_id) {
+            let mut rt = test_runtime();
+            rt.set_warn_numeric_eq(true);
+            let warning_format =
+                |message: &str, code_span| format_bc_warning(&ctx.sources, message, code_span);
+            let mut interpreter = BcInterpreter::new(&mut rt);
+            interpreter.set_warning_format(&warning_format);
+            let _ = interpreter.run(&bc, vec![]);
+            assert_eq!(
+                rt.take_output(),
+                run_output,
+                "the interpreters warn differently"
+            );
+        }
+        output
+    })
+}
+
+// Prints the HIR after the default passes, like `natrix --dump-hir`.
+fn test_hir(path: &Path) -> test_utils::TestResult {
+    run_golden_test(path, |input| {
+        let mut ctx = context_for(input);
+        let source_id = ctx.sources.add_from_string(input);
+        let result = parse(&mut ctx, source_id).and_then(|program| {
+            let mut hir = analyze(&ctx, &program)?;
+            PassManager::with_default_passes().run(&ctx, &mut hir)?;
+            Ok(hir)
+        });
+        match result {
+            Ok(hir) => format!("{:?}", hir.debug_with(&ctx)),
+            Err(error) => format!("{}", error.display_with(&ctx.sources)),
+        }
+    })
+}
+
+// Mirrors `natrix --ast --strict`: the strict analysis runs before the AST interpreter.
+fn test_strict(path: &Path) -> test_utils::TestResult {
+    run_golden_test(path, |input| {
+        let mut ctx = context_for(input);
+        ctx.strict = true;
+        let source_id = ctx.sources.add_from_string(input);
+        let program = match parse(&mut ctx, source_id) {
+            Ok(program) => program,
+            Err(error) => {
+                return format!("{}", error.display_with(&ctx.sources));
+            }
+        };
+        if let Err(error) = analyze(&ctx, &program) {
+            return format!("{}", error.display_with(&ctx.sources));
+        }
+        let mut rt = test_runtime();
+        rt.set_strict(true);
+        let mut interpreter = AstInterpreter::new(&ctx, &mut rt);
+        interpreter.skip_decl_checks();
         let result = interpreter.run(program, vec![]);
         let mut output = rt.take_output();
         if let Err(error) = result {
@@ -68,14 +249,178 @@ fn test_ast_interpreter(path: &Path) -> test_utils::TestResult {
 }
 
 fn compile_to_bc(ctx: &mut CompilerContext, source_id: SourceId) -> SourceResult<Bytecode> {
-    let program = parse(ctx, source_id)?;
-    let hir = analyze(&ctx, &program)?;
+    compile_to_bc_with(ctx, source_id, &PassManager::new())
+}
+
+fn compile_to_bc_with(
+    ctx: &mut CompilerContext,
+    source_id: SourceId,
+    passes: &PassManager,
+) -> SourceResult<Bytecode> {
+    let program = parse_program(ctx, &[source_id])?;
+    let mut hir = analyze(&ctx, &program)?;
+    passes.run(&ctx, &mut hir)?;
     compile(&ctx, &hir)
 }
 
+// The program is named `<string>` in errors, but `embed` reads files relative to `path`.
+fn run_bc(path: &Path, input: &str, passes: &PassManager) -> String {
+    let mut ctx = context_for(input);
+    let source_id = ctx
+        .sources
+        .add_from_named_string_at("<string>", input, path);
+    run_bc_in(ctx, source_id, passes)
+}
+
+fn run_bc_in(mut ctx: CompilerContext, source_id: SourceId, passes: &PassManager) -> String {
+    ctx.debug_info = true; // for locals()
+    let bc = match compile_to_bc_with(&mut ctx, source_id, passes) {
+        Ok(bc) => bc,
+        Err(error) => {
+            return format!("{}\n", error.display_with(&ctx.sources));
+        }
+    };
+
+    let mut rt = test_runtime();
+    let mut interpreter = BcInterpreter::new(&mut rt);
+    let result = interpreter.run(&bc, vec![]);
+    let mut output = rt.take_output();
+    if let Err(error) = result {
+        output.push_str(&bc_error(&ctx, error));
+    }
+    output
+}
+
+// Reports an error of the bytecode interpreter at the instruction that failed, errors raised
+// before the first instruction have no location
+fn bc_error(ctx: &CompilerContext, error: NxError) -> String {
+    match error.span {
+        Some(code_span) => {
+            let span = Span::from_code_span(&ctx.sources, code_span);
+            format!(
+                "{}\n",
+                error
+                    .err_at_bc(&ctx.sources, span)
+                    .display_with(&ctx.sources)
+            )
+        }
+        None => format!("{:?}\n", error),
+    }
+}
+
+// The optimization passes must not change the output, including the order of side effects, so
+// every program also runs with the default passes and the outputs are compared. Allocation counts
+// are not part of that guarantee, folding saves allocations, so a test printing them opts out
+// with the directive `// compare-optimized: false`.
 fn test_bc_interpreter(path: &Path) -> test_utils::TestResult {
     run_golden_test_variant(path, "bc", |input| {
-        let mut ctx = CompilerContext::default();
+        let output = run_bc(path, input, &PassManager::new());
+        if directive(input, "compare-optimized") != Some("false") {
+            let optimized = run_bc(path, input, &PassManager::with_default_passes());
+            assert_eq!(
+                output, optimized,
+                "the optimized program behaves differently"
+            );
+        }
+        output
+    })
+}
+
+// Programs importing the modules of tests/lib. They are read from their file, so that imports are
+// resolved relative to it, and run by both interpreters, which must print the same (except that
+// only the AST interpreter ends a compile error with a newline).
+fn test_modules(path: &Path) -> test_utils::TestResult {
+    // relative, so that the locations in the output do not depend on where the tests run
+    let cwd = std::env::current_dir()?;
+    let relative = path.strip_prefix(&cwd).unwrap_or(path);
+    let load = || {
+        let mut ctx = CompilerContext::with_edition(Edition::E2);
+        let source_id = ctx.sources.add_from_file(relative).unwrap();
+        (ctx, source_id)
+    };
+    let (ctx, source_id) = load();
+    let output = run_bc_in(ctx, source_id, &PassManager::new());
+    let (ctx, source_id) = load();
+    let optimized = run_bc_in(ctx, source_id, &PassManager::with_default_passes());
+    assert_eq!(
+        output, optimized,
+        "the optimized program behaves differently"
+    );
+    let (ctx, source_id) = load();
+    assert_eq!(
+        output.trim_end(),
+        run_ast(ctx, source_id).trim_end(),
+        "the interpreters behave differently"
+    );
+    assert_golden(path, &output, "")
+}
+
+// Programs importing geometry.nxlib, compiled from tests/lib/geometry.nx into a directory of each
+// test that is searched for imports, so that the library is only available as bytecode.
+fn test_libraries(path: &Path) -> test_utils::TestResult {
+    let dir = std::env::temp_dir().join(format!(
+        "natrix-libraries-{}-{}",
+        std::process::id(),
+        path.file_stem().unwrap().to_string_lossy()
+    ));
+    let mut ctx = CompilerContext::with_edition(Edition::E2);
+    let source_id = ctx.sources.add_from_file("../tests/lib/geometry.nx")?;
+    let program = parse_program(&mut ctx, &[source_id]).unwrap();
+    let hir = analyze(&ctx, &program).unwrap();
+    let library = compile_library(&ctx, &hir);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("geometry.nxlib"), library.serialize().unwrap())?;
+
+    let cwd = std::env::current_dir()?;
+    let relative = path.strip_prefix(&cwd).unwrap_or(path);
+    let load = || {
+        let mut ctx = CompilerContext::with_edition(Edition::E2);
+        ctx.modules.add_search_dir(dir.clone());
+        let source_id = ctx.sources.add_from_file(relative).unwrap();
+        (ctx, source_id)
+    };
+    let (ctx, source_id) = load();
+    let output = run_bc_in(ctx, source_id, &PassManager::new());
+    let (ctx, source_id) = load();
+    let optimized = run_bc_in(ctx, source_id, &PassManager::with_default_passes());
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(
+        output, optimized,
+        "the optimized program behaves differently"
+    );
+    assert_golden(path, &output, "")
+}
+
+// Programs calling the native functions of `test_runtime`, run by both interpreters, which must
+// print the same (except that only the AST interpreter ends a compile error with a newline).
+fn test_natives(path: &Path) -> test_utils::TestResult {
+    let input = std::fs::read_to_string(path)?;
+    let load = || {
+        let mut ctx = context_for(&input);
+        ctx.declare_natives(&test_runtime());
+        let source_id = ctx.sources.add_from_string(&input);
+        (ctx, source_id)
+    };
+    let (ctx, source_id) = load();
+    let output = run_bc_in(ctx, source_id, &PassManager::new());
+    let (ctx, source_id) = load();
+    let optimized = run_bc_in(ctx, source_id, &PassManager::with_default_passes());
+    assert_eq!(
+        output, optimized,
+        "the optimized program behaves differently"
+    );
+    let (ctx, source_id) = load();
+    assert_eq!(
+        output.trim_end(),
+        run_ast(ctx, source_id).trim_end(),
+        "the interpreters behave differently"
+    );
+    assert_golden(path, &output, "")
+}
+
+fn test_coverage(path: &Path) -> test_utils::TestResult {
+    run_golden_test(path, |input| {
+        let mut ctx = context_for(input);
         let source_id = ctx.sources.add_from_string(input);
         let bc = match compile_to_bc(&mut ctx, source_id) {
             Ok(bc) => bc,
@@ -84,12 +429,50 @@ fn test_bc_interpreter(path: &Path) -> test_utils::TestResult {
             }
         };
 
-        let mut rt = RuntimeContext::with_capture();
+        let mut rt = test_runtime();
         let mut interpreter = BcInterpreter::new(&mut rt);
+        interpreter.collect_coverage();
         let result = interpreter.run(&bc, vec![]);
+        let coverage = interpreter.take_coverage().unwrap();
         let mut output = rt.take_output();
         if let Err(error) = result {
-            writeln!(output, "{:?}", error).unwrap();
+            output.push_str(&bc_error(&ctx, error));
+        }
+        output.push_str(&annotate(&ctx, &bc, &coverage));
+        output.push_str(&lcov_report(&ctx, &bc, &coverage));
+        output
+    })
+}
+
+fn test_repl(path: &Path) -> test_utils::TestResult {
+    run_golden_test(path, |input| {
+        let mut repl = Repl::new();
+        let mut output = String::new();
+        let mut entry = String::new();
+        for line in input.lines() {
+            writeln!(
+                output,
+                "{} {}",
+                if entry.is_empty() { ">" } else { "." },
+                line
+            )
+            .unwrap();
+            entry.push_str(line);
+            entry.push('\n');
+            if is_incomplete(&entry) {
+                continue;
+            }
+            let mut rt = test_runtime();
+            let result = repl.eval(&mut rt, &entry);
+            output.push_str(&rt.take_output());
+            match result {
+                Ok(value) if !value.is_null() => writeln!(output, "{}", value).unwrap(),
+                Ok(_) => {}
+                Err(error) => {
+                    writeln!(output, "{}", error.display_with(&repl.ctx.sources)).unwrap()
+                }
+            }
+            entry.clear();
         }
         output
     })
@@ -100,8 +483,17 @@ const INPUT_PATTERN: &str = r".*\.nx$";
 datatest_stable::harness! {
     { test = test_tokenizer, root = "../tests/tokenizer", pattern = INPUT_PATTERN },
     { test = test_parser, root = "../tests/parser", pattern = INPUT_PATTERN },
+    { test = test_doc, root = "../tests/doc", pattern = INPUT_PATTERN },
     { test = test_ast_interpreter, root = "../tests/ast_interpreter", pattern = INPUT_PATTERN },
     { test = test_ast_interpreter, root = "../tests/common_interpreter", pattern = INPUT_PATTERN },
     { test = test_bc_interpreter, root = "../tests/common_interpreter", pattern = INPUT_PATTERN },
     { test = test_bc_interpreter, root = "../tests/bc_interpreter", pattern = INPUT_PATTERN },
+    { test = test_modules, root = "../tests/modules", pattern = INPUT_PATTERN },
+    { test = test_libraries, root = "../tests/libraries", pattern = INPUT_PATTERN },
+    { test = test_natives, root = "../tests/natives", pattern = INPUT_PATTERN },
+    { test = test_coverage, root = "../tests/coverage", pattern = INPUT_PATTERN },
+    { test = test_hir, root = "../tests/hir", pattern = INPUT_PATTERN },
+    { test = test_warnings, root = "../tests/warnings", pattern = INPUT_PATTERN },
+    { test = test_strict, root = "../tests/strict", pattern = INPUT_PATTERN },
+    { test = test_repl, root = "../tests/repl", pattern = INPUT_PATTERN },
 }
@@ -0,0 +1,58 @@
+/* The C ABI of natrix plugins, mirroring natrix-runtime/src/plugin.rs.
+ *
+ * A plugin is a shared library exporting `const NxPlugin natrix_plugin`, loaded with
+ * `natrix --plugin`. The host checks `abi_version` before reading anything else, so a plugin
+ * built for another version is rejected instead of misread. */
+#ifndef NATRIX_PLUGIN_H
+#define NATRIX_PLUGIN_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#define NX_PLUGIN_ABI_VERSION 1
+
+/* UTF-8 text that is not owned by the handle (and not nul-terminated). */
+typedef struct {
+    const uint8_t *ptr;
+    size_t len;
+} NxStr;
+
+enum { NX_NULL = 0, NX_BOOL = 1, NX_INT = 2, NX_FLOAT = 3, NX_STRING = 4 };
+
+/* A value passed between the host and a plugin, `tag` says which field of `payload` is set. */
+typedef struct {
+    uint32_t tag;
+    union {
+        uint8_t null;
+        bool boolean;
+        int64_t int_;
+        double float_;
+        NxStr string;
+    } payload;
+} NxValue;
+
+/* The functions of the host a native function reports its outcome with. The result is a copy
+ * of the value, null if neither function is called. */
+typedef struct NxCall {
+    uint32_t abi_version;
+    void (*set_result)(struct NxCall *call, const NxValue *value);
+    void (*set_error)(struct NxCall *call, NxStr message);
+} NxCall;
+
+/* A native function, which may only read its arguments until it returns. */
+typedef void (*NxNativeFn)(NxCall *call, const NxValue *args, size_t arg_count);
+
+/* The functions of the host a plugin registers its natives with. */
+typedef struct NxRegistry {
+    uint32_t abi_version;
+    void (*register_native)(struct NxRegistry *registry, NxStr name, size_t arity, NxNativeFn fun);
+} NxRegistry;
+
+/* The entry point of a plugin, exported as `natrix_plugin`. */
+typedef struct {
+    uint32_t abi_version;
+    void (*register_natives)(NxRegistry *registry);
+} NxPlugin;
+
+#endif
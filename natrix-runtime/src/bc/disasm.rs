@@ -0,0 +1,227 @@
+use crate::bc::{Bytecode, Immediate, Opcode};
+use crate::leb128::{decode_sleb128, decode_uleb128};
+use crate::value::{Builtin, Function};
+use std::fmt::Write;
+use std::ops::Range;
+
+impl Bytecode {
+    /// Renders the instructions in `range` one per line, prefixed with their code offsets.
+    ///
+    /// Jump targets are shown as absolute offsets, constants and builtins are resolved.
+    pub fn disassemble(&self, range: Range<usize>) -> String {
+        let mut out = String::new();
+        let mut ip = range.start;
+        while ip < range.end {
+            let offset = ip;
+            let opcode = Opcode::from_u8(self.code[ip]).expect("invalid opcode");
+            ip += 1;
+            let fetch = || {
+                let byte = self.code[ip];
+                ip += 1;
+                byte
+            };
+            write!(out, "{:5}  {}", offset, opcode.name()).unwrap();
+            match (opcode.immediate(), opcode) {
+                (Immediate::None, _) => {}
+                (Immediate::Uleb, Opcode::PushConst) => {
+                    let index = decode_uleb128(fetch);
+                    let constant = &self.constants[index];
+                    if constant.is_string() {
+                        write!(out, " {} ({:?})", index, constant.unwrap_string()).unwrap();
+                    } else {
+                        write!(out, " {} ({})", index, constant).unwrap();
+                    }
+                }
+                (Immediate::Uleb, Opcode::LoadBuiltin) => {
+                    let index = decode_uleb128(fetch);
+                    write!(out, " {} ({})", index, Builtin::ALL[index].name()).unwrap();
+                }
+                (Immediate::Uleb, _) => write!(out, " {}", decode_uleb128(fetch)).unwrap(),
+                (Immediate::Sleb, _) => write!(out, " {}", decode_sleb128(fetch)).unwrap(),
+                (Immediate::Label, _) => {
+                    let target = offset as i64 + decode_sleb128(fetch);
+                    write!(out, " {}", target).unwrap();
+                }
+            }
+            writeln!(out).unwrap();
+        }
+        out
+    }
+
+    /// Renders the disassembly of every user-defined function in the order of their code, each
+    /// after a `fun name:` header and separated by empty lines.
+    pub fn disassemble_functions(&self) -> String {
+        let mut functions: Vec<(Box<str>, usize)> = self
+            .globals
+            .iter()
+            .filter(|global| global.is_function())
+            .filter_map(|global| match global.unwrap_function().as_ref() {
+                Function::UserDefined {
+                    name, code_handle, ..
+                } => Some((name.clone(), *code_handle)),
+                Function::Builtin(_) | Function::Native { .. } => None,
+            })
+            .collect();
+        functions.sort_by_key(|(_, code_handle)| *code_handle);
+        let mut out = String::new();
+        for (index, (name, code_handle)) in functions.into_iter().enumerate() {
+            if index > 0 {
+                out.push('\n');
+            }
+            writeln!(out, "fun {}:", name).unwrap();
+            out.push_str(&self.disassemble(self.function_range(code_handle)));
+        }
+        out
+    }
+
+    /// Renders everything the bytecode contains: sizes, the constant pool, the globals table, the
+    /// disassembly of each function and the line table.
+    ///
+    /// Source locations are shown as source index and byte range, since the sources are not
+    /// part of the bytecode.
+    pub fn inspect(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "code size:  {} bytes", self.code.len()).unwrap();
+        writeln!(out, "constants:  {}", self.constants.len()).unwrap();
+        writeln!(out, "globals:    {}", self.globals.len()).unwrap();
+        writeln!(out, "main:       global {}", self.main_index).unwrap();
+
+        writeln!(out, "\nconstant pool:").unwrap();
+        for (index, constant) in self.constants.iter().enumerate() {
+            if constant.is_string() {
+                writeln!(out, "{:5}  {:?}", index, constant.unwrap_string()).unwrap();
+            } else {
+                writeln!(out, "{:5}  {}", index, constant).unwrap();
+            }
+        }
+
+        writeln!(out, "\nglobals:").unwrap();
+        for (index, global) in self.globals.iter().enumerate() {
+            if global.is_function()
+                && let Function::UserDefined {
+                    name,
+                    param_count,
+                    max_slots,
+                    code_handle,
+                    ..
+                } = global.unwrap_function().as_ref()
+            {
+                writeln!(
+                    out,
+                    "{:5}  fun {} (params: {}, slots: {}, code: {})",
+                    index, name, param_count, max_slots, code_handle
+                )
+                .unwrap();
+            } else {
+                writeln!(out, "{:5}  {}", index, global).unwrap();
+            }
+        }
+
+        out.push('\n');
+        out.push_str(&self.disassemble_functions());
+
+        writeln!(out, "\nline table:").unwrap();
+        for (offset, span) in &self.line_table {
+            writeln!(
+                out,
+                "{:5}  source {} bytes {}..{}",
+                offset, span.source_index, span.start, span.end
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bc::CodeSpan;
+    use crate::value::Value;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_inspect() {
+        let span = CodeSpan {
+            source_index: 0,
+            start: 13,
+            end: 23,
+        };
+        let bc = Bytecode {
+            code: vec![Opcode::PushInt.as_u8(), 42, Opcode::Ret.as_u8()],
+            constants: vec![Value::from_string("hi".into())],
+            line_table: vec![(0, span), (2, span)],
+            globals: vec![Value::from_function(Rc::new(Function::UserDefined {
+                name: "main".into(),
+                param_count: 1,
+                max_slots: 1,
+                code_handle: 0,
+                location: None,
+            }))],
+            main_index: 0,
+            local_names: None,
+        };
+        assert_eq!(
+            bc.inspect(),
+            "\
+code size:  3 bytes
+constants:  1
+globals:    1
+main:       global 0
+
+constant pool:
+    0  \"hi\"
+
+globals:
+    0  fun main (params: 1, slots: 1, code: 0)
+
+fun main:
+    0  push_int 42
+    2  ret
+
+line table:
+    0  source 0 bytes 13..23
+    2  source 0 bytes 13..23
+"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_functions() {
+        let function = |name: &str, code_handle| {
+            Value::from_function(Rc::new(Function::UserDefined {
+                name: name.into(),
+                param_count: 0,
+                max_slots: 0,
+                code_handle,
+                location: None,
+            }))
+        };
+        let bc = Bytecode {
+            code: vec![
+                Opcode::PushTrue.as_u8(),
+                Opcode::JFalse.as_u8(),
+                4,
+                Opcode::PushConst.as_u8(),
+                0,
+                Opcode::Ret.as_u8(),
+                Opcode::LoadBuiltin.as_u8(),
+                Builtin::Len.index() as u8,
+                Opcode::Ret.as_u8(),
+            ],
+            constants: vec![Value::from_float(1.5)],
+            line_table: Vec::new(),
+            globals: vec![function("g", 6), Value::NULL, function("main", 0)],
+            main_index: 2,
+            local_names: None,
+        };
+        assert_eq!(
+            bc.disassemble_functions(),
+            format!(
+                "fun main:\n    0  push_true\n    1  jfalse 5\n    3  push_const 0 (1.5)\n    \
+                 5  ret\n\nfun g:\n    6  load_builtin {} (len)\n    8  ret\n",
+                Builtin::Len.index()
+            )
+        );
+    }
+}
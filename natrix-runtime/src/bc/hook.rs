@@ -0,0 +1,292 @@
+use crate::bc::{Bytecode, Opcode};
+use crate::error::NxError;
+use crate::value::{Function, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::io;
+
+/// Observes the execution of bytecode, registered with [`Interpreter::add_hook`].
+///
+/// All methods do nothing by default, so a hook only implements the events it needs. The
+/// coverage, tracer, profiler and debugger are all hooks.
+///
+/// [`Interpreter::add_hook`]: crate::bc::Interpreter::add_hook
+pub trait VmHook {
+    /// Called before each instruction. `frame` holds the locals of the current function followed
+    /// by its temporaries, so the last value is the top of the stack.
+    fn on_opcode(&mut self, _bc: &Bytecode, _offset: usize, _opcode: Opcode, _frame: &[Value]) {}
+
+    /// Called when a function is called, including `main` when a script is started.
+    fn on_call(&mut self, _function: &Function, _args: &[Value]) {}
+
+    /// Called when a function returns, once for each `on_call`.
+    fn on_return(&mut self, _value: &Value) {}
+
+    /// Called when the instruction at `offset` fails, which stops the script.
+    fn on_error(&mut self, _offset: usize, _error: &NxError) {}
+}
+
+/// Execution counts collected when coverage is enabled, indexed by code offset.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    /// Number of times the instruction starting at each offset was executed.
+    pub hits: Vec<u64>,
+    /// Number of times the conditional jump starting at each offset was taken.
+    pub taken: Vec<u64>,
+}
+
+impl VmHook for Coverage {
+    fn on_opcode(&mut self, bc: &Bytecode, offset: usize, opcode: Opcode, frame: &[Value]) {
+        if self.hits.len() < bc.code.len() {
+            self.hits.resize(bc.code.len(), 0);
+            self.taken.resize(bc.code.len(), 0);
+        }
+        self.hits[offset] += 1;
+        // the condition is on top of the stack, a jump on anything else fails instead
+        let jumps_if = match opcode {
+            Opcode::JFalse => false,
+            Opcode::JTrue => true,
+            _ => return,
+        };
+        if frame
+            .last()
+            .is_some_and(|v| v.is_bool() && v.unwrap_bool() == jumps_if)
+        {
+            self.taken[offset] += 1;
+        }
+    }
+}
+
+/// Writes each executed instruction, indented by the call depth, for `--trace`.
+///
+/// A filter selects the instructions to write by the name of the function they belong to and
+/// their opcode, everything is written without one.
+pub struct Tracer {
+    out: Box<dyn io::Write>,
+    filter: Option<TraceFilter>,
+    calls: Vec<String>, // names of the functions being executed, innermost last
+}
+
+type TraceFilter = Box<dyn FnMut(&str, Opcode) -> bool>;
+
+impl Tracer {
+    pub fn new(out: Box<dyn io::Write>) -> Self {
+        Self {
+            out,
+            filter: None,
+            calls: Vec::new(),
+        }
+    }
+
+    pub fn with_filter(mut self, filter: impl FnMut(&str, Opcode) -> bool + 'static) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+}
+
+impl VmHook for Tracer {
+    fn on_opcode(&mut self, bc: &Bytecode, offset: usize, opcode: Opcode, _frame: &[Value]) {
+        let function = self.calls.last().map_or("", String::as_str);
+        if let Some(filter) = &mut self.filter
+            && !filter(function, opcode)
+        {
+            return;
+        }
+        let instruction = bc.disassemble(offset..offset + 1);
+        // tracing is best effort, a closed output must not stop the script
+        let _ = write!(
+            self.out,
+            "{:indent$}{}",
+            "",
+            instruction,
+            indent = 2 * self.calls.len().saturating_sub(1)
+        );
+    }
+
+    fn on_call(&mut self, function: &Function, _args: &[Value]) {
+        self.calls.push(function.name().to_string());
+    }
+
+    fn on_return(&mut self, _value: &Value) {
+        self.calls.pop();
+    }
+
+    fn on_error(&mut self, offset: usize, error: &NxError) {
+        let _ = writeln!(self.out, "error at offset {}: {}", offset, error.message);
+    }
+}
+
+/// Counts calls and executed instructions per function and per opcode, for `--profile`.
+///
+/// The instructions of a function do not include the ones of the functions it calls.
+#[derive(Default)]
+pub struct Profiler {
+    functions: HashMap<String, (u64, u64)>, // calls and instructions
+    opcodes: HashMap<&'static str, u64>,
+    calls: Vec<String>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Two tables, functions by instructions executed and opcodes by execution count.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by(|(a_name, a), (b_name, b)| b.1.cmp(&a.1).then(a_name.cmp(b_name)));
+        writeln!(
+            out,
+            "{:20} {:>10} {:>14}",
+            "function", "calls", "instructions"
+        )
+        .unwrap();
+        for (name, (calls, instructions)) in functions {
+            writeln!(out, "{:20} {:>10} {:>14}", name, calls, instructions).unwrap();
+        }
+        let mut opcodes: Vec<_> = self.opcodes.iter().collect();
+        opcodes.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then(a_name.cmp(b_name)));
+        write!(out, "\n{:20} {:>10}", "opcode", "count").unwrap();
+        for (name, count) in opcodes {
+            write!(out, "\n{:20} {:>10}", name, count).unwrap();
+        }
+        out
+    }
+}
+
+impl VmHook for Profiler {
+    fn on_opcode(&mut self, _bc: &Bytecode, _offset: usize, opcode: Opcode, _frame: &[Value]) {
+        *self.opcodes.entry(opcode.name()).or_default() += 1;
+        if let Some(function) = self.calls.last() {
+            self.functions.get_mut(function).unwrap().1 += 1;
+        }
+    }
+
+    fn on_call(&mut self, function: &Function, _args: &[Value]) {
+        let name = function.name().to_string();
+        self.functions.entry(name.clone()).or_default().0 += 1;
+        self.calls.push(name);
+    }
+
+    fn on_return(&mut self, _value: &Value) {
+        self.calls.pop();
+    }
+}
+
+/// Calls a function when the execution reaches a breakpoint, for `--break`.
+///
+/// Breakpoints are code offsets, the host maps source lines to offsets with the line table. The
+/// callback gets the offset and the frame of the current function, see [`VmHook::on_opcode`].
+pub struct Debugger {
+    breakpoints: HashSet<usize>,
+    on_break: BreakCallback,
+}
+
+type BreakCallback = Box<dyn FnMut(&Bytecode, usize, &[Value])>;
+
+impl Debugger {
+    pub fn new(on_break: impl FnMut(&Bytecode, usize, &[Value]) + 'static) -> Self {
+        Self {
+            breakpoints: HashSet::new(),
+            on_break: Box::new(on_break),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, offset: usize) {
+        self.breakpoints.insert(offset);
+    }
+
+    pub fn remove_breakpoint(&mut self, offset: usize) {
+        self.breakpoints.remove(&offset);
+    }
+}
+
+impl VmHook for Debugger {
+    fn on_opcode(&mut self, bc: &Bytecode, offset: usize, _opcode: Opcode, frame: &[Value]) {
+        if self.breakpoints.contains(&offset) {
+            (self.on_break)(bc, offset, frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bc::Interpreter;
+    use crate::ctx::RuntimeContext;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        let main = Function::UserDefined {
+            name: "main".into(),
+            param_count: 0,
+            max_slots: 0,
+            code_handle: 0,
+            location: None,
+        };
+        // the jump is taken, so the push_null at offset 3 is skipped
+        let code = vec![
+            Opcode::PushTrue.as_u8(),
+            Opcode::JTrue.as_u8(),
+            3,
+            Opcode::PushNull.as_u8(),
+            Opcode::Push1.as_u8(),
+            Opcode::Ret.as_u8(),
+        ];
+        let bc = Bytecode {
+            code,
+            constants: Vec::new(),
+            line_table: Vec::new(),
+            globals: vec![Value::from_function(Rc::new(main))],
+            main_index: 0,
+            local_names: None,
+        };
+
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        let breaks = Rc::new(RefCell::new(Vec::new()));
+        let mut rt = RuntimeContext::new();
+        {
+            let mut interpreter = Interpreter::new(&mut rt);
+            interpreter.collect_coverage();
+            interpreter.add_hook(profiler.clone());
+            let tracer = Tracer::new(Box::new(SharedBuffer(trace.clone())))
+                .with_filter(|function, opcode| function == "main" && opcode.has_operand());
+            interpreter.add_hook(Rc::new(RefCell::new(tracer)));
+            let breaks = breaks.clone();
+            let mut debugger = Debugger::new(move |_, offset, frame: &[Value]| {
+                breaks.borrow_mut().push((offset, frame.len()))
+            });
+            debugger.add_breakpoint(4);
+            interpreter.add_hook(Rc::new(RefCell::new(debugger)));
+
+            assert_eq!(interpreter.run(&bc, Vec::new()).unwrap().unwrap_int(), 1);
+            let coverage = interpreter.take_coverage().unwrap();
+            assert_eq!(coverage.hits, [1, 1, 0, 0, 1, 1]);
+            assert_eq!(coverage.taken, [0, 1, 0, 0, 0, 0]);
+        }
+        assert_eq!(*trace.borrow(), b"    1  jtrue 4\n");
+        assert_eq!(*breaks.borrow(), [(4, 0)]);
+        let report = profiler.borrow().report();
+        assert!(
+            report.contains("\nmain                          1              4\n"),
+            "{}",
+            report
+        );
+    }
+}
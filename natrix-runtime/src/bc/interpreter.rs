@@ -1,29 +1,168 @@
-use crate::bc::{Bytecode, Opcode};
+#[cfg(debug_assertions)]
+use crate::bc::Immediate;
+use crate::bc::{Bytecode, CodeSpan, Coverage, Opcode, VmHook};
 use crate::ctx::RuntimeContext;
-use crate::error::{nx_err, NxResult};
+use crate::error::{nx_err, nx_err_of, nx_error, ErrorKind, NxError, NxResult};
 use crate::leb128::{decode_sleb128, decode_uleb128};
-use crate::value::{Builtin, Function, Value};
+use crate::value::{BinaryOp, Builtin, Function, IntoValue, UnaryOp, Value};
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 struct CallFrame {
     ret_addr: usize,
     prev_fp: usize,
+    fp: usize,            // the frame of the callee, whose function object is just below it
+    call_site: usize,     // offset of the instruction that made the call
+    started: Option<f64>, // set for calls made by time_it, whose result is the elapsed time
+    interrupt: bool,      // set for calls of the interrupt handler, whose result is dropped
 }
 
+// A `try` expression being evaluated, where an instruction that fails continues with null, or a
+// deferred statement of a block being executed, which an error unwinding to a `try` runs first
+struct Handler {
+    kind: HandlerKind,
+    target: usize, // the end of the expression, or the code of the deferred statement
+    frames: usize, // the number of call frames, the innermost one runs the expression or block
+    fp: usize,
+    height: usize, // of the stack before the expression or statement
+}
+
+enum HandlerKind {
+    Try,
+    Defer,
+    Unwinding(NxError), // a deferred statement runs for the error, which unwinds on after it
+}
+
+/// The state of a script after a call to [`Interpreter::step`].
+#[derive(Debug)]
+pub enum Step {
+    /// The quantum ran out before the script finished, the next `step` continues it.
+    Continue,
+    /// `main` returned this value.
+    Done(Value),
+    /// The script stopped with a runtime error.
+    Trap(NxError),
+}
+
+/// The state of a script after [`Interpreter::run_with_fuel`] or [`Interpreter::resume`].
+pub enum Fuel<'b> {
+    /// `main` returned this value.
+    Done(Value),
+    /// The script executed as many instructions as it was given fuel, and can be resumed.
+    OutOfFuel(Suspended<'b>),
+}
+
+/// A script that ran out of fuel, with everything needed to continue it.
+///
+/// The interpreter does not keep suspended scripts, so a host can hold any number of them and
+/// resume them in any order on the same interpreter, e.g. to run many scripts cooperatively with
+/// the same amount of fuel each. Dropping it abandons the script.
+pub struct Suspended<'b> {
+    execution: Execution<'b>,
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+}
+
+// A script being executed, kept between steps if it was started by `start`
+struct Execution<'a> {
+    bc: &'a Bytecode,
+    globals: Vec<Value>,
+    stack: Vec<Value>,
+    ip: usize,
+    fp: usize,
+    warned: HashSet<usize>, // offsets of instructions already reported
+}
+
+/// Executes bytecode.
+///
+/// An interpreter can run any number of scripts one after another. The stack and frames of a
+/// finished script are cleared but keep their capacity, so that a host running many small
+/// scripts does not allocate them for each one. Its globals are kept for [`call`](Self::call).
 pub struct Interpreter<'a> {
     rt: &'a mut RuntimeContext,
+    builtins: Vec<Value>,
     frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    hooks: Vec<Rc<RefCell<dyn VmHook>>>,
+    coverage: Option<Rc<RefCell<Coverage>>>,
+    execution: Option<Execution<'a>>,
+    quantum: usize,
+    // buffers of the last finished script, empty but with their capacity, except for the
+    // globals which keep their values
+    stack: Vec<Value>,
+    globals: Vec<Value>,
+    globals_of: Option<Rc<Function>>, // `main` of the bytecode that left the globals
+    warned: HashSet<usize>,
+    warning_format: Option<&'a WarningFormat<'a>>,
 }
 
+// Formats a warning given its message and the location of the instruction raising it
+type WarningFormat<'a> = dyn Fn(&str, CodeSpan) -> String + 'a;
+
 impl<'a> Interpreter<'a> {
     pub fn new(rt: &'a mut RuntimeContext) -> Self {
+        Self::with_capacity(rt, 0, 0)
+    }
+
+    /// Creates an interpreter whose operand stack and call frames are preallocated for
+    /// `stack` values and `frames` nested calls.
+    pub fn with_capacity(rt: &'a mut RuntimeContext, stack: usize, frames: usize) -> Self {
         Self {
             rt,
-            frames: Vec::new(),
+            builtins: Self::prepare_builtins(),
+            frames: Vec::with_capacity(frames),
+            handlers: Vec::new(),
+            hooks: Vec::new(),
+            coverage: None,
+            execution: None,
+            quantum: usize::MAX,
+            stack: Vec::with_capacity(stack),
+            globals: Vec::new(),
+            globals_of: None,
+            warned: HashSet::new(),
+            warning_format: None,
         }
     }
 
+    /// Sets how warnings are printed, given the message and the location of the instruction
+    /// raising them, so that the host can show the source like it does for errors. By default
+    /// only the message is printed.
+    pub fn set_warning_format(&mut self, format: &'a WarningFormat<'a>) {
+        self.warning_format = Some(format);
+    }
+
+    /// Sets the number of instructions executed by each [`step`](Self::step), unlimited by
+    /// default. A quantum of zero is treated as one.
+    pub fn set_quantum(&mut self, quantum: usize) {
+        self.quantum = quantum.max(1);
+    }
+
+    /// Registers a hook notified of the events of subsequent executions. The caller keeps its
+    /// own reference to read what the hook collected.
+    pub fn add_hook(&mut self, hook: Rc<RefCell<dyn VmHook>>) {
+        self.hooks.push(hook);
+    }
+
+    pub fn remove_hook<H: VmHook>(&mut self, hook: &Rc<RefCell<H>>) {
+        self.hooks
+            .retain(|h| !std::ptr::addr_eq(Rc::as_ptr(h), Rc::as_ptr(hook)));
+    }
+
+    /// Enables counting of executed instructions and taken branches in subsequent runs.
+    pub fn collect_coverage(&mut self) {
+        let coverage = Rc::new(RefCell::new(Coverage::default()));
+        self.add_hook(coverage.clone());
+        self.coverage = Some(coverage);
+    }
+
+    /// Returns the counts collected so far, or `None` if coverage was not enabled.
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        let coverage = self.coverage.take()?;
+        self.remove_hook(&coverage);
+        Some(coverage.take())
+    }
+
     fn prepare_builtins() -> Vec<Value> {
         Builtin::ALL
             .iter()
@@ -31,7 +170,7 @@ impl<'a> Interpreter<'a> {
             .collect()
     }
 
-    fn prepare_stack(main: Value, mut args: Vec<Value>) -> NxResult<(Vec<Value>, usize)> {
+    fn prepare_stack(stack: &mut Vec<Value>, main: Value, args: Vec<Value>) -> NxResult<usize> {
         match main.unwrap_function().as_ref() {
             Function::UserDefined {
                 max_slots,
@@ -39,24 +178,313 @@ impl<'a> Interpreter<'a> {
                 ..
             } => {
                 main.unwrap_function().check_args(args.len())?;
-                let mut stack = Vec::new();
+                let args_count = args.len();
                 stack.push(main.clone());
-                stack.append(&mut args);
-                stack.resize(stack.len() + *max_slots - args.len(), Value::NULL);
-                Ok((stack, *code_handle))
+                stack.extend(args);
+                stack.resize(stack.len() + *max_slots - args_count, Value::NULL);
+                Ok(*code_handle)
             }
-            _ => panic!("Bytecode main_index is not a user defined function"),
+            _ => panic!("entry point is not a user defined function"),
         }
     }
 
+    /// Runs `main` of `bc` with `args` until it returns, abandoning the script in progress, if
+    /// any.
+    ///
+    /// Unlike [`start`](Self::start), `bc` only needs to live for the call, so the same
+    /// interpreter can run scripts compiled one after another.
     pub fn run(&mut self, bc: &Bytecode, args: Vec<Value>) -> NxResult<Value> {
-        let builtins = Self::prepare_builtins();
+        self.run_global(bc, bc.main_index, args, false)
+    }
+
+    /// Like [`run`](Self::run), but calls the user-defined function `name` instead of `main`.
+    pub fn run_function(&mut self, bc: &Bytecode, name: &str, args: Vec<Value>) -> NxResult<Value> {
+        let index = Self::function_index(bc, name)?;
+        self.run_global(bc, index, args, false)
+    }
+
+    /// Calls the user-defined function `name` of `bc` with the globals left by the previous
+    /// run or call, so that a host can call the functions of a script (e.g. callbacks it
+    /// registered in `main`) any number of times, and they see the values stored in globals
+    /// by the previous ones.
+    ///
+    /// If there was none, it was cleared or it was of another bytecode, the call starts from the
+    /// initial globals of `bc`.
+    pub fn call(&mut self, bc: &Bytecode, name: &str, args: Vec<Value>) -> NxResult<Value> {
+        let index = Self::function_index(bc, name)?;
+        let keep_globals = match (&self.globals_of, Self::main_of(bc)) {
+            (Some(kept), Some(main)) => Rc::ptr_eq(kept, &main),
+            _ => false,
+        };
+        self.run_global(bc, index, args, keep_globals)
+    }
+
+    // The function object of `main` identifies a bytecode: it is created with it, and the
+    // interpreter holds it as long as it keeps the globals, so no other bytecode can have it
+    fn main_of(bc: &Bytecode) -> Option<Rc<Function>> {
+        bc.globals
+            .get(bc.main_index)
+            .filter(|main| main.is_function())
+            .map(Value::unwrap_function)
+    }
+
+    fn function_index(bc: &Bytecode, name: &str) -> NxResult<usize> {
+        match bc.function_index(name) {
+            Some(index) => Ok(index),
+            None => nx_err(format!("no function named {} defined", name)),
+        }
+    }
+
+    fn run_global(
+        &mut self,
+        bc: &Bytecode,
+        index: usize,
+        args: Vec<Value>,
+        keep_globals: bool,
+    ) -> NxResult<Value> {
+        self.abandon();
+        let mut execution = self.begin(bc, index, args, keep_globals)?;
+        let result = loop {
+            match self.execute(&mut execution, usize::MAX) {
+                Ok(None) => {}
+                Ok(Some(value)) => break Ok(value),
+                Err(err) => break Err(err),
+            }
+        };
+        self.finish(execution, result)
+    }
+
+    /// Runs `main` of `bc` with `args` for at most `fuel` instructions, abandoning the script
+    /// started by [`start`](Self::start), if any.
+    ///
+    /// Fuel makes the preemption deterministic: a script given the same fuel always stops at the
+    /// same instruction, wherever it runs.
+    pub fn run_with_fuel<'b>(
+        &mut self,
+        bc: &'b Bytecode,
+        args: Vec<Value>,
+        fuel: usize,
+    ) -> NxResult<Fuel<'b>> {
+        self.abandon();
+        let execution = self.begin(bc, bc.main_index, args, false)?;
+        self.execute_with_fuel(execution, fuel)
+    }
+
+    /// Continues a script that ran out of fuel for at most `fuel` more instructions, abandoning
+    /// the script started by [`start`](Self::start), if any.
+    pub fn resume<'b>(&mut self, suspended: Suspended<'b>, fuel: usize) -> NxResult<Fuel<'b>> {
+        self.abandon();
+        self.frames = suspended.frames;
+        self.handlers = suspended.handlers;
+        self.execute_with_fuel(suspended.execution, fuel)
+    }
+
+    fn execute_with_fuel<'b>(
+        &mut self,
+        mut execution: Execution<'b>,
+        fuel: usize,
+    ) -> NxResult<Fuel<'b>> {
+        let result = match self.execute(&mut execution, fuel) {
+            Ok(None) => {
+                let frames = std::mem::take(&mut self.frames);
+                let handlers = std::mem::take(&mut self.handlers);
+                return Ok(Fuel::OutOfFuel(Suspended {
+                    execution,
+                    frames,
+                    handlers,
+                }));
+            }
+            Ok(Some(value)) => Ok(value),
+            Err(err) => Err(err),
+        };
+        self.finish(execution, result).map(Fuel::Done)
+    }
+
+    /// Prepares the call of `main` of `bc` with `args`, without executing any instruction.
+    ///
+    /// The script is then executed by calls to [`step`](Self::step), so that a host can
+    /// interleave it with its own work on the same thread. Starting a script abandons the one
+    /// in progress, if any. Only bytecode can be run this way, the AST interpreter keeps its
+    /// state on the Rust stack.
+    pub fn start(&mut self, bc: &'a Bytecode, args: Vec<Value>) -> NxResult<()> {
+        self.abandon();
+        self.execution = Some(self.begin(bc, bc.main_index, args, false)?);
+        Ok(())
+    }
+
+    /// Abandons the script in progress, if any, and releases the values it holds, including
+    /// the globals kept for [`call`](Self::call). The buffers keep their capacity for the next
+    /// script.
+    pub fn clear(&mut self) {
+        self.abandon();
+        self.globals.clear();
+        self.globals_of = None;
+    }
+
+    fn abandon(&mut self) {
+        if let Some(execution) = self.execution.take() {
+            self.recycle(execution);
+        }
+        self.frames.clear();
+        self.handlers.clear();
+    }
+
+    // Prepares the call of the function in global `index` in the buffers of the last script
+    fn begin<'b>(
+        &mut self,
+        bc: &'b Bytecode,
+        index: usize,
+        args: Vec<Value>,
+        keep_globals: bool,
+    ) -> NxResult<Execution<'b>> {
+        let args_count = args.len();
+        let main = bc.globals[index].clone();
+        // the arguments are checked before anything is pushed
+        let ip = Self::prepare_stack(&mut self.stack, main.clone(), args)?;
+        let stack = std::mem::take(&mut self.stack);
+        let mut globals = std::mem::take(&mut self.globals);
+        if !keep_globals {
+            globals.clone_from(&bc.globals);
+        }
+        for hook in &self.hooks {
+            hook.borrow_mut()
+                .on_call(&main.unwrap_function(), &stack[1..=args_count]);
+        }
+        Ok(Execution {
+            bc,
+            globals,
+            stack,
+            ip,
+            fp: 1,
+            warned: std::mem::take(&mut self.warned),
+        })
+    }
+
+    // Locates an error at the failing instruction and the calls in progress, notifies the hooks
+    // of it and keeps the buffers of the finished script
+    fn finish(&mut self, execution: Execution, mut result: NxResult<Value>) -> NxResult<Value> {
+        if let Err(err) = &mut result {
+            err.span = execution.bc.span_at(execution.ip);
+            err.trace = self
+                .frames
+                .iter()
+                .rev()
+                .map(|frame| {
+                    let callee = execution.stack[frame.fp - 1].unwrap_function();
+                    (callee.name().into(), execution.bc.span_at(frame.call_site))
+                })
+                .collect();
+            for hook in &self.hooks {
+                hook.borrow_mut().on_error(execution.ip, err);
+            }
+        }
+        self.recycle(execution);
+        self.frames.clear();
+        self.handlers.clear();
+        result
+    }
+
+    fn recycle(&mut self, mut execution: Execution) {
+        execution.stack.clear();
+        execution.warned.clear();
+        self.stack = execution.stack;
+        self.globals = execution.globals;
+        self.globals_of = Self::main_of(execution.bc);
+        self.warned = execution.warned;
+    }
+
+    /// Executes up to a quantum of instructions of the script prepared by
+    /// [`start`](Self::start).
+    ///
+    /// After `Done` or `Trap` the script is finished and further steps trap until another one
+    /// is started.
+    pub fn step(&mut self) -> Step {
+        let Some(mut execution) = self.execution.take() else {
+            return Step::Trap(nx_error("no script is running"));
+        };
+        let result = match self.execute(&mut execution, self.quantum) {
+            Ok(None) => {
+                self.execution = Some(execution);
+                return Step::Continue;
+            }
+            Ok(Some(value)) => Ok(value),
+            Err(err) => Err(err),
+        };
+        match self.finish(execution, result) {
+            Ok(value) => Step::Done(value),
+            Err(err) => Step::Trap(err),
+        }
+    }
+
+    // Executes up to `budget` instructions, returns `None` if the script has not finished. An
+    // instruction that fails inside a `try` expression unwinds to its end, which continues with
+    // null as the value of the expression. The deferred statements of the blocks left on the way
+    // run first, and an error of one of them unwinds on in place of the original error.
+    fn execute(&mut self, execution: &mut Execution, mut budget: usize) -> NxResult<Option<Value>> {
+        loop {
+            let err = match self.execute_until_error(execution, &mut budget) {
+                Err(err) => err,
+                result => return result,
+            };
+            // an error that no `try` catches stops the script without running deferred statements
+            if !self
+                .handlers
+                .iter()
+                .any(|handler| matches!(handler.kind, HandlerKind::Try))
+            {
+                return Err(err);
+            }
+            let handler = loop {
+                let handler = self.handlers.pop().unwrap();
+                if !matches!(handler.kind, HandlerKind::Unwinding(_)) {
+                    break handler;
+                }
+            };
+            // the hooks see a return from each call left by the error
+            for _ in handler.frames..self.frames.len() {
+                for hook in &self.hooks {
+                    hook.borrow_mut().on_return(&Value::NULL);
+                }
+            }
+            self.frames.truncate(handler.frames);
+            execution.stack.truncate(handler.height);
+            execution.fp = handler.fp;
+            execution.ip = handler.target;
+            match handler.kind {
+                HandlerKind::Try => execution.stack.push(Value::NULL),
+                // `defer_end` takes the error back after the deferred statement
+                _ => self.handlers.push(Handler {
+                    kind: HandlerKind::Unwinding(err),
+                    ..handler
+                }),
+            }
+        }
+    }
+
+    fn execute_until_error(
+        &mut self,
+        execution: &mut Execution,
+        budget: &mut usize,
+    ) -> NxResult<Option<Value>> {
+        let bc = execution.bc;
+        let builtins = &self.builtins;
         let constants = &bc.constants;
-        let mut globals = bc.globals.clone();
-        let main = &globals[bc.main_index];
-        let (mut stack, mut ip) = Self::prepare_stack(main.clone(), args)?;
+        let globals = &mut execution.globals;
+        let stack = &mut execution.stack;
+        let mut ip = execution.ip;
         let code = &bc.code;
-        let mut fp = 1usize;
+        let mut fp = execution.fp;
+        let strict = self.rt.is_strict();
+        let warn_numeric_eq = self.rt.warns_numeric_eq() && !strict;
+        let warned = &mut execution.warned;
+
+        macro_rules! hook {
+            ($method:ident($($arg:expr),*)) => {
+                for hook in &self.hooks {
+                    hook.borrow_mut().$method($($arg),*);
+                }
+            };
+        }
 
         macro_rules! fetch_u8 {
             () => {{
@@ -101,7 +529,7 @@ impl<'a> Interpreter<'a> {
             () => {{
                 let value = pop!();
                 if !value.is_bool() {
-                    nx_err("expected a boolean value")
+                    nx_err_of(ErrorKind::Type, "expected a boolean value")
                 } else {
                     Ok(value.unwrap_bool())
                 }
@@ -109,22 +537,112 @@ impl<'a> Interpreter<'a> {
         }
 
         macro_rules! unary {
-            ($op:ident) => {{
+            ($op:ident, $method:ident) => {{
                 let v: Value = pop!();
-                push!(v.$op()?)
+                if strict {
+                    UnaryOp::$op.check_strict(&v)?;
+                }
+                push!(v.$method()?)
             }};
         }
 
         macro_rules! binary {
-            ($op:ident) => {{
+            ($op:ident, $method:ident) => {{
                 let r: Value = pop!();
                 let l: Value = pop!();
-                push!(l.$op(&r)?)
+                if strict {
+                    BinaryOp::$op.check_strict(&l, &r)?;
+                }
+                let v = l.$method(&r)?;
+                self.rt.track_alloc(&v);
+                push!(v)
+            }};
+        }
+
+        // Notifies the hooks of the return from a builtin or native function, also if it failed,
+        // so that they see a return for each call when the error is caught by `try`
+        macro_rules! returned {
+            ($result:expr) => {{
+                let result: NxResult<Value> = $result;
+                hook!(on_return(result.as_ref().unwrap_or(&Value::NULL)));
+                result?
+            }};
+        }
+
+        // Reports the warning once per instruction, at its location if the host formats it
+        macro_rules! check_numeric_eq {
+            ($op:ident, $offset:expr) => {{
+                if warn_numeric_eq {
+                    let (l, r) = (&stack[stack.len() - 2], &stack[stack.len() - 1]);
+                    if let Some(message) = BinaryOp::$op.numeric_eq_warning(l, r)
+                        && warned.insert($offset)
+                    {
+                        let text = match (&self.warning_format, bc.span_at($offset)) {
+                            (Some(format), Some(span)) => format(&message, span),
+                            _ => format!("warning: {}", message),
+                        };
+                        self.rt.warn(&text);
+                    }
+                }
+            }};
+        }
+
+        // Calls the interrupt handler if the process was interrupted, returning to `ip`, as if the
+        // instruction at `$call_site` called it. The interpreters check at the start of a function
+        // and at the jump back to the condition of a loop, so that a script notices an interrupt
+        // in bounded time.
+        macro_rules! safepoint {
+            ($call_site:expr) => {{
+                if let Some(handler) = self.rt.take_interrupt() {
+                    let handler_obj = handler.unwrap_function();
+                    hook!(on_call(&handler_obj, &[]));
+                    match handler_obj.as_ref() {
+                        Function::Builtin(builtin) => {
+                            returned!(builtin.eval(self.rt, &[]));
+                        }
+                        Function::Native { fun, .. } => {
+                            returned!(fun.call(self.rt, &[]));
+                        }
+                        Function::UserDefined {
+                            max_slots,
+                            code_handle,
+                            ..
+                        } => {
+                            push!(handler.clone());
+                            let new_fp = stack.len();
+                            stack.resize(new_fp + *max_slots, Value::NULL);
+                            self.frames.push(CallFrame {
+                                ret_addr: ip,
+                                prev_fp: fp,
+                                fp: new_fp,
+                                call_site: $call_site,
+                                started: None,
+                                interrupt: true,
+                            });
+                            fp = new_fp;
+                            ip = *code_handle;
+                        }
+                    }
+                }
             }};
         }
 
         loop {
-            match Opcode::from_u8(fetch_u8!()).unwrap() {
+            if *budget == 0 {
+                execution.ip = ip;
+                execution.fp = fp;
+                return Ok(None);
+            }
+            *budget -= 1;
+            let ins_start = ip;
+            execution.ip = ins_start; // reported if the instruction fails
+            let opcode = Opcode::from_u8(fetch_u8!()).unwrap();
+            hook!(on_opcode(bc, ins_start, opcode, &stack[fp..]));
+            #[cfg(feature = "profile-alloc")]
+            crate::profile::set_opcode(opcode);
+            #[cfg(debug_assertions)]
+            let (fp_before, height_before) = (fp, stack.len());
+            match opcode {
                 Opcode::Push0 => push!(Value::from_int(0)),
                 Opcode::Push1 => push!(Value::from_int(1)),
                 Opcode::PushNull => push!(Value::NULL),
@@ -132,19 +650,25 @@ impl<'a> Interpreter<'a> {
                 Opcode::PushTrue => push!(Value::TRUE),
                 Opcode::PushInt => push!(Value::from_int(fetch_sleb!())),
                 Opcode::PushConst => push!(constants[fetch_uleb!()].clone()),
-                Opcode::Add => binary!(add),
-                Opcode::Sub => binary!(sub),
-                Opcode::Mul => binary!(mul),
-                Opcode::Div => binary!(div),
-                Opcode::Mod => binary!(rem),
-                Opcode::Eq => binary!(eq),
-                Opcode::Ne => binary!(ne),
-                Opcode::Lt => binary!(lt),
-                Opcode::Le => binary!(le),
-                Opcode::Gt => binary!(gt),
-                Opcode::Ge => binary!(ge),
-                Opcode::Neg => unary!(negate),
-                Opcode::Not => unary!(not),
+                Opcode::Add => binary!(Add, add),
+                Opcode::Sub => binary!(Sub, sub),
+                Opcode::Mul => binary!(Mul, mul),
+                Opcode::Div => binary!(Div, div),
+                Opcode::Mod => binary!(Mod, rem),
+                Opcode::Eq => {
+                    check_numeric_eq!(Eq, ins_start);
+                    binary!(Eq, eq)
+                }
+                Opcode::Ne => {
+                    check_numeric_eq!(Ne, ins_start);
+                    binary!(Ne, ne)
+                }
+                Opcode::Lt => binary!(Lt, lt),
+                Opcode::Le => binary!(Le, le),
+                Opcode::Gt => binary!(Gt, gt),
+                Opcode::Ge => binary!(Ge, ge),
+                Opcode::Neg => unary!(Neg, negate),
+                Opcode::Not => unary!(Not, not),
                 Opcode::Load0 => push!(stack[fp].clone()),
                 Opcode::LoadLocal => push!(stack[fp + fetch_uleb!()].clone()),
                 Opcode::StoreLocal => stack[fp + fetch_uleb!()] = pop!(),
@@ -155,12 +679,28 @@ impl<'a> Interpreter<'a> {
                     let n = fetch_uleb!();
                     let v = stack[stack.len() - n..].to_vec();
                     stack.truncate(stack.len() - n);
-                    push!(Value::from_list(Rc::new(RefCell::new(v))))
+                    let list = Value::from_list(Rc::new(v));
+                    self.rt.track_alloc(&list);
+                    push!(list)
+                }
+                Opcode::Unpack => {
+                    let n = fetch_uleb!();
+                    let elements = pop!().unpack(n)?;
+                    stack.extend(elements.into_iter().rev());
                 }
                 Opcode::GetItem => {
                     let index = pop!();
                     let array = pop!();
-                    push!(array.get_item(index)?)
+                    let item = array.get_item(index)?;
+                    self.rt.track_alloc(&item);
+                    push!(item)
+                }
+                Opcode::GetSlice => {
+                    let end = pop!();
+                    let start = pop!();
+                    let slice = pop!().get_slice(start, end)?;
+                    self.rt.track_alloc(&slice);
+                    push!(slice)
                 }
                 Opcode::SetItem => {
                     let value = pop!();
@@ -168,7 +708,13 @@ impl<'a> Interpreter<'a> {
                     let array = pop!();
                     array.set_item(index, value)?
                 }
-                Opcode::Jmp => ip = fetch_jump_target!(),
+                Opcode::Jmp => {
+                    let from = ip - 1;
+                    ip = fetch_jump_target!();
+                    if ip <= from {
+                        safepoint!(ins_start);
+                    }
+                }
                 Opcode::JFalse => {
                     let target = fetch_jump_target!();
                     if !pop_bool!()? {
@@ -188,12 +734,79 @@ impl<'a> Interpreter<'a> {
                     let fun_obj = if fun_obj.is_function() {
                         fun_obj.unwrap_function()
                     } else {
-                        return nx_err("expected a function");
+                        return nx_err_of(ErrorKind::Type, "expected a function");
                     };
                     fun_obj.check_args(arg_count)?;
                     match fun_obj.as_ref() {
+                        Function::Builtin(Builtin::TimeIt) => {
+                            // replace time_it with its argument and call it like a regular function
+                            let callee = pop!();
+                            let started = self.rt.now();
+                            if !callee.is_function() {
+                                return nx_err_of(ErrorKind::Type, "time_it expects a function");
+                            }
+                            let callee_obj = callee.unwrap_function();
+                            callee_obj.check_args(0)?;
+                            hook!(on_call(&callee_obj, &[]));
+                            match callee_obj.as_ref() {
+                                Function::Builtin(builtin) => {
+                                    returned!(builtin.eval(self.rt, &[]));
+                                    stack[new_fp - 1] = Value::from_float(self.rt.now() - started);
+                                }
+                                Function::Native { fun, .. } => {
+                                    returned!(fun.call(self.rt, &[]));
+                                    stack[new_fp - 1] = Value::from_float(self.rt.now() - started);
+                                }
+                                Function::UserDefined {
+                                    max_slots,
+                                    code_handle,
+                                    ..
+                                } => {
+                                    stack[new_fp - 1] = callee.clone();
+                                    stack.resize(stack.len() + *max_slots, Value::NULL);
+                                    self.frames.push(CallFrame {
+                                        ret_addr: ip,
+                                        prev_fp: fp,
+                                        fp: new_fp,
+                                        call_site: ins_start,
+                                        started: Some(started),
+                                        interrupt: false,
+                                    });
+                                    fp = new_fp;
+                                    ip = *code_handle;
+                                }
+                            }
+                        }
+                        Function::Builtin(Builtin::Locals) => {
+                            // the slots are only named in bytecode compiled with debug info
+                            let Some(locals) = bc.locals_at(ins_start, &stack[fp..new_fp - 1])
+                            else {
+                                return nx_err("locals needs debug info, see --debug");
+                            };
+                            hook!(on_call(&fun_obj, &[]));
+                            let r = locals
+                                .into_iter()
+                                .map(|(name, value)| (name.to_string(), value))
+                                .collect::<HashMap<_, _>>()
+                                .into_value();
+                            self.rt.track_alloc(&r);
+                            hook!(on_return(&r));
+                            stack[new_fp - 1] = r;
+                        }
                         Function::Builtin(builtin) => {
-                            let r = builtin.eval(self.rt, &stack[new_fp..new_fp + arg_count])?;
+                            hook!(on_call(&fun_obj, &stack[new_fp..]));
+                            let r = returned!(
+                                builtin.eval(self.rt, &stack[new_fp..new_fp + arg_count])
+                            );
+                            self.rt.track_alloc(&r);
+                            stack[new_fp - 1] = r;
+                            stack.truncate(new_fp);
+                        }
+                        Function::Native { fun, .. } => {
+                            hook!(on_call(&fun_obj, &stack[new_fp..]));
+                            let r =
+                                returned!(fun.call(self.rt, &stack[new_fp..new_fp + arg_count]));
+                            self.rt.track_alloc(&r);
                             stack[new_fp - 1] = r;
                             stack.truncate(new_fp);
                         }
@@ -202,33 +815,348 @@ impl<'a> Interpreter<'a> {
                             code_handle,
                             ..
                         } => {
+                            hook!(on_call(&fun_obj, &stack[new_fp..]));
                             stack.resize(stack.len() + *max_slots - arg_count, Value::NULL);
                             self.frames.push(CallFrame {
                                 ret_addr: ip,
                                 prev_fp: fp,
+                                fp: new_fp,
+                                call_site: ins_start,
+                                started: None,
+                                interrupt: false,
                             });
                             fp = new_fp;
                             ip = *code_handle;
+                            safepoint!(ins_start);
                         }
                     }
                 }
                 Opcode::Ret => {
+                    hook!(on_return(stack.last().unwrap()));
                     stack[fp - 1] = stack.last().unwrap().clone();
                     stack.truncate(fp);
                     match self.frames.pop() {
                         Some(frame) => {
+                            if let Some(started) = frame.started {
+                                stack[fp - 1] = Value::from_float(self.rt.now() - started);
+                            }
+                            if frame.interrupt {
+                                stack.pop();
+                            }
                             ip = frame.ret_addr;
                             fp = frame.prev_fp;
+                            // the compiler never returns from inside `try`, other code may
+                            while self
+                                .handlers
+                                .last()
+                                .is_some_and(|handler| handler.frames > self.frames.len())
+                            {
+                                self.handlers.pop();
+                            }
                         }
                         None => {
-                            return Ok(pop!());
+                            return Ok(Some(pop!()));
                         }
                     }
                 }
                 Opcode::Pop => {
                     pop!();
                 }
+                Opcode::TryBegin => {
+                    let target = fetch_jump_target!();
+                    self.handlers.push(Handler {
+                        kind: HandlerKind::Try,
+                        target,
+                        frames: self.frames.len(),
+                        fp,
+                        height: stack.len(),
+                    });
+                }
+                Opcode::TryEnd => {
+                    if self.handlers.pop().is_none() {
+                        return nx_err("try_end without try_begin");
+                    }
+                }
+                Opcode::DeferBegin => {
+                    let target = fetch_jump_target!();
+                    self.handlers.push(Handler {
+                        kind: HandlerKind::Defer,
+                        target,
+                        frames: self.frames.len(),
+                        fp,
+                        height: stack.len(),
+                    });
+                }
+                Opcode::DeferEnd => match self.handlers.pop() {
+                    Some(Handler {
+                        kind: HandlerKind::Unwinding(err),
+                        ..
+                    }) => return Err(err),
+                    _ => return nx_err("defer_end without an error"),
+                },
+            }
+            // catches compiler bugs, calls and returns switch to another frame
+            #[cfg(debug_assertions)]
+            if fp == fp_before {
+                check_stack_effect(code, ins_start, opcode, height_before, stack.len());
             }
         }
     }
 }
+
+// Panics if the instruction at `offset` did not change the height of the stack as declared.
+#[cfg(debug_assertions)]
+fn check_stack_effect(code: &[u8], offset: usize, opcode: Opcode, before: usize, after: usize) {
+    // the effects only depend on counts
+    let mut ip = offset + 1;
+    let operand = if opcode.immediate() == Immediate::Uleb {
+        decode_uleb128(|| {
+            ip += 1;
+            code[ip - 1]
+        })
+    } else {
+        0
+    };
+    let expected = before as isize + opcode.stack_effect(operand);
+    assert_eq!(
+        after as isize,
+        expected,
+        "stack height after {} at {} differs from its declared effect",
+        opcode.name(),
+        offset
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytecode(code: Vec<u8>) -> Bytecode {
+        let main = Function::UserDefined {
+            name: "main".into(),
+            param_count: 0,
+            max_slots: 0,
+            code_handle: 0,
+            location: None,
+        };
+        Bytecode {
+            code,
+            constants: Vec::new(),
+            line_table: Vec::new(),
+            globals: vec![Value::from_function(Rc::new(main))],
+            main_index: 0,
+            local_names: None,
+        }
+    }
+
+    #[test]
+    fn test_step() {
+        let add = bytecode(vec![
+            Opcode::Push1.as_u8(),
+            Opcode::Push1.as_u8(),
+            Opcode::Add.as_u8(),
+            Opcode::Ret.as_u8(),
+        ]);
+        let mut rt = RuntimeContext::new();
+        let mut interpreter = Interpreter::new(&mut rt);
+        interpreter.set_quantum(3);
+        interpreter.start(&add, Vec::new()).unwrap();
+        assert!(matches!(interpreter.step(), Step::Continue));
+        let Step::Done(value) = interpreter.step() else {
+            panic!("expected the script to finish");
+        };
+        assert_eq!(value.unwrap_int(), 2);
+        assert!(matches!(interpreter.step(), Step::Trap(_)));
+
+        let negate_bool = bytecode(vec![Opcode::PushTrue.as_u8(), Opcode::Neg.as_u8()]);
+        interpreter.start(&negate_bool, Vec::new()).unwrap();
+        assert!(matches!(interpreter.step(), Step::Trap(_)));
+        // a run is not limited by the quantum
+        assert_eq!(interpreter.run(&add, Vec::new()).unwrap().unwrap_int(), 2);
+    }
+
+    #[test]
+    fn test_fuel() {
+        let add = bytecode(vec![
+            Opcode::Push1.as_u8(),
+            Opcode::Push1.as_u8(),
+            Opcode::Add.as_u8(),
+            Opcode::Ret.as_u8(),
+        ]);
+        let one = bytecode(vec![Opcode::Push1.as_u8(), Opcode::Ret.as_u8()]);
+        let mut rt = RuntimeContext::new();
+        let mut interpreter = Interpreter::new(&mut rt);
+        // both scripts run one instruction at a time, taking turns
+        let mut scripts = vec![
+            interpreter.run_with_fuel(&add, Vec::new(), 1).unwrap(),
+            interpreter.run_with_fuel(&one, Vec::new(), 1).unwrap(),
+        ];
+        let mut turns = 2;
+        while scripts.iter().any(|s| matches!(s, Fuel::OutOfFuel(_))) {
+            scripts = scripts
+                .into_iter()
+                .map(|script| match script {
+                    Fuel::OutOfFuel(suspended) => {
+                        turns += 1;
+                        interpreter.resume(suspended, 1).unwrap()
+                    }
+                    done => done,
+                })
+                .collect();
+        }
+        let results: Vec<i64> = scripts
+            .iter()
+            .map(|s| match s {
+                Fuel::Done(value) => value.unwrap_int(),
+                Fuel::OutOfFuel(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(results, [2, 1]);
+        assert_eq!(turns, 6);
+
+        let Fuel::Done(value) = interpreter.run_with_fuel(&add, Vec::new(), 4).unwrap() else {
+            panic!("expected four instructions to be enough");
+        };
+        assert_eq!(value.unwrap_int(), 2);
+        let negate_bool = bytecode(vec![Opcode::PushTrue.as_u8(), Opcode::Neg.as_u8()]);
+        let Ok(Fuel::OutOfFuel(suspended)) = interpreter.run_with_fuel(&negate_bool, Vec::new(), 1)
+        else {
+            panic!("expected the script to run out of fuel");
+        };
+        assert!(interpreter.resume(suspended, 1).is_err());
+        assert!(interpreter.stack.is_empty() && interpreter.frames.is_empty());
+    }
+
+    #[test]
+    fn test_reuse() {
+        let mut rt = RuntimeContext::new();
+        let mut interpreter = Interpreter::with_capacity(&mut rt, 16, 4);
+        for _ in 0..3 {
+            // compiled for each run, it only needs to live as long as the run
+            let bc = bytecode(vec![Opcode::Push1.as_u8(), Opcode::Ret.as_u8()]);
+            assert_eq!(interpreter.run(&bc, Vec::new()).unwrap().unwrap_int(), 1);
+            assert!(interpreter.stack.is_empty() && interpreter.stack.capacity() >= 16);
+        }
+        let bc = bytecode(vec![Opcode::PushTrue.as_u8(), Opcode::Neg.as_u8()]);
+        assert!(interpreter.run(&bc, Vec::new()).is_err());
+        assert!(interpreter.stack.is_empty());
+
+        let bc = bytecode(vec![Opcode::Push1.as_u8(), Opcode::Ret.as_u8()]);
+        interpreter.set_quantum(1);
+        interpreter.start(&bc, Vec::new()).unwrap();
+        assert!(matches!(interpreter.step(), Step::Continue));
+        interpreter.clear();
+        assert!(interpreter.stack.is_empty() && interpreter.frames.is_empty());
+        assert!(interpreter.globals.is_empty());
+        assert!(matches!(interpreter.step(), Step::Trap(_)));
+    }
+
+    #[test]
+    fn test_try() {
+        // [1, try -(true, true)], with the extra true dropped by the unwinding
+        let bc = bytecode(vec![
+            Opcode::Push1.as_u8(),
+            Opcode::TryBegin.as_u8(),
+            6,
+            Opcode::PushTrue.as_u8(),
+            Opcode::PushTrue.as_u8(),
+            Opcode::Neg.as_u8(),
+            Opcode::TryEnd.as_u8(),
+            Opcode::MakeList.as_u8(),
+            2,
+            Opcode::Ret.as_u8(),
+        ]);
+        let mut rt = RuntimeContext::new();
+        let mut interpreter = Interpreter::new(&mut rt);
+        let result = interpreter.run(&bc, Vec::new()).unwrap();
+        assert_eq!(result.to_string(), "[1, null]");
+        assert!(interpreter.handlers.is_empty());
+
+        let bc = bytecode(vec![Opcode::TryEnd.as_u8(), Opcode::Ret.as_u8()]);
+        assert!(interpreter.run(&bc, Vec::new()).is_err());
+        let bc = bytecode(vec![Opcode::DeferEnd.as_u8(), Opcode::Ret.as_u8()]);
+        assert!(interpreter.run(&bc, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_run_function() {
+        let mut bc = bytecode(vec![
+            Opcode::Push1.as_u8(),
+            Opcode::Ret.as_u8(),
+            Opcode::Load0.as_u8(),
+            Opcode::Ret.as_u8(),
+        ]);
+        bc.globals
+            .push(Value::from_function(Rc::new(Function::UserDefined {
+                name: "id".into(),
+                param_count: 1,
+                max_slots: 1,
+                code_handle: 2,
+                location: None,
+            })));
+        let mut rt = RuntimeContext::new();
+        let mut interpreter = Interpreter::new(&mut rt);
+        let result = interpreter.run_function(&bc, "id", vec![Value::from_int(7)]);
+        assert_eq!(result.unwrap().unwrap_int(), 7);
+        let err = interpreter.run_function(&bc, "id", Vec::new()).unwrap_err();
+        assert_eq!(
+            err.message.as_ref(),
+            "function id expects 1 argument, but 0 were provided"
+        );
+        let err = interpreter
+            .run_function(&bc, "setup", Vec::new())
+            .unwrap_err();
+        assert_eq!(err.message.as_ref(), "no function named setup defined");
+    }
+
+    #[test]
+    fn test_call() {
+        // count() increments global 2 and returns it
+        let mut bc = bytecode(vec![
+            Opcode::Push1.as_u8(),
+            Opcode::Ret.as_u8(),
+            Opcode::LoadGlobal.as_u8(),
+            2,
+            Opcode::Push1.as_u8(),
+            Opcode::Add.as_u8(),
+            Opcode::StoreGlobal.as_u8(),
+            2,
+            Opcode::LoadGlobal.as_u8(),
+            2,
+            Opcode::Ret.as_u8(),
+        ]);
+        bc.globals
+            .push(Value::from_function(Rc::new(Function::UserDefined {
+                name: "count".into(),
+                param_count: 0,
+                max_slots: 0,
+                code_handle: 2,
+                location: None,
+            })));
+        bc.globals.push(Value::from_int(0));
+        let mut rt = RuntimeContext::new();
+        let mut interpreter = Interpreter::new(&mut rt);
+        let call = |interpreter: &mut Interpreter| {
+            interpreter
+                .call(&bc, "count", Vec::new())
+                .unwrap()
+                .unwrap_int()
+        };
+        assert_eq!(call(&mut interpreter), 1);
+        assert_eq!(call(&mut interpreter), 2);
+        // a run starts from the initial globals, the calls after it continue from its globals
+        interpreter.run(&bc, Vec::new()).unwrap();
+        assert_eq!(call(&mut interpreter), 1);
+        assert_eq!(call(&mut interpreter), 2);
+        interpreter.clear();
+        assert_eq!(call(&mut interpreter), 1);
+
+        // the globals of another bytecode with as many globals are not kept
+        let mut other = bytecode(bc.code.clone());
+        other.globals.extend_from_slice(&bc.globals[1..]);
+        assert_eq!(call(&mut interpreter), 2);
+        let result = interpreter.call(&other, "count", Vec::new()).unwrap();
+        assert_eq!(result.unwrap_int(), 1);
+        assert_eq!(call(&mut interpreter), 1);
+    }
+}
@@ -1,19 +1,198 @@
-use crate::value::Value;
-pub use interpreter::Interpreter;
+use crate::value::{Function, Value};
+pub use hook::{Coverage, Debugger, Profiler, Tracer, VmHook};
+pub use interpreter::{Fuel, Interpreter, Step, Suspended};
+pub use serialize::{FORMAT_VERSION, LIBRARY_MAGIC, MAGIC};
+use std::fmt;
+use std::ops::Range;
+use std::rc::Rc;
 
+mod disasm;
+mod hook;
 mod interpreter;
+mod serialize;
+mod verify;
 
 #[derive(Debug)]
 pub struct Bytecode {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
-    // TODO line table
+    pub line_table: Vec<(usize, CodeSpan)>, // one entry per instruction, sorted by code offset
     pub globals: Vec<Value>,
     pub main_index: usize,
+    pub local_names: Option<Vec<LocalName>>, // only kept when compiled with debug info
+}
+
+impl Bytecode {
+    /// Returns the source location of the instruction containing the given code offset.
+    pub fn span_at(&self, offset: usize) -> Option<CodeSpan> {
+        let index = self.line_table.partition_point(|(o, _)| *o <= offset);
+        if index == 0 {
+            None
+        } else {
+            Some(self.line_table[index - 1].1)
+        }
+    }
+
+    /// The names and values of the local variables live at `offset`, in the order of their
+    /// slots, or `None` if the bytecode has no debug info. `frame` holds the slots of the
+    /// function, see [`VmHook::on_opcode`]. A variable shadowed by another one is left out.
+    pub fn locals_at(&self, offset: usize, frame: &[Value]) -> Option<Vec<(Rc<str>, Value)>> {
+        let mut live: Vec<&LocalName> = self
+            .local_names
+            .as_ref()?
+            .iter()
+            .filter(|local| local.live.contains(&offset))
+            .collect();
+        live.sort_by_key(|local| local.slot);
+        let mut locals: Vec<(Rc<str>, Value)> = Vec::new();
+        for local in live {
+            let value = frame[local.slot].clone();
+            // the shadowing variable was declared later, so it has the higher slot
+            match locals.iter_mut().find(|(name, _)| *name == local.name) {
+                Some(shadowed) => shadowed.1 = value,
+                None => locals.push((local.name.clone(), value)),
+            }
+        }
+        Some(locals)
+    }
+
+    /// Returns the index of the global holding the user-defined function named `name`.
+    pub fn function_index(&self, name: &str) -> Option<usize> {
+        self.globals.iter().position(|global| {
+            global.is_function()
+                && matches!(global.unwrap_function().as_ref(),
+                    Function::UserDefined { name: n, .. } if &**n == name)
+        })
+    }
+
+    /// Returns the code range of the user-defined function starting at `code_handle`.
+    pub fn function_range(&self, code_handle: usize) -> Range<usize> {
+        let end = self
+            .globals
+            .iter()
+            .filter(|global| global.is_function())
+            .filter_map(|global| match global.unwrap_function().as_ref() {
+                Function::UserDefined { code_handle, .. } => Some(*code_handle),
+                Function::Builtin(_) | Function::Native { .. } => None,
+            })
+            .filter(|start| *start > code_handle)
+            .min()
+            .unwrap_or(self.code.len());
+        code_handle..end
+    }
+
+    /// Counts the instructions, functions and constants, see `BytecodeStats`.
+    pub fn stats(&self) -> BytecodeStats {
+        let mut stats = BytecodeStats {
+            code_bytes: self.code.len(),
+            constants: self.constants.len(),
+            ..BytecodeStats::default()
+        };
+        let mut ip = 0;
+        while ip < self.code.len() {
+            let opcode = Opcode::from_u8(self.code[ip]).expect("invalid opcode");
+            ip += 1;
+            if opcode.has_operand() {
+                while self.code[ip] & 0x80 != 0 {
+                    ip += 1;
+                }
+                ip += 1;
+            }
+            stats.instructions += 1;
+            if let Opcode::PushConst = opcode {
+                stats.constant_loads += 1;
+            }
+        }
+        for constant in &self.constants {
+            if constant.is_string() {
+                stats.string_constants += 1;
+                stats.string_bytes += constant.unwrap_string().len();
+            }
+        }
+        stats.functions = self
+            .globals
+            .iter()
+            .filter(|global| global.is_function())
+            .filter(|global| {
+                matches!(
+                    global.unwrap_function().as_ref(),
+                    Function::UserDefined { .. }
+                )
+            })
+            .count();
+        stats
+    }
+}
+
+/// A precompiled library, the bytecode of a set of modules without `main` and the functions
+/// that programs importing it can call, see [`Library::serialize`].
+#[derive(Debug)]
+pub struct Library {
+    pub bc: Bytecode,
+    pub exports: Vec<(Rc<str>, usize)>, // the name of each exported function and its global
+}
+
+/// Size of compiled bytecode. Every distinct constant is stored once in the constant pool, so
+/// `constant_loads` is usually larger than `constants`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct BytecodeStats {
+    pub code_bytes: usize,
+    pub instructions: usize,
+    pub functions: usize,
+    pub constants: usize,
+    pub string_constants: usize,
+    pub string_bytes: usize,   // total length of the string constants
+    pub constant_loads: usize, // push_const instructions
+}
+
+impl fmt::Display for BytecodeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "code:       {} bytes, {} instructions in {} functions",
+            self.code_bytes, self.instructions, self.functions
+        )?;
+        write!(
+            f,
+            "constants:  {} ({} strings of {} bytes, {} floats), loaded by {} instructions",
+            self.constants,
+            self.string_constants,
+            self.string_bytes,
+            self.constants - self.string_constants,
+            self.constant_loads
+        )
+    }
+}
+
+/// The name of a local variable and where it lives, debug info for `locals()` and debuggers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalName {
+    pub name: Rc<str>,
+    pub slot: usize,
+    pub live: Range<usize>, // code offsets at which the slot holds the variable
+}
+
+/// Source location of an instruction - the index of the source file (as assigned by the compiler)
+/// and a byte range within it. The runtime does not interpret it, it only carries it around so
+/// that tools and error messages can map code offsets back to the source.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CodeSpan {
+    pub source_index: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The operand of an instruction, encoded right after its opcode.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Immediate {
+    None,
+    Uleb,  // an index or a count
+    Sleb,  // an integer constant
+    Label, // a jump offset relative to the opcode, SLEB128 encoded
 }
 
 macro_rules! define_opcodes {
-    ($($variant:ident => $name:literal);* $(;)?) => {
+    ($($variant:ident => $name:literal, $immediate:ident, $effect:expr);* $(;)?) => {
         #[repr(u8)]
         #[derive(Copy, Clone, Debug)]
         pub enum Opcode {
@@ -31,6 +210,28 @@ macro_rules! define_opcodes {
                 }
             }
 
+            /// The change of the height of the operand stack of the current frame, given the
+            /// operand of the instruction. The interpreter checks it in debug builds, except for
+            /// instructions that enter or leave a frame.
+            pub fn stack_effect(self, operand: usize) -> isize {
+                let effect: fn(isize) -> isize = match self {
+                    $(Opcode::$variant => $effect),*
+                };
+                effect(operand as isize)
+            }
+
+            /// The kind of the operand following the opcode.
+            pub const fn immediate(self) -> Immediate {
+                match self {
+                    $(Opcode::$variant => Immediate::$immediate),*
+                }
+            }
+
+            /// Whether the opcode is followed by a LEB128 operand.
+            pub const fn has_operand(self) -> bool {
+                !matches!(self.immediate(), Immediate::None)
+            }
+
             pub fn as_u8(&self) -> u8 {
                 *self as u8
             }
@@ -48,39 +249,109 @@ macro_rules! define_opcodes {
 }
 
 define_opcodes! {
-    Push0 => "push_0";              // 00
-    Push1 => "push_1";              // 01
-    PushNull => "push_null";        // 02
-    PushFalse => "push_false";      // 03
-    PushTrue => "push_true";        // 04
-    PushInt => "push_int";          // 05
-    PushConst => "push_const";      // 06 // N
-    Add => "add";                   // 07
-    Sub => "sub";                   // 08
-    Mul => "mul";                   // 09
-    Div => "div";                   // 0A
-    Mod => "mod";                   // 0B
-    Eq => "eq";                     // 0C
-    Ne => "ne";                     // 0D
-    Lt => "lt";                     // 0E
-    Le => "le";                     // 0F
-    Gt => "gt";                     // 10
-    Ge => "ge";                     // 11
-    Neg => "neg";                   // 12
-    Not => "not";                   // 13
-    Load0 => "load_0";              // 14
-    LoadLocal => "load_local";      // 15 // N
-    StoreLocal => "store_local";    // 16 // N
-    LoadGlobal => "load_global";    // 17 // N
-    StoreGlobal => "store_global";  // 18 // N
-    LoadBuiltin => "load_builtin";  // 19 // N
-    MakeList => "make_list";        // 1A // N
-    GetItem => "get_item";          // 1B
-    SetItem => "set_item";          // 1C
-    Jmp => "jmp";                   // 1D // offset
-    JFalse => "jfalse";             // 1E // offset
-    JTrue => "jtrue";               // 1F // offset
-    Call => "call";                 // 20 // N
-    Ret => "ret";                   // 21
-    Pop => "pop";                   // 22
+    Push0 => "push_0", None, |_| 1;               // 00
+    Push1 => "push_1", None, |_| 1;               // 01
+    PushNull => "push_null", None, |_| 1;         // 02
+    PushFalse => "push_false", None, |_| 1;       // 03
+    PushTrue => "push_true", None, |_| 1;         // 04
+    PushInt => "push_int", Sleb, |_| 1;           // 05
+    PushConst => "push_const", Uleb, |_| 1;       // 06
+    Add => "add", None, |_| -1;                   // 07
+    Sub => "sub", None, |_| -1;                   // 08
+    Mul => "mul", None, |_| -1;                   // 09
+    Div => "div", None, |_| -1;                   // 0A
+    Mod => "mod", None, |_| -1;                   // 0B
+    Eq => "eq", None, |_| -1;                     // 0C
+    Ne => "ne", None, |_| -1;                     // 0D
+    Lt => "lt", None, |_| -1;                     // 0E
+    Le => "le", None, |_| -1;                     // 0F
+    Gt => "gt", None, |_| -1;                     // 10
+    Ge => "ge", None, |_| -1;                     // 11
+    Neg => "neg", None, |_| 0;                    // 12
+    Not => "not", None, |_| 0;                    // 13
+    Load0 => "load_0", None, |_| 1;               // 14
+    LoadLocal => "load_local", Uleb, |_| 1;       // 15
+    StoreLocal => "store_local", Uleb, |_| -1;    // 16
+    LoadGlobal => "load_global", Uleb, |_| 1;     // 17
+    StoreGlobal => "store_global", Uleb, |_| -1;  // 18
+    LoadBuiltin => "load_builtin", Uleb, |_| 1;   // 19
+    MakeList => "make_list", Uleb, |n| 1 - n;     // 1A
+    GetItem => "get_item", None, |_| -1;          // 1B
+    SetItem => "set_item", None, |_| -3;          // 1C
+    Jmp => "jmp", Label, |_| 0;                   // 1D
+    JFalse => "jfalse", Label, |_| -1;            // 1E
+    JTrue => "jtrue", Label, |_| -1;              // 1F
+    Call => "call", Uleb, |n| -n;                 // 20
+    Ret => "ret", None, |_| -1;                   // 21
+    Pop => "pop", None, |_| -1;                   // 22
+    Unpack => "unpack", Uleb, |n| n - 1;          // 23
+    GetSlice => "get_slice", None, |_| -2;        // 24
+    TryBegin => "try_begin", Label, |_| 0;        // 25
+    TryEnd => "try_end", None, |_| 0;             // 26
+    DeferBegin => "defer_begin", Label, |_| 0;    // 27
+    DeferEnd => "defer_end", None, |_| 0;         // 28
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_stats() {
+        let code = vec![
+            Opcode::PushConst.as_u8(),
+            0,
+            Opcode::PushInt.as_u8(),
+            0x80,
+            0x01,
+            Opcode::PushConst.as_u8(),
+            0,
+            Opcode::Add.as_u8(),
+            Opcode::Ret.as_u8(),
+        ];
+        let bc = Bytecode {
+            code,
+            constants: vec![Value::from_string("hi".into())],
+            line_table: Vec::new(),
+            globals: vec![Value::from_function(Rc::new(Function::UserDefined {
+                name: "main".into(),
+                param_count: 0,
+                max_slots: 0,
+                code_handle: 0,
+                location: None,
+            }))],
+            main_index: 0,
+            local_names: None,
+        };
+        let stats = bc.stats();
+        assert_eq!(
+            stats,
+            BytecodeStats {
+                code_bytes: 9,
+                instructions: 5,
+                functions: 1,
+                constants: 1,
+                string_constants: 1,
+                string_bytes: 2,
+                constant_loads: 2,
+            }
+        );
+        assert_eq!(
+            stats.to_string(),
+            "code:       9 bytes, 5 instructions in 1 functions\n\
+             constants:  1 (1 strings of 2 bytes, 0 floats), loaded by 2 instructions"
+        );
+    }
+
+    #[test]
+    fn test_stack_effect() {
+        assert_eq!(Opcode::Add.stack_effect(0), -1);
+        assert_eq!(Opcode::SetItem.stack_effect(0), -3);
+        // a call replaces the function and its arguments by the result
+        assert_eq!(Opcode::Call.stack_effect(2), -2);
+        assert_eq!(Opcode::MakeList.stack_effect(0), 1);
+        assert_eq!(Opcode::MakeList.stack_effect(3), -2);
+        assert_eq!(Opcode::Unpack.stack_effect(3), 2);
+    }
 }
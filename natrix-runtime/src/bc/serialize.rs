@@ -0,0 +1,523 @@
+use crate::bc::{Bytecode, CodeSpan, Library, LocalName, Opcode};
+use crate::error::{nx_err, NxResult};
+use crate::leb128::{decode_sleb128, decode_uleb128, encode_sleb128, encode_uleb128};
+use crate::value::{Builtin, Function, Value};
+use std::rc::Rc;
+
+/// The first bytes of a `.nxc` file.
+pub const MAGIC: &[u8; 4] = b"NXC\0";
+
+/// The first bytes of a `.nxlib` file.
+pub const LIBRARY_MAGIC: &[u8; 4] = b"NXL\0";
+
+/// Version of the layout of `.nxc` and `.nxlib` files, incremented whenever it changes.
+pub const FORMAT_VERSION: usize = 2;
+
+/// Feature bit of a `.nxc` file that has the names of locals (compiled with debug info).
+pub const FEATURE_DEBUG_INFO: usize = 1 << 0;
+
+/// The feature bits this version of the runtime can read. A file setting any other bit needs
+/// something this runtime does not have, and is rejected instead of being misread.
+pub const KNOWN_FEATURES: usize = FEATURE_DEBUG_INFO;
+
+// Tags of the values in the constant pool and the globals table
+const TAG_NULL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_FLOAT: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_BUILTIN: u8 = 6;
+const TAG_FUNCTION: u8 = 7;
+
+impl Bytecode {
+    /// Encodes the bytecode in the `.nxc` format, so that it can be run later without the
+    /// sources.
+    ///
+    /// The file starts with [`MAGIC`], [`FORMAT_VERSION`], the feature bits it uses (see
+    /// [`KNOWN_FEATURES`]), the number of opcodes of the instruction set and the names of the
+    /// builtins, which `load_builtin` refers to by index. They are followed by the code, the
+    /// constant pool, the globals table, the index of `main`, the line table and the names of the
+    /// locals if the bytecode has debug info. Numbers
+    /// are ULEB128 encoded. Only the values the compiler puts in the constant pool and the
+    /// globals table can be encoded, other values are an error.
+    pub fn serialize(&self) -> NxResult<Vec<u8>> {
+        let mut w = Writer(MAGIC.to_vec());
+        w.uleb(FORMAT_VERSION);
+        w.uleb(match self.local_names {
+            Some(_) => FEATURE_DEBUG_INFO,
+            None => 0,
+        });
+        w.uleb(Opcode::ALL.len());
+        w.uleb(Builtin::ALL.len());
+        for builtin in Builtin::ALL {
+            w.string(builtin.name());
+        }
+        w.bytes(&self.code);
+        w.uleb(self.constants.len());
+        for constant in &self.constants {
+            w.value(constant)?;
+        }
+        w.uleb(self.globals.len());
+        for global in &self.globals {
+            w.value(global)?;
+        }
+        w.uleb(self.main_index);
+        w.uleb(self.line_table.len());
+        for (offset, span) in &self.line_table {
+            w.uleb(*offset);
+            w.uleb(span.source_index);
+            w.uleb(span.start);
+            w.uleb(span.end);
+        }
+        if let Some(local_names) = &self.local_names {
+            w.uleb(local_names.len());
+            for local in local_names {
+                w.string(&local.name);
+                w.uleb(local.slot);
+                w.uleb(local.live.start);
+                w.uleb(local.live.end);
+            }
+        }
+        Ok(w.0)
+    }
+
+    /// Decodes bytecode written by [`Bytecode::serialize`] and verifies it.
+    ///
+    /// Files of another format version, using features or opcodes that this version of the
+    /// runtime does not know, or compiled with other builtins are rejected.
+    pub fn deserialize(bytes: &[u8]) -> NxResult<Bytecode> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(MAGIC.len())? != MAGIC {
+            return nx_err("not a natrix bytecode file");
+        }
+        let version = r.uleb()?;
+        if version != FORMAT_VERSION {
+            return nx_err(format!(
+                "unsupported bytecode format version {} (expected {})",
+                version, FORMAT_VERSION
+            ));
+        }
+        let features = r.uleb()?;
+        if features & !KNOWN_FEATURES != 0 {
+            return nx_err(format!(
+                "the bytecode uses unknown features (bits {:#x})",
+                features & !KNOWN_FEATURES
+            ));
+        }
+        let opcode_count = r.uleb()?;
+        if opcode_count > Opcode::ALL.len() {
+            return nx_err("the bytecode was compiled for a newer instruction set");
+        }
+        let builtin_count = r.uleb()?;
+        let builtins = (0..builtin_count)
+            .map(|_| r.string())
+            .collect::<NxResult<Vec<_>>>()?;
+        if !builtins
+            .iter()
+            .map(|name| &**name)
+            .eq(Builtin::ALL.iter().map(|b| b.name()))
+        {
+            return nx_err("the bytecode was compiled with other builtins");
+        }
+        let code = r.bytes()?.to_vec();
+        let constants = (0..r.uleb()?).map(|_| r.value()).collect::<NxResult<_>>()?;
+        let globals = (0..r.uleb()?).map(|_| r.value()).collect::<NxResult<_>>()?;
+        let main_index = r.uleb()?;
+        let line_table = (0..r.uleb()?)
+            .map(|_| {
+                let offset = r.uleb()?;
+                let span = CodeSpan {
+                    source_index: r.uleb()?,
+                    start: r.uleb()?,
+                    end: r.uleb()?,
+                };
+                Ok((offset, span))
+            })
+            .collect::<NxResult<_>>()?;
+        let local_names = match features & FEATURE_DEBUG_INFO {
+            0 => None,
+            _ => Some(
+                (0..r.uleb()?)
+                    .map(|_| {
+                        Ok(LocalName {
+                            name: r.string()?,
+                            slot: r.uleb()?,
+                            live: r.uleb()?..r.uleb()?,
+                        })
+                    })
+                    .collect::<NxResult<_>>()?,
+            ),
+        };
+        if r.pos != bytes.len() {
+            return nx_err("unexpected data after the bytecode");
+        }
+        let bc = Bytecode {
+            code,
+            constants,
+            line_table,
+            globals,
+            main_index,
+            local_names,
+        };
+        bc.verify(opcode_count)?;
+        bc.verify_local_names()?;
+        Ok(bc)
+    }
+
+    // The slot of each local must exist in the frame of the function holding its live range
+    fn verify_local_names(&self) -> NxResult<()> {
+        for local in self.local_names.iter().flatten() {
+            let in_range = self.globals.iter().any(|global| {
+                global.is_function()
+                    && matches!(global.unwrap_function().as_ref(),
+                        Function::UserDefined { max_slots, code_handle, .. }
+                            if local.slot < *max_slots
+                                && self.function_range(*code_handle).contains(&local.live.start))
+            });
+            if !in_range {
+                return nx_err(format!(
+                    "invalid slot {} of local {}",
+                    local.slot, local.name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Library {
+    /// Encodes the library in the `.nxlib` format: [`LIBRARY_MAGIC`], [`FORMAT_VERSION`], the
+    /// export table (the name and global index of each exported function) and the bytecode in
+    /// the `.nxc` format.
+    pub fn serialize(&self) -> NxResult<Vec<u8>> {
+        let mut w = Writer(LIBRARY_MAGIC.to_vec());
+        w.uleb(FORMAT_VERSION);
+        w.uleb(self.exports.len());
+        for (name, global) in &self.exports {
+            w.string(name);
+            w.uleb(*global);
+        }
+        w.bytes(&self.bc.serialize()?);
+        Ok(w.0)
+    }
+
+    /// Decodes a library written by [`Library::serialize`], verifying its bytecode and that
+    /// each export is a user-defined function.
+    pub fn deserialize(bytes: &[u8]) -> NxResult<Library> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.take(LIBRARY_MAGIC.len())? != LIBRARY_MAGIC {
+            return nx_err("not a natrix library file");
+        }
+        let version = r.uleb()?;
+        if version != FORMAT_VERSION {
+            return nx_err(format!(
+                "unsupported library format version {} (expected {})",
+                version, FORMAT_VERSION
+            ));
+        }
+        let exports: Vec<(Rc<str>, usize)> = (0..r.uleb()?)
+            .map(|_| Ok((r.string()?, r.uleb()?)))
+            .collect::<NxResult<_>>()?;
+        let bc = Bytecode::deserialize(r.bytes()?)?;
+        if r.pos != bytes.len() {
+            return nx_err("unexpected data after the library");
+        }
+        for (name, global) in &exports {
+            let is_function = bc.globals.get(*global).is_some_and(|global| {
+                global.is_function()
+                    && matches!(
+                        global.unwrap_function().as_ref(),
+                        Function::UserDefined { .. }
+                    )
+            });
+            if !is_function {
+                return nx_err(format!("export {} is not a function", name));
+            }
+        }
+        Ok(Library { bc, exports })
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn uleb(&mut self, value: usize) {
+        encode_uleb128(value, |byte| self.0.push(byte));
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.uleb(bytes.len());
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn string(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    fn value(&mut self, value: &Value) -> NxResult<()> {
+        if value.is_null() {
+            self.0.push(TAG_NULL);
+        } else if value.is_bool() {
+            self.0.push(if value.unwrap_bool() {
+                TAG_TRUE
+            } else {
+                TAG_FALSE
+            });
+        } else if value.is_int() {
+            self.0.push(TAG_INT);
+            encode_sleb128(value.unwrap_int(), |byte| self.0.push(byte));
+        } else if value.is_float() {
+            self.0.push(TAG_FLOAT);
+            self.0
+                .extend_from_slice(&value.unwrap_float().to_le_bytes());
+        } else if value.is_string() {
+            self.0.push(TAG_STRING);
+            self.string(&value.unwrap_string());
+        } else if value.is_function() {
+            match value.unwrap_function().as_ref() {
+                Function::Builtin(builtin) => {
+                    // by name, so that new builtins do not change the meaning of the file
+                    self.0.push(TAG_BUILTIN);
+                    self.string(builtin.name());
+                }
+                // only exists in the process that registered it
+                Function::Native { .. } => return nx_err(format!("cannot serialize {}", value)),
+                Function::UserDefined {
+                    name,
+                    param_count,
+                    max_slots,
+                    code_handle,
+                    location,
+                } => {
+                    self.0.push(TAG_FUNCTION);
+                    self.string(name);
+                    self.uleb(*param_count);
+                    self.uleb(*max_slots);
+                    self.uleb(*code_handle);
+                    match location {
+                        Some(location) => {
+                            self.uleb(1);
+                            self.string(location);
+                        }
+                        None => self.uleb(0),
+                    }
+                }
+            }
+        } else {
+            return nx_err(format!("cannot serialize {}", value));
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> NxResult<&'a [u8]> {
+        match self.bytes.get(self.pos..self.pos.saturating_add(len)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => nx_err("truncated bytecode file"),
+        }
+    }
+
+    fn byte(&mut self) -> NxResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    // The encoded number must end within the file and fit in 64 bits
+    fn leb(&mut self) -> NxResult<&'a [u8]> {
+        let rest = &self.bytes[self.pos..];
+        match rest.iter().take(10).position(|byte| byte & 0x80 == 0) {
+            Some(last) => self.take(last + 1),
+            None if rest.len() < 10 => nx_err("truncated bytecode file"),
+            None => nx_err("invalid number in bytecode file"),
+        }
+    }
+
+    fn uleb(&mut self) -> NxResult<usize> {
+        let mut bytes = self.leb()?.iter();
+        Ok(decode_uleb128(|| *bytes.next().unwrap()))
+    }
+
+    fn sleb(&mut self) -> NxResult<i64> {
+        let mut bytes = self.leb()?.iter();
+        Ok(decode_sleb128(|| *bytes.next().unwrap()))
+    }
+
+    fn bytes(&mut self) -> NxResult<&'a [u8]> {
+        let len = self.uleb()?;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> NxResult<Rc<str>> {
+        match std::str::from_utf8(self.bytes()?) {
+            Ok(s) => Ok(s.into()),
+            Err(_) => nx_err("invalid string in bytecode file"),
+        }
+    }
+
+    fn value(&mut self) -> NxResult<Value> {
+        Ok(match self.byte()? {
+            TAG_NULL => Value::NULL,
+            TAG_FALSE => Value::from_bool(false),
+            TAG_TRUE => Value::from_bool(true),
+            TAG_INT => Value::from_int(self.sleb()?),
+            TAG_FLOAT => {
+                let bytes = self.take(8)?.try_into().unwrap();
+                Value::from_float(f64::from_le_bytes(bytes))
+            }
+            TAG_STRING => Value::from_string(self.string()?),
+            TAG_BUILTIN => {
+                let name = self.string()?;
+                match Builtin::ALL.iter().find(|builtin| builtin.name() == &*name) {
+                    Some(builtin) => Value::from_function(Rc::new(Function::Builtin(*builtin))),
+                    None => return nx_err(format!("unknown builtin {}", name)),
+                }
+            }
+            TAG_FUNCTION => {
+                let name = self.string()?;
+                let param_count = self.uleb()?;
+                let max_slots = self.uleb()?;
+                let code_handle = self.uleb()?;
+                let location = match self.uleb()? {
+                    0 => None,
+                    _ => Some(self.string()?),
+                };
+                if param_count > max_slots {
+                    return nx_err(format!("function {} has more parameters than slots", name));
+                }
+                Value::from_function(Rc::new(Function::UserDefined {
+                    name: (*name).into(),
+                    param_count,
+                    max_slots,
+                    code_handle,
+                    location: location.map(|location| (*location).into()),
+                }))
+            }
+            tag => return nx_err(format!("invalid value tag {} in bytecode file", tag)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Bytecode {
+        let main = Function::UserDefined {
+            name: "main".into(),
+            param_count: 0,
+            max_slots: 1,
+            code_handle: 0,
+            location: Some("<string>:1".into()),
+        };
+        Bytecode {
+            code: vec![
+                Opcode::PushConst as u8,
+                0,
+                Opcode::StoreLocal as u8,
+                0,
+                Opcode::PushNull as u8,
+                Opcode::Ret as u8,
+            ],
+            constants: vec![Value::from_float(1.5), Value::from_string("s".into())],
+            line_table: vec![(
+                0,
+                CodeSpan {
+                    source_index: 0,
+                    start: 3,
+                    end: 7,
+                },
+            )],
+            globals: vec![
+                Value::from_function(Rc::new(main)),
+                Value::from_function(Rc::new(Function::Builtin(Builtin::Print))),
+            ],
+            main_index: 0,
+            local_names: Some(vec![LocalName {
+                name: "x".into(),
+                slot: 0,
+                live: 4..6,
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bc = sample();
+        let bytes = bc.serialize().unwrap();
+        assert_eq!(&bytes[..4], MAGIC);
+        let decoded = Bytecode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.inspect(), bc.inspect());
+        assert_eq!(decoded.local_names.unwrap()[0].live, 4..6);
+        let mut release = sample();
+        release.local_names = None;
+        let decoded = Bytecode::deserialize(&release.serialize().unwrap()).unwrap();
+        assert!(decoded.local_names.is_none());
+    }
+
+    #[test]
+    fn test_rejects_invalid_files() {
+        let bytes = sample().serialize().unwrap();
+        for len in 0..bytes.len() {
+            assert!(Bytecode::deserialize(&bytes[..len]).is_err());
+        }
+        let mut version = bytes.clone();
+        version[4] = 99;
+        assert_eq!(
+            Bytecode::deserialize(&version)
+                .unwrap_err()
+                .message
+                .as_ref(),
+            "unsupported bytecode format version 99 (expected 2)"
+        );
+        // the feature bits follow the version
+        let mut features = bytes.clone();
+        features[5] |= 0x40;
+        assert_eq!(
+            Bytecode::deserialize(&features)
+                .unwrap_err()
+                .message
+                .as_ref(),
+            "the bytecode uses unknown features (bits 0x40)"
+        );
+        let mut invalid_opcode = sample();
+        invalid_opcode.code[0] = 0xff;
+        let bytes = invalid_opcode.serialize().unwrap();
+        assert!(Bytecode::deserialize(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_library_round_trip() {
+        let library = Library {
+            bc: sample(),
+            exports: vec![("main".into(), 0)],
+        };
+        let bytes = library.serialize().unwrap();
+        assert_eq!(&bytes[..4], LIBRARY_MAGIC);
+        let decoded = Library::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.exports, library.exports);
+        assert_eq!(decoded.bc.inspect(), library.bc.inspect());
+        // a plain bytecode file is not a library
+        assert!(Library::deserialize(&library.bc.serialize().unwrap()).is_err());
+
+        let builtin = Library {
+            bc: sample(),
+            exports: vec![("print".into(), 1)],
+        };
+        assert_eq!(
+            Library::deserialize(&builtin.serialize().unwrap())
+                .unwrap_err()
+                .message
+                .as_ref(),
+            "export print is not a function"
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use crate::bc::{Bytecode, Immediate, Opcode};
+use crate::error::{nx_err, nx_error, NxResult};
+use crate::leb128::{decode_sleb128, decode_uleb128};
+use crate::value::{Builtin, Function};
+use std::ops::Range;
+
+// An encoded operand never needs more bytes than this, longer ones are rejected instead of
+// overflowing the decoder
+const MAX_OPERAND_LEN: usize = 10;
+
+impl Bytecode {
+    /// Checks that the code of every function can be executed safely.
+    ///
+    /// Only the first `opcode_count` opcodes are accepted, so that bytecode produced for an older
+    /// instruction set can be rejected when it uses newer opcodes. Operands must be complete and
+    /// refer to existing constants, builtins, globals and local slots, and jumps must target an
+    /// instruction of the same function. The operand stack must hold the values each instruction
+    /// takes and have one height at each instruction, whichever path leads there.
+    pub fn verify(&self, opcode_count: usize) -> NxResult<()> {
+        let opcode_count = opcode_count.min(Opcode::ALL.len());
+        let mut has_main = false;
+        for (index, global) in self.globals.iter().enumerate() {
+            if !global.is_function() {
+                continue;
+            }
+            if let Function::UserDefined {
+                name,
+                max_slots,
+                code_handle,
+                ..
+            } = global.unwrap_function().as_ref()
+            {
+                has_main |= index == self.main_index;
+                if *code_handle > self.code.len() {
+                    return nx_err(format!("function {} starts outside the code", name));
+                }
+                let range = self.function_range(*code_handle);
+                self.verify_function(range, *max_slots, opcode_count)
+                    .map_err(|err| nx_error(format!("function {}: {}", name, err.message)))?;
+            }
+        }
+        if !has_main {
+            return nx_err(format!(
+                "main index {} is not a user-defined function",
+                self.main_index
+            ));
+        }
+        Ok(())
+    }
+
+    fn verify_function(
+        &self,
+        range: Range<usize>,
+        max_slots: usize,
+        opcode_count: usize,
+    ) -> NxResult<()> {
+        let mut instructions = Vec::new();
+        let mut ip = range.start;
+        while ip < range.end {
+            let offset = ip;
+            let byte = self.code[ip];
+            ip += 1;
+            let opcode = match Opcode::from_u8(byte) {
+                Some(opcode) if (byte as usize) < opcode_count => opcode,
+                _ => return nx_err(format!("invalid opcode {:#04x} at offset {}", byte, offset)),
+            };
+            if matches!(opcode, Opcode::Load0) && max_slots == 0 {
+                return nx_err(format!("local slot 0 out of range at offset {}", offset));
+            }
+            let mut ins = Instruction {
+                offset,
+                opcode,
+                count: 0,
+                label: 0,
+                target: 0,
+            };
+            if opcode.has_operand() {
+                let bytes = self.operand(&mut ip, range.end, offset)?;
+                match opcode.immediate() {
+                    Immediate::None | Immediate::Sleb => {}
+                    Immediate::Uleb => {
+                        let index = decode_uleb128(fetch(bytes));
+                        match opcode {
+                            Opcode::PushConst => {
+                                check_index(index, self.constants.len(), "constant", offset)?
+                            }
+                            Opcode::LoadBuiltin => {
+                                check_index(index, Builtin::ALL.len(), "builtin", offset)?
+                            }
+                            Opcode::LoadGlobal | Opcode::StoreGlobal => {
+                                check_index(index, self.globals.len(), "global", offset)?
+                            }
+                            Opcode::LoadLocal | Opcode::StoreLocal => {
+                                check_index(index, max_slots, "local slot", offset)?
+                            }
+                            _ => ins.count = index,
+                        }
+                    }
+                    Immediate::Label => ins.label = offset as i64 + decode_sleb128(fetch(bytes)),
+                }
+            }
+            instructions.push(ins);
+        }
+        for i in 0..instructions.len() {
+            let ins = &instructions[i];
+            if ins.opcode.immediate() != Immediate::Label {
+                continue;
+            }
+            let target = usize::try_from(ins.label).ok().and_then(|label| {
+                instructions
+                    .binary_search_by_key(&label, |ins| ins.offset)
+                    .ok()
+            });
+            match target {
+                Some(target) => instructions[i].target = target,
+                None => {
+                    return nx_err(format!(
+                        "jump at offset {} to {} is not an instruction of the function",
+                        ins.offset, ins.label
+                    ));
+                }
+            }
+        }
+        check_stack_heights(&instructions)
+    }
+
+    // Skips the operand starting at `ip` and returns its bytes
+    fn operand(&self, ip: &mut usize, end: usize, offset: usize) -> NxResult<&[u8]> {
+        let start = *ip;
+        loop {
+            if *ip >= end || *ip - start == MAX_OPERAND_LEN {
+                return nx_err(format!("truncated operand at offset {}", offset));
+            }
+            let byte = self.code[*ip];
+            *ip += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(&self.code[start..*ip])
+    }
+}
+
+// A decoded instruction of a function being verified
+struct Instruction {
+    offset: usize,
+    opcode: Opcode,
+    count: usize,  // the operand of the instructions taking a number of values
+    label: i64,    // the offset a jump refers to
+    target: usize, // the index of the instruction at `label`
+}
+
+// The number of values an instruction takes from the operand stack, at least as many as its
+// effect removes
+fn stack_inputs(opcode: Opcode, count: usize) -> usize {
+    match opcode {
+        Opcode::Neg
+        | Opcode::Not
+        | Opcode::StoreLocal
+        | Opcode::StoreGlobal
+        | Opcode::JFalse
+        | Opcode::JTrue
+        | Opcode::Ret
+        | Opcode::Pop
+        | Opcode::Unpack => 1,
+        Opcode::Add
+        | Opcode::Sub
+        | Opcode::Mul
+        | Opcode::Div
+        | Opcode::Mod
+        | Opcode::Eq
+        | Opcode::Ne
+        | Opcode::Lt
+        | Opcode::Le
+        | Opcode::Gt
+        | Opcode::Ge
+        | Opcode::GetItem => 2,
+        Opcode::SetItem | Opcode::GetSlice => 3,
+        Opcode::MakeList => count,
+        Opcode::Call => count + 1, // the callee and its arguments
+        _ => 0,
+    }
+}
+
+// Follows every path through the code of a function, checking that no instruction takes more
+// values than the operand stack holds, that the stack has the same height on every path to an
+// instruction, and that no path runs past the last instruction. The handler of a `try_begin`
+// continues with the null of the failed expression on the stack, the one of a `defer_begin`
+// with the stack it was installed with.
+fn check_stack_heights(instructions: &[Instruction]) -> NxResult<()> {
+    let mut heights: Vec<Option<usize>> = vec![None; instructions.len()];
+    let mut paths = vec![(0, 0)];
+    while let Some((index, height)) = paths.pop() {
+        let Some(ins) = instructions.get(index) else {
+            return nx_err("the code runs past the end of the function");
+        };
+        match heights[index] {
+            Some(known) if known == height => continue,
+            Some(known) => {
+                return nx_err(format!(
+                    "stack height {} at offset {} differs from {} on another path",
+                    height, ins.offset, known
+                ));
+            }
+            None => heights[index] = Some(height),
+        }
+        let inputs = stack_inputs(ins.opcode, ins.count);
+        if height < inputs {
+            return nx_err(format!(
+                "{} at offset {} takes {} values from a stack of {}",
+                ins.opcode.name(),
+                ins.offset,
+                inputs,
+                height
+            ));
+        }
+        let after = (height as isize + ins.opcode.stack_effect(ins.count)) as usize;
+        match ins.opcode {
+            Opcode::Ret | Opcode::DeferEnd => {}
+            Opcode::Jmp => paths.push((ins.target, after)),
+            Opcode::JFalse | Opcode::JTrue => {
+                paths.extend([(ins.target, after), (index + 1, after)])
+            }
+            Opcode::TryBegin => paths.extend([(ins.target, height + 1), (index + 1, after)]),
+            Opcode::DeferBegin => paths.extend([(ins.target, height), (index + 1, after)]),
+            _ => paths.push((index + 1, after)),
+        }
+    }
+    Ok(())
+}
+
+fn fetch(bytes: &[u8]) -> impl FnMut() -> u8 + '_ {
+    let mut bytes = bytes.iter();
+    move || *bytes.next().unwrap()
+}
+
+fn check_index(index: usize, len: usize, what: &str, offset: usize) -> NxResult<()> {
+    if index < len {
+        Ok(())
+    } else {
+        nx_err(format!(
+            "{} {} out of range at offset {}",
+            what, index, offset
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+    use std::rc::Rc;
+
+    fn bytecode(code: Vec<u8>) -> Bytecode {
+        let main = Function::UserDefined {
+            name: "main".into(),
+            param_count: 0,
+            max_slots: 1,
+            code_handle: 0,
+            location: None,
+        };
+        Bytecode {
+            code,
+            constants: vec![Value::from_float(1.5)],
+            line_table: Vec::new(),
+            globals: vec![Value::from_function(Rc::new(main))],
+            main_index: 0,
+            local_names: None,
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let count = Opcode::ALL.len();
+        let ret = Opcode::Ret.as_u8();
+        let valid = vec![
+            Opcode::PushTrue.as_u8(),
+            Opcode::JTrue.as_u8(),
+            2,
+            Opcode::PushConst.as_u8(),
+            0,
+            ret,
+        ];
+        assert!(bytecode(valid.clone()).verify(count).is_ok());
+
+        let errors = [
+            (vec![0x7f], count, "invalid opcode 0x7f at offset 0"),
+            (
+                valid.clone(),
+                ret as usize,
+                "invalid opcode 0x21 at offset 5",
+            ),
+            (
+                vec![Opcode::PushInt.as_u8(), 0x80],
+                count,
+                "truncated operand at offset 0",
+            ),
+            (
+                vec![Opcode::PushConst.as_u8(), 1, ret],
+                count,
+                "constant 1 out of range at offset 0",
+            ),
+            (
+                vec![Opcode::StoreLocal.as_u8(), 1, ret],
+                count,
+                "local slot 1 out of range at offset 0",
+            ),
+            (
+                vec![Opcode::Jmp.as_u8(), 1, ret],
+                count,
+                "jump at offset 0 to 1 is not an instruction of the function",
+            ),
+            (
+                vec![Opcode::Jmp.as_u8(), 0x7e, ret],
+                count,
+                "jump at offset 0 to -2 is not an instruction of the function",
+            ),
+            (
+                vec![Opcode::Push1.as_u8(), Opcode::Call.as_u8(), 1, ret],
+                count,
+                "call at offset 1 takes 2 values from a stack of 1",
+            ),
+            (
+                vec![Opcode::Ret.as_u8()],
+                count,
+                "ret at offset 0 takes 1 values from a stack of 0",
+            ),
+            (
+                // the value pushed on one path is still on the stack where they meet
+                vec![
+                    Opcode::PushTrue.as_u8(),
+                    Opcode::JTrue.as_u8(),
+                    3,
+                    Opcode::Push1.as_u8(),
+                    Opcode::Push0.as_u8(),
+                    ret,
+                ],
+                count,
+                "stack height 0 at offset 4 differs from 1 on another path",
+            ),
+            (
+                vec![Opcode::Push1.as_u8(), Opcode::Pop.as_u8()],
+                count,
+                "the code runs past the end of the function",
+            ),
+        ];
+        for (code, opcode_count, message) in errors {
+            let err = bytecode(code).verify(opcode_count).unwrap_err();
+            assert_eq!(err.message.as_ref(), format!("function main: {}", message));
+        }
+    }
+}
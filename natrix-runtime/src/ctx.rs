@@ -1,28 +1,747 @@
+use crate::error::{nx_err_of, ErrorKind, NxResult};
+use crate::plugin::NxNativeFn;
+use crate::value::{FileMode, FileStream, Function, NativeCode, NativeFn, Value};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::fmt::Write;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write as _};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Everything a running program can observe of its host: the output, input, clock, random
+/// numbers, environment variables and files.
+///
+/// By default these are the real stdout, stdin, system clock, process environment and file
+/// system, though the file system can only be used with the capabilities granted by the host.
+/// Tests replace them with fixed values to make the output of programs deterministic.
 pub struct RuntimeContext {
-    output: Option<String>,
+    output: Output,
+    input: Box<dyn BufRead>,
+    clock: Clock,
+    rng_state: u64,
+    env: Option<HashMap<String, String>>, // None reads the environment of the process
+    files: Option<Rc<RefCell<MemoryFs>>>, // None uses the file system
+    capabilities: Vec<Capability>,
+    interrupts: Option<Arc<Interrupts>>,
+    interrupt_handler: Option<Value>, // registered with on_interrupt
+    mem_stats: MemStats,
+    strict: bool,
+    warn_numeric_eq: bool,
+    log_level: LogLevel,
+    log_sink: Box<dyn io::Write>,
+    args: Value,
+    natives: Vec<Rc<Function>>, // see `register_native`
+}
+
+// Where `print` writes
+enum Output {
+    Stdout,                     // diagnostics go to stderr
+    Captured(String),           // along with diagnostics, see `take_output`
+    Writer(Box<dyn io::Write>), // diagnostics go to stderr, see `set_output`
+}
+
+enum Clock {
+    /// Seconds since the Unix epoch, measured from the creation of the context with a monotonic
+    /// clock so that time_it never gets negative durations.
+    System { start: Instant, start_epoch: f64 },
+    /// Starts at `now` and advances by `step` seconds every time it is read.
+    Fixed { now: f64, step: f64 },
+}
+
+/// Access to the host that a program only has if the host grants it, see
+/// [`RuntimeContext::grant`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Capability {
+    /// Reading files and listing directories.
+    Read,
+    /// Creating, writing and removing files and directories.
+    Write,
+}
+
+/// Interrupts of the process (Ctrl-C), raised by the signal handler of the host and handled by
+/// the program at the next safepoint, see [`RuntimeContext::set_interrupts`].
+#[derive(Debug, Default)]
+pub struct Interrupts {
+    pending: AtomicBool,
+    handled: AtomicBool, // the program registered a handler
+}
+
+impl Interrupts {
+    pub const fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            handled: AtomicBool::new(false),
+        }
+    }
+
+    /// Records an interrupt, only using atomics so it can be called from a signal handler.
+    ///
+    /// Returns false if the program cannot handle it, because it registered no handler or has
+    /// not handled the previous interrupt yet. The host should then stop the process, which makes
+    /// a second Ctrl-C a way out of a program stuck outside of a safepoint.
+    pub fn raise(&self) -> bool {
+        let was_pending = self.pending.swap(true, Ordering::SeqCst);
+        self.handled.load(Ordering::SeqCst) && !was_pending
+    }
+}
+
+// The files set with `RuntimeContext::set_files`, and the directories created since
+#[derive(Default)]
+struct MemoryFs {
+    files: HashMap<String, String>,
+    dirs: HashSet<String>,
+}
+
+/// Severity of a message of the `log_*` builtins. Messages below the level of the context are
+/// dropped.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+    Off, // only as the level of the context, drops every message
+}
+
+impl LogLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+            LogLevel::Off => "off",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(LogLevel::Debug),
+            "info" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            "off" => Ok(LogLevel::Off),
+            _ => Err(format!("unknown log level {:?}", s)),
+        }
+    }
+}
+
+/// Number and size of heap values (strings and lists) allocated by a running program.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct MemStats {
+    pub string_count: u64,
+    pub string_bytes: u64,
+    pub list_count: u64,
+    pub list_bytes: u64, // size of the element slots, not including the elements themselves
 }
 
 impl RuntimeContext {
     pub fn new() -> Self {
-        Self { output: None }
+        Self::with_output(Output::Stdout)
     }
 
+    /// Creates a context collecting the output in a string, see `take_output`.
+    ///
+    /// Log messages are discarded unless a sink is set with `set_log_sink`.
     pub fn with_capture() -> Self {
+        let mut rt = Self::with_output(Output::Captured(String::new()));
+        rt.log_sink = Box::new(io::sink());
+        rt
+    }
+
+    fn with_output(output: Output) -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time is before UNIX epoch");
         Self {
-            output: Some(String::new()),
+            output,
+            input: Box::new(BufReader::new(io::stdin())),
+            clock: Clock::System {
+                start: Instant::now(),
+                start_epoch: since_epoch.as_secs_f64(),
+            },
+            rng_state: since_epoch.as_nanos() as u64 | 1,
+            env: None,
+            files: None,
+            capabilities: Vec::new(),
+            interrupts: None,
+            interrupt_handler: None,
+            mem_stats: MemStats::default(),
+            strict: false,
+            warn_numeric_eq: false,
+            log_level: LogLevel::default(),
+            log_sink: Box::new(io::stderr()),
+            args: Value::from_list(Rc::new(Vec::new())),
+            natives: Vec::new(),
+        }
+    }
+
+    /// Adds a function of the host that programs call by `name` like a built-in function, with
+    /// `arity` arguments. Registering a name again replaces its function.
+    ///
+    /// The compiler only knows the natives it is told about, see
+    /// `CompilerContext::declare_natives`, which should be called after registering them.
+    pub fn register_native(&mut self, name: &str, arity: usize, fun: NativeFn) {
+        self.add_native(name, arity, NativeCode::Host(fun));
+    }
+
+    /// Adds a function of a plugin, see `plugin::register_plugin`.
+    pub(crate) fn register_plugin_native(&mut self, name: &str, arity: usize, fun: NxNativeFn) {
+        self.add_native(name, arity, NativeCode::Plugin(fun));
+    }
+
+    fn add_native(&mut self, name: &str, arity: usize, fun: NativeCode) {
+        let native = Rc::new(Function::Native {
+            name: name.into(),
+            param_count: arity,
+            fun,
+        });
+        match self.natives.iter_mut().find(|n| n.name() == name) {
+            Some(existing) => *existing = native,
+            None => self.natives.push(native),
+        }
+    }
+
+    /// The functions added with `register_native`, in the order of their first registration.
+    pub fn natives(&self) -> &[Rc<Function>] {
+        &self.natives
+    }
+
+    /// Sets the list of command line arguments seen by the program as `ARGS`, empty by default.
+    pub fn set_args(&mut self, args: Value) {
+        self.args = args;
+    }
+
+    pub fn args(&self) -> Value {
+        self.args.clone()
+    }
+
+    /// Replaces stdout as the destination of what the program prints, e.g. with a file or a
+    /// buffer shared with the host. Diagnostics still go to stderr.
+    pub fn set_output(&mut self, output: Box<dyn io::Write>) {
+        self.output = Output::Writer(output);
+    }
+
+    /// Replaces stdin, e.g. with `io::Cursor::new("first line\nsecond line\n")`.
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = input;
+    }
+
+    /// Replaces the system clock by one that starts at `now` and advances by `step` seconds
+    /// every time it is read.
+    pub fn set_fixed_clock(&mut self, now: f64, step: f64) {
+        self.clock = Clock::Fixed { now, step };
+    }
+
+    /// Restarts the random number generator from `seed` instead of a seed based on the time.
+    pub fn set_seed(&mut self, seed: u64) {
+        // xorshift gets stuck at zero
+        self.rng_state = seed.max(1);
+    }
+
+    /// Replaces the environment variables of the process.
+    pub fn set_env(&mut self, env: HashMap<String, String>) {
+        self.env = Some(env);
+    }
+
+    /// Replaces the file system by files held in memory, keyed by path. Files opened for writing
+    /// and directories created by the program are added to them.
+    pub fn set_files(&mut self, files: HashMap<String, String>) {
+        self.files = Some(Rc::new(RefCell::new(MemoryFs {
+            files,
+            dirs: HashSet::new(),
+        })));
+    }
+
+    /// Delivers the interrupts raised on `interrupts` to the program, which ignores them by
+    /// default.
+    pub fn set_interrupts(&mut self, interrupts: Arc<Interrupts>) {
+        interrupts
+            .handled
+            .store(self.interrupt_handler.is_some(), Ordering::SeqCst);
+        self.interrupts = Some(interrupts);
+    }
+
+    /// Sets the function the interpreters call when the process is interrupted, or removes it.
+    pub fn set_interrupt_handler(&mut self, handler: Option<Value>) {
+        if let Some(interrupts) = &self.interrupts {
+            interrupts
+                .handled
+                .store(handler.is_some(), Ordering::SeqCst);
+        }
+        self.interrupt_handler = handler;
+    }
+
+    /// The handler to call if an interrupt is pending, which is then no longer pending. Called
+    /// by the interpreters at their safepoints: the start of a function and the end of an
+    /// iteration of a loop.
+    pub fn take_interrupt(&mut self) -> Option<Value> {
+        let interrupts = self.interrupts.as_ref()?;
+        let handler = self.interrupt_handler.as_ref()?;
+        if interrupts.pending.load(Ordering::Relaxed)
+            && interrupts.pending.swap(false, Ordering::SeqCst)
+        {
+            Some(handler.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Allows the program to use the file system, which it cannot by default.
+    pub fn grant(&mut self, capability: Capability) {
+        if !self.capabilities.contains(&capability) {
+            self.capabilities.push(capability);
+        }
+    }
+
+    /// Fails unless `capability` was granted.
+    pub fn require(&self, capability: Capability) -> NxResult<()> {
+        if self.capabilities.contains(&capability) {
+            return Ok(());
+        }
+        let action = match capability {
+            Capability::Read => "reading files",
+            Capability::Write => "writing files",
+        };
+        nx_err_of(
+            ErrorKind::Permission,
+            format!("{} was not allowed by the host", action),
+        )
+    }
+
+    /// Opens a file for the `open` builtin.
+    pub fn open(&mut self, path: &str, mode: FileMode) -> io::Result<FileStream> {
+        let Some(files) = &self.files else {
+            return Ok(match mode {
+                FileMode::Read => {
+                    FileStream::Reader(Box::new(BufReader::new(fs::File::open(path)?)))
+                }
+                FileMode::Write => {
+                    FileStream::Writer(Box::new(BufWriter::new(fs::File::create(path)?)))
+                }
+                FileMode::Append => {
+                    let file = OpenOptions::new().append(true).create(true).open(path)?;
+                    FileStream::Writer(Box::new(BufWriter::new(file)))
+                }
+            });
+        };
+        let mut memory = files.borrow_mut();
+        match mode {
+            FileMode::Read => match memory.files.get(path) {
+                Some(content) => Ok(FileStream::Reader(Box::new(io::Cursor::new(
+                    content.clone().into_bytes(),
+                )))),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+            },
+            FileMode::Write | FileMode::Append => {
+                let content = memory.files.entry(path.to_string()).or_default();
+                if mode == FileMode::Write {
+                    content.clear();
+                }
+                Ok(FileStream::Writer(Box::new(MemoryFile {
+                    files: files.clone(),
+                    path: path.to_string(),
+                })))
+            }
+        }
+    }
+
+    /// The names of the entries of a directory, sorted.
+    pub fn list_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        let Some(files) = &self.files else {
+            let mut names = fs::read_dir(path)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<io::Result<Vec<_>>>()?;
+            names.sort();
+            return Ok(names);
+        };
+        let memory = files.borrow();
+        let prefix = match path.trim_start_matches("./").trim_end_matches('/') {
+            "" | "." => String::new(),
+            dir => format!("{}/", dir),
+        };
+        let names: BTreeSet<&str> = memory
+            .files
+            .keys()
+            .chain(&memory.dirs)
+            .filter_map(|entry| entry.strip_prefix(&prefix))
+            .map(|rest| rest.split('/').next().unwrap())
+            .collect();
+        if names.is_empty()
+            && !prefix.is_empty()
+            && !memory.dirs.contains(&prefix[..prefix.len() - 1])
+        {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no such directory"));
+        }
+        Ok(names.into_iter().map(String::from).collect())
+    }
+
+    /// Creates a directory and its missing parents, nothing if it already exists.
+    pub fn mkdir(&mut self, path: &str) -> io::Result<()> {
+        match &self.files {
+            Some(files) => {
+                let mut memory = files.borrow_mut();
+                let mut dir = String::new();
+                for part in path.trim_end_matches('/').split('/') {
+                    if !dir.is_empty() {
+                        dir.push('/');
+                    }
+                    dir.push_str(part);
+                    if memory.files.contains_key(&dir) {
+                        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "is a file"));
+                    }
+                    memory.dirs.insert(dir.clone());
+                }
+                Ok(())
+            }
+            None => fs::create_dir_all(path),
+        }
+    }
+
+    pub fn remove_file(&mut self, path: &str) -> io::Result<()> {
+        match &self.files {
+            Some(files) => match files.borrow_mut().files.remove(path) {
+                Some(_) => Ok(()),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no such file")),
+            },
+            None => fs::remove_file(path),
+        }
+    }
+
+    /// Reads a line of input without the line terminator, `None` at the end of input.
+    pub fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+        }
+    }
+
+    /// Current time in seconds since the Unix epoch.
+    pub fn now(&mut self) -> f64 {
+        match &mut self.clock {
+            Clock::System { start, start_epoch } => *start_epoch + start.elapsed().as_secs_f64(),
+            Clock::Fixed { now, step } => {
+                let time = *now;
+                *now += *step;
+                time
+            }
+        }
+    }
+
+    /// Next pseudo-random number (xorshift64*), not suitable for cryptography.
+    pub fn random(&mut self) -> u64 {
+        let mut x = self.rng_state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng_state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn env_var(&self, name: &str) -> Option<String> {
+        match &self.env {
+            Some(env) => env.get(name).cloned(),
+            None => std::env::var(name).ok(),
+        }
+    }
+
+    /// Enables the extra checks of strict mode in operators, see `BinaryOp::check_strict`.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Enables warnings for `==` and `!=` between an int and a float, see
+    /// `BinaryOp::numeric_eq_warning`.
+    pub fn set_warn_numeric_eq(&mut self, warn: bool) {
+        self.warn_numeric_eq = warn;
+    }
+
+    pub fn warns_numeric_eq(&self) -> bool {
+        self.warn_numeric_eq
+    }
+
+    /// Drops log messages below `level`.
+    pub fn set_log_level(&mut self, level: LogLevel) {
+        self.log_level = level;
+    }
+
+    /// Replaces the destination of log messages, stderr by default. Logs never go to the output,
+    /// so that they do not mix with what the program prints.
+    pub fn set_log_sink(&mut self, sink: Box<dyn io::Write>) {
+        self.log_sink = sink;
+    }
+
+    /// Writes `message` to the log sink prefixed with its level, if the level is enabled.
+    pub fn log(&mut self, level: LogLevel, message: &str) {
+        if level >= self.log_level && level != LogLevel::Off {
+            // a log that cannot be written is not a reason to stop the program
+            let _ = writeln!(self.log_sink, "[{}] {}", level.name(), message);
+        }
+    }
+
+    /// Reports a diagnostic on stderr, or in the captured output so that tests can check it.
+    pub fn warn(&mut self, message: &str) {
+        match &mut self.output {
+            Output::Captured(output) => writeln!(output, "{}", message).unwrap(),
+            Output::Stdout | Output::Writer(_) => eprintln!("{}", message),
         }
     }
 
     pub fn write(&mut self, value: &str) {
         match &mut self.output {
-            Some(output) => writeln!(output, "{}", value).unwrap(),
-            None => println!("{}", value),
+            Output::Stdout => println!("{}", value),
+            Output::Captured(output) => writeln!(output, "{}", value).unwrap(),
+            // like println!, which fails when stdout is closed
+            Output::Writer(output) => writeln!(output, "{}", value).expect("failed writing output"),
         }
     }
 
     pub fn take_output(self) -> String {
-        self.output
-            .expect("Runtime was not configured to capture output")
+        match self.output {
+            Output::Captured(output) => output,
+            _ => panic!("Runtime was not configured to capture output"),
+        }
+    }
+
+    /// Counts `value` as an allocation if it is a string or list that has just been created.
+    ///
+    /// Interpreters call this for the results of operations that may allocate. A heap value
+    /// that is not referenced from anywhere else must be new, so existing values passing
+    /// through (e.g. a list element returned by indexing) are not counted again.
+    pub fn track_alloc(&mut self, value: &Value) {
+        if let Some(bytes) = value.unique_heap_size() {
+            if value.is_string() {
+                self.mem_stats.string_count += 1;
+                self.mem_stats.string_bytes += bytes as u64;
+            } else {
+                self.mem_stats.list_count += 1;
+                self.mem_stats.list_bytes += bytes as u64;
+            }
+        }
+    }
+
+    pub fn mem_stats(&self) -> MemStats {
+        self.mem_stats
+    }
+}
+
+// A file of the ones set with `set_files`, appended to in place
+struct MemoryFile {
+    files: Rc<RefCell<MemoryFs>>,
+    path: String,
+}
+
+impl io::Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let s =
+            std::str::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.files
+            .borrow_mut()
+            .files
+            .entry(self.path.clone())
+            .or_default()
+            .push_str(s);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Display for MemStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "strings: {} allocations, {} bytes",
+            self.string_count, self.string_bytes
+        )?;
+        write!(
+            f,
+            "lists: {} allocations, {} bytes",
+            self.list_count, self.list_bytes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_injected_host() {
+        let mut rt = RuntimeContext::with_capture();
+        rt.set_input(Box::new(io::Cursor::new("first\r\nsecond")));
+        assert_eq!(rt.read_line().as_deref(), Some("first"));
+        assert_eq!(rt.read_line().as_deref(), Some("second"));
+        assert_eq!(rt.read_line(), None);
+
+        rt.set_fixed_clock(10.0, 0.5);
+        assert_eq!((rt.now(), rt.now()), (10.0, 10.5));
+
+        rt.set_seed(7);
+        let first: Vec<u64> = (0..3).map(|_| rt.random()).collect();
+        rt.set_seed(7);
+        let second: Vec<u64> = (0..3).map(|_| rt.random()).collect();
+        assert_eq!(first, second);
+
+        rt.set_env(HashMap::from([("HOME".to_string(), "/nx".to_string())]));
+        assert_eq!(rt.env_var("HOME").as_deref(), Some("/nx"));
+        assert_eq!(rt.env_var("PATH"), None);
+    }
+
+    #[test]
+    fn test_files() {
+        let read_all = |rt: &mut RuntimeContext, path: &str| {
+            let FileStream::Reader(mut reader) = rt.open(path, FileMode::Read).unwrap() else {
+                panic!("expected a reader");
+            };
+            let mut content = String::new();
+            reader.read_to_string(&mut content).unwrap();
+            content
+        };
+        let write = |rt: &mut RuntimeContext, path: &str, mode: FileMode, s: &str| {
+            let FileStream::Writer(mut writer) = rt.open(path, mode).unwrap() else {
+                panic!("expected a writer");
+            };
+            writer.write_all(s.as_bytes()).unwrap();
+        };
+
+        let path = std::env::temp_dir().join(format!("natrix-test-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut rt = RuntimeContext::new();
+        write(&mut rt, path, FileMode::Write, "one\n");
+        write(&mut rt, path, FileMode::Append, "two\n");
+        assert_eq!(read_all(&mut rt, path), "one\ntwo\n");
+        fs::remove_file(path).unwrap();
+        assert!(rt.open(path, FileMode::Read).is_err());
+
+        rt.set_files(HashMap::from([("a.txt".to_string(), "old".to_string())]));
+        write(&mut rt, "a.txt", FileMode::Append, "er");
+        assert_eq!(read_all(&mut rt, "a.txt"), "older");
+        write(&mut rt, "a.txt", FileMode::Write, "new");
+        assert_eq!(read_all(&mut rt, "a.txt"), "new");
+        assert!(rt.open(path, FileMode::Read).is_err());
+
+        rt.mkdir("logs/old/").unwrap();
+        write(&mut rt, "logs/run.log", FileMode::Write, "");
+        assert_eq!(rt.list_dir(".").unwrap(), ["a.txt", "logs"]);
+        assert_eq!(rt.list_dir("logs").unwrap(), ["old", "run.log"]);
+        assert!(rt.list_dir("logs/old").unwrap().is_empty());
+        assert!(rt.list_dir("missing").is_err());
+        assert!(rt.mkdir("a.txt/b").is_err());
+        rt.remove_file("logs/run.log").unwrap();
+        assert!(rt.remove_file("logs/run.log").is_err());
+        assert_eq!(rt.list_dir("logs").unwrap(), ["old"]);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let mut rt = RuntimeContext::new();
+        let err = rt.require(Capability::Write).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Permission);
+        assert_eq!(
+            err.message.as_ref(),
+            "writing files was not allowed by the host"
+        );
+        rt.grant(Capability::Write);
+        assert!(rt.require(Capability::Write).is_ok());
+        assert!(rt.require(Capability::Read).is_err());
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let interrupts = Arc::new(Interrupts::new());
+        let mut rt = RuntimeContext::new();
+        rt.set_interrupts(interrupts.clone());
+        // without a handler the host stops the process
+        assert!(!interrupts.raise());
+        assert!(rt.take_interrupt().is_none());
+
+        rt.set_interrupt_handler(Some(Value::from_int(1)));
+        assert!(rt.take_interrupt().is_some());
+        assert!(rt.take_interrupt().is_none());
+        assert!(interrupts.raise());
+        // a second interrupt before the first one is handled
+        assert!(!interrupts.raise());
+        assert!(rt.take_interrupt().is_some());
+
+        rt.set_interrupt_handler(None);
+        assert!(!interrupts.raise());
+    }
+
+    #[derive(Clone, Default)]
+    struct Sink(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output_writer() {
+        let sink = Sink::default();
+        let mut rt = RuntimeContext::new();
+        rt.set_output(Box::new(sink.clone()));
+        rt.write("first");
+        rt.warn("not part of the output");
+        rt.write("second");
+        assert_eq!(String::from_utf8(sink.0.take()).unwrap(), "first\nsecond\n");
+    }
+
+    #[test]
+    fn test_log() {
+        let sink = Sink::default();
+        let mut rt = RuntimeContext::with_capture();
+        rt.set_log_sink(Box::new(sink.clone()));
+        rt.set_log_level("warn".parse().unwrap());
+        rt.log(LogLevel::Info, "dropped");
+        rt.log(LogLevel::Warn, "low disk");
+        rt.log(LogLevel::Error, "failed");
+        rt.write("printed");
+        assert_eq!(
+            String::from_utf8(sink.0.take()).unwrap(),
+            "[warn] low disk\n[error] failed\n"
+        );
+        assert_eq!(rt.take_output(), "printed\n");
+        assert!("verbose".parse::<LogLevel>().is_err());
+    }
+
+    #[test]
+    fn test_register_native() {
+        let mut rt = RuntimeContext::new();
+        rt.register_native("answer", 0, |_, _| Ok(Value::from_int(41)));
+        rt.register_native("twice", 1, |_, args| {
+            Ok(Value::from_int(2 * args[0].unwrap_int()))
+        });
+        rt.register_native("answer", 0, |_, _| Ok(Value::from_int(42)));
+        let names: Vec<&str> = rt.natives().iter().map(|n| n.name()).collect();
+        assert_eq!(names, ["answer", "twice"]);
+        let Function::Native { fun, .. } = rt.natives()[0].as_ref() else {
+            panic!("expected a native function");
+        };
+        let fun = *fun;
+        assert_eq!(fun.call(&mut rt, &[]).unwrap().unwrap_int(), 42);
     }
 }
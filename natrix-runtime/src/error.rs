@@ -1,14 +1,68 @@
+use crate::bc::CodeSpan;
+
+/// Classification of runtime errors, so that hosts (and scripts, once they can catch errors)
+/// can tell them apart without parsing the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A value of the wrong type, including calls of a non-function or with the wrong number of
+    /// arguments.
+    Type,
+    /// An index that is negative or out of bounds.
+    Index,
+    /// An integer division or remainder by zero.
+    DivisionByZero,
+    /// An integer overflow in strict mode, or a result too large to allocate.
+    Overflow,
+    /// A value of the right type that cannot be used, like `int("x")`.
+    Value,
+    /// A file that cannot be opened, read or written.
+    Io,
+    /// An access to the host that was not granted, see `RuntimeContext::grant`.
+    Permission,
+    /// Any other error, including all errors reported before running.
+    Other,
+}
+
+impl ErrorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Type => "TypeError",
+            ErrorKind::Index => "IndexError",
+            ErrorKind::DivisionByZero => "DivisionByZero",
+            ErrorKind::Overflow => "OverflowError",
+            ErrorKind::Value => "ValueError",
+            ErrorKind::Io => "IOError",
+            ErrorKind::Permission => "PermissionError",
+            ErrorKind::Other => "Error",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct NxError {
+    pub kind: ErrorKind,
     pub message: Box<str>,
+    /// The source location of the instruction that failed, set by the bytecode interpreter from
+    /// the line table of the bytecode.
+    pub span: Option<CodeSpan>,
+    /// The calls in progress when the error occurred, innermost first: the name of the called
+    /// function and the location of the call, set by the bytecode interpreter.
+    pub trace: Vec<(Box<str>, Option<CodeSpan>)>,
 }
 
 pub type NxResult<T> = Result<T, NxError>;
 
 impl NxError {
     pub fn new(msg: impl Into<Box<str>>) -> Self {
+        Self::with_kind(ErrorKind::Other, msg)
+    }
+
+    pub fn with_kind(kind: ErrorKind, msg: impl Into<Box<str>>) -> Self {
         NxError {
+            kind,
             message: msg.into(),
+            span: None,
+            trace: Vec::new(),
         }
     }
 }
@@ -20,3 +74,11 @@ pub fn nx_err<T>(message: impl Into<Box<str>>) -> NxResult<T> {
 pub fn nx_error(message: impl Into<Box<str>>) -> NxError {
     NxError::new(message)
 }
+
+pub fn nx_err_of<T>(kind: ErrorKind, message: impl Into<Box<str>>) -> NxResult<T> {
+    Err(NxError::with_kind(kind, message))
+}
+
+pub fn nx_error_of(kind: ErrorKind, message: impl Into<Box<str>>) -> NxError {
+    NxError::with_kind(kind, message)
+}
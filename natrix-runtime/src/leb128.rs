@@ -147,6 +147,35 @@ where
     }
 }
 
+/// Encodes a signed value as SLEB128 using at least `min_len` bytes.
+///
+/// Shorter encodings are padded with continuation bytes carrying the sign, which decode to the
+/// same value. This lets a value keep its size when it is re-encoded with a smaller magnitude.
+///
+/// # Example
+/// ```
+/// # use natrix_runtime::leb128::encode_sleb128_padded;
+/// let mut output = Vec::new();
+/// encode_sleb128_padded(-2, 3, |byte| output.push(byte));
+/// assert_eq!(output, [0xfe, 0xff, 0x7f]);
+/// ```
+#[inline]
+pub fn encode_sleb128_padded<F>(value: i64, min_len: usize, mut emit: F)
+where
+    F: FnMut(u8),
+{
+    let len = sleb128_len(value).max(min_len);
+    let mut value = value;
+    for i in 0..len {
+        let mut byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if i + 1 < len {
+            byte |= 0x80; // Set continuation bit
+        }
+        emit(byte);
+    }
+}
+
 /// Computes the number of bytes required to encode a value as ULEB128.
 ///
 /// # Example
@@ -374,6 +403,18 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sleb128_padded_roundtrip() {
+        for value in [0, 1, -1, 63, -64, 64, -65, 8191, -8192, i64::MAX, i64::MIN] {
+            for min_len in [1, 2, 3, 10] {
+                let mut bytes = Vec::new();
+                encode_sleb128_padded(value, min_len, |b| bytes.push(b));
+                assert_eq!(bytes.len(), sleb128_len(value).max(min_len));
+                assert_eq!(decode_sleb_from_slice(&bytes), (value, bytes.len()));
+            }
+        }
+    }
+
     #[test]
     fn test_decode_with_trailing_bytes() {
         // Ensure decoder stops at the right place and doesn't consume extra bytes
@@ -2,4 +2,7 @@ pub mod bc;
 pub mod ctx;
 pub mod error;
 pub mod leb128;
+pub mod plugin;
+#[cfg(feature = "profile-alloc")]
+pub mod profile;
 pub mod value;
@@ -0,0 +1,450 @@
+// The interface of plugins, shared libraries adding native functions to the runtime.
+//
+// The interface is a C ABI, so that a plugin does not depend on the compiler, the version of
+// natrix-runtime or even the language it was built with. A plugin exports a static `NxPlugin`
+// named `natrix_plugin`, whose first field is the version of the ABI it was built for. The host
+// reads only that field before checking it, and calls `register` only if it is its own version.
+// Everything the two sides exchange afterwards is `#[repr(C)]`: values are passed as `NxValue`
+// handles (null, bool, int, float or a borrowed string), and the host hands out tables of its own
+// functions (`NxRegistry`, `NxCall`) that start with the version too. A change to any of these
+// types must increment `PLUGIN_ABI_VERSION`.
+//
+// Rust plugins declare their entry point with `export_plugin!`, the safe methods of the handles
+// spare them most of the raw pointers.
+
+use crate::ctx::RuntimeContext;
+use crate::error::{nx_err, nx_err_of, ErrorKind, NxResult};
+use crate::value::{Value, ValueType};
+use std::ffi::CStr;
+use std::rc::Rc;
+
+/// The version of the plugin ABI, the first field of each of its tables.
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+/// The symbol of the [`NxPlugin`] a plugin exports.
+pub const PLUGIN_SYMBOL: &CStr = c"natrix_plugin";
+
+/// The entry point of a plugin.
+#[repr(C)]
+pub struct NxPlugin {
+    /// The `PLUGIN_ABI_VERSION` the plugin was built for.
+    pub abi_version: u32,
+    /// Registers the native functions of the plugin.
+    pub register: unsafe extern "C" fn(registry: *mut NxRegistry),
+}
+
+/// The functions of the host a plugin registers its natives with.
+#[repr(C)]
+pub struct NxRegistry {
+    pub abi_version: u32,
+    /// Adds a native function that programs call by `name` with `arity` arguments.
+    pub register:
+        unsafe extern "C" fn(registry: *mut NxRegistry, name: NxStr, arity: usize, fun: NxNativeFn),
+}
+
+/// A native function of a plugin, called with the `arg_count` arguments at `args`, which it may
+/// only read until it returns. It sets its result or error with the functions of `call`, its
+/// result is null if it sets neither.
+pub type NxNativeFn =
+    unsafe extern "C" fn(call: *mut NxCall, args: *const NxValue, arg_count: usize);
+
+/// The functions of the host a native function reports its outcome with.
+#[repr(C)]
+pub struct NxCall {
+    pub abi_version: u32,
+    /// Makes a copy of `value` the result of the call.
+    pub set_result: unsafe extern "C" fn(call: *mut NxCall, value: *const NxValue),
+    /// Makes the call fail with a copy of `message`.
+    pub set_error: unsafe extern "C" fn(call: *mut NxCall, message: NxStr),
+}
+
+/// UTF-8 text that is not owned by the handle (and not nul-terminated).
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct NxStr {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+pub const NX_NULL: u32 = 0;
+pub const NX_BOOL: u32 = 1;
+pub const NX_INT: u32 = 2;
+pub const NX_FLOAT: u32 = 3;
+pub const NX_STRING: u32 = 4;
+
+/// A value passed between the host and a plugin: `tag` is one of the `NX_*` constants and says
+/// which field of `payload` is set. Lists and the other values that own resources of the runtime
+/// cannot be passed.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct NxValue {
+    pub tag: u32,
+    pub payload: NxPayload,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union NxPayload {
+    pub null: u8,
+    pub boolean: bool,
+    pub int: i64,
+    pub float: f64,
+    pub string: NxStr,
+}
+
+impl NxStr {
+    pub fn new(text: &str) -> Self {
+        NxStr {
+            ptr: text.as_ptr(),
+            len: text.len(),
+        }
+    }
+
+    /// The text, with invalid UTF-8 replaced.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` bytes that stay valid for `'a`.
+    pub unsafe fn to_str<'a>(self) -> std::borrow::Cow<'a, str> {
+        if self.len == 0 {
+            return "".into();
+        }
+        // SAFETY: guaranteed by the caller
+        String::from_utf8_lossy(unsafe { std::slice::from_raw_parts(self.ptr, self.len) })
+    }
+}
+
+impl NxValue {
+    pub const NULL: NxValue = NxValue {
+        tag: NX_NULL,
+        payload: NxPayload { null: 0 },
+    };
+
+    pub fn from_bool(v: bool) -> Self {
+        NxValue {
+            tag: NX_BOOL,
+            payload: NxPayload { boolean: v },
+        }
+    }
+
+    pub fn from_int(v: i64) -> Self {
+        NxValue {
+            tag: NX_INT,
+            payload: NxPayload { int: v },
+        }
+    }
+
+    pub fn from_float(v: f64) -> Self {
+        NxValue {
+            tag: NX_FLOAT,
+            payload: NxPayload { float: v },
+        }
+    }
+
+    /// A handle borrowing `v`, which must outlive its uses.
+    pub fn from_string(v: &str) -> Self {
+        NxValue {
+            tag: NX_STRING,
+            payload: NxPayload {
+                string: NxStr::new(v),
+            },
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        if self.tag != NX_BOOL {
+            return None;
+        }
+        // SAFETY: the tag says which field is set
+        Some(unsafe { self.payload.boolean })
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        if self.tag != NX_INT {
+            return None;
+        }
+        // SAFETY: the tag says which field is set
+        Some(unsafe { self.payload.int })
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        if self.tag != NX_FLOAT {
+            return None;
+        }
+        // SAFETY: the tag says which field is set
+        Some(unsafe { self.payload.float })
+    }
+
+    /// The text of a string handle.
+    ///
+    /// # Safety
+    ///
+    /// The string must outlive the returned text, which holds for the arguments of a call until
+    /// it returns.
+    pub unsafe fn as_str(&self) -> Option<std::borrow::Cow<'_, str>> {
+        // SAFETY: the tag says which field is set, the caller guarantees the rest
+        (self.tag == NX_STRING).then(|| unsafe { self.payload.string.to_str() })
+    }
+
+    // The handle of a value of the host, borrowing its string
+    fn borrow(value: &Value) -> NxResult<NxValue> {
+        Ok(match value.get_type() {
+            ValueType::Null => NxValue::NULL,
+            ValueType::Bool => NxValue::from_bool(value.unwrap_bool()),
+            ValueType::Int => NxValue::from_int(value.unwrap_int()),
+            ValueType::Float => NxValue::from_float(value.unwrap_float()),
+            ValueType::String => {
+                let string = value.unwrap_string();
+                // SAFETY: the caller keeps `value`, so the text outlives the handle
+                NxValue::from_string(unsafe { &*Rc::as_ptr(&string) })
+            }
+            other => {
+                return nx_err_of(
+                    ErrorKind::Type,
+                    format!("a {} cannot be passed to a plugin", other),
+                );
+            }
+        })
+    }
+
+    // A value of the host with a copy of the handle's string
+    //
+    // SAFETY: a string handle must point to valid memory
+    unsafe fn to_value(self) -> NxResult<Value> {
+        // SAFETY: the tag says which field is set, the caller guarantees the string
+        unsafe {
+            Ok(match self.tag {
+                NX_NULL => Value::NULL,
+                NX_BOOL => Value::from_bool(self.payload.boolean),
+                NX_INT => Value::from_int(self.payload.int),
+                NX_FLOAT => Value::from_float(self.payload.float),
+                NX_STRING => Value::from_string(self.payload.string.to_str().into()),
+                tag => return nx_err(format!("a plugin returned a value of unknown tag {}", tag)),
+            })
+        }
+    }
+}
+
+impl NxCall {
+    /// Makes `value` the result of the call.
+    pub fn set_result(&mut self, value: &NxValue) {
+        // SAFETY: the table was made by the host for this call
+        unsafe { (self.set_result)(self, value) }
+    }
+
+    /// Makes the call fail with `message`.
+    pub fn set_error(&mut self, message: &str) {
+        // SAFETY: the table was made by the host for this call
+        unsafe { (self.set_error)(self, NxStr::new(message)) }
+    }
+}
+
+impl NxRegistry {
+    /// Adds a native function that programs call by `name` with `arity` arguments.
+    pub fn register(&mut self, name: &str, arity: usize, fun: NxNativeFn) {
+        // SAFETY: the table was made by the host for this registration
+        unsafe { (self.register)(self, NxStr::new(name), arity, fun) }
+    }
+}
+
+// The state of the host behind the tables it hands out, which come first so that a pointer to
+// the table is one to the state
+#[repr(C)]
+struct Registration<'a> {
+    registry: NxRegistry,
+    rt: &'a mut RuntimeContext,
+}
+
+#[repr(C)]
+struct Call {
+    call: NxCall,
+    outcome: NxResult<Value>,
+}
+
+unsafe extern "C" fn register_native(
+    registry: *mut NxRegistry,
+    name: NxStr,
+    arity: usize,
+    fun: NxNativeFn,
+) {
+    // SAFETY: the registry is the first field of the `Registration` passed to the plugin, and the
+    // name is valid during the call
+    unsafe {
+        let registration = &mut *(registry as *mut Registration);
+        registration
+            .rt
+            .register_plugin_native(&name.to_str(), arity, fun);
+    }
+}
+
+unsafe extern "C" fn set_result(call: *mut NxCall, value: *const NxValue) {
+    // SAFETY: the table is the first field of the `Call` passed to the native, which passes a
+    // valid value
+    unsafe {
+        let call = &mut *(call as *mut Call);
+        call.outcome = (*value).to_value();
+    }
+}
+
+unsafe extern "C" fn set_error(call: *mut NxCall, message: NxStr) {
+    // SAFETY: the table is the first field of the `Call` passed to the native, which passes a
+    // valid message
+    unsafe {
+        let call = &mut *(call as *mut Call);
+        call.outcome = nx_err(message.to_str());
+    }
+}
+
+/// Registers the native functions of `plugin` with `rt`, if it was built for this version of the
+/// ABI.
+///
+/// # Safety
+///
+/// `plugin` must point to the `NxPlugin` of a plugin, or at least to a `u32` if its version is
+/// not `PLUGIN_ABI_VERSION`.
+pub unsafe fn register_plugin(
+    rt: &mut RuntimeContext,
+    plugin: *const NxPlugin,
+) -> Result<(), String> {
+    // SAFETY: the version comes first in every version of the ABI
+    let abi_version = unsafe { *(plugin as *const u32) };
+    if abi_version != PLUGIN_ABI_VERSION {
+        return Err(format!(
+            "the plugin was built for version {} of the plugin ABI, not {}",
+            abi_version, PLUGIN_ABI_VERSION
+        ));
+    }
+    let mut registration = Registration {
+        registry: NxRegistry {
+            abi_version: PLUGIN_ABI_VERSION,
+            register: register_native,
+        },
+        rt,
+    };
+    // SAFETY: the plugin was built for this version, so it has this layout
+    unsafe { ((*plugin).register)(&mut registration.registry) };
+    Ok(())
+}
+
+/// Calls the native function `fun` of a plugin.
+pub(crate) fn call_native(fun: NxNativeFn, args: &[Value]) -> NxResult<Value> {
+    let args: Vec<NxValue> = args.iter().map(NxValue::borrow).collect::<NxResult<_>>()?;
+    let mut call = Call {
+        call: NxCall {
+            abi_version: PLUGIN_ABI_VERSION,
+            set_result,
+            set_error,
+        },
+        outcome: Ok(Value::NULL),
+    };
+    // SAFETY: the plugin was registered for this version of the ABI, and the strings of `args`
+    // live until the call returns
+    unsafe { fun(&mut call.call, args.as_ptr(), args.len()) };
+    call.outcome
+}
+
+/// Exports the entry point of a plugin, whose functions are registered by `$register`, a
+/// `fn(&mut NxRegistry)`:
+///
+/// ```
+/// use natrix_runtime::plugin::{NxCall, NxRegistry, NxValue};
+///
+/// unsafe extern "C" fn answer(call: *mut NxCall, _args: *const NxValue, _arg_count: usize) {
+///     unsafe { (*call).set_result(&NxValue::from_int(42)) }
+/// }
+///
+/// fn register(registry: &mut NxRegistry) {
+///     registry.register("answer", 0, answer);
+/// }
+///
+/// natrix_runtime::export_plugin!(register);
+/// ```
+#[macro_export]
+macro_rules! export_plugin {
+    ($register:path) => {
+        #[unsafe(no_mangle)]
+        pub static natrix_plugin: $crate::plugin::NxPlugin = {
+            unsafe extern "C" fn register_natives(registry: *mut $crate::plugin::NxRegistry) {
+                // SAFETY: the host passes a valid registry
+                $register(unsafe { &mut *registry })
+            }
+            $crate::plugin::NxPlugin {
+                abi_version: $crate::plugin::PLUGIN_ABI_VERSION,
+                register: register_natives,
+            }
+        };
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Function;
+
+    unsafe extern "C" fn greet(call: *mut NxCall, args: *const NxValue, arg_count: usize) {
+        // SAFETY: the host passes `arg_count` arguments and a valid call
+        let (call, args) = unsafe { (&mut *call, std::slice::from_raw_parts(args, arg_count)) };
+        // SAFETY: the argument lives until the call returns
+        match unsafe { args[0].as_str() } {
+            Some(name) => call.set_result(&NxValue::from_string(&format!("hello, {}", name))),
+            None => call.set_error("expected a name"),
+        }
+    }
+
+    unsafe extern "C" fn half(call: *mut NxCall, args: *const NxValue, _arg_count: usize) {
+        // SAFETY: the host passes one argument and a valid call
+        let (call, arg) = unsafe { (&mut *call, &*args) };
+        if let Some(n) = arg.as_int() {
+            call.set_result(&NxValue::from_float(n as f64 / 2.0));
+        }
+    }
+
+    fn register(registry: &mut NxRegistry) {
+        registry.register("plugin_greet", 1, greet);
+        registry.register("plugin_half", 1, half);
+    }
+
+    crate::export_plugin!(register);
+
+    fn call(rt: &RuntimeContext, index: usize, arg: Value) -> NxResult<Value> {
+        let Function::Native { fun, .. } = rt.natives()[index].as_ref() else {
+            panic!("expected a native function");
+        };
+        fun.call(&mut RuntimeContext::new(), &[arg])
+    }
+
+    #[test]
+    fn test_register_plugin() {
+        let mut rt = RuntimeContext::new();
+        unsafe { register_plugin(&mut rt, &natrix_plugin) }.unwrap();
+        let names: Vec<&str> = rt.natives().iter().map(|n| n.name()).collect();
+        assert_eq!(names, ["plugin_greet", "plugin_half"]);
+
+        let greeting = call(&rt, 0, Value::from_string("natrix".into())).unwrap();
+        assert_eq!(&*greeting.unwrap_string(), "hello, natrix");
+        let err = call(&rt, 0, Value::from_int(1)).unwrap_err();
+        assert_eq!(&*err.message, "expected a name");
+        let err = call(&rt, 0, Value::from_list(Rc::new(Vec::new()))).unwrap_err();
+        assert_eq!(&*err.message, "a list cannot be passed to a plugin");
+        assert_eq!(
+            call(&rt, 1, Value::from_int(3)).unwrap().unwrap_float(),
+            1.5
+        );
+        assert!(call(&rt, 1, Value::NULL).unwrap().is_null());
+    }
+
+    #[test]
+    fn test_register_plugin_of_other_version() {
+        let mut rt = RuntimeContext::new();
+        let version = PLUGIN_ABI_VERSION + 1;
+        let err = unsafe { register_plugin(&mut rt, &version as *const u32 as *const NxPlugin) };
+        assert_eq!(
+            err.unwrap_err(),
+            format!(
+                "the plugin was built for version {} of the plugin ABI, not {}",
+                version, PLUGIN_ABI_VERSION
+            )
+        );
+        assert!(rt.natives().is_empty());
+    }
+}
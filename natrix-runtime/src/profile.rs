@@ -0,0 +1,180 @@
+//! Allocation profiling for `--profile-alloc`, only built with the `profile-alloc` feature.
+//!
+//! Counts heap allocations, reallocations, `Value` clones, reference count increments of heap
+//! values and copies of shared list elements, attributed to the opcode the bytecode interpreter
+//! is executing. Anything that happens outside of an instruction (setting up the call of `main`,
+//! or the AST interpreter) is attributed to a separate `(outside)` row.
+//!
+//! The counters are global atomics rather than interpreter state, because the allocator has no
+//! access to the interpreter. The current opcode is per thread, so that interpreters running on
+//! other threads (like tests) do not change where the events of this one are attributed.
+//! Allocations are only counted if the binary installs [`CountingAllocator`] as its global
+//! allocator.
+
+use crate::bc::Opcode;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const ALLOCS: usize = 0;
+const ALLOC_BYTES: usize = 1;
+const REALLOCS: usize = 2;
+const CLONES: usize = 3;
+const RC_INCREMENTS: usize = 4;
+const LIST_COPIES: usize = 5;
+const COUNTERS: usize = 6;
+
+// Title and width of the column of each counter in the report
+const COLUMNS: [(&str, usize); COUNTERS] = [
+    ("allocs", 10),
+    ("bytes", 12),
+    ("reallocs", 10),
+    ("clones", 10),
+    ("rc incs", 10),
+    ("list copies", 11),
+];
+
+const OUTSIDE: usize = Opcode::ALL.len();
+const SLOTS: usize = OUTSIDE + 1;
+
+static COUNTS: [[AtomicU64; COUNTERS]; SLOTS] =
+    [const { [const { AtomicU64::new(0) }; COUNTERS] }; SLOTS];
+
+thread_local! {
+    // no destructor and no lazy initialization, so the allocator can use it at any time
+    static CURRENT: Cell<usize> = const { Cell::new(OUTSIDE) };
+}
+
+fn count(counter: usize, n: u64) {
+    COUNTS[CURRENT.get()][counter].fetch_add(n, Ordering::Relaxed);
+}
+
+/// Attributes the following events to `opcode`.
+pub fn set_opcode(opcode: Opcode) {
+    CURRENT.set(opcode.as_u8() as usize);
+}
+
+/// Attributes the following events to no opcode.
+pub fn leave_opcode() {
+    CURRENT.set(OUTSIDE);
+}
+
+/// Counts a clone of a value, which increments a reference count if the value is on the heap.
+pub fn count_clone(on_heap: bool) {
+    count(CLONES, 1);
+    if on_heap {
+        count(RC_INCREMENTS, 1);
+    }
+}
+
+/// Counts a copy of list elements made because they were shared with another list.
+pub fn count_list_copy() {
+    count(LIST_COPIES, 1);
+}
+
+/// Clears all counters.
+pub fn reset() {
+    for slot in &COUNTS {
+        for counter in slot {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A table with one row per opcode that had any event, the most allocating ones first, and the
+/// totals.
+pub fn report() -> String {
+    let rows: Vec<(&str, [u64; COUNTERS])> = COUNTS
+        .iter()
+        .enumerate()
+        .map(|(slot, counts)| {
+            let name = Opcode::ALL.get(slot).map_or("(outside)", |op| op.name());
+            (name, counts.each_ref().map(|c| c.load(Ordering::Relaxed)))
+        })
+        .collect();
+    let mut totals = [0; COUNTERS];
+    for (_, counts) in &rows {
+        for (total, n) in totals.iter_mut().zip(counts) {
+            *total += n;
+        }
+    }
+    let mut rows: Vec<_> = rows
+        .into_iter()
+        .filter(|(_, counts)| counts.iter().any(|&n| n > 0))
+        .collect();
+    rows.sort_by(|(a_name, a), (b_name, b)| {
+        (b[ALLOCS], b[CLONES])
+            .cmp(&(a[ALLOCS], a[CLONES]))
+            .then(a_name.cmp(b_name))
+    });
+    rows.push(("total", totals));
+
+    let mut out = String::new();
+    write!(out, "{:14}", "opcode").unwrap();
+    for (title, width) in COLUMNS {
+        write!(out, " {:>width$}", title).unwrap();
+    }
+    for (name, counts) in rows {
+        write!(out, "\n{:14}", name).unwrap();
+        for (n, (_, width)) in counts.iter().zip(COLUMNS) {
+            write!(out, " {:>width$}", n).unwrap();
+        }
+    }
+    out
+}
+
+/// A global allocator that forwards to the system allocator and counts the allocations and
+/// reallocations.
+pub struct CountingAllocator;
+
+// SAFETY: all the work is done by the system allocator, the counting does not allocate
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(ALLOCS, 1);
+        count(ALLOC_BYTES, layout.size() as u64);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(ALLOCS, 1);
+        count(ALLOC_BYTES, layout.size() as u64);
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(REALLOCS, 1);
+        count(ALLOC_BYTES, new_size.saturating_sub(layout.size()) as u64);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn test_report() {
+        // the counts are shared with other tests running at the same time, which may execute
+        // the same opcode, so only check that they went up
+        let before = COUNTS[Opcode::Neg.as_u8() as usize][RC_INCREMENTS].load(Ordering::Relaxed);
+        set_opcode(Opcode::Neg);
+        let s = Value::from_string("a".into());
+        let copies = [s.clone(), s.clone(), Value::from_int(1).clone()];
+        leave_opcode();
+        drop(copies);
+        let after = COUNTS[Opcode::Neg.as_u8() as usize][RC_INCREMENTS].load(Ordering::Relaxed);
+        assert!(after >= before + 2);
+
+        let report = report();
+        let header = report.lines().next().unwrap();
+        assert!(header.starts_with("opcode "), "{}", report);
+        assert!(report.contains("\nneg "), "{}", report);
+        assert!(report.lines().last().unwrap().starts_with("total "));
+    }
+}
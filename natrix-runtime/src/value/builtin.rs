@@ -1,5 +1,5 @@
 macro_rules! define_builtins {
-    ($($variant:ident => $name:literal, $param_count:expr);* $(;)?) => {
+    ($($variant:ident => $name:literal, $param_count:expr, $signature:literal, $description:literal);* $(;)?) => {
         #[repr(u8)]
         #[derive(Copy, Clone, Debug)]
         pub enum Builtin {
@@ -23,6 +23,20 @@ macro_rules! define_builtins {
                 }
             }
 
+            /// How the builtin is called, with the names of its parameters, e.g. `len(value)`.
+            pub const fn signature(self) -> &'static str {
+                match self {
+                    $(Builtin::$variant => $signature),*
+                }
+            }
+
+            /// One sentence about what the builtin does, shown by `help` and `natrix doc`.
+            pub const fn description(self) -> &'static str {
+                match self {
+                    $(Builtin::$variant => $description),*
+                }
+            }
+
             pub fn index(&self) -> usize {
                 *self as u8 as usize
             }
@@ -31,10 +45,117 @@ macro_rules! define_builtins {
 }
 
 define_builtins! {
-    Float => "float", 1;
-    Int => "int", 1;
-    Len => "len", 1;
-    Print => "print", 1;
-    Str => "str", 1;
-    Time => "time", 0;
+    Args => "ARGS", 0, "ARGS",
+        "The command line arguments after `--`, as a list of strings.";
+    Basename => "basename", 1, "basename(path)",
+        "The last component of a path, like \"b.txt\" for \"a/b.txt\".";
+    Builtins => "builtins", 0, "builtins()",
+        "The names of all builtins, as a list of strings.";
+    Close => "close", 1, "close(file)",
+        "Closes a file, writing out what was written to it. Closing it again does nothing.";
+    Contains => "contains", 2, "contains(haystack, needle)",
+        "Whether a string contains a substring, or a list an element.";
+    Count => "count", 2, "count(haystack, needle)",
+        "Number of non-overlapping occurrences of a substring in a string, or of an element in a list.";
+    Dump => "dump", 1, "dump(value)",
+        "Prints the structure of a value, showing the type of each element.";
+    Embed => "embed", 1, "embed(path)",
+        "The content of a file, read at compile time relative to the directory of the script.";
+    Extension => "extension", 1, "extension(path)",
+        "The extension of the last component of a path without the dot, or \"\".";
+    Find => "find", 3, "find(haystack, needle, start)",
+        "Index of the first occurrence of a substring or element at or after start, or -1.";
+    Float => "float", 1, "float(value)",
+        "Converts an int or a string to a float.";
+    Fnmatch => "fnmatch", 2, "fnmatch(name, pattern)",
+        "Whether a name matches a pattern, where * matches any characters, ? one and [a-z] one in a set.";
+    Glob => "glob", 1, "glob(pattern)",
+        "The sorted paths matching a pattern like \"src/*.nx\", see fnmatch.";
+    Help => "help", 1, "help(builtin)",
+        "The signature and description of a builtin, given as a function or by name.";
+    Insert => "insert", 3, "insert(list, index, value)",
+        "Inserts a value into a list before an index, which may be the length to append it.";
+    Int => "int", 1, "int(value)",
+        "Converts a float (truncating towards zero) or a string to an int.";
+    Join => "join", 2, "join(list, separator)",
+        "Concatenates a list of strings, with the separator between them.";
+    Len => "len", 1, "len(value)",
+        "Number of bytes of a string or elements of a list.";
+    ListDir => "list_dir", 1, "list_dir(path)",
+        "The names of the files and directories in a directory, sorted.";
+    Locals => "locals", 0, "locals()",
+        "The local variables of the calling function as a list of [name, value] lists sorted by name, needs --debug.";
+    LogDebug => "log_debug", 1, "log_debug(value)",
+        "Writes a message to the log if the log level is debug.";
+    LogError => "log_error", 1, "log_error(value)",
+        "Writes a message to the log unless logging is off.";
+    LogInfo => "log_info", 1, "log_info(value)",
+        "Writes a message to the log if the log level is info or lower.";
+    LogWarn => "log_warn", 1, "log_warn(value)",
+        "Writes a message to the log if the log level is warn or lower.";
+    Lower => "lower", 1, "lower(string)",
+        "A string with its letters in lowercase.";
+    MemStats => "mem_stats", 0, "mem_stats()",
+        "Number and bytes of the strings and lists allocated so far, as a list of four ints.";
+    Mkdir => "mkdir", 1, "mkdir(path)",
+        "Creates a directory and its missing parents, nothing if it already exists.";
+    NatrixVersion => "NATRIX_VERSION", 0, "NATRIX_VERSION",
+        "The version of natrix running the script, as a string.";
+    OnInterrupt => "on_interrupt", 1, "on_interrupt(handler)",
+        "Calls a function without arguments on Ctrl-C instead of stopping, a second Ctrl-C before it runs stops. null removes it.";
+    Open => "open", 2, "open(path, mode)",
+        "Opens a file for reading (\"r\"), writing (\"w\") or appending (\"a\") and returns it.";
+    PathJoin => "path_join", 2, "path_join(path, other)",
+        "Appends a path to another with a separator, or returns it if it is absolute.";
+    Platform => "PLATFORM", 0, "PLATFORM",
+        "The operating system the script runs on, like \"linux\" or \"windows\".";
+    Pop => "pop", 1, "pop(list)",
+        "Removes the last element of a list and returns it.";
+    Print => "print", 1, "print(value)",
+        "Writes a value followed by a newline to the output.";
+    Push => "push", 2, "push(list, value)",
+        "Appends a value to a list.";
+    ReadLine => "read_line", 1, "read_line(file)",
+        "The next line of a file opened for reading, without the line terminator, or false at the end.";
+    Remove => "remove", 2, "remove(list, value)",
+        "Removes the first element of a list equal to a value, returns whether there was one.";
+    RemoveAt => "remove_at", 2, "remove_at(list, index)",
+        "Removes the element at an index of a list and returns it.";
+    RemoveFile => "remove_file", 1, "remove_file(path)",
+        "Deletes a file.";
+    Replace => "replace", 3, "replace(string, old, new)",
+        "A string with all non-overlapping occurrences of a substring replaced by another.";
+    SbBuild => "sb_build", 1, "sb_build(builder)",
+        "The string built so far by a string builder.";
+    SbNew => "sb_new", 0, "sb_new()",
+        "Creates an empty string builder.";
+    SbPush => "sb_push", 2, "sb_push(builder, string)",
+        "Appends a string to a string builder.";
+    Split => "split", 2, "split(string, separator)",
+        "The parts of a string between occurrences of a non-empty separator, as a list of strings.";
+    Str => "str", 1, "str(value)",
+        "Converts a value to a string, like print shows it.";
+    Time => "time", 0, "time()",
+        "Seconds since the Unix epoch, as a float.";
+    TimeIt => "time_it", 1, "time_it(function)",
+        "Calls a function without arguments and returns the seconds it took.";
+    TomlParse => "toml_parse", 1, "toml_parse(string)",
+        "Parses a TOML document into a table, where table[\"key\"] is the value of a key or null and table[i] the i-th [key, value] entry.";
+    Trim => "trim", 1, "trim(string)",
+        "A string without its leading and trailing whitespace.";
+    Upper => "upper", 1, "upper(string)",
+        "A string with its letters in uppercase.";
+    Write => "write", 2, "write(file, string)",
+        "Writes a string to a file opened for writing or appending.";
+}
+
+impl Builtin {
+    /// Whether the builtin is a predeclared value rather than a function. Its name evaluates to
+    /// the result of calling it, so `ARGS` is a list and not a function returning one.
+    pub const fn is_value(self) -> bool {
+        matches!(
+            self,
+            Builtin::Args | Builtin::NatrixVersion | Builtin::Platform
+        )
+    }
 }
@@ -0,0 +1,199 @@
+use crate::error::{nx_err_of, ErrorKind, NxResult};
+use crate::value::{Value, ValueType};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
+
+/// Conversion of host data to a value, for passing arguments to a script.
+///
+/// Natrix has no dictionaries, so a map becomes a list of `[key, value]` lists sorted by key,
+/// which a script reads with `key, value = entry`. `None` becomes null.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+/// Conversion of a value to host data, for reading the results of a script.
+///
+/// The conversions are as strict as the language: an int is not accepted as a float, and a
+/// string is not parsed as a number.
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> NxResult<Self>;
+}
+
+fn expect(value: &Value, expected: ValueType) -> NxResult<()> {
+    if value.get_type() == expected {
+        Ok(())
+    } else {
+        nx_err_of(
+            ErrorKind::Type,
+            format!("expected {}, got {}", expected, value.get_type()),
+        )
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(value: &Value) -> NxResult<Self> {
+        Ok(value.clone())
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::from_bool(self)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> NxResult<Self> {
+        expect(value, ValueType::Bool)?;
+        Ok(value.unwrap_bool())
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Value {
+        Value::from_int(self)
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> NxResult<Self> {
+        expect(value, ValueType::Int)?;
+        Ok(value.unwrap_int())
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::from_float(self)
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: &Value) -> NxResult<Self> {
+        expect(value, ValueType::Float)?;
+        Ok(value.unwrap_float())
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::from_string(self.into())
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::from_string(self.into())
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> NxResult<Self> {
+        expect(value, ValueType::String)?;
+        Ok(value.unwrap_string().to_string())
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        self.map_or(Value::NULL, T::into_value)
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> NxResult<Self> {
+        if value.is_null() {
+            Ok(None)
+        } else {
+            T::from_value(value).map(Some)
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::from_list(Rc::new(self.into_iter().map(T::into_value).collect()))
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: &Value) -> NxResult<Self> {
+        expect(value, ValueType::List)?;
+        value.unwrap_list().iter().map(T::from_value).collect()
+    }
+}
+
+impl<K: IntoValue + Ord, V: IntoValue> IntoValue for HashMap<K, V> {
+    fn into_value(self) -> Value {
+        let mut entries: Vec<(K, V)> = self.into_iter().collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let entries = entries
+            .into_iter()
+            .map(|(k, v)| Value::from_list(Rc::new(vec![k.into_value(), v.into_value()])))
+            .collect();
+        Value::from_list(Rc::new(entries))
+    }
+}
+
+impl<K: FromValue + Eq + Hash, V: FromValue> FromValue for HashMap<K, V> {
+    fn from_value(value: &Value) -> NxResult<Self> {
+        expect(value, ValueType::List)?;
+        let mut map = HashMap::new();
+        for entry in value.unwrap_list().iter() {
+            expect(entry, ValueType::List)?;
+            let pair = entry.unwrap_list();
+            if pair.len() != 2 {
+                return nx_err_of(
+                    ErrorKind::Value,
+                    format!(
+                        "expected a [key, value] list, got a list of {} elements",
+                        pair.len()
+                    ),
+                );
+            }
+            map.insert(K::from_value(&pair[0])?, V::from_value(&pair[1])?);
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data = vec![Some(1i64), None, Some(3)];
+        let value = data.clone().into_value();
+        assert_eq!(value.to_string(), "[1, null, 3]");
+        assert_eq!(Vec::<Option<i64>>::from_value(&value).unwrap(), data);
+
+        let map = HashMap::from([("b".to_string(), 2.5), ("a".to_string(), 1.0)]);
+        let value = map.clone().into_value();
+        assert_eq!(value.to_string(), r#"[["a", 1.0], ["b", 2.5]]"#);
+        assert_eq!(HashMap::<String, f64>::from_value(&value).unwrap(), map);
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let err = f64::from_value(&Value::from_int(1)).unwrap_err();
+        assert_eq!(err.message.as_ref(), "expected float, got int");
+        let err =
+            Vec::<String>::from_value(&vec!["a".into_value(), true.into_value()].into_value())
+                .unwrap_err();
+        assert_eq!(err.message.as_ref(), "expected string, got bool");
+        let entries = vec![vec![1i64]].into_value();
+        let err = HashMap::<i64, i64>::from_value(&entries).unwrap_err();
+        assert_eq!(
+            err.message.as_ref(),
+            "expected a [key, value] list, got a list of 1 elements"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use crate::error::{nx_err_of, ErrorKind, NxResult};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+/// How the `open` builtin opens a file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileMode {
+    Read,
+    Write, // truncates the file
+    Append,
+}
+
+impl FromStr for FileMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "r" => Ok(FileMode::Read),
+            "w" => Ok(FileMode::Write),
+            "a" => Ok(FileMode::Append),
+            _ => Err(format!(
+                "unknown file mode {:?}, expected \"r\", \"w\" or \"a\"",
+                s
+            )),
+        }
+    }
+}
+
+/// The buffered reader or writer of an open file, see [`RuntimeContext::open`].
+///
+/// [`RuntimeContext::open`]: crate::ctx::RuntimeContext::open
+pub enum FileStream {
+    Reader(Box<dyn BufRead>),
+    Writer(Box<dyn Write>),
+}
+
+/// A file opened by the `open` builtin.
+///
+/// Reads and writes go through a buffer, so a large file can be processed one line at a time
+/// without loading all of it. The stream is dropped when the file is closed.
+pub struct FileHandle {
+    path: Box<str>,
+    stream: Option<FileStream>,
+}
+
+impl FileHandle {
+    pub fn new(path: &str, stream: FileStream) -> Self {
+        Self {
+            path: path.into(),
+            stream: Some(stream),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    /// Reads the next line without the line terminator, `None` at the end of the file.
+    pub fn read_line(&mut self) -> NxResult<Option<String>> {
+        let reader = match &mut self.stream {
+            Some(FileStream::Reader(reader)) => reader,
+            Some(FileStream::Writer(_)) => return self.misuse("is not open for reading"),
+            None => return self.misuse("is closed"),
+        };
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(line.trim_end_matches(['\n', '\r']).to_string())),
+            Err(err) => self.io_error("read", err),
+        }
+    }
+
+    pub fn write(&mut self, s: &str) -> NxResult<()> {
+        let writer = match &mut self.stream {
+            Some(FileStream::Writer(writer)) => writer,
+            Some(FileStream::Reader(_)) => return self.misuse("is not open for writing"),
+            None => return self.misuse("is closed"),
+        };
+        match writer.write_all(s.as_bytes()) {
+            Ok(()) => Ok(()),
+            Err(err) => self.io_error("write", err),
+        }
+    }
+
+    /// Flushes what was written and releases the file. Closing a closed file does nothing.
+    pub fn close(&mut self) -> NxResult<()> {
+        if let Some(FileStream::Writer(mut writer)) = self.stream.take()
+            && let Err(err) = writer.flush()
+        {
+            return self.io_error("write", err);
+        }
+        Ok(())
+    }
+
+    fn misuse<T>(&self, problem: &str) -> NxResult<T> {
+        nx_err_of(
+            ErrorKind::Value,
+            format!("file {:?} {}", self.path, problem),
+        )
+    }
+
+    fn io_error<T>(&self, action: &str, err: io::Error) -> NxResult<T> {
+        nx_err_of(
+            ErrorKind::Io,
+            format!("cannot {} file {:?}: {}", action, self.path, err),
+        )
+    }
+}
+
+impl fmt::Debug for FileHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileHandle")
+            .field("path", &self.path)
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
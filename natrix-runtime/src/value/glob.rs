@@ -0,0 +1,150 @@
+use crate::ctx::RuntimeContext;
+
+/// Whether `name` matches a shell-style `pattern`: `*` matches any characters, `?` any one
+/// character and `[...]` one of the listed characters or ranges like `a-z` (`[!...]` one that is
+/// not listed). Other characters, and a `[` without a closing `]`, match themselves.
+pub fn fnmatch(name: &str, pattern: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut n, mut p) = (0, 0);
+    // where to resume after the last star: the pattern after it and the name it has matched up to
+    let mut star = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, n));
+        } else if let Some(len) = match_one(&pattern[p..], name[n]) {
+            p += len;
+            n += 1;
+        } else if let Some((after_star, matched)) = star {
+            // let the star match one more character
+            p = after_star;
+            n = matched + 1;
+            star = Some((after_star, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// The length of the element at the start of `pattern` if it matches `c`
+fn match_one(pattern: &[char], c: char) -> Option<usize> {
+    match pattern.first()? {
+        '?' => Some(1),
+        '[' => match match_class(pattern, c) {
+            Some((matched, len)) => matched.then_some(len),
+            None => (c == '[').then_some(1),
+        },
+        &p => (p == c).then_some(1),
+    }
+}
+
+// Whether the `[...]` class at the start of `pattern` matches `c` and its length, `None` if the
+// class is not closed. A `]` right after the `[` or `[!` is listed rather than closing it.
+fn match_class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(pattern.get(1), Some('!' | '^'));
+    let start = if negated { 2 } else { 1 };
+    let mut i = start;
+    let mut matched = false;
+    loop {
+        let &first = pattern.get(i)?;
+        if first == ']' && i > start {
+            return Some((matched != negated, i + 1));
+        }
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(&last)) if last != ']' => {
+                matched |= (first..=last).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= first == c;
+                i += 1;
+            }
+        }
+    }
+}
+
+/// The paths matching `pattern`, sorted.
+///
+/// Each component of the pattern is matched against the entries of a directory with `fnmatch`,
+/// so wildcards do not match a `/`, and names starting with a dot are only matched by components
+/// starting with a dot. Directories that cannot be listed have no matches.
+pub fn glob(rt: &mut RuntimeContext, pattern: &str) -> Vec<String> {
+    let root = if pattern.starts_with('/') { "/" } else { "" };
+    let mut paths = vec![root.to_string()];
+    for component in pattern.split('/').filter(|c| !c.is_empty()) {
+        let mut matches = Vec::new();
+        for path in &paths {
+            if component == "." || component == ".." {
+                matches.push(join(path, component));
+                continue;
+            }
+            let dir = if path.is_empty() { "." } else { path };
+            let Ok(names) = rt.list_dir(dir) else {
+                continue;
+            };
+            for name in names {
+                if fnmatch(&name, component)
+                    && (!name.starts_with('.') || component.starts_with('.'))
+                {
+                    matches.push(join(path, &name));
+                }
+            }
+        }
+        paths = matches;
+    }
+    paths.retain(|path| !path.is_empty());
+    paths.sort();
+    paths
+}
+
+fn join(path: &str, name: &str) -> String {
+    if path.is_empty() || path.ends_with('/') {
+        format!("{}{}", path, name)
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_fnmatch() {
+        assert!(fnmatch("main.nx", "*.nx"));
+        assert!(fnmatch("main.nx", "m??n.*"));
+        assert!(fnmatch("abcabd", "*ab?"));
+        assert!(!fnmatch("main.nx", "*.rs"));
+        assert!(!fnmatch("main.nx", "main"));
+        assert!(fnmatch("", "*"));
+        assert!(fnmatch("b1", "[a-c][0-9]"));
+        assert!(!fnmatch("d1", "[a-c][0-9]"));
+        assert!(fnmatch("d1", "[!a-c]1"));
+        assert!(fnmatch("]", "[]]"));
+        assert!(fnmatch("-", "[a-]"));
+        assert!(fnmatch("[x", "[x"));
+    }
+
+    #[test]
+    fn test_glob() {
+        let mut rt = RuntimeContext::new();
+        rt.set_files(HashMap::from([
+            ("a.txt".to_string(), String::new()),
+            (".hidden.txt".to_string(), String::new()),
+            ("src/b.txt".to_string(), String::new()),
+            ("src/c.nx".to_string(), String::new()),
+            ("tests/d.txt".to_string(), String::new()),
+        ]));
+        assert_eq!(glob(&mut rt, "*.txt"), ["a.txt"]);
+        assert_eq!(glob(&mut rt, ".*"), [".hidden.txt"]);
+        assert_eq!(glob(&mut rt, "*/*.txt"), ["src/b.txt", "tests/d.txt"]);
+        assert_eq!(glob(&mut rt, "./src/c.nx"), ["./src/c.nx"]);
+        assert_eq!(glob(&mut rt, "src/*"), ["src/b.txt", "src/c.nx"]);
+        assert!(glob(&mut rt, "a.txt/*").is_empty());
+        assert!(glob(&mut rt, "missing/*").is_empty());
+        assert!(glob(&mut rt, "").is_empty());
+    }
+}
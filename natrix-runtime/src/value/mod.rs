@@ -1,8 +1,16 @@
 mod builtin;
+mod convert;
+mod file;
+mod glob;
 mod ops;
+pub mod toml;
 
-use crate::error::{nx_err, NxResult};
+use crate::ctx::RuntimeContext;
+use crate::error::{nx_err_of, ErrorKind, NxResult};
+use crate::plugin::{self, NxNativeFn};
 pub use builtin::Builtin;
+pub use convert::{FromValue, IntoValue};
+pub use file::{FileHandle, FileMode, FileStream};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -36,16 +44,44 @@ pub enum ValueType {
     String,
     List,
     Function,
+    StringBuilder,
+    File,
+    Table,
+}
+
+/// The implementation of a native function, called with the arguments of the program.
+pub type NativeFn = fn(&mut RuntimeContext, &[Value]) -> NxResult<Value>;
+
+/// The code of a native function: a Rust function of the host or a C function of a plugin.
+#[derive(Debug, Clone, Copy)]
+pub enum NativeCode {
+    Host(NativeFn),
+    Plugin(NxNativeFn),
+}
+
+impl NativeCode {
+    pub fn call(self, rt: &mut RuntimeContext, args: &[Value]) -> NxResult<Value> {
+        match self {
+            NativeCode::Host(fun) => fun(rt, args),
+            NativeCode::Plugin(fun) => plugin::call_native(fun, args),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Function {
     Builtin(Builtin),
+    Native {
+        name: Box<str>,
+        param_count: usize,
+        fun: NativeCode,
+    }, // registered by the host, see `RuntimeContext::register_native`
     UserDefined {
         name: Box<str>,
         param_count: usize,
         max_slots: usize, // includes parameters
         code_handle: usize,
+        location: Option<Box<str>>, // file and line of the name, if compiled from sources
     },
 }
 
@@ -53,27 +89,32 @@ impl Function {
     pub fn name(&self) -> &str {
         match self {
             Function::Builtin(builtin) => builtin.name(),
-            Function::UserDefined { name, .. } => &*name,
+            Function::Native { name, .. } | Function::UserDefined { name, .. } => &*name,
         }
     }
 
     pub fn param_count(&self) -> usize {
         match self {
             Function::Builtin(builtin) => builtin.param_count(),
-            Function::UserDefined { param_count, .. } => *param_count,
+            Function::Native { param_count, .. } | Function::UserDefined { param_count, .. } => {
+                *param_count
+            }
         }
     }
 
     pub fn check_args(&self, args_count: usize) -> NxResult<()> {
         let param_count = self.param_count();
         if args_count != param_count {
-            nx_err(format!(
-                "function {} expects {} argument{}, but {} were provided",
-                self.name(),
-                param_count,
-                if param_count == 1 { "" } else { "s" },
-                args_count
-            ))
+            nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "function {} expects {} argument{}, but {} were provided",
+                    self.name(),
+                    param_count,
+                    if param_count == 1 { "" } else { "s" },
+                    args_count
+                ),
+            )
         } else {
             Ok(())
         }
@@ -87,13 +128,28 @@ pub(super) enum ValueImpl {
     Int(i64),
     Float(f64),
     String(Rc<str>),
-    List(Rc<RefCell<Vec<Value>>>),
+    List(Rc<RefCell<Rc<Vec<Value>>>>), // the outer Rc is the list object, the inner one its elements
     Function(Rc<Function>),
+    StringBuilder(Rc<RefCell<String>>),
+    File(Rc<RefCell<FileHandle>>),
+    Table(Rc<Vec<(Rc<str>, Value)>>), // sorted by key
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
+#[cfg_attr(not(feature = "profile-alloc"), derive(Clone))]
 pub struct Value(pub(super) ValueImpl);
 
+#[cfg(feature = "profile-alloc")]
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        crate::profile::count_clone(!matches!(
+            self.0,
+            ValueImpl::Null | ValueImpl::Bool(_) | ValueImpl::Int(_) | ValueImpl::Float(_)
+        ));
+        Value(self.0.clone())
+    }
+}
+
 impl Value {
     pub const NULL: Value = Value(ValueImpl::Null);
     pub const TRUE: Value = Value(ValueImpl::Bool(true));
@@ -115,14 +171,31 @@ impl Value {
         Value(ValueImpl::String(v))
     }
 
-    pub fn from_list(v: Rc<RefCell<Vec<Value>>>) -> Self {
-        Value(ValueImpl::List(v))
+    /// Creates a new list object with the given elements.
+    ///
+    /// The elements are copy-on-write - they can be shared by several lists (e.g. `a + []`) and
+    /// are only copied when one of those lists is mutated.
+    pub fn from_list(v: Rc<Vec<Value>>) -> Self {
+        Value(ValueImpl::List(Rc::new(RefCell::new(v))))
     }
 
     pub fn from_function(v: Rc<Function>) -> Self {
         Value(ValueImpl::Function(v))
     }
 
+    pub fn from_string_builder(v: Rc<RefCell<String>>) -> Self {
+        Value(ValueImpl::StringBuilder(v))
+    }
+
+    pub fn from_file(v: FileHandle) -> Self {
+        Value(ValueImpl::File(Rc::new(RefCell::new(v))))
+    }
+
+    /// Creates a read-only table with the given entries, which must be sorted by key.
+    pub fn from_table(entries: Rc<Vec<(Rc<str>, Value)>>) -> Self {
+        Value(ValueImpl::Table(entries))
+    }
+
     pub fn get_type(&self) -> ValueType {
         match self.0 {
             ValueImpl::Null => ValueType::Null,
@@ -132,6 +205,9 @@ impl Value {
             ValueImpl::String(_) => ValueType::String,
             ValueImpl::List(_) => ValueType::List,
             ValueImpl::Function(_) => ValueType::Function,
+            ValueImpl::StringBuilder(_) => ValueType::StringBuilder,
+            ValueImpl::File(_) => ValueType::File,
+            ValueImpl::Table(_) => ValueType::Table,
         }
     }
 
@@ -163,6 +239,18 @@ impl Value {
         matches!(self.0, ValueImpl::Function(_))
     }
 
+    pub fn is_string_builder(&self) -> bool {
+        matches!(self.0, ValueImpl::StringBuilder(_))
+    }
+
+    pub fn is_file(&self) -> bool {
+        matches!(self.0, ValueImpl::File(_))
+    }
+
+    pub fn is_table(&self) -> bool {
+        matches!(self.0, ValueImpl::Table(_))
+    }
+
     pub fn unwrap_bool(&self) -> bool {
         match self.0 {
             ValueImpl::Bool(v) => v,
@@ -191,9 +279,10 @@ impl Value {
         }
     }
 
-    pub fn unwrap_list(&self) -> Rc<RefCell<Vec<Value>>> {
+    /// Returns the current elements of the list - later mutations of the list are not visible.
+    pub fn unwrap_list(&self) -> Rc<Vec<Value>> {
         match &self.0 {
-            ValueImpl::List(v) => v.clone(),
+            ValueImpl::List(v) => v.borrow().clone(),
             _ => panic!("expected list, got {:?}", self.get_type()),
         }
     }
@@ -204,4 +293,42 @@ impl Value {
             _ => panic!("expected function, got {:?}", self.get_type()),
         }
     }
+
+    pub fn unwrap_string_builder(&self) -> Rc<RefCell<String>> {
+        match &self.0 {
+            ValueImpl::StringBuilder(v) => v.clone(),
+            _ => panic!("expected string builder, got {:?}", self.get_type()),
+        }
+    }
+
+    pub fn unwrap_file(&self) -> Rc<RefCell<FileHandle>> {
+        match &self.0 {
+            ValueImpl::File(v) => v.clone(),
+            _ => panic!("expected file, got {:?}", self.get_type()),
+        }
+    }
+
+    pub fn unwrap_table(&self) -> Rc<Vec<(Rc<str>, Value)>> {
+        match &self.0 {
+            ValueImpl::Table(v) => v.clone(),
+            _ => panic!("expected table, got {:?}", self.get_type()),
+        }
+    }
+
+    /// Returns the heap size in bytes of a string or list that is not shared with any other value.
+    pub fn unique_heap_size(&self) -> Option<usize> {
+        match &self.0 {
+            ValueImpl::String(v) if Rc::strong_count(v) == 1 => Some(v.len()),
+            ValueImpl::List(v) if Rc::strong_count(v) == 1 => {
+                // a new list sharing the elements of another one does not allocate element slots
+                let elements = v.borrow();
+                if Rc::strong_count(&elements) == 1 {
+                    Some(elements.len() * size_of::<Value>())
+                } else {
+                    Some(0)
+                }
+            }
+            _ => None,
+        }
+    }
 }
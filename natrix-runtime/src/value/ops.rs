@@ -1,8 +1,13 @@
-use crate::ctx::RuntimeContext;
-use crate::error::{nx_err, nx_error, NxResult};
-use crate::value::{BinaryOp, Builtin, Function, UnaryOp, Value, ValueImpl, ValueType};
-use std::cell::RefCell;
-use std::fmt::Display;
+use crate::ctx::{Capability, LogLevel, RuntimeContext};
+use crate::error::{nx_err, nx_err_of, nx_error_of, ErrorKind, NxResult};
+use crate::value::{
+    glob, toml, BinaryOp, Builtin, FileHandle, FileMode, Function, UnaryOp, Value, ValueImpl,
+    ValueType,
+};
+use std::cell::{RefCell, RefMut};
+use std::fmt::{Display, Write};
+use std::io;
+use std::path::Path;
 use std::rc::Rc;
 use std::str::FromStr;
 
@@ -22,6 +27,73 @@ impl BinaryOp {
             BinaryOp::Lt => left.lt(&right),
         }
     }
+
+    /// Checks the additional rules of strict mode before the operator is evaluated.
+    ///
+    /// Strict mode rejects comparisons of values of different types (except comparing with
+    /// null for equality) and integer arithmetic that overflows instead of wrapping around.
+    pub fn check_strict(&self, left: &Value, right: &Value) -> NxResult<()> {
+        if let Some(message) = self.numeric_eq_warning(left, right) {
+            return nx_err_of(ErrorKind::Type, format!("{} in strict mode", message));
+        }
+        match self {
+            BinaryOp::Eq | BinaryOp::Ne if left.is_null() || right.is_null() => Ok(()),
+            BinaryOp::Eq
+            | BinaryOp::Ne
+            | BinaryOp::Ge
+            | BinaryOp::Gt
+            | BinaryOp::Le
+            | BinaryOp::Lt => {
+                if left.get_type() == right.get_type() {
+                    Ok(())
+                } else {
+                    nx_err_of(
+                        ErrorKind::Type,
+                        format!(
+                            "cannot compare {:?} with {:?} in strict mode",
+                            left.get_type(),
+                            right.get_type()
+                        ),
+                    )
+                }
+            }
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => {
+                let Some((l, r)) = left.as_i64_pair(right) else {
+                    return Ok(());
+                };
+                let result = match self {
+                    BinaryOp::Add => l.checked_add(r),
+                    BinaryOp::Sub => l.checked_sub(r),
+                    BinaryOp::Mul => l.checked_mul(r),
+                    // division by zero is reported by the operator itself
+                    _ if r == 0 => Some(0),
+                    BinaryOp::Div => l.checked_div(r),
+                    _ => l.checked_rem(r),
+                };
+                match result {
+                    Some(_) => Ok(()),
+                    None => nx_err_of(ErrorKind::Overflow, "integer overflow"),
+                }
+            }
+        }
+    }
+
+    /// Describes the implicit conversion made by `==` or `!=` between an int and a float.
+    ///
+    /// The int is converted to float, so e.g. `3 == 6 / 2.0` holds but a float that is not
+    /// exactly integral (often the result of a division) is silently unequal.
+    pub fn numeric_eq_warning(&self, left: &Value, right: &Value) -> Option<String> {
+        let mixed = (left.is_int() && right.is_float()) || (left.is_float() && right.is_int());
+        if mixed && matches!(self, BinaryOp::Eq | BinaryOp::Ne) {
+            Some(format!(
+                "comparing {} with {} implicitly converts the int to float",
+                left.get_type(),
+                right.get_type()
+            ))
+        } else {
+            None
+        }
+    }
 }
 
 impl UnaryOp {
@@ -31,62 +103,699 @@ impl UnaryOp {
             UnaryOp::Not => arg.not(),
         }
     }
+
+    /// Checks the additional rules of strict mode, see [`BinaryOp::check_strict`].
+    pub fn check_strict(&self, arg: &Value) -> NxResult<()> {
+        match self {
+            UnaryOp::Neg if arg.is_int() && arg.unwrap_int() == i64::MIN => {
+                nx_err_of(ErrorKind::Overflow, "integer overflow")
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 impl Builtin {
     pub fn eval(&self, rt: &mut RuntimeContext, args: &[Value]) -> NxResult<Value> {
         debug_assert!(args.len() == self.param_count());
         match self {
+            Builtin::Args => Ok(rt.args()),
+            Builtin::Basename => Builtin::basename(&args[0]),
+            Builtin::Builtins => Ok(Builtin::builtins()),
+            Builtin::Close => Builtin::close(&args[0]),
+            Builtin::Contains => Builtin::contains(&args[0], &args[1]),
+            Builtin::Count => Builtin::count(&args[0], &args[1]),
+            Builtin::Dump => Builtin::dump(rt, &args[0]),
+            // replaced by the file content at compile time when called directly
+            Builtin::Embed => nx_err("embed can only be called directly with a string literal"),
+            Builtin::Extension => Builtin::extension(&args[0]),
+            Builtin::Find => Builtin::find(&args[0], &args[1], &args[2]),
             Builtin::Float => Builtin::float(&args[0]),
+            Builtin::Fnmatch => Builtin::fnmatch(&args[0], &args[1]),
+            Builtin::Glob => Builtin::glob(rt, &args[0]),
+            Builtin::Help => Builtin::help(&args[0]),
+            Builtin::Insert => Builtin::insert(&args[0], &args[1], &args[2]),
             Builtin::Int => Builtin::int(&args[0]),
+            Builtin::Join => Builtin::join(&args[0], &args[1]),
             Builtin::Len => Builtin::len(&args[0]),
+            Builtin::ListDir => Builtin::list_dir(rt, &args[0]),
+            // reads the frame of the caller, so the interpreters implement direct calls themselves
+            Builtin::Locals => nx_err("locals can only be called directly"),
+            Builtin::LogDebug => Builtin::log(rt, LogLevel::Debug, &args[0]),
+            Builtin::LogError => Builtin::log(rt, LogLevel::Error, &args[0]),
+            Builtin::LogInfo => Builtin::log(rt, LogLevel::Info, &args[0]),
+            Builtin::LogWarn => Builtin::log(rt, LogLevel::Warn, &args[0]),
+            Builtin::Lower => Builtin::lower(&args[0]),
+            Builtin::MemStats => Builtin::mem_stats(rt),
+            Builtin::Mkdir => Builtin::mkdir(rt, &args[0]),
+            Builtin::NatrixVersion => Ok(Builtin::natrix_version()),
+            Builtin::OnInterrupt => Builtin::on_interrupt(rt, &args[0]),
+            Builtin::Open => Builtin::open(rt, &args[0], &args[1]),
+            Builtin::PathJoin => Builtin::path_join(&args[0], &args[1]),
+            Builtin::Platform => Ok(Builtin::platform()),
+            Builtin::Pop => Builtin::pop(&args[0]),
             Builtin::Print => Builtin::print(rt, &args[0]),
+            Builtin::Push => Builtin::push(&args[0], &args[1]),
+            Builtin::ReadLine => Builtin::read_line(&args[0]),
+            Builtin::Remove => Builtin::remove(&args[0], &args[1]),
+            Builtin::RemoveAt => Builtin::remove_at(&args[0], &args[1]),
+            Builtin::RemoveFile => Builtin::remove_file(rt, &args[0]),
+            Builtin::Replace => Builtin::replace(&args[0], &args[1], &args[2]),
+            Builtin::SbBuild => Builtin::sb_build(&args[0]),
+            Builtin::SbNew => Builtin::sb_new(),
+            Builtin::SbPush => Builtin::sb_push(&args[0], &args[1]),
+            Builtin::Split => Builtin::split(&args[0], &args[1]),
             Builtin::Str => Builtin::str(&args[0]),
-            Builtin::Time => Builtin::time(),
+            Builtin::Time => Builtin::time(rt),
+            // calls back into user code, so the interpreters implement it themselves
+            Builtin::TimeIt => unreachable!("time_it must be handled by the interpreter"),
+            Builtin::TomlParse => Builtin::toml_parse(&args[0]),
+            Builtin::Trim => Builtin::trim(&args[0]),
+            Builtin::Upper => Builtin::upper(&args[0]),
+            Builtin::Write => Builtin::write(&args[0], &args[1]),
         }
     }
 
     pub fn eval_const(&self, args: &[Value]) -> NxResult<Option<Value>> {
         debug_assert!(args.len() == self.param_count());
         match self {
+            Builtin::Args => Ok(None),
+            Builtin::Basename => Ok(Some(Builtin::basename(&args[0])?)),
+            Builtin::Builtins => Ok(None), // a new list every time
+            Builtin::Close => Ok(None),
+            Builtin::Contains => Ok(Some(Builtin::contains(&args[0], &args[1])?)),
+            Builtin::Count => Ok(Some(Builtin::count(&args[0], &args[1])?)),
+            Builtin::Dump => Ok(None),
+            Builtin::Embed => Ok(None),
+            Builtin::Extension => Ok(Some(Builtin::extension(&args[0])?)),
+            Builtin::Find => Ok(Some(Builtin::find(&args[0], &args[1], &args[2])?)),
             Builtin::Float => Ok(Some(Builtin::float(&args[0])?)),
+            Builtin::Fnmatch => Ok(Some(Builtin::fnmatch(&args[0], &args[1])?)),
+            Builtin::Glob => Ok(None),
+            Builtin::Help => Ok(Some(Builtin::help(&args[0])?)),
+            Builtin::Insert => Ok(None),
             Builtin::Int => Ok(Some(Builtin::int(&args[0])?)),
+            Builtin::Join => Ok(Some(Builtin::join(&args[0], &args[1])?)),
             Builtin::Len => Ok(Some(Builtin::len(&args[0])?)),
+            Builtin::ListDir => Ok(None),
+            Builtin::Locals => Ok(None),
+            Builtin::LogDebug | Builtin::LogError | Builtin::LogInfo | Builtin::LogWarn => Ok(None),
+            Builtin::Lower => Ok(Some(Builtin::lower(&args[0])?)),
+            Builtin::MemStats => Ok(None),
+            Builtin::Mkdir => Ok(None),
+            Builtin::OnInterrupt => Ok(None),
+            Builtin::Open => Ok(None),
+            // depend on where the program runs, which can differ from where it is compiled
+            Builtin::NatrixVersion | Builtin::PathJoin | Builtin::Platform => Ok(None),
+            // modify their list argument
+            Builtin::Pop | Builtin::Push | Builtin::Remove | Builtin::RemoveAt => Ok(None),
             Builtin::Print => Ok(None),
+            Builtin::ReadLine => Ok(None),
+            Builtin::RemoveFile => Ok(None),
+            Builtin::Replace => Ok(Some(Builtin::replace(&args[0], &args[1], &args[2])?)),
+            Builtin::SbBuild => Ok(None),
+            Builtin::SbNew => Ok(None),
+            Builtin::SbPush => Ok(None),
+            Builtin::Split => Ok(None), // a new list every time
             Builtin::Str => Ok(Some(Builtin::str(&args[0])?)),
             Builtin::Time => Ok(None),
+            Builtin::TimeIt => Ok(None),
+            Builtin::TomlParse => Ok(None), // a new table every time
+            Builtin::Trim => Ok(Some(Builtin::trim(&args[0])?)),
+            Builtin::Upper => Ok(Some(Builtin::upper(&args[0])?)),
+            Builtin::Write => Ok(None),
         }
     }
 
+    /// Whether calling the builtin can do more than return a value or fail, e.g. print, touch the
+    /// file system or modify its arguments.
+    ///
+    /// Used to warn about expression statements whose value is unused.
+    pub fn has_side_effects(&self) -> bool {
+        match self {
+            Builtin::Args
+            | Builtin::Basename
+            | Builtin::Builtins
+            | Builtin::Contains
+            | Builtin::Count
+            | Builtin::Embed
+            | Builtin::Extension
+            | Builtin::Find
+            | Builtin::Float
+            | Builtin::Fnmatch
+            | Builtin::Glob
+            | Builtin::Help
+            | Builtin::Int
+            | Builtin::Join
+            | Builtin::Len
+            | Builtin::ListDir
+            | Builtin::Locals
+            | Builtin::Lower
+            | Builtin::MemStats
+            | Builtin::NatrixVersion
+            | Builtin::PathJoin
+            | Builtin::Platform
+            | Builtin::Replace
+            | Builtin::SbBuild
+            | Builtin::SbNew
+            | Builtin::Split
+            | Builtin::Str
+            | Builtin::Time
+            | Builtin::TomlParse
+            | Builtin::Trim
+            | Builtin::Upper => false,
+            Builtin::Close
+            | Builtin::Dump
+            | Builtin::Insert
+            | Builtin::LogDebug
+            | Builtin::LogError
+            | Builtin::LogInfo
+            | Builtin::LogWarn
+            | Builtin::Mkdir
+            | Builtin::OnInterrupt
+            | Builtin::Open
+            | Builtin::Pop
+            | Builtin::Print
+            | Builtin::Push
+            | Builtin::ReadLine
+            | Builtin::Remove
+            | Builtin::RemoveAt
+            | Builtin::RemoveFile
+            | Builtin::SbPush
+            | Builtin::TimeIt
+            | Builtin::Write => true,
+        }
+    }
+
+    /// The types accepted by parameter `index`, or `None` if it accepts any value.
+    ///
+    /// Used to reject calls with arguments of known type at compile time, the functions themselves
+    /// check their arguments at run time.
+    pub fn param_types(&self, index: usize) -> Option<&'static [ValueType]> {
+        debug_assert!(index < self.param_count());
+        match (self, index) {
+            (Builtin::Contains | Builtin::Count | Builtin::Find, 0) => {
+                Some(&[ValueType::String, ValueType::List])
+            }
+            (Builtin::Find, 2) => Some(&[ValueType::Int]),
+            (Builtin::Contains | Builtin::Count | Builtin::Find, _) => None,
+            (Builtin::Float | Builtin::Int, _) => {
+                Some(&[ValueType::Int, ValueType::Float, ValueType::String])
+            }
+            (Builtin::Help, _) => Some(&[ValueType::Function, ValueType::String]),
+            (Builtin::Join, 0) => Some(&[ValueType::List]),
+            (Builtin::Join, _) => Some(&[ValueType::String]),
+            (Builtin::Embed, _) => Some(&[ValueType::String]),
+            (Builtin::Len, _) => Some(&[ValueType::String, ValueType::List, ValueType::Table]),
+            (Builtin::Insert | Builtin::Push | Builtin::Remove, 0) | (Builtin::Pop, _) => {
+                Some(&[ValueType::List])
+            }
+            (Builtin::Insert, 1) => Some(&[ValueType::Int]),
+            (Builtin::Insert | Builtin::Push | Builtin::Remove, _) => None,
+            (Builtin::RemoveAt, 0) => Some(&[ValueType::List]),
+            (Builtin::RemoveAt, _) => Some(&[ValueType::Int]),
+            (Builtin::SbBuild, _) => Some(&[ValueType::StringBuilder]),
+            (Builtin::SbPush, 0) => Some(&[ValueType::StringBuilder]),
+            (Builtin::SbPush, _) => Some(&[ValueType::String]),
+            (Builtin::TimeIt, _) => Some(&[ValueType::Function]),
+            (Builtin::OnInterrupt, _) => Some(&[ValueType::Function, ValueType::Null]),
+            (Builtin::Close | Builtin::ReadLine, _) => Some(&[ValueType::File]),
+            (
+                Builtin::Basename
+                | Builtin::Extension
+                | Builtin::Fnmatch
+                | Builtin::Glob
+                | Builtin::ListDir
+                | Builtin::Mkdir
+                | Builtin::Open
+                | Builtin::PathJoin
+                | Builtin::RemoveFile
+                | Builtin::TomlParse,
+                _,
+            ) => Some(&[ValueType::String]),
+            (
+                Builtin::Lower | Builtin::Replace | Builtin::Split | Builtin::Trim | Builtin::Upper,
+                _,
+            ) => Some(&[ValueType::String]),
+            (Builtin::Write, 0) => Some(&[ValueType::File]),
+            (Builtin::Write, _) => Some(&[ValueType::String]),
+            (Builtin::LogDebug | Builtin::LogError | Builtin::LogInfo | Builtin::LogWarn, _) => {
+                None
+            }
+            (Builtin::Dump | Builtin::Print | Builtin::Str, _) => None,
+            (
+                Builtin::Args
+                | Builtin::Builtins
+                | Builtin::Locals
+                | Builtin::MemStats
+                | Builtin::NatrixVersion
+                | Builtin::Platform
+                | Builtin::SbNew
+                | Builtin::Time,
+                _,
+            ) => unreachable!(),
+        }
+    }
+
+    // Number of occurrences of `needle`: non-overlapping substrings of a string (an empty needle
+    // occurs before every byte and at the end), or elements of a list equal to it
+    fn count(haystack: &Value, needle: &Value) -> NxResult<Value> {
+        let count = match (haystack.get_type(), needle.get_type()) {
+            (ValueType::String, ValueType::String) => {
+                let haystack = haystack.string_ref().as_bytes();
+                let needle = needle.string_ref().as_bytes();
+                if needle.is_empty() {
+                    haystack.len() + 1
+                } else {
+                    let mut count = 0;
+                    let mut offset = 0;
+                    while let Some(i) = find_bytes(&haystack[offset..], needle) {
+                        count += 1;
+                        offset += i + needle.len();
+                    }
+                    count
+                }
+            }
+            (ValueType::List, _) => {
+                let mut count = 0;
+                for element in haystack.list_ref().borrow().iter() {
+                    if element.eq(needle)?.unwrap_bool() {
+                        count += 1;
+                    }
+                }
+                count
+            }
+            (h, n) => {
+                return nx_err_of(
+                    ErrorKind::Type,
+                    format!("count cannot be applied to {:?} and {:?}", h, n),
+                )
+            }
+        };
+        Ok(Value::from_int(count as i64))
+    }
+
+    fn contains(haystack: &Value, needle: &Value) -> NxResult<Value> {
+        let contains = match (haystack.get_type(), needle.get_type()) {
+            (ValueType::String, ValueType::String) => find_bytes(
+                haystack.string_ref().as_bytes(),
+                needle.string_ref().as_bytes(),
+            )
+            .is_some(),
+            (ValueType::List, _) => {
+                let mut contains = false;
+                for element in haystack.list_ref().borrow().iter() {
+                    if element.eq(needle)?.unwrap_bool() {
+                        contains = true;
+                        break;
+                    }
+                }
+                contains
+            }
+            (h, n) => {
+                return nx_err_of(
+                    ErrorKind::Type,
+                    format!("contains cannot be applied to {:?} and {:?}", h, n),
+                )
+            }
+        };
+        Ok(Value::from_bool(contains))
+    }
+
+    fn dump(rt: &mut RuntimeContext, value: &Value) -> NxResult<Value> {
+        let mut out = String::new();
+        value.dump_to(&mut out, &mut Vec::new());
+        rt.write(&out);
+        Ok(Value::NULL)
+    }
+
+    // Index of the first occurrence of `needle` at or after `start`, or -1 if there is none.
+    // Strings are searched for a substring and indexed by byte like `s[i]`, lists for an element
+    // equal to `needle`.
+    fn find(haystack: &Value, needle: &Value, start: &Value) -> NxResult<Value> {
+        let found = match (haystack.get_type(), needle.get_type(), start.get_type()) {
+            (ValueType::String, ValueType::String, ValueType::Int)
+            | (ValueType::List, _, ValueType::Int)
+                if start.unwrap_int() < 0 =>
+            {
+                return nx_err_of(ErrorKind::Index, "index cannot be negative");
+            }
+            (ValueType::String, ValueType::String, ValueType::Int) => {
+                let start = start.unwrap_int() as usize;
+                let haystack = haystack.string_ref().as_bytes();
+                haystack
+                    .get(start..)
+                    .and_then(|tail| find_bytes(tail, needle.string_ref().as_bytes()))
+                    .map(|i| i + start)
+            }
+            (ValueType::List, _, ValueType::Int) => {
+                let start = start.unwrap_int() as usize;
+                let mut found = None;
+                for (i, element) in haystack.list_ref().borrow().iter().enumerate().skip(start) {
+                    if element.eq(needle)?.unwrap_bool() {
+                        found = Some(i);
+                        break;
+                    }
+                }
+                found
+            }
+            (h, n, s) => {
+                return nx_err_of(
+                    ErrorKind::Type,
+                    format!("find cannot be applied to {:?}, {:?} and {:?}", h, n, s),
+                )
+            }
+        };
+        Ok(Value::from_int(found.map_or(-1, |i| i as i64)))
+    }
+
     fn float(arg: &Value) -> NxResult<Value> {
         match arg.get_type() {
             ValueType::Int => Ok(Value::from_float(arg.unwrap_int() as f64)),
             ValueType::Float => Ok(arg.clone()),
             ValueType::String => Ok(Value::from_float(
-                f64::from_str(&arg.unwrap_string()).map_err(|e| nx_error(e.to_string()))?,
+                f64::from_str(&arg.unwrap_string())
+                    .map_err(|e| nx_error_of(ErrorKind::Value, e.to_string()))?,
             )),
-            t => nx_err(format!("float cannot be applied to {:?}", t)),
+            t => nx_err_of(
+                ErrorKind::Type,
+                format!("float cannot be applied to {:?}", t),
+            ),
         }
     }
 
+    fn builtins() -> Value {
+        let names = Builtin::ALL
+            .iter()
+            .map(|b| Value::from_string(b.name().into()))
+            .collect();
+        Value::from_list(Rc::new(names))
+    }
+
+    // Signature and description of a builtin, given as a function or by name
+    fn help(arg: &Value) -> NxResult<Value> {
+        let builtin = match arg.get_type() {
+            ValueType::String => {
+                let name = arg.unwrap_string();
+                match Builtin::ALL.iter().find(|b| b.name() == &*name) {
+                    Some(builtin) => *builtin,
+                    None => {
+                        return nx_err_of(ErrorKind::Value, format!("no builtin named {:?}", name))
+                    }
+                }
+            }
+            ValueType::Function => match arg.unwrap_function().as_ref() {
+                Function::Builtin(builtin) => *builtin,
+                Function::Native { name, .. } => {
+                    return nx_err_of(
+                        ErrorKind::Value,
+                        format!("help expects a builtin, {} is a native function", name),
+                    );
+                }
+                Function::UserDefined { name, .. } => {
+                    return nx_err_of(
+                        ErrorKind::Value,
+                        format!("help expects a builtin, {} is user-defined", name),
+                    );
+                }
+            },
+            t => {
+                return nx_err_of(
+                    ErrorKind::Type,
+                    format!("help cannot be applied to {:?}", t),
+                )
+            }
+        };
+        Ok(Value::from_string(
+            format!("{}: {}", builtin.signature(), builtin.description()).into(),
+        ))
+    }
+
     fn int(arg: &Value) -> NxResult<Value> {
         match arg.get_type() {
             ValueType::Int => Ok(arg.clone()),
             // Truncates towards zero, saturates on overflow, NaN → 0
             ValueType::Float => Ok(Value::from_int(arg.unwrap_float() as i64)),
             ValueType::String => Ok(Value::from_int(
-                i64::from_str(&arg.unwrap_string()).map_err(|e| nx_error(e.to_string()))?,
+                i64::from_str(&arg.unwrap_string())
+                    .map_err(|e| nx_error_of(ErrorKind::Value, e.to_string()))?,
             )),
-            t => nx_err(format!("int cannot be applied to {:?}", t)),
+            t => nx_err_of(ErrorKind::Type, format!("int cannot be applied to {:?}", t)),
         }
     }
 
+    fn join(list: &Value, separator: &Value) -> NxResult<Value> {
+        if !list.is_list() || !separator.is_string() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "join cannot be applied to {:?} and {:?}",
+                    list.get_type(),
+                    separator.get_type()
+                ),
+            );
+        }
+        let elements = list.unwrap_list();
+        let mut result = String::new();
+        for (i, e) in elements.iter().enumerate() {
+            if !e.is_string() {
+                return nx_err_of(
+                    ErrorKind::Type,
+                    format!("join expects a list of strings, found {:?}", e.get_type()),
+                );
+            }
+            if i > 0 {
+                result.push_str(separator.string_ref());
+            }
+            result.push_str(e.string_ref());
+        }
+        Ok(Value::from_string(result.into()))
+    }
+
+    // The parts of `string` between the occurrences of `separator`, which cannot be empty: an
+    // empty string has one part, and a separator at either end gives an empty part there
+    fn split(string: &Value, separator: &Value) -> NxResult<Value> {
+        if !string.is_string() || !separator.is_string() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "split cannot be applied to {:?} and {:?}",
+                    string.get_type(),
+                    separator.get_type()
+                ),
+            );
+        }
+        let separator = separator.string_ref();
+        if separator.is_empty() {
+            return nx_err_of(ErrorKind::Value, "split separator cannot be empty");
+        }
+        let parts = string
+            .string_ref()
+            .split(separator.as_ref())
+            .map(|part| Value::from_string(part.into()))
+            .collect();
+        Ok(Value::from_list(Rc::new(parts)))
+    }
+
+    // `string` with non-overlapping occurrences of `old` replaced from left to right, an empty
+    // `old` occurs before every character and at the end
+    fn replace(string: &Value, old: &Value, new: &Value) -> NxResult<Value> {
+        if !string.is_string() || !old.is_string() || !new.is_string() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "replace cannot be applied to {:?}, {:?} and {:?}",
+                    string.get_type(),
+                    old.get_type(),
+                    new.get_type()
+                ),
+            );
+        }
+        let replaced = string
+            .string_ref()
+            .replace(old.string_ref().as_ref(), new.string_ref());
+        Ok(Value::from_string(replaced.into()))
+    }
+
+    fn lower(string: &Value) -> NxResult<Value> {
+        Ok(Value::from_string(
+            string_arg("lower", string)?.to_lowercase().into(),
+        ))
+    }
+
+    fn upper(string: &Value) -> NxResult<Value> {
+        Ok(Value::from_string(
+            string_arg("upper", string)?.to_uppercase().into(),
+        ))
+    }
+
+    // Whitespace as defined by Unicode, which includes the ASCII space, tab and line breaks
+    fn trim(string: &Value) -> NxResult<Value> {
+        Ok(Value::from_string(
+            string_arg("trim", string)?.trim().into(),
+        ))
+    }
+
     fn len(arg: &Value) -> NxResult<Value> {
         match arg.get_type() {
             ValueType::String => Ok(Value::from_int(arg.unwrap_string().len() as i64)),
-            ValueType::List => Ok(Value::from_int(arg.unwrap_list().borrow().len() as i64)),
-            t => nx_err(format!("len cannot be applied to {:?}", t)),
+            ValueType::List => Ok(Value::from_int(arg.unwrap_list().len() as i64)),
+            ValueType::Table => Ok(Value::from_int(arg.unwrap_table().len() as i64)),
+            t => nx_err_of(ErrorKind::Type, format!("len cannot be applied to {:?}", t)),
+        }
+    }
+
+    // Like print, but to the log sink and only if `level` is enabled
+    fn log(rt: &mut RuntimeContext, level: LogLevel, value: &Value) -> NxResult<Value> {
+        rt.log(level, format!("{}", value).as_str());
+        Ok(Value::NULL)
+    }
+
+    // [string allocations, string bytes, list allocations, list bytes]
+    fn mem_stats(rt: &RuntimeContext) -> NxResult<Value> {
+        let stats = rt.mem_stats();
+        let values = [
+            stats.string_count,
+            stats.string_bytes,
+            stats.list_count,
+            stats.list_bytes,
+        ];
+        Ok(Value::from_list(Rc::new(
+            values.iter().map(|v| Value::from_int(*v as i64)).collect(),
+        )))
+    }
+
+    fn on_interrupt(rt: &mut RuntimeContext, handler: &Value) -> NxResult<Value> {
+        if handler.is_null() {
+            rt.set_interrupt_handler(None);
+        } else if handler.is_function() {
+            handler.unwrap_function().check_args(0)?;
+            rt.set_interrupt_handler(Some(handler.clone()));
+        } else {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!("on_interrupt cannot be applied to {:?}", handler.get_type()),
+            );
+        }
+        Ok(Value::NULL)
+    }
+
+    fn open(rt: &mut RuntimeContext, path: &Value, mode: &Value) -> NxResult<Value> {
+        if !path.is_string() || !mode.is_string() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "open cannot be applied to {:?} and {:?}",
+                    path.get_type(),
+                    mode.get_type()
+                ),
+            );
+        }
+        let mode: FileMode = mode
+            .string_ref()
+            .parse()
+            .map_err(|msg: String| nx_error_of(ErrorKind::Value, msg))?;
+        rt.require(match mode {
+            FileMode::Read => Capability::Read,
+            FileMode::Write | FileMode::Append => Capability::Write,
+        })?;
+        let path = path.string_ref();
+        let stream = io_result("open file", path, rt.open(path, mode))?;
+        Ok(Value::from_file(FileHandle::new(path, stream)))
+    }
+
+    fn path_join(path: &Value, other: &Value) -> NxResult<Value> {
+        if !path.is_string() || !other.is_string() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "path_join cannot be applied to {:?} and {:?}",
+                    path.get_type(),
+                    other.get_type()
+                ),
+            );
+        }
+        let joined = Path::new(path.string_ref().as_ref()).join(other.string_ref().as_ref());
+        Ok(Value::from_string(joined.to_string_lossy().into()))
+    }
+
+    fn basename(path: &Value) -> NxResult<Value> {
+        let name = Path::new(string_arg("basename", path)?).file_name();
+        Ok(Value::from_string(
+            name.map_or("".into(), |name| name.to_string_lossy().into()),
+        ))
+    }
+
+    fn extension(path: &Value) -> NxResult<Value> {
+        let extension = Path::new(string_arg("extension", path)?).extension();
+        Ok(Value::from_string(
+            extension.map_or("".into(), |ext| ext.to_string_lossy().into()),
+        ))
+    }
+
+    fn toml_parse(source: &Value) -> NxResult<Value> {
+        if !source.is_string() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!("toml_parse cannot be applied to {:?}", source.get_type()),
+            );
         }
+        toml::parse(source.string_ref())
+    }
+
+    fn fnmatch(name: &Value, pattern: &Value) -> NxResult<Value> {
+        if !name.is_string() || !pattern.is_string() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "fnmatch cannot be applied to {:?} and {:?}",
+                    name.get_type(),
+                    pattern.get_type()
+                ),
+            );
+        }
+        Ok(Value::from_bool(glob::fnmatch(
+            name.string_ref(),
+            pattern.string_ref(),
+        )))
+    }
+
+    fn glob(rt: &mut RuntimeContext, pattern: &Value) -> NxResult<Value> {
+        let pattern = string_arg("glob", pattern)?;
+        rt.require(Capability::Read)?;
+        let paths = glob::glob(rt, pattern);
+        Ok(Value::from_list(Rc::new(
+            paths
+                .into_iter()
+                .map(|path| Value::from_string(path.into()))
+                .collect(),
+        )))
+    }
+
+    fn list_dir(rt: &mut RuntimeContext, path: &Value) -> NxResult<Value> {
+        let path = string_arg("list_dir", path)?;
+        rt.require(Capability::Read)?;
+        let names = io_result("list directory", path, rt.list_dir(path))?;
+        Ok(Value::from_list(Rc::new(
+            names
+                .into_iter()
+                .map(|name| Value::from_string(name.into()))
+                .collect(),
+        )))
+    }
+
+    fn mkdir(rt: &mut RuntimeContext, path: &Value) -> NxResult<Value> {
+        let path = string_arg("mkdir", path)?;
+        rt.require(Capability::Write)?;
+        io_result("create directory", path, rt.mkdir(path))?;
+        Ok(Value::NULL)
+    }
+
+    fn remove_file(rt: &mut RuntimeContext, path: &Value) -> NxResult<Value> {
+        let path = string_arg("remove_file", path)?;
+        rt.require(Capability::Write)?;
+        io_result("remove file", path, rt.remove_file(path))?;
+        Ok(Value::NULL)
     }
 
     fn print(rt: &mut RuntimeContext, value: &Value) -> NxResult<Value> {
@@ -94,17 +803,184 @@ impl Builtin {
         Ok(Value::NULL)
     }
 
+    fn read_line(file: &Value) -> NxResult<Value> {
+        if !file.is_file() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!("read_line cannot be applied to {:?}", file.get_type()),
+            );
+        }
+        let line = file.unwrap_file().borrow_mut().read_line()?;
+        Ok(line.map_or(Value::FALSE, |line| Value::from_string(line.into())))
+    }
+
+    // Removes the element at `index` and returns it, the following elements move down by one
+    fn remove_at(list: &Value, index: &Value) -> NxResult<Value> {
+        if !list.is_list() || !index.is_int() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "remove_at cannot be applied to {:?} and {:?}",
+                    list.get_type(),
+                    index.get_type()
+                ),
+            );
+        }
+        let idx = index.unwrap_int();
+        if idx < 0 {
+            return nx_err_of(ErrorKind::Index, "index cannot be negative");
+        }
+        if idx as usize >= list.list_ref().borrow().len() {
+            return nx_err_of(ErrorKind::Index, "list index out of bounds");
+        }
+        Ok(list.list_elements_mut().remove(idx as usize))
+    }
+
+    // Inserts before `index`, the following elements move up by one
+    fn insert(list: &Value, index: &Value, value: &Value) -> NxResult<Value> {
+        if !list.is_list() || !index.is_int() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "insert cannot be applied to {:?} and {:?}",
+                    list.get_type(),
+                    index.get_type()
+                ),
+            );
+        }
+        let idx = index.unwrap_int();
+        if idx < 0 {
+            return nx_err_of(ErrorKind::Index, "index cannot be negative");
+        }
+        if idx as usize > list.list_ref().borrow().len() {
+            return nx_err_of(ErrorKind::Index, "list index out of bounds");
+        }
+        list.list_elements_mut().insert(idx as usize, value.clone());
+        Ok(Value::NULL)
+    }
+
+    fn push(list: &Value, value: &Value) -> NxResult<Value> {
+        if !list.is_list() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!("push cannot be applied to {:?}", list.get_type()),
+            );
+        }
+        list.list_elements_mut().push(value.clone());
+        Ok(Value::NULL)
+    }
+
+    fn pop(list: &Value) -> NxResult<Value> {
+        if !list.is_list() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!("pop cannot be applied to {:?}", list.get_type()),
+            );
+        }
+        if list.list_ref().borrow().is_empty() {
+            return nx_err_of(ErrorKind::Index, "pop from an empty list");
+        }
+        Ok(list.list_elements_mut().pop().unwrap())
+    }
+
+    // Removes the first element equal to `value`, compared like `==`
+    fn remove(list: &Value, value: &Value) -> NxResult<Value> {
+        if !list.is_list() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!("remove cannot be applied to {:?}", list.get_type()),
+            );
+        }
+        let mut found = None;
+        for (i, element) in list.list_ref().borrow().iter().enumerate() {
+            if element.eq(value)?.unwrap_bool() {
+                found = Some(i);
+                break;
+            }
+        }
+        if let Some(i) = found {
+            list.list_elements_mut().remove(i);
+        }
+        Ok(Value::from_bool(found.is_some()))
+    }
+
+    fn close(file: &Value) -> NxResult<Value> {
+        if !file.is_file() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!("close cannot be applied to {:?}", file.get_type()),
+            );
+        }
+        file.unwrap_file().borrow_mut().close()?;
+        Ok(Value::NULL)
+    }
+
+    fn sb_build(builder: &Value) -> NxResult<Value> {
+        if !builder.is_string_builder() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!("sb_build cannot be applied to {:?}", builder.get_type()),
+            );
+        }
+        let s = builder.unwrap_string_builder().borrow().as_str().into();
+        Ok(Value::from_string(s))
+    }
+
+    fn sb_new() -> NxResult<Value> {
+        Ok(Value::from_string_builder(Rc::new(RefCell::new(
+            String::new(),
+        ))))
+    }
+
+    // Appends in place, so building a string of n pieces is O(n) unlike repeated `+`
+    fn sb_push(builder: &Value, s: &Value) -> NxResult<Value> {
+        if !builder.is_string_builder() || !s.is_string() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "sb_push cannot be applied to {:?} and {:?}",
+                    builder.get_type(),
+                    s.get_type()
+                ),
+            );
+        }
+        builder
+            .unwrap_string_builder()
+            .borrow_mut()
+            .push_str(s.string_ref());
+        Ok(Value::NULL)
+    }
+
     fn str(arg: &Value) -> NxResult<Value> {
         Ok(Value::from_string(format!("{}", arg).into()))
     }
 
-    fn time() -> NxResult<Value> {
-        let now = std::time::SystemTime::now();
-        let duration = now
-            .duration_since(std::time::UNIX_EPOCH)
-            .expect("System time is before UNIX epoch");
-        let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0;
-        Ok(Value::from_float(seconds))
+    fn write(file: &Value, s: &Value) -> NxResult<Value> {
+        if !file.is_file() || !s.is_string() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "write cannot be applied to {:?} and {:?}",
+                    file.get_type(),
+                    s.get_type()
+                ),
+            );
+        }
+        file.unwrap_file().borrow_mut().write(s.string_ref())?;
+        Ok(Value::NULL)
+    }
+
+    fn time(rt: &mut RuntimeContext) -> NxResult<Value> {
+        Ok(Value::from_float(rt.now()))
+    }
+
+    fn natrix_version() -> Value {
+        Value::from_string(env!("CARGO_PKG_VERSION").into())
+    }
+
+    // The operating system, as named by Rust (`linux`, `macos`, `windows`, ...)
+    fn platform() -> Value {
+        Value::from_string(std::env::consts::OS.into())
     }
 }
 
@@ -113,6 +989,48 @@ impl Value {
         matches!(self.get_type(), ValueType::Int | ValueType::Float)
     }
 
+    // Renders the value for `dump`: its type and value on one line, followed by the elements of a
+    // list, one per line and indented by their depth. `ancestors` holds the enclosing lists, so that
+    // a list containing itself is shown as a reference instead of recursing forever.
+    fn dump_to(&self, out: &mut String, ancestors: &mut Vec<*const RefCell<Rc<Vec<Value>>>>) {
+        match self.get_type() {
+            ValueType::Null => out.push_str("null"),
+            ValueType::Bool | ValueType::Int | ValueType::Float => {
+                write!(out, "{} {}", self.get_type(), self).unwrap()
+            }
+            ValueType::String => write!(out, "string {:?}", self.string_ref()).unwrap(),
+            ValueType::List => {
+                let list = Rc::as_ptr(self.list_ref());
+                if let Some(pos) = ancestors.iter().position(|a| *a == list) {
+                    let levels = ancestors.len() - pos;
+                    write!(out, "list <cycle: {} up>", plural(levels, "level")).unwrap();
+                    return;
+                }
+                let elements = self.unwrap_list();
+                write!(out, "list of {}", plural(elements.len(), "element")).unwrap();
+                ancestors.push(list);
+                for (i, element) in elements.iter().enumerate() {
+                    write!(out, "\n{}[{}] ", "  ".repeat(ancestors.len()), i).unwrap();
+                    element.dump_to(out, ancestors);
+                }
+                ancestors.pop();
+            }
+            // tables hold no lists that could contain them
+            ValueType::Table => {
+                let entries = self.unwrap_table();
+                write!(out, "table of {}", plural(entries.len(), "key")).unwrap();
+                for (key, value) in entries.iter() {
+                    write!(out, "\n{}[{:?}] ", "  ".repeat(ancestors.len() + 1), key).unwrap();
+                    value.dump_to(out, ancestors);
+                }
+            }
+            // already annotated with their type
+            ValueType::Function | ValueType::StringBuilder | ValueType::File => {
+                write!(out, "{}", self).unwrap()
+            }
+        }
+    }
+
     fn string_ref(&self) -> &Rc<str> {
         match &self.0 {
             ValueImpl::String(s) => s,
@@ -120,13 +1038,24 @@ impl Value {
         }
     }
 
-    fn list_ref(&self) -> &Rc<RefCell<Vec<Value>>> {
+    fn list_ref(&self) -> &Rc<RefCell<Rc<Vec<Value>>>> {
         match &self.0 {
             ValueImpl::List(v) => v,
             _ => panic!("expected list, got {:?}", self.get_type()),
         }
     }
 
+    // The elements of a list to modify in place, copied first if they are shared with another list
+    fn list_elements_mut(&self) -> RefMut<'_, Vec<Value>> {
+        let mut elements = self.list_ref().borrow_mut();
+        if Rc::strong_count(&elements) > 1 {
+            #[cfg(feature = "profile-alloc")]
+            crate::profile::count_list_copy();
+            *elements = Rc::new(elements.as_ref().clone());
+        }
+        RefMut::map(elements, |elements| Rc::get_mut(elements).unwrap())
+    }
+
     fn function_ref(&self) -> &Rc<Function> {
         match &self.0 {
             ValueImpl::Function(v) => v,
@@ -156,12 +1085,15 @@ impl Value {
         if self.is_numeric() && other.is_numeric() {
             Ok(())
         } else {
-            nx_err(format!(
-                "operator {} cannot be applied to {:?} and {:?}",
-                op,
-                self.get_type(),
-                other.get_type()
-            ))
+            nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "operator {} cannot be applied to {:?} and {:?}",
+                    op,
+                    self.get_type(),
+                    other.get_type()
+                ),
+            )
         }
     }
 
@@ -178,10 +1110,16 @@ impl Value {
         if self.is_list() && other.is_list() {
             let v1 = self.list_ref().borrow();
             let v2 = other.list_ref().borrow();
+            if v2.is_empty() {
+                return Ok(Value::from_list(v1.clone()));
+            }
+            if v1.is_empty() {
+                return Ok(Value::from_list(v2.clone()));
+            }
             let mut result = Vec::with_capacity(v1.len() + v2.len());
             result.extend(v1.iter().cloned());
             result.extend(v2.iter().cloned());
-            return Ok(Value::from_list(Rc::new(RefCell::new(result))));
+            return Ok(Value::from_list(Rc::new(result)));
         }
 
         self.check_numeric_operands(other, "+")?;
@@ -209,15 +1147,17 @@ impl Value {
             let s = self.string_ref();
             let cnt = other.unwrap_int();
             if cnt < 0 {
-                return nx_err("string repetition count cannot be negative");
+                return nx_err_of(
+                    ErrorKind::Value,
+                    "string repetition count cannot be negative",
+                );
             }
             let cnt = cnt as usize;
 
             // Check for overflow before allocating
-            let new_len = s
-                .len()
-                .checked_mul(cnt)
-                .ok_or_else(|| nx_error("string repetition result too large"))?;
+            let new_len = s.len().checked_mul(cnt).ok_or_else(|| {
+                nx_error_of(ErrorKind::Overflow, "string repetition result too large")
+            })?;
 
             let mut result = String::with_capacity(new_len);
             for _ in 0..cnt {
@@ -231,21 +1171,23 @@ impl Value {
             let l = self.list_ref().borrow();
             let cnt = other.unwrap_int();
             if cnt < 0 {
-                return nx_err("list repetition count cannot be negative");
+                return nx_err_of(ErrorKind::Value, "list repetition count cannot be negative");
             }
             let cnt = cnt as usize;
+            if cnt == 1 {
+                return Ok(Value::from_list(l.clone()));
+            }
 
             // Check for overflow before allocating
-            let new_len = l
-                .len()
-                .checked_mul(cnt)
-                .ok_or_else(|| nx_error("list repetition result too large"))?;
+            let new_len = l.len().checked_mul(cnt).ok_or_else(|| {
+                nx_error_of(ErrorKind::Overflow, "list repetition result too large")
+            })?;
 
             let mut result = Vec::with_capacity(new_len);
             for _ in 0..cnt {
                 result.extend(l.iter().cloned());
             }
-            return Ok(Value::from_list(Rc::new(RefCell::new(result))));
+            return Ok(Value::from_list(Rc::new(result)));
         }
 
         if self.is_int() && (other.is_string() || other.is_list()) {
@@ -261,12 +1203,15 @@ impl Value {
         }
     }
 
+    /// Divides two numbers. Two ints give an int rounded towards zero, and a divisor of zero is an
+    /// error. Otherwise both are converted to float and divided as IEEE 754 floats, so a divisor of
+    /// zero gives an infinity or NaN. `i64::MIN / -1` wraps around to `i64::MIN`.
     pub fn div(&self, other: &Value) -> NxResult<Value> {
         self.check_numeric_operands(other, "/")?;
 
         if let Some((l, r)) = self.as_i64_pair(other) {
             if r == 0 {
-                return nx_err("division by zero");
+                return nx_err_of(ErrorKind::DivisionByZero, "division by zero");
             }
             Ok(Value::from_int(l.wrapping_div(r)))
         } else {
@@ -274,12 +1219,22 @@ impl Value {
         }
     }
 
+    /// The remainder of [`Value::div`], the definition of `%` for both interpreters and constant
+    /// folding.
+    ///
+    /// The division rounds towards zero, so `a % b == a - a / b * b` for ints and the remainder
+    /// has the sign of the dividend (`-7 % 3 == -1`, `7 % -3 == 1`), like in C, Rust or
+    /// JavaScript and unlike the floored modulo of Python. Two ints give an int, and a divisor of
+    /// zero is an error. Otherwise both are converted to float and the result is that of C's
+    /// `fmod`: exact, with the sign of the dividend (also for a zero result, `-4.0 % 2 == -0.0`),
+    /// the dividend itself for an infinite divisor, and NaN for a divisor of zero or an infinite
+    /// dividend. `i64::MIN % -1` is 0.
     pub fn rem(&self, other: &Value) -> NxResult<Value> {
         self.check_numeric_operands(other, "%")?;
 
         if let Some((l, r)) = self.as_i64_pair(other) {
             if r == 0 {
-                return nx_err("division by zero");
+                return nx_err_of(ErrorKind::DivisionByZero, "division by zero");
             }
             Ok(Value::from_int(l.wrapping_rem(r)))
         } else {
@@ -312,6 +1267,20 @@ impl Value {
             return Ok(Value::TRUE);
         }
 
+        // Tables - entry-wise comparison, the keys are sorted
+        if self.is_table() && other.is_table() {
+            let (t1, t2) = (self.unwrap_table(), other.unwrap_table());
+            if t1.len() != t2.len() {
+                return Ok(Value::FALSE);
+            }
+            for ((k1, v1), (k2, v2)) in t1.iter().zip(t2.iter()) {
+                if k1 != k2 || !v1.eq(v2)?.unwrap_bool() {
+                    return Ok(Value::FALSE);
+                }
+            }
+            return Ok(Value::TRUE);
+        }
+
         // Functions
         if self.is_function() && other.is_function() {
             return Ok(Value::from_bool(Rc::ptr_eq(
@@ -320,6 +1289,22 @@ impl Value {
             )));
         }
 
+        // String builders
+        if self.is_string_builder() && other.is_string_builder() {
+            return Ok(Value::from_bool(Rc::ptr_eq(
+                &self.unwrap_string_builder(),
+                &other.unwrap_string_builder(),
+            )));
+        }
+
+        // Files
+        if self.is_file() && other.is_file() {
+            return Ok(Value::from_bool(Rc::ptr_eq(
+                &self.unwrap_file(),
+                &other.unwrap_file(),
+            )));
+        }
+
         // Bools
         if self.is_bool() && other.is_bool() {
             return Ok(Value::from_bool(self.unwrap_bool() == other.unwrap_bool()));
@@ -404,7 +1389,10 @@ impl Value {
         match self.get_type() {
             ValueType::Int => Ok(Value::from_int(self.unwrap_int().wrapping_neg())),
             ValueType::Float => Ok(Value::from_float(-self.unwrap_float())),
-            t => nx_err(format!("unary negation cannot be applied to {:?}", t)),
+            t => nx_err_of(
+                ErrorKind::Type,
+                format!("unary negation cannot be applied to {:?}", t),
+            ),
         }
     }
 
@@ -412,23 +1400,37 @@ impl Value {
         if self.is_bool() {
             Ok(Value::from_bool(!self.unwrap_bool()))
         } else {
-            nx_err(format!(
-                "logical negation cannot be applied to {:?}",
-                self.get_type()
-            ))
+            nx_err_of(
+                ErrorKind::Type,
+                format!(
+                    "logical negation cannot be applied to {:?}",
+                    self.get_type()
+                ),
+            )
         }
     }
 
     // Index operations
 
+    /// Returns `value[index]`: an element of a list, a byte of a string, or the value of the key
+    /// `index` of a table, null if it has none. An integer index gives the entry of a table at that
+    /// position as a `[key, value]` list, so that it can be iterated like a list.
     pub fn get_item(&self, index: Value) -> NxResult<Value> {
+        if self.is_table() && index.is_string() {
+            let entries = self.unwrap_table();
+            let key = index.string_ref();
+            return Ok(entries
+                .binary_search_by(|(k, _)| k.cmp(key))
+                .map_or(Value::NULL, |i| entries[i].1.clone()));
+        }
+
         if !index.is_int() {
-            return nx_err("index must be an integer");
+            return nx_err_of(ErrorKind::Type, "index must be an integer");
         }
 
         let idx = index.unwrap_int();
         if idx < 0 {
-            return nx_err("index cannot be negative");
+            return nx_err_of(ErrorKind::Index, "index cannot be negative");
         }
         let idx = idx as usize;
 
@@ -436,7 +1438,7 @@ impl Value {
             let list = self.list_ref().borrow();
             return match list.get(idx) {
                 Some(v) => Ok(v.clone()),
-                None => nx_err("list index out of bounds"),
+                None => nx_err_of(ErrorKind::Index, "list index out of bounds"),
             };
         }
 
@@ -444,39 +1446,155 @@ impl Value {
             let string = self.string_ref();
             return match string.as_bytes().get(idx) {
                 Some(&byte) => Ok(Value::from_int(byte as i64)),
-                None => nx_err("string index out of bounds"),
+                None => nx_err_of(ErrorKind::Index, "string index out of bounds"),
+            };
+        }
+
+        if self.is_table() {
+            return match self.unwrap_table().get(idx) {
+                Some((key, value)) => Ok(Value::from_list(Rc::new(vec![
+                    Value::from_string(key.clone()),
+                    value.clone(),
+                ]))),
+                None => nx_err_of(ErrorKind::Index, "table index out of bounds"),
             };
         }
 
-        nx_err("only lists and strings support indexing")
+        nx_err_of(
+            ErrorKind::Type,
+            "only lists, strings and tables support indexing",
+        )
+    }
+
+    /// Returns the elements of a list, or the bytes of a string, from `start` up to but not
+    /// including `end`, for `value[start:end]`.
+    ///
+    /// A negative bound counts from the end, and a null bound stands for the start or the end.
+    /// Bounds beyond either end are clamped, so the result is empty rather than an error if
+    /// `start` is not before `end`. A string can only be sliced at character boundaries. A slice
+    /// of a whole list shares its elements until one of the two lists is modified.
+    pub fn get_slice(&self, start: Value, end: Value) -> NxResult<Value> {
+        let len = if self.is_list() {
+            self.list_ref().borrow().len()
+        } else if self.is_string() {
+            self.string_ref().len()
+        } else {
+            return nx_err_of(ErrorKind::Type, "only lists and strings support slicing");
+        };
+        let start = slice_bound(start, len, 0)?;
+        let end = slice_bound(end, len, len)?.max(start);
+
+        if self.is_list() {
+            let list = self.list_ref().borrow();
+            if start == 0 && end == len {
+                return Ok(Value::from_list(list.clone()));
+            }
+            return Ok(Value::from_list(Rc::new(list[start..end].to_vec())));
+        }
+
+        match self.string_ref().get(start..end) {
+            Some(slice) => Ok(Value::from_string(slice.into())),
+            None => nx_err_of(
+                ErrorKind::Value,
+                "string slice does not start and end at character boundaries",
+            ),
+        }
     }
 
     pub fn set_item(&self, index: Value, value: Value) -> NxResult<()> {
         if !index.is_int() {
-            return nx_err("index must be an integer");
+            return nx_err_of(ErrorKind::Type, "index must be an integer");
         }
 
         let idx = index.unwrap_int();
         if idx < 0 {
-            return nx_err("index cannot be negative");
+            return nx_err_of(ErrorKind::Index, "index cannot be negative");
         }
         let idx = idx as usize;
 
         if self.is_list() {
-            let mut list = self.list_ref().borrow_mut();
-            return match list.get_mut(idx) {
-                Some(v) => {
-                    *v = value;
-                    Ok(())
-                }
-                None => nx_err("list index out of bounds"),
-            };
+            if idx >= self.list_ref().borrow().len() {
+                return nx_err_of(ErrorKind::Index, "list index out of bounds");
+            }
+            self.list_elements_mut()[idx] = value;
+            return Ok(());
+        }
+
+        nx_err_of(
+            ErrorKind::Type,
+            "only lists support indexing in assignments",
+        )
+    }
+
+    /// Returns the elements of a list that has exactly `count` elements, for `a, b = value;`.
+    pub fn unpack(&self, count: usize) -> NxResult<Vec<Value>> {
+        if !self.is_list() {
+            return nx_err_of(
+                ErrorKind::Type,
+                format!("unpacking cannot be applied to {:?}", self.get_type()),
+            );
+        }
+        let list = self.list_ref().borrow();
+        if list.len() != count {
+            return nx_err_of(
+                ErrorKind::Value,
+                format!(
+                    "cannot unpack a list of {} elements into {} targets",
+                    list.len(),
+                    count
+                ),
+            );
+        }
+        Ok(list.to_vec())
+    }
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    format!("{} {}{}", count, noun, if count == 1 { "" } else { "s" })
+}
+
+// Scans for the first byte of the needle and compares the rest only where it occurs, which is
+// fast for the short needles typical in scripts
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let Some((&first, rest)) = needle.split_first() else {
+        return Some(0);
+    };
+    let mut offset = 0;
+    while haystack.len() - offset >= needle.len() {
+        let last_start = haystack.len() - needle.len();
+        let at = offset
+            + haystack[offset..=last_start]
+                .iter()
+                .position(|&b| b == first)?;
+        if &haystack[at + 1..at + needle.len()] == rest {
+            return Some(at);
         }
+        offset = at + 1;
+    }
+    None
+}
 
-        nx_err("only lists support indexing in assignments")
+// The string argument of builtins taking one, like a path or the string of `upper`
+fn string_arg<'v>(builtin: &str, arg: &'v Value) -> NxResult<&'v str> {
+    if arg.is_string() {
+        Ok(arg.string_ref())
+    } else {
+        nx_err_of(
+            ErrorKind::Type,
+            format!("{} cannot be applied to {:?}", builtin, arg.get_type()),
+        )
     }
 }
 
+fn io_result<T>(action: &str, path: &str, result: io::Result<T>) -> NxResult<T> {
+    result.map_err(|err| {
+        nx_error_of(
+            ErrorKind::Io,
+            format!("cannot {} {:?}: {}", action, path, err),
+        )
+    })
+}
+
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.0 {
@@ -502,12 +1620,150 @@ impl Display for Value {
                 Function::Builtin(builtin) => {
                     write!(f, "<built-in function {}>", builtin.name())
                 }
+                Function::Native { name, .. } => write!(f, "<native function {}>", name),
                 Function::UserDefined {
-                    name, code_handle, ..
-                } => {
-                    write!(f, "<function {} at {:#x}>", name, code_handle)
-                }
+                    name,
+                    location: Some(location),
+                    ..
+                } => write!(f, "<function {} at {}>", name, location),
+                Function::UserDefined { name, .. } => write!(f, "<function {}>", name),
             },
+            ValueImpl::StringBuilder(v) => {
+                write!(f, "<string builder of length {}>", v.borrow().len())
+            }
+            ValueImpl::File(v) => {
+                let file = v.borrow();
+                let state = if file.is_closed() { "closed " } else { "" };
+                write!(f, "<{}file {:?}>", state, file.path())
+            }
+            ValueImpl::Table(entries) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match &value.0 {
+                        ValueImpl::String(s) => write!(f, "{:?}: {:?}", key, s)?,
+                        _ => write!(f, "{:?}: {}", key, value)?,
+                    }
+                }
+                write!(f, "}}")
+            }
         }
     }
 }
+
+impl Display for ValueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ValueType::Null => "null",
+            ValueType::Bool => "bool",
+            ValueType::Int => "int",
+            ValueType::Float => "float",
+            ValueType::String => "string",
+            ValueType::List => "list",
+            ValueType::Function => "function",
+            ValueType::StringBuilder => "string builder",
+            ValueType::File => "file",
+            ValueType::Table => "table",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Resolves a bound of a slice of a value of `len` elements to an index from 0 to `len`
+fn slice_bound(bound: Value, len: usize, default: usize) -> NxResult<usize> {
+    if bound.is_null() {
+        return Ok(default);
+    }
+    if !bound.is_int() {
+        return nx_err_of(ErrorKind::Type, "slice bounds must be integers or null");
+    }
+    let bound = bound.unwrap_int();
+    let len = len as i64;
+    let bound = if bound < 0 { bound + len } else { bound };
+    Ok(bound.clamp(0, len) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_kinds() {
+        let kind = |result: NxResult<Value>| result.unwrap_err().kind;
+        let (one, zero) = (Value::from_int(1), Value::from_int(0));
+        let list = Value::from_list(Rc::new(vec![one.clone()]));
+        assert_eq!(kind(one.div(&zero)), ErrorKind::DivisionByZero);
+        assert_eq!(kind(list.get_item(one.clone())), ErrorKind::Index);
+        assert_eq!(kind(list.get_item(list.clone())), ErrorKind::Type);
+        assert_eq!(kind(one.add(&list)), ErrorKind::Type);
+        let x = Value::from_string("x".into());
+        let mut rt = RuntimeContext::new();
+        assert_eq!(kind(Builtin::Int.eval(&mut rt, &[x])), ErrorKind::Value);
+    }
+
+    #[test]
+    fn test_get_slice() {
+        let slice = |value: &Value, start: Option<i64>, end: Option<i64>| {
+            let bound = |b: Option<i64>| b.map_or(Value::NULL, Value::from_int);
+            value.get_slice(bound(start), bound(end))
+        };
+        let s = Value::from_string("hello".into());
+        assert_eq!(slice(&s, Some(1), Some(3)).unwrap().to_string(), "el");
+        assert_eq!(slice(&s, None, Some(-1)).unwrap().to_string(), "hell");
+        assert_eq!(slice(&s, Some(-3), None).unwrap().to_string(), "llo");
+        assert_eq!(slice(&s, Some(-10), Some(10)).unwrap().to_string(), "hello");
+        assert_eq!(slice(&s, Some(4), Some(2)).unwrap().to_string(), "");
+        let list = Value::from_list(Rc::new((0..5).map(Value::from_int).collect()));
+        assert_eq!(slice(&list, Some(3), None).unwrap().to_string(), "[3, 4]");
+        assert_eq!(
+            slice(&list, None, None).unwrap().to_string(),
+            "[0, 1, 2, 3, 4]"
+        );
+
+        let kind = |result: NxResult<Value>| result.unwrap_err().kind;
+        let accent = Value::from_string("é".into());
+        assert_eq!(kind(slice(&accent, Some(1), None)), ErrorKind::Value);
+        assert_eq!(
+            kind(slice(&Value::from_int(1), None, None)),
+            ErrorKind::Type
+        );
+        assert_eq!(
+            kind(s.get_slice(Value::from_float(1.0), Value::NULL)),
+            ErrorKind::Type
+        );
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let elements = |value: &Value| Rc::as_ptr(&value.unwrap_list());
+        let list = Value::from_list(Rc::new((0..3).map(Value::from_int).collect()));
+        let original = elements(&list);
+        let copy = list.get_slice(Value::NULL, Value::NULL).unwrap();
+        assert_eq!(elements(&copy), original);
+        // only the first write to the copy finds the elements shared
+        copy.set_item(Value::from_int(0), Value::from_int(10))
+            .unwrap();
+        let copied = elements(&copy);
+        assert_ne!(copied, original);
+        copy.set_item(Value::from_int(1), Value::from_int(20))
+            .unwrap();
+        assert_eq!(elements(&copy), copied);
+        list.set_item(Value::from_int(2), Value::from_int(30))
+            .unwrap();
+        assert_eq!(elements(&list), original);
+        assert_eq!(list.to_string(), "[0, 1, 30]");
+        assert_eq!(copy.to_string(), "[10, 20, 2]");
+    }
+
+    #[test]
+    fn test_find_bytes() {
+        assert_eq!(find_bytes(b"abcabd", b"abd"), Some(3));
+        assert_eq!(find_bytes(b"abcabd", b"d"), Some(5));
+        assert_eq!(find_bytes(b"abcabd", b"abdx"), None);
+        assert_eq!(find_bytes(b"ab", b"abc"), None);
+        assert_eq!(find_bytes(b"ab", b""), Some(0));
+        assert_eq!(find_bytes(b"", b"a"), None);
+    }
+}
@@ -0,0 +1,641 @@
+use crate::error::{nx_err_of, ErrorKind, NxResult};
+use crate::value::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::rc::Rc;
+
+/// Parses a TOML document for the `toml_parse` builtin.
+///
+/// A table becomes a table value, whose keys are sorted. Arrays and arrays of tables become
+/// lists.
+pub(crate) fn parse(source: &str) -> NxResult<Value> {
+    Toml::parse(source).map(Toml::into_value)
+}
+
+/// A parsed TOML value, for Rust code reading TOML files such as the manifests of packages.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Toml {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Toml>),           // also an array of tables
+    Table(Vec<(String, Toml)>), // sorted by key
+}
+
+impl Toml {
+    /// Parses a document into the table of its top-level keys and tables. Dates and times are not
+    /// supported. Errors give the line and column of the document where they occur.
+    pub fn parse(source: &str) -> NxResult<Toml> {
+        let mut parser = Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        };
+        let root = parser.document()?;
+        Ok(table_toml(root))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Toml> {
+        let entries = self.as_table()?;
+        let index = entries
+            .binary_search_by(|(k, _)| k.as_str().cmp(key))
+            .ok()?;
+        Some(&entries[index].1)
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Toml::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Toml::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Toml]> {
+        match self {
+            Toml::Array(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&[(String, Toml)]> {
+        match self {
+            Toml::Table(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            Toml::String(s) => Value::from_string(s.into()),
+            Toml::Integer(n) => Value::from_int(n),
+            Toml::Float(x) => Value::from_float(x),
+            Toml::Bool(b) => Value::from_bool(b),
+            Toml::Array(elements) => Value::from_list(Rc::new(
+                elements.into_iter().map(Toml::into_value).collect(),
+            )),
+            Toml::Table(entries) => Value::from_table(Rc::new(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key.into(), value.into_value()))
+                    .collect(),
+            )),
+        }
+    }
+}
+
+type Table = BTreeMap<String, Item>;
+
+enum Item {
+    Value(Toml),
+    // declared with a [header] or a dotted key, so later headers and keys can add to it
+    Table(Table),
+    // declared with [[headers]], keys are added to the last one
+    TableArray(Vec<Table>),
+}
+
+fn table_toml(table: Table) -> Toml {
+    let entries = table
+        .into_iter()
+        .map(|(key, item)| (key, item_toml(item)))
+        .collect();
+    Toml::Table(entries)
+}
+
+fn item_toml(item: Item) -> Toml {
+    match item {
+        Item::Value(value) => value,
+        Item::Table(table) => table_toml(table),
+        Item::TableArray(tables) => Toml::Array(tables.into_iter().map(table_toml).collect()),
+    }
+}
+
+// The table at `path` below `table`, created if missing. An array of tables stands for its last
+// table.
+fn table_at<'t>(mut table: &'t mut Table, path: &[String]) -> Result<&'t mut Table, String> {
+    for (i, key) in path.iter().enumerate() {
+        let item = table
+            .entry(key.clone())
+            .or_insert_with(|| Item::Table(Table::new()));
+        table = match item {
+            Item::Table(table) => table,
+            Item::TableArray(tables) => tables.last_mut().unwrap(),
+            Item::Value(_) => return Err(format!("{} is not a table", path[..=i].join("."))),
+        };
+    }
+    Ok(table)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl AsRef<str>) -> NxResult<T> {
+        let before = &self.chars[..self.pos];
+        let line = before.iter().filter(|&&c| c == '\n').count() + 1;
+        let column = before.iter().rev().take_while(|&&c| c != '\n').count() + 1;
+        nx_err_of(
+            ErrorKind::Value,
+            format!(
+                "invalid TOML at line {}, column {}: {}",
+                line,
+                column,
+                message.as_ref()
+            ),
+        )
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn looking_at(&self, s: &str) -> bool {
+        s.chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c))
+    }
+
+    fn expect(&mut self, c: char) -> NxResult<()> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            self.error(format!("expected '{}'", c))
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.pos += 1;
+        }
+    }
+
+    // Spaces, newlines and comments, between the lines of the document or the elements of an array
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ' | '\t' | '\r' | '\n') => self.pos += 1,
+                Some('#') => self.skip_comment(),
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.pos += 1;
+        }
+    }
+
+    fn end_of_line(&mut self) -> NxResult<()> {
+        self.skip_spaces();
+        if self.peek() == Some('#') {
+            self.skip_comment();
+        }
+        if self.looking_at("\r\n") {
+            self.pos += 2;
+        }
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(_) => self.error("expected the end of the line"),
+        }
+    }
+
+    fn document(&mut self) -> NxResult<Table> {
+        let mut root = Table::new();
+        let mut current = Vec::new(); // the path of the last header
+        let mut headers = HashSet::new();
+        loop {
+            self.skip_blank();
+            let start = self.pos;
+            if self.peek().is_none() {
+                return Ok(root);
+            }
+            if self.looking_at("[[") {
+                self.pos += 2;
+                let path = self.header_key()?;
+                self.expect(']')?;
+                self.expect(']')?;
+                let (last, parent) = path.split_last().unwrap();
+                let parent = table_at(&mut root, parent).or_else(|e| self.error_at(start, e))?;
+                match parent
+                    .entry(last.clone())
+                    .or_insert(Item::TableArray(Vec::new()))
+                {
+                    Item::TableArray(tables) => tables.push(Table::new()),
+                    _ => {
+                        let message = format!("{} is not an array of tables", path.join("."));
+                        return self.error_at(start, message);
+                    }
+                }
+                current = path;
+            } else if self.peek() == Some('[') {
+                self.pos += 1;
+                let path = self.header_key()?;
+                self.expect(']')?;
+                if !headers.insert(path.clone()) {
+                    let message = format!("table {} is declared twice", path.join("."));
+                    return self.error_at(start, message);
+                }
+                table_at(&mut root, &path).or_else(|e| self.error_at(start, e))?;
+                current = path;
+            } else {
+                let table = table_at(&mut root, &current).or_else(|e| self.error_at(start, e))?;
+                self.key_value(table)?;
+            }
+            self.end_of_line()?;
+        }
+    }
+
+    fn error_at<T>(&mut self, pos: usize, message: String) -> NxResult<T> {
+        self.pos = pos;
+        self.error(message)
+    }
+
+    fn header_key(&mut self) -> NxResult<Vec<String>> {
+        self.skip_spaces();
+        let key = self.key()?;
+        self.skip_spaces();
+        Ok(key)
+    }
+
+    // `key = value`, the key may be dotted to declare it in a nested table
+    fn key_value(&mut self, table: &mut Table) -> NxResult<()> {
+        let start = self.pos;
+        let path = self.key()?;
+        self.skip_spaces();
+        self.expect('=')?;
+        self.skip_spaces();
+        let value = self.value()?;
+        let (last, parent) = path.split_last().unwrap();
+        let table = table_at(table, parent).or_else(|e| self.error_at(start, e))?;
+        if table.contains_key(last) {
+            return self.error_at(start, format!("duplicate key {}", path.join(".")));
+        }
+        table.insert(last.clone(), Item::Value(value));
+        Ok(())
+    }
+
+    fn key(&mut self) -> NxResult<Vec<String>> {
+        let mut path = vec![self.simple_key()?];
+        loop {
+            let pos = self.pos;
+            self.skip_spaces();
+            if self.peek() != Some('.') {
+                self.pos = pos;
+                return Ok(path);
+            }
+            self.pos += 1;
+            self.skip_spaces();
+            path.push(self.simple_key()?);
+        }
+    }
+
+    fn simple_key(&mut self) -> NxResult<String> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            Some(c) if is_bare_key_char(c) => {
+                let start = self.pos;
+                while self.peek().is_some_and(is_bare_key_char) {
+                    self.pos += 1;
+                }
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+            _ => self.error("expected a key"),
+        }
+    }
+
+    fn value(&mut self) -> NxResult<Toml> {
+        match self.peek() {
+            Some('"') if self.looking_at("\"\"\"") => self.multiline_string(),
+            Some('"') => Ok(Toml::String(self.basic_string()?)),
+            Some('\'') if self.looking_at("'''") => self.multiline_string(),
+            Some('\'') => Ok(Toml::String(self.literal_string()?)),
+            Some('[') => self.array(),
+            Some('{') => self.inline_table(),
+            Some(c) if c.is_ascii_alphanumeric() || c == '+' || c == '-' => self.scalar(),
+            _ => self.error("expected a value"),
+        }
+    }
+
+    fn array(&mut self) -> NxResult<Toml> {
+        self.pos += 1;
+        let mut elements = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                break;
+            }
+            elements.push(self.value()?);
+            self.skip_blank();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(']') => break,
+                _ => return self.error("expected ',' or ']'"),
+            }
+        }
+        self.pos += 1;
+        Ok(Toml::Array(elements))
+    }
+
+    fn inline_table(&mut self) -> NxResult<Toml> {
+        self.pos += 1;
+        let mut table = Table::new();
+        self.skip_spaces();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(table_toml(table));
+        }
+        loop {
+            self.skip_spaces();
+            self.key_value(&mut table)?;
+            self.skip_spaces();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some('}') => break,
+                _ => return self.error("expected ',' or '}'"),
+            }
+        }
+        self.pos += 1;
+        Ok(table_toml(table))
+    }
+
+    fn basic_string(&mut self) -> NxResult<String> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                Some('\\') => s.push(self.escape()?),
+                Some('\n') | None => return self.error("unterminated string"),
+                Some(c) => {
+                    s.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> NxResult<String> {
+        self.pos += 1;
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                Some('\'') => {
+                    self.pos += 1;
+                    return Ok(self.chars[start..self.pos - 1].iter().collect());
+                }
+                Some('\n') | None => return self.error("unterminated string"),
+                Some(_) => self.pos += 1,
+            }
+        }
+    }
+
+    // `"""..."""` with escapes or `'''...'''` without, a newline right after the opening quotes
+    // is dropped
+    fn multiline_string(&mut self) -> NxResult<Toml> {
+        let quote = self.peek().unwrap();
+        let delimiter: String = [quote; 3].iter().collect();
+        self.pos += 3;
+        if self.looking_at("\r\n") {
+            self.pos += 2;
+        } else if self.peek() == Some('\n') {
+            self.pos += 1;
+        }
+        let mut s = String::new();
+        loop {
+            if self.looking_at(&delimiter) {
+                self.pos += 3;
+                return Ok(Toml::String(s));
+            }
+            match self.peek() {
+                None => return self.error("unterminated string"),
+                Some('\\') if quote == '"' && self.line_ending_backslash() => {}
+                Some('\\') if quote == '"' => s.push(self.escape()?),
+                Some(c) => {
+                    s.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    // A backslash at the end of a line skips the newline and the whitespace after it
+    fn line_ending_backslash(&mut self) -> bool {
+        let mut end = self.pos + 1;
+        while matches!(self.chars.get(end), Some(' ' | '\t')) {
+            end += 1;
+        }
+        if !matches!(self.chars.get(end), Some('\r' | '\n')) {
+            return false;
+        }
+        self.pos = end;
+        while matches!(self.peek(), Some(' ' | '\t' | '\r' | '\n')) {
+            self.pos += 1;
+        }
+        true
+    }
+
+    fn escape(&mut self) -> NxResult<char> {
+        let start = self.pos;
+        self.pos += 2;
+        let c = match self.chars.get(start + 1) {
+            Some('b') => '\u{8}',
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('f') => '\u{c}',
+            Some('r') => '\r',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(&u @ ('u' | 'U')) => {
+                let len = if u == 'u' { 4 } else { 8 };
+                let digits: String = self.chars[self.pos..].iter().take(len).collect();
+                self.pos += len;
+                match u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                {
+                    Some(c) if digits.len() == len => c,
+                    _ => return self.error_at(start, "invalid unicode escape".to_string()),
+                }
+            }
+            _ => return self.error_at(start, "invalid escape sequence".to_string()),
+        };
+        Ok(c)
+    }
+
+    // A boolean or a number
+    fn scalar(&mut self) -> NxResult<Toml> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '+' | '-' | '.' | ':'))
+        {
+            self.pos += 1;
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+        let value = match token.as_str() {
+            "true" => Some(Toml::Bool(true)),
+            "false" => Some(Toml::Bool(false)),
+            "inf" | "+inf" => Some(Toml::Float(f64::INFINITY)),
+            "-inf" => Some(Toml::Float(f64::NEG_INFINITY)),
+            "nan" | "+nan" | "-nan" => Some(Toml::Float(f64::NAN)),
+            _ => parse_number(&token),
+        };
+        match value {
+            Some(value) => Ok(value),
+            None if token.contains(':') || token[1..].contains('-') => {
+                self.error_at(start, "dates and times are not supported".to_string())
+            }
+            None => self.error_at(start, format!("invalid value {}", token)),
+        }
+    }
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn parse_number(token: &str) -> Option<Toml> {
+    // underscores may only separate digits
+    let chars: Vec<char> = token.chars().collect();
+    for (i, &c) in chars.iter().enumerate() {
+        if c == '_'
+            && !(i > 0
+                && chars[i - 1].is_ascii_alphanumeric()
+                && chars.get(i + 1).is_some_and(char::is_ascii_alphanumeric))
+        {
+            return None;
+        }
+    }
+    let token = token.replace('_', "");
+    for (prefix, radix) in [("0x", 16), ("0o", 8), ("0b", 2)] {
+        if let Some(digits) = token.strip_prefix(prefix) {
+            return i64::from_str_radix(digits, radix).ok().map(Toml::Integer);
+        }
+    }
+    let digits = token.trim_start_matches(['+', '-']);
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    if digits.contains(['.', 'e', 'E']) {
+        token.parse().ok().map(Toml::Float)
+    } else {
+        token.parse().ok().map(Toml::Integer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(source: &str) -> String {
+        parse(source).unwrap().to_string()
+    }
+
+    fn parse_err(source: &str) -> String {
+        parse(source).unwrap_err().message.to_string()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_ok(""), "{}");
+        assert_eq!(
+            parse_ok("b = 1_000 # one thousand\na = \"x\\ty\"\n"),
+            r#"{"a": "x\ty", "b": 1000}"#
+        );
+        assert_eq!(
+            parse_ok("[server]\nhost = 'localhost'\nport = 0x1F\n\n[server.tls]\non = true\n"),
+            r#"{"server": {"host": "localhost", "port": 31, "tls": {"on": true}}}"#
+        );
+        assert_eq!(
+            parse_ok(
+                "[[task]]\nname = \"a\"\n[[task]]\nname = \"b\"\ndeps = [\n  \"a\", # first\n]\n"
+            ),
+            r#"{"task": [{"name": "a"}, {"deps": ["a"], "name": "b"}]}"#
+        );
+        assert_eq!(
+            parse_ok("point = { x = 1.5, y = -2e3 }\nsite.\"a b\" = [[1, 2], []]\n"),
+            r#"{"point": {"x": 1.5, "y": -2000.0}, "site": {"a b": [[1, 2], []]}}"#
+        );
+        assert_eq!(
+            parse_ok("s = \"\"\"\none \\\n    two\"\"\"\nr = '''a\\b'''"),
+            r#"{"r": "a\\b", "s": "one two"}"#
+        );
+    }
+
+    #[test]
+    fn test_typed() {
+        let toml = Toml::parse("[package]\nname = \"a\"\nedition = 2\ndirs = [\"x\"]").unwrap();
+        let package = toml.get("package").unwrap();
+        assert_eq!(package.get("name").and_then(Toml::as_str), Some("a"));
+        assert_eq!(package.get("edition").and_then(Toml::as_integer), Some(2));
+        assert_eq!(
+            package.get("dirs").and_then(Toml::as_array),
+            Some(&[Toml::String("x".to_string())][..])
+        );
+        let keys: Vec<&str> = package
+            .as_table()
+            .unwrap()
+            .iter()
+            .map(|(k, _)| k.as_str())
+            .collect();
+        assert_eq!(keys, ["dirs", "edition", "name"]);
+        assert_eq!(toml.get("name"), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            parse_err("a = 1\nb = \n"),
+            "invalid TOML at line 2, column 5: expected a value"
+        );
+        assert_eq!(
+            parse_err("a = 1\na = 2"),
+            "invalid TOML at line 2, column 1: duplicate key a"
+        );
+        assert_eq!(
+            parse_err("[a]\n[a]"),
+            "invalid TOML at line 2, column 1: table a is declared twice"
+        );
+        assert_eq!(
+            parse_err("a = 1\n[a.b]"),
+            "invalid TOML at line 2, column 1: a is not a table"
+        );
+        assert_eq!(
+            parse_err("a = 1 2"),
+            "invalid TOML at line 1, column 7: expected the end of the line"
+        );
+        assert_eq!(
+            parse_err("day = 1979-05-27"),
+            "invalid TOML at line 1, column 7: dates and times are not supported"
+        );
+        assert_eq!(
+            parse_err("a = \"\\q\""),
+            "invalid TOML at line 1, column 6: invalid escape sequence"
+        );
+        assert_eq!(
+            parse_err("a = [1 2]"),
+            "invalid TOML at line 1, column 8: expected ',' or ']'"
+        );
+        assert_eq!(
+            parse_err("n = 1__0"),
+            "invalid TOML at line 1, column 5: invalid value 1__0"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+// Bytecode kept between runs by --cache, in a `.natrix-cache` directory next to the first source.
+//
+// An entry is named after a hash of everything the bytecode depends on: the version of natrix,
+// the options of the compilation, the name and content of each source and the libraries they import. A change to any of
+// them leads to another entry, so entries are never invalidated, only left behind.
+
+use natrix_compiler::ctx::CompilerContext;
+use natrix_compiler::hir::pass::PassManager;
+use natrix_compiler::src::SourceId;
+use natrix_runtime::bc::{Bytecode, FORMAT_VERSION};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DIR: &str = ".natrix-cache";
+
+// A 64-bit FNV-1a hash, which unlike the hasher of the standard library is the same in every build
+struct KeyHasher(u64);
+
+impl KeyHasher {
+    fn new() -> Self {
+        KeyHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    // Adds a part of the key, followed by a byte that does not occur in UTF-8 so that the parts
+    // "ab", "c" and "a", "bc" differ
+    fn add(&mut self, part: &str) {
+        self.add_bytes(part.as_bytes());
+    }
+
+    fn add_bytes(&mut self, part: &[u8]) {
+        for byte in part.iter().copied().chain([0xff]) {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// The path of the entry for the bytecode compiled from `source_ids` with the options of `ctx`,
+/// `passes` and the entry point, if any. The libraries imported by the sources, which are linked
+/// into the bytecode, are part of the key as well.
+pub fn entry_path(
+    ctx: &CompilerContext,
+    passes: &PassManager,
+    entry: Option<&str>,
+    source_ids: &[SourceId],
+) -> PathBuf {
+    let mut key = KeyHasher::new();
+    key.add(env!("CARGO_PKG_VERSION"));
+    key.add(&FORMAT_VERSION.to_string());
+    key.add(&format!(
+        "{:?} strict={} debug={} prune={} entry={}",
+        ctx.edition,
+        ctx.strict,
+        ctx.debug_info,
+        ctx.prune,
+        entry.is_some()
+    ));
+    let mut defines: Vec<_> = ctx.defines.iter().collect();
+    defines.sort();
+    for (name, value) in defines {
+        key.add(name);
+        key.add(value);
+    }
+    for (pass, enabled) in passes.passes() {
+        key.add(pass.name());
+        key.add(if enabled { "on" } else { "off" });
+    }
+    for source_id in source_ids {
+        let source = ctx.sources.get_by_id(*source_id);
+        key.add(source.name());
+        key.add(source.content());
+    }
+    for library in ctx.modules.libraries() {
+        for (name, global) in &library.exports {
+            key.add(ctx.interner.resolve(*name));
+            key.add(&global.to_string());
+        }
+        if let Ok(bytes) = library.bc.serialize() {
+            key.add_bytes(&bytes);
+        }
+    }
+    let first = ctx.sources.get_by_id(source_ids[0]).name();
+    let dir = Path::new(first).parent().unwrap_or(Path::new(""));
+    dir.join(DIR).join(format!("{:016x}.nxc", key.0))
+}
+
+/// Loads the bytecode of an entry, or `None` if there is no entry or it cannot be read, e.g.
+/// because it is only partially written.
+pub fn load(path: &Path) -> Option<Bytecode> {
+    let bytes = fs::read(path).ok()?;
+    Bytecode::deserialize(&bytes).ok()
+}
+
+/// Writes an entry. The cache only saves time, so failures are ignored.
+pub fn store(path: &Path, bc: &Bytecode) {
+    if let (Some(dir), Ok(bytes)) = (path.parent(), bc.serialize()) {
+        let _ = fs::create_dir_all(dir).and_then(|()| fs::write(path, bytes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(source: &str, configure: impl FnOnce(&mut CompilerContext)) -> PathBuf {
+        let mut ctx = CompilerContext::new();
+        configure(&mut ctx);
+        let source_id = ctx.sources.add_from_named_string("demos/a.nx", source);
+        entry_path(
+            &ctx,
+            &PassManager::with_default_passes(),
+            None,
+            &[source_id],
+        )
+    }
+
+    #[test]
+    fn test_entry_path() {
+        let base = path("fun main() {}", |_| {});
+        assert_eq!(base.parent(), Some(Path::new("demos/.natrix-cache")));
+        assert_eq!(path("fun main() {}", |_| {}), base);
+        assert_ne!(path("fun main() { }", |_| {}), base);
+        assert_ne!(path("fun main() {}", |ctx| ctx.debug_info = true), base);
+        assert_ne!(
+            path("fun main() {}", |ctx| {
+                ctx.defines.insert("a".into(), "1".into());
+            }),
+            base
+        );
+    }
+}
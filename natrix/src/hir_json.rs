@@ -0,0 +1,368 @@
+use crate::json::Json;
+use natrix_compiler::ctx::CompilerContext;
+use natrix_compiler::hir::{
+    Expr, ExprKind, FunDecl, GlobalKind, LocalKind, Program, Stmt, StmtKind,
+};
+use natrix_compiler::src::Span;
+
+/// Serializes the HIR for `--emit=hir-json`.
+///
+/// Every node is an object with a `kind` and a `span`. Globals, locals and loops are referenced
+/// by their numeric ids, and references to globals and locals also carry the resolved name.
+pub fn to_json(ctx: &CompilerContext, program: &Program) -> Json {
+    let writer = Writer { ctx, program };
+    writer.program()
+}
+
+struct Writer<'a> {
+    ctx: &'a CompilerContext,
+    program: &'a Program,
+}
+
+impl Writer<'_> {
+    fn program(&self) -> Json {
+        let globals = self
+            .program
+            .globals
+            .iter()
+            .map(|global| {
+                let mut members = vec![
+                    ("id".into(), global.id.0.into()),
+                    ("name".into(), self.ctx.interner.resolve(global.name).into()),
+                    ("name_span".into(), self.span(global.name_span)),
+                ];
+                match &global.kind {
+                    GlobalKind::Function(fun_decl) => members.extend(self.function(fun_decl)),
+                    GlobalKind::Imported(imported) => members.extend([
+                        ("kind".into(), "Imported".into()),
+                        ("param_count".into(), imported.param_count.into()),
+                        ("library".into(), imported.library.into()),
+                        ("global".into(), imported.global.into()),
+                    ]),
+                    GlobalKind::Native(function) => members.extend([
+                        ("kind".into(), "Native".into()),
+                        ("param_count".into(), function.param_count().into()),
+                    ]),
+                    GlobalKind::Variable => members.push(("kind".into(), "Variable".into())),
+                }
+                Json::Object(members)
+            })
+            .collect();
+        Json::Object(vec![
+            ("span".into(), self.span(self.program.span)),
+            ("globals".into(), Json::Array(globals)),
+        ])
+    }
+
+    fn function(&self, fun_decl: &FunDecl) -> Vec<(String, Json)> {
+        let locals = fun_decl
+            .locals
+            .iter()
+            .map(|local| {
+                let (kind, index) = match local.kind {
+                    LocalKind::Parameter(index) => ("Parameter", index.into()),
+                    LocalKind::LocalVariable => ("LocalVariable", Json::Null),
+                    LocalKind::Temporary => ("Temporary", Json::Null),
+                };
+                Json::Object(vec![
+                    ("id".into(), local.id.0.into()),
+                    ("name".into(), self.ctx.interner.resolve(local.name).into()),
+                    ("name_span".into(), self.span(local.name_span)),
+                    ("kind".into(), kind.into()),
+                    ("param_index".into(), index),
+                ])
+            })
+            .collect();
+        let body = fun_decl
+            .body
+            .iter()
+            .map(|stmt| self.stmt(fun_decl, stmt))
+            .collect();
+        vec![
+            ("kind".into(), "Function".into()),
+            ("param_count".into(), fun_decl.param_count.into()),
+            ("locals".into(), Json::Array(locals)),
+            ("body".into(), Json::Array(body)),
+        ]
+    }
+
+    // `fun_decl` is the function containing the node, used to resolve the names of locals
+    fn stmt(&self, fun_decl: &FunDecl, stmt: &Stmt) -> Json {
+        let expr = |e: &Expr| self.expr(fun_decl, e);
+        let (kind, members): (&str, Vec<(&str, Json)>) = match &stmt.kind {
+            StmtKind::Block(stmts) => (
+                "Block",
+                vec![(
+                    "stmts",
+                    Json::Array(stmts.iter().map(|s| self.stmt(fun_decl, s)).collect()),
+                )],
+            ),
+            StmtKind::Break(loop_id, value) => (
+                "Break",
+                vec![
+                    ("loop", loop_id.0.into()),
+                    ("value", value.as_ref().map_or(Json::Null, expr)),
+                ],
+            ),
+            StmtKind::Continue(loop_id) => ("Continue", vec![("loop", loop_id.0.into())]),
+            StmtKind::Defer(body) => ("Defer", vec![("body", self.stmt(fun_decl, body))]),
+            StmtKind::Expr(e) => ("Expr", vec![("expr", expr(e))]),
+            StmtKind::If(cond, then_body, else_body) => (
+                "If",
+                vec![
+                    ("cond", expr(cond)),
+                    ("then", self.stmt(fun_decl, then_body)),
+                    (
+                        "else",
+                        else_body
+                            .as_ref()
+                            .map_or(Json::Null, |s| self.stmt(fun_decl, s)),
+                    ),
+                ],
+            ),
+            StmtKind::Return(e) => ("Return", vec![("value", expr(e))]),
+            StmtKind::SetItem(array, index, value) => (
+                "SetItem",
+                vec![
+                    ("array", expr(array)),
+                    ("index", expr(index)),
+                    ("value", expr(value)),
+                ],
+            ),
+            StmtKind::StoreGlobal(id, value) => (
+                "StoreGlobal",
+                vec![
+                    ("global", id.0.into()),
+                    ("name", self.global_name(id.0)),
+                    ("value", expr(value)),
+                ],
+            ),
+            StmtKind::StoreLocal(id, value) => (
+                "StoreLocal",
+                vec![
+                    ("local", id.0.into()),
+                    ("name", self.local_name(fun_decl, id.0)),
+                    ("value", expr(value)),
+                ],
+            ),
+            StmtKind::Unpack(ids, value) => (
+                "Unpack",
+                vec![
+                    (
+                        "locals",
+                        Json::Array(ids.iter().map(|id| id.0.into()).collect()),
+                    ),
+                    ("value", expr(value)),
+                ],
+            ),
+            StmtKind::VarDecl(id, value) => (
+                "VarDecl",
+                vec![
+                    ("local", id.0.into()),
+                    ("name", self.local_name(fun_decl, id.0)),
+                    ("value", value.as_ref().map_or(Json::Null, expr)),
+                ],
+            ),
+            StmtKind::While(loop_id, cond, body, update) => (
+                "While",
+                vec![
+                    ("loop", loop_id.0.into()),
+                    ("cond", expr(cond)),
+                    ("body", self.stmt(fun_decl, body)),
+                    (
+                        "update",
+                        update
+                            .as_ref()
+                            .map_or(Json::Null, |s| self.stmt(fun_decl, s)),
+                    ),
+                ],
+            ),
+        };
+        self.node(kind, stmt.span, members)
+    }
+
+    fn expr(&self, fun_decl: &FunDecl, e: &Expr) -> Json {
+        let expr = |e: &Expr| self.expr(fun_decl, e);
+        let (kind, members): (&str, Vec<(&str, Json)>) = match &e.kind {
+            ExprKind::Binary(op, op_span, left, right) => (
+                "Binary",
+                vec![
+                    ("op", format!("{:?}", op).as_str().into()),
+                    ("op_span", self.span(*op_span)),
+                    ("left", expr(left)),
+                    ("right", expr(right)),
+                ],
+            ),
+            ExprKind::Call(callee, args) => (
+                "Call",
+                vec![
+                    ("callee", expr(callee)),
+                    ("args", Json::Array(args.iter().map(expr).collect())),
+                ],
+            ),
+            ExprKind::Conditional(cond, then_expr, else_expr) => (
+                "Conditional",
+                vec![
+                    ("cond", expr(cond)),
+                    ("then", expr(then_expr)),
+                    ("else", expr(else_expr)),
+                ],
+            ),
+            ExprKind::ConstBool(v) => ("ConstBool", vec![("value", Json::Bool(*v))]),
+            ExprKind::ConstFloat(v) => ("ConstFloat", vec![("value", Json::Number(*v))]),
+            ExprKind::ConstInt(v) => ("ConstInt", vec![("value", Json::Int(*v))]),
+            ExprKind::ConstNull => ("ConstNull", vec![]),
+            ExprKind::ConstString(v) => ("ConstString", vec![("value", v.as_ref().into())]),
+            ExprKind::GetItem(array, index) => (
+                "GetItem",
+                vec![("array", expr(array)), ("index", expr(index))],
+            ),
+            ExprKind::GetSlice(array, start, end) => (
+                "GetSlice",
+                vec![
+                    ("array", expr(array)),
+                    ("start", expr(start)),
+                    ("end", expr(end)),
+                ],
+            ),
+            ExprKind::LoadBuiltin(builtin) => {
+                ("LoadBuiltin", vec![("name", builtin.name().into())])
+            }
+            ExprKind::LoadGlobal(id) => (
+                "LoadGlobal",
+                vec![("global", id.0.into()), ("name", self.global_name(id.0))],
+            ),
+            ExprKind::LoadLocal(id) => (
+                "LoadLocal",
+                vec![
+                    ("local", id.0.into()),
+                    ("name", self.local_name(fun_decl, id.0)),
+                ],
+            ),
+            ExprKind::LogicalBinary(and, op_span, left, right) => (
+                "LogicalBinary",
+                vec![
+                    ("op", if *and { "and" } else { "or" }.into()),
+                    ("op_span", self.span(*op_span)),
+                    ("left", expr(left)),
+                    ("right", expr(right)),
+                ],
+            ),
+            ExprKind::MakeList(elements) => (
+                "MakeList",
+                vec![("elements", Json::Array(elements.iter().map(expr).collect()))],
+            ),
+            ExprKind::Try(arg) => ("Try", vec![("arg", expr(arg))]),
+            ExprKind::Unary(op, op_span, arg) => (
+                "Unary",
+                vec![
+                    ("op", format!("{:?}", op).as_str().into()),
+                    ("op_span", self.span(*op_span)),
+                    ("arg", expr(arg)),
+                ],
+            ),
+            ExprKind::While(loop_id, cond, body) => (
+                "While",
+                vec![
+                    ("loop", loop_id.0.into()),
+                    ("cond", expr(cond)),
+                    ("body", self.stmt(fun_decl, body)),
+                ],
+            ),
+        };
+        self.node(kind, e.span, members)
+    }
+
+    fn node(&self, kind: &str, span: Span, members: Vec<(&str, Json)>) -> Json {
+        let mut object = vec![
+            ("kind".to_string(), kind.into()),
+            ("span".to_string(), self.span(span)),
+        ];
+        object.extend(members.into_iter().map(|(k, v)| (k.to_string(), v)));
+        Json::Object(object)
+    }
+
+    fn global_name(&self, index: usize) -> Json {
+        self.ctx
+            .interner
+            .resolve(self.program.globals[index].name)
+            .into()
+    }
+
+    fn local_name(&self, fun_decl: &FunDecl, index: usize) -> Json {
+        self.ctx
+            .interner
+            .resolve(fun_decl.locals[index].name)
+            .into()
+    }
+
+    // One-based positions, like the ones in error messages
+    fn span(&self, span: Span) -> Json {
+        let sources = &self.ctx.sources;
+        let (line, column) = span.start_pos(sources);
+        let (end_line, end_column) = span.end_pos(sources);
+        Json::Object(vec![
+            (
+                "source".into(),
+                sources.get_by_id(span.source_id()).name().into(),
+            ),
+            ("line".into(), line.into()),
+            ("column".into(), column.into()),
+            ("end_line".into(), end_line.into()),
+            ("end_column".into(), end_column.into()),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use natrix_compiler::analyze::analyze;
+    use natrix_compiler::parser::parse;
+
+    #[test]
+    fn test_to_json() {
+        let mut ctx = CompilerContext::default();
+        let source_id = ctx
+            .sources
+            .add_from_named_string("t.nx", "fun main(a) { return -a; }");
+        let ast = parse(&mut ctx, source_id).unwrap();
+        let program = analyze(&ctx, &ast).unwrap();
+        let json = to_json(&ctx, &program);
+        let globals = json.get("globals").unwrap();
+        let Json::Array(globals) = globals else {
+            panic!("expected an array: {}", globals);
+        };
+        let main = &globals[0];
+        assert_eq!(main.get("name"), Some(&"main".into()));
+        assert_eq!(
+            main.get("locals").unwrap().to_string(),
+            r#"[{"id":0,"name":"a","name_span":{"source":"t.nx","line":1,"column":10,"end_line":1,"end_column":11},"kind":"Parameter","param_index":0}]"#
+        );
+        let Json::Array(body) = main.get("body").unwrap() else {
+            panic!("expected an array");
+        };
+        let value = body[0].get("value").unwrap();
+        assert_eq!(value.get("kind"), Some(&"Unary".into()));
+        assert_eq!(value.get("op"), Some(&"Neg".into()));
+        let arg = value.get("arg").unwrap();
+        assert_eq!(arg.get("kind"), Some(&"LoadLocal".into()));
+        assert_eq!(arg.get("name"), Some(&"a".into()));
+    }
+
+    #[test]
+    fn test_exact_int() {
+        let mut ctx = CompilerContext::default();
+        let source_id = ctx
+            .sources
+            .add_from_string("fun main() { return 9007199254740993; }");
+        let ast = parse(&mut ctx, source_id).unwrap();
+        let program = analyze(&ctx, &ast).unwrap();
+        let json = to_json(&ctx, &program).to_string();
+        assert!(
+            json.ends_with(r#""value":9007199254740993}}]}]}"#),
+            "{}",
+            json
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::json::Json;
+use natrix_compiler::repl::Repl;
+use natrix_compiler::token_type::Edition;
+use natrix_runtime::ctx::{Capability, RuntimeContext};
+use std::io::{self, BufRead, Write};
+
+/// Serves the notebook protocol on stdin/stdout until the end of input.
+///
+/// Every request is a JSON object on a single line, `{"id": ..., "code": "..."}`, and is answered
+/// by a single line `{"id": ..., "stdout": "...", "result": ..., "error": ...}`. The cells share
+/// one REPL session, so they can use the functions and variables declared by previous cells.
+/// Every cell is granted `capabilities`.
+pub fn run(edition: Edition, capabilities: &[Capability]) -> io::Result<()> {
+    let mut repl = Repl::with_edition(edition);
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(stdout, "{}", handle(&mut repl, &line, capabilities))?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+// `result` is the displayed value of a trailing expression or null, `error` is null or an object
+// with the message, the kind of error (e.g. `TypeError`, `Error` for errors found before running)
+// and the one-based start and end position within the cell.
+fn handle(repl: &mut Repl, line: &str, capabilities: &[Capability]) -> Json {
+    let request = match Json::parse(line) {
+        Ok(request @ Json::Object(_)) => request,
+        Ok(_) => return error_response(Json::Null, "invalid request: expected an object"),
+        Err(message) => {
+            return error_response(Json::Null, &format!("invalid request: {}", message))
+        }
+    };
+    let id = request.get("id").cloned().unwrap_or(Json::Null);
+    let Some(code) = request.get("code").and_then(Json::as_str) else {
+        return error_response(id, "invalid request: missing code");
+    };
+    let mut rt = RuntimeContext::with_capture();
+    for &capability in capabilities {
+        rt.grant(capability);
+    }
+    let result = repl.eval(&mut rt, code);
+    let (result, error) = match result {
+        Ok(value) if value.is_null() => (Json::Null, Json::Null),
+        Ok(value) => (Json::String(value.to_string()), Json::Null),
+        Err(err) => {
+            let sources = &repl.ctx.sources;
+            let (line, column) = err.span.start_pos(sources);
+            let (end_line, end_column) = err.span.end_pos(sources);
+            let error = Json::Object(vec![
+                ("message".into(), err.message.as_ref().into()),
+                ("kind".into(), err.kind.name().into()),
+                ("line".into(), line.into()),
+                ("column".into(), column.into()),
+                ("end_line".into(), end_line.into()),
+                ("end_column".into(), end_column.into()),
+            ]);
+            (Json::Null, error)
+        }
+    };
+    Json::Object(vec![
+        ("id".into(), id),
+        ("stdout".into(), rt.take_output().as_str().into()),
+        ("result".into(), result),
+        ("error".into(), error),
+    ])
+}
+
+fn error_response(id: Json, message: &str) -> Json {
+    Json::Object(vec![
+        ("id".into(), id),
+        ("stdout".into(), "".into()),
+        ("result".into(), Json::Null),
+        (
+            "error".into(),
+            Json::Object(vec![("message".into(), message.into())]),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cells_share_session() {
+        let mut repl = Repl::new();
+        assert_eq!(
+            handle(
+                &mut repl,
+                r#"{"id": 1, "code": "fun sq(x) { return x * x; }\nprint(sq(3));"}"#,
+                &[]
+            )
+            .to_string(),
+            r#"{"id":1,"stdout":"9\n","result":null,"error":null}"#
+        );
+        assert_eq!(
+            handle(&mut repl, r#"{"id": "b", "code": "sq(4) + 1"}"#, &[]).to_string(),
+            r#"{"id":"b","stdout":"","result":"17","error":null}"#
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut repl = Repl::new();
+        assert_eq!(
+            handle(
+                &mut repl,
+                r#"{"id": 2, "code": "var a = 1;\nprint(a + b);"}"#,
+                &[]
+            )
+            .to_string(),
+            r#"{"id":2,"stdout":"","result":null,"error":{"message":"undeclared variable \"b\"","kind":"Error","line":2,"column":11,"end_line":2,"end_column":12}}"#
+        );
+        assert_eq!(
+            handle(&mut repl, r#"{"id": 4, "code": "[1, 2][2]"}"#, &[]).to_string(),
+            r#"{"id":4,"stdout":"","result":null,"error":{"message":"list index out of bounds","kind":"IndexError","line":1,"column":1,"end_line":1,"end_column":10}}"#
+        );
+        assert_eq!(
+            handle(&mut repl, r#"{"id": 5, "code": "list_dir(\".\")"}"#, &[]).to_string(),
+            r#"{"id":5,"stdout":"","result":null,"error":{"message":"reading files was not allowed by the host","kind":"PermissionError","line":1,"column":1,"end_line":1,"end_column":14}}"#
+        );
+        assert_eq!(
+            handle(&mut repl, r#"{"id": 3}"#, &[]).to_string(),
+            r#"{"id":3,"stdout":"","result":null,"error":{"message":"invalid request: missing code"}}"#
+        );
+        assert_eq!(
+            handle(&mut repl, "[1", &[]).to_string(),
+            r#"{"id":null,"stdout":"","result":null,"error":{"message":"invalid request: expected ','"}}"#
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Minimal line editor for the REPL with history and tab completion.
+///
+/// The terminal is switched to raw mode using `stty` while a line is being edited. If stdin is
+/// not a terminal, lines are read as they are, without prompts or editing.
+pub struct LineEditor {
+    history: Vec<String>,
+    history_file: Option<PathBuf>,
+    interactive: bool,
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Tab,
+    KillToStart,
+    KillToEnd,
+    Interrupt,
+    Eof,
+    Unknown,
+}
+
+impl LineEditor {
+    /// Creates an editor whose history is loaded from and appended to `history_file`.
+    pub fn new(history_file: Option<PathBuf>) -> Self {
+        let history = history_file
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|content| content.lines().map(String::from).collect())
+            .unwrap_or_default();
+        Self {
+            history,
+            history_file,
+            interactive: io::stdin().is_terminal() && io::stdout().is_terminal(),
+        }
+    }
+
+    /// Reads a line, returning `None` at the end of input.
+    ///
+    /// `complete` returns the candidates for the identifier prefix before the cursor.
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        complete: impl Fn(&str) -> Vec<String>,
+    ) -> io::Result<Option<String>> {
+        if !self.interactive {
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            return Ok(Some(line.trim_end_matches(['\n', '\r']).to_string()));
+        }
+        stty(&["raw", "-echo"])?;
+        let result = self.edit(prompt, complete);
+        stty(&["-raw", "echo"])?;
+        result
+    }
+
+    /// Appends a non-empty line to the history, skipping immediate repetitions.
+    pub fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if let Some(path) = &self.history_file
+            && let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path)
+        {
+            // Failing to save the history should not interrupt the session
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    fn edit(
+        &mut self,
+        prompt: &str,
+        complete: impl Fn(&str) -> Vec<String>,
+    ) -> io::Result<Option<String>> {
+        let mut buffer: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        let mut history_index = self.history.len();
+        let mut pending = String::new(); // line being edited before browsing the history
+        redraw(prompt, &buffer, cursor)?;
+        loop {
+            match read_key()? {
+                Key::Char(c) => {
+                    buffer.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Enter => {
+                    print!("\r\n");
+                    io::stdout().flush()?;
+                    return Ok(Some(buffer.into_iter().collect()));
+                }
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    buffer.remove(cursor);
+                }
+                Key::Delete if cursor < buffer.len() => {
+                    buffer.remove(cursor);
+                }
+                Key::Left if cursor > 0 => cursor -= 1,
+                Key::Right if cursor < buffer.len() => cursor += 1,
+                Key::Home => cursor = 0,
+                Key::End => cursor = buffer.len(),
+                Key::Up if history_index > 0 => {
+                    if history_index == self.history.len() {
+                        pending = buffer.iter().collect();
+                    }
+                    history_index -= 1;
+                    buffer = self.history[history_index].chars().collect();
+                    cursor = buffer.len();
+                }
+                Key::Down if history_index < self.history.len() => {
+                    history_index += 1;
+                    buffer = self
+                        .history
+                        .get(history_index)
+                        .unwrap_or(&pending)
+                        .chars()
+                        .collect();
+                    cursor = buffer.len();
+                }
+                Key::Tab => {
+                    let start = buffer[..cursor]
+                        .iter()
+                        .rposition(|c| !c.is_ascii_alphanumeric() && *c != '_')
+                        .map_or(0, |i| i + 1);
+                    let prefix: String = buffer[start..cursor].iter().collect();
+                    let candidates = complete(&prefix);
+                    let common = common_prefix(&candidates);
+                    if common.len() > prefix.len() {
+                        for c in common[prefix.len()..].chars() {
+                            buffer.insert(cursor, c);
+                            cursor += 1;
+                        }
+                    } else if candidates.len() > 1 {
+                        print!("\r\n{}\r\n", candidates.join("  "));
+                    }
+                }
+                Key::KillToStart => {
+                    buffer.drain(..cursor);
+                    cursor = 0;
+                }
+                Key::KillToEnd => buffer.truncate(cursor),
+                Key::Interrupt => {
+                    print!("^C\r\n");
+                    buffer.clear();
+                    cursor = 0;
+                    history_index = self.history.len();
+                }
+                Key::Eof if buffer.is_empty() => {
+                    print!("\r\n");
+                    io::stdout().flush()?;
+                    return Ok(None);
+                }
+                Key::Eof if cursor < buffer.len() => {
+                    buffer.remove(cursor);
+                }
+                _ => {}
+            }
+            redraw(prompt, &buffer, cursor)?;
+        }
+    }
+}
+
+fn stty(args: &[&str]) -> io::Result<()> {
+    Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .status()?;
+    Ok(())
+}
+
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    match io::stdin().read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// Decodes a key press, including the escape sequences of arrows, Home, End and Delete.
+fn read_key() -> io::Result<Key> {
+    let byte = match read_byte()? {
+        Some(byte) => byte,
+        None => return Ok(Key::Eof),
+    };
+    Ok(match byte {
+        1 => Key::Home,
+        3 => Key::Interrupt,
+        4 => Key::Eof,
+        5 => Key::End,
+        9 => Key::Tab,
+        11 => Key::KillToEnd,
+        10 | 13 => Key::Enter,
+        21 => Key::KillToStart,
+        8 | 127 => Key::Backspace,
+        27 => match (read_byte()?, read_byte()?) {
+            (Some(b'[' | b'O'), Some(b'A')) => Key::Up,
+            (Some(b'[' | b'O'), Some(b'B')) => Key::Down,
+            (Some(b'[' | b'O'), Some(b'C')) => Key::Right,
+            (Some(b'[' | b'O'), Some(b'D')) => Key::Left,
+            (Some(b'[' | b'O'), Some(b'H')) => Key::Home,
+            (Some(b'[' | b'O'), Some(b'F')) => Key::End,
+            (Some(b'['), Some(b'3')) if read_byte()? == Some(b'~') => Key::Delete,
+            _ => Key::Unknown,
+        },
+        byte if byte < 32 => Key::Unknown,
+        byte => {
+            // Collect the continuation bytes of a multi-byte UTF-8 character
+            let len = match byte {
+                0xf0.. => 4,
+                0xe0.. => 3,
+                0xc0.. => 2,
+                _ => 1,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                bytes.extend(read_byte()?);
+            }
+            match std::str::from_utf8(&bytes) {
+                Ok(s) => Key::Char(s.chars().next().unwrap()),
+                Err(_) => Key::Unknown,
+            }
+        }
+    })
+}
+
+fn redraw(prompt: &str, buffer: &[char], cursor: usize) -> io::Result<()> {
+    let line: String = buffer.iter().collect();
+    print!("\r{}{}\x1b[K", prompt, line);
+    if cursor < buffer.len() {
+        print!("\x1b[{}D", buffer.len() - cursor);
+    }
+    io::stdout().flush()
+}
+
+fn common_prefix(candidates: &[String]) -> &str {
+    let Some(first) = candidates.first() else {
+        return "";
+    };
+    let mut len = first.len();
+    for candidate in &candidates[1..] {
+        len = first
+            .char_indices()
+            .zip(candidate.chars())
+            .take_while(|((i, a), b)| *i < len && a == b)
+            .map(|((i, a), _)| i + a.len_utf8())
+            .last()
+            .unwrap_or(0);
+    }
+    &first[..len]
+}
@@ -1,16 +1,64 @@
+use manifest::Package;
 use natrix_compiler::analyze::analyze;
-use natrix_compiler::ast::Interpreter as AstInterpreter;
-use natrix_compiler::bc::compiler::compile;
+use natrix_compiler::ast::{Interpreter as AstInterpreter, Program};
+use natrix_compiler::bc::compiler::{compile, compile_functions, compile_library};
+use natrix_compiler::coverage::{annotate, lcov_report};
 use natrix_compiler::ctx::CompilerContext;
-use natrix_compiler::error::{AttachErrSpan, SourceResult};
-use natrix_compiler::hir::opt::fold_constants;
+use natrix_compiler::doc::{builtins_markdown, to_markdown};
+use natrix_compiler::error::{format_bc_warning, AttachErrSpan, SourceResult};
+use natrix_compiler::hir::callgraph::CallGraph;
+use natrix_compiler::hir::cfg;
+use natrix_compiler::hir::desugar::explain_desugaring;
+use natrix_compiler::hir::pass::PassManager;
+use natrix_compiler::module::{parse_program, LIBRARY_EXTENSION};
 use natrix_compiler::parser::parse;
-use natrix_runtime::bc::Interpreter as BcInterpreter;
-use natrix_runtime::ctx::RuntimeContext;
+use natrix_compiler::repl::{is_incomplete, Repl};
+use natrix_compiler::src::{SourceId, Span};
+use natrix_compiler::token::{TokenType, Tokenizer};
+use natrix_compiler::token_type::Edition;
+use natrix_runtime::bc::{Bytecode, Debugger, Interpreter as BcInterpreter, Profiler, Tracer};
+use natrix_runtime::ctx::{Capability, LogLevel, RuntimeContext};
+use natrix_runtime::error::{nx_error, NxResult};
 use natrix_runtime::value::Value;
 use std::cell::RefCell;
-use std::io::Read;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::thread;
+use timing::PhaseTimer;
+
+#[cfg(feature = "profile-alloc")]
+#[global_allocator]
+static ALLOCATOR: natrix_runtime::profile::CountingAllocator =
+    natrix_runtime::profile::CountingAllocator;
+
+mod cache;
+mod hir_json;
+mod json;
+mod kernel;
+mod line_editor;
+mod manifest;
+mod plugin;
+mod signal;
+mod test_runner;
+mod timing;
+
+use line_editor::LineEditor;
+use test_runner::TestOptions;
+
+enum Command {
+    Run,
+    Doc,
+    Repl,
+    Kernel,
+    Test,
+    Inspect,
+    Build, // compiles the package in the given directory, see `manifest`
+    PrintPasses,
+    RunBytecode, // runs the `.nxc` file given by --run-bc
+}
 
 enum Mode {
     Ast,
@@ -18,10 +66,44 @@ enum Mode {
 }
 
 struct Config {
+    command: Command,
     mode: Mode,
     input: Input,
     dump_ast: bool,
+    dump_ast_dot: bool,
     dump_hir: bool,
+    dump_cfg_dot: bool,
+    dump_bc: bool,
+    dump_callgraph: bool,
+    explain_desugar: bool,
+    emit_hir_json: bool,
+    coverage_file: Option<String>,
+    annotate_coverage: bool,
+    mem_stats: bool,
+    profile: bool,
+    profile_alloc: bool,
+    trace: Option<Vec<String>>, // functions to trace, all if empty
+    breakpoints: Vec<usize>,
+    debug: bool,    // keeps the names of locals for locals() and --break
+    keep_all: bool, // keeps functions unreachable from main in the bytecode
+    jobs: usize,    // threads parsing the files of the program
+    cache: bool,    // reuses the bytecode of a previous run, see `cache`
+    stats: bool,
+    time: bool,
+    edition: Edition,
+    defines: HashMap<String, String>,
+    capabilities: Vec<Capability>, // granted to the script by --allow-read and --allow-write
+    plugins: Vec<String>,          // shared libraries adding native functions, see `plugin`
+    strict: bool,
+    warn_numeric_eq: bool,
+    log_level: LogLevel,
+    bless: bool,
+    doc_builtins: bool,
+    entry: Option<String>,          // function called instead of `main`
+    compile_output: Option<String>, // the `.nxc` file written by --compile instead of running
+    bytecode_file: Option<String>,  // the `.nxc` file run by --run-bc
+    library: bool,                  // build writes a `.nxlib` instead of a `.nxc`
+    passes: PassManager,
     args: Vec<String>,
 }
 
@@ -36,16 +118,172 @@ fn parse_args() -> Result<Config, String> {
     let mut mode = Mode::Bytecode;
     let mut filenames = Vec::new();
     let mut dump_ast = false;
+    let mut dump_ast_dot = false;
     let mut dump_hir = false;
+    let mut dump_cfg_dot = false;
+    let mut dump_bc = false;
+    let mut dump_callgraph = false;
+    let mut explain_desugar = false;
+    let mut emit_hir_json = false;
+    let mut coverage_file = None;
+    let mut annotate_coverage = false;
+    let mut mem_stats = false;
+    let mut profile = false;
+    let mut profile_alloc = false;
+    let mut trace = None;
+    let mut breakpoints = Vec::new();
+    let mut debug = false;
+    let mut keep_all = false;
+    let mut jobs = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut cache = false;
+    let mut library = false;
+    let mut stats = false;
+    let mut time = false;
+    let mut edition = Edition::default();
+    let mut defines = HashMap::new();
+    let mut capabilities = Vec::new();
+    let mut plugins = Vec::new();
+    let mut strict = false;
+    let mut warn_numeric_eq = false;
+    let mut log_level = LogLevel::default();
+    let mut bless = false;
+    let mut doc_builtins = false;
+    let mut entry = None;
+    let mut compile_output = None;
+    let mut bytecode_file = None;
+    let mut passes = PassManager::with_default_passes();
     let mut program_args = Vec::new();
 
     let mut i = 1;
+    let mut command = match args.get(1).map(String::as_str) {
+        Some("doc") => {
+            i += 1;
+            Command::Doc
+        }
+        Some("repl") => {
+            i += 1;
+            Command::Repl
+        }
+        Some("test") => {
+            i += 1;
+            Command::Test
+        }
+        Some("inspect") => {
+            i += 1;
+            Command::Inspect
+        }
+        Some("build") => {
+            i += 1;
+            Command::Build
+        }
+        _ => Command::Run,
+    };
     while i < args.len() {
         match args[i].as_str() {
             "--ast" => mode = Mode::Ast,
             "--bc" => mode = Mode::Bytecode,
             "--dump-ast" => dump_ast = true,
+            "--dump-ast=dot" => dump_ast_dot = true,
             "--dump-hir" => dump_hir = true,
+            "--dump-cfg=dot" => dump_cfg_dot = true,
+            "--dump-bc" => dump_bc = true,
+            "--dump-callgraph" => dump_callgraph = true,
+            "--explain-desugar" => explain_desugar = true,
+            "--emit=hir-json" => emit_hir_json = true,
+            "--coverage-annotate" => annotate_coverage = true,
+            "--mem-stats" => mem_stats = true,
+            "--profile" => profile = true,
+            "--trace" => trace = Some(Vec::new()),
+            arg if arg.starts_with("--trace=") => {
+                trace = Some(
+                    arg["--trace=".len()..]
+                        .split(',')
+                        .map(str::to_string)
+                        .collect(),
+                );
+            }
+            arg if arg.starts_with("--break=") => {
+                let line = &arg["--break=".len()..];
+                breakpoints.push(
+                    line.parse()
+                        .map_err(|_| format!("invalid line number {:?}", line))?,
+                );
+            }
+            "--debug" => debug = true,
+            "--keep-all" => keep_all = true,
+            "--cache" => cache = true,
+            "--lib" => library = true,
+            "--profile-alloc" if cfg!(feature = "profile-alloc") => profile_alloc = true,
+            "--profile-alloc" => {
+                return Err(
+                    "--profile-alloc needs natrix built with --features profile-alloc".to_string(),
+                );
+            }
+            "--stats" => stats = true,
+            "--time" => time = true,
+            "--kernel" => command = Command::Kernel,
+            "--strict" => strict = true,
+            "--allow-read" => capabilities.push(Capability::Read),
+            "--allow-write" => capabilities.push(Capability::Write),
+            "--warn-numeric-eq" => warn_numeric_eq = true,
+            "--bless" => bless = true,
+            "--builtins" => doc_builtins = true,
+            "--define" => {
+                i += 1;
+                let Some(define) = args.get(i) else {
+                    return Err("--define needs NAME or NAME=VALUE".to_string());
+                };
+                let (name, value) = define.split_once('=').unwrap_or((define, "true"));
+                defines.insert(name.to_string(), value.to_string());
+            }
+            "--entry" => {
+                i += 1;
+                let Some(name) = args.get(i) else {
+                    return Err("--entry needs a function name".to_string());
+                };
+                entry = Some(name.clone());
+            }
+            "--compile" => {
+                i += 1;
+                let Some(path) = args.get(i) else {
+                    return Err("--compile needs an output file".to_string());
+                };
+                compile_output = Some(path.clone());
+            }
+            "--plugin" => {
+                i += 1;
+                let Some(path) = args.get(i) else {
+                    return Err("--plugin needs a shared library".to_string());
+                };
+                plugins.push(path.clone());
+            }
+            "--run-bc" => {
+                i += 1;
+                let Some(path) = args.get(i) else {
+                    return Err("--run-bc needs a bytecode file".to_string());
+                };
+                command = Command::RunBytecode;
+                bytecode_file = Some(path.clone());
+            }
+            "--print-passes" => command = Command::PrintPasses,
+            arg if arg.starts_with("--disable-pass=") => {
+                passes.disable(&arg["--disable-pass=".len()..])?;
+            }
+            arg if arg.starts_with("--edition=") => {
+                edition = arg["--edition=".len()..].parse()?;
+            }
+            arg if arg.starts_with("--jobs=") => {
+                jobs = match arg["--jobs=".len()..].parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err("--jobs needs a positive number of threads".to_string()),
+                };
+            }
+            arg if arg.starts_with("--log-level=") => {
+                log_level = arg["--log-level=".len()..].parse()?;
+            }
+            arg if arg.starts_with("--coverage=") => {
+                coverage_file = Some(arg["--coverage=".len()..].to_string());
+            }
             "--" => {
                 // Everything after -- goes to program args
                 program_args.extend_from_slice(&args[i + 1..]);
@@ -61,6 +299,74 @@ fn parse_args() -> Result<Config, String> {
         i += 1;
     }
 
+    if (coverage_file.is_some() || annotate_coverage) && matches!(mode, Mode::Ast) {
+        return Err("coverage is only supported by the bytecode interpreter".to_string());
+    }
+
+    if matches!(command, Command::Inspect) && matches!(mode, Mode::Ast) {
+        return Err("the inspect command shows bytecode and cannot be used with --ast".to_string());
+    }
+
+    if matches!(command, Command::Build) && (matches!(mode, Mode::Ast) || filenames.len() > 1) {
+        return Err("the build command compiles the bytecode of one package directory".to_string());
+    }
+
+    if library && !matches!(command, Command::Build) {
+        return Err("--lib is only supported by the build command".to_string());
+    }
+
+    if matches!(command, Command::Repl | Command::Kernel) && !filenames.is_empty() {
+        return Err("interactive sessions do not take input files".to_string());
+    }
+
+    if bless && !matches!(command, Command::Test) {
+        return Err("--bless is only supported by the test command".to_string());
+    }
+
+    if doc_builtins && !matches!(command, Command::Doc) {
+        return Err("--builtins is only supported by the doc command".to_string());
+    }
+
+    if entry.is_some() && !matches!(command, Command::Run) {
+        return Err("--entry is only supported when running a script".to_string());
+    }
+
+    // the functions of plugins only exist in the process, so bytecode using them cannot be saved
+    if !plugins.is_empty()
+        && (!matches!(command, Command::Run) || compile_output.is_some() || cache)
+    {
+        return Err(
+            "--plugin is only supported when running scripts without saving bytecode".to_string(),
+        );
+    }
+
+    if compile_output.is_some()
+        && (!matches!(command, Command::Run) || matches!(mode, Mode::Ast) || entry.is_some())
+    {
+        return Err("--compile needs a script with main and the bytecode interpreter".to_string());
+    }
+
+    if matches!(command, Command::RunBytecode)
+        && (!filenames.is_empty() || matches!(mode, Mode::Ast))
+    {
+        return Err("--run-bc runs a compiled file and takes no sources".to_string());
+    }
+
+    if cache
+        && (!matches!(command, Command::Run) || matches!(mode, Mode::Ast) || filenames.is_empty())
+    {
+        return Err("--cache needs script files and the bytecode interpreter".to_string());
+    }
+
+    // the cache skips the analysis, so the outputs of the analysis and the passes are missing
+    if cache && (dump_hir || dump_cfg_dot || dump_callgraph || emit_hir_json) {
+        return Err("--cache cannot be combined with the outputs of the HIR".to_string());
+    }
+
+    if matches!(command, Command::Test) && filenames.is_empty() {
+        return Err("the test command needs a directory or file".to_string());
+    }
+
     let input = if filenames.is_empty() {
         Input::Stdin
     } else {
@@ -68,86 +374,510 @@ fn parse_args() -> Result<Config, String> {
     };
 
     Ok(Config {
+        command,
         mode,
         input,
         dump_ast,
+        dump_ast_dot,
         dump_hir,
+        dump_cfg_dot,
+        dump_bc,
+        dump_callgraph,
+        explain_desugar,
+        emit_hir_json,
+        // coverage reports also list the functions that never run
+        keep_all: keep_all || coverage_file.is_some() || annotate_coverage,
+        jobs,
+        coverage_file,
+        annotate_coverage,
+        mem_stats,
+        profile,
+        profile_alloc,
+        trace,
+        debug: debug || !breakpoints.is_empty(),
+        breakpoints,
+        cache,
+        stats,
+        time,
+        edition,
+        defines,
+        capabilities,
+        plugins,
+        strict,
+        warn_numeric_eq,
+        log_level,
+        bless,
+        doc_builtins,
+        entry,
+        compile_output,
+        bytecode_file,
+        library,
+        passes,
         args: program_args,
     })
 }
 
-fn run(ctx: &mut CompilerContext, config: Config) -> SourceResult<()> {
-    // Parse sources
-    let ast = match config.input {
-        Input::Files(paths) => {
-            // Parse first file
-            let source_id = ctx
-                .sources
-                .add_from_file(&paths[0])
-                .expect("Unable to load source file");
-            let mut program = parse(ctx, source_id)?;
-
-            // Append remaining files
-            for path in &paths[1..] {
-                let source_id = ctx
-                    .sources
-                    .add_from_file(path)
-                    .expect("Unable to load source file");
-                let mut ast = parse(ctx, source_id)?;
-                program.decls.append(&mut ast.decls);
-            }
+// Reports a file that could not be read or written, which is not an error of the program, and
+// exits
+fn exit_with_io_error(action: &str, path: impl fmt::Display, err: io::Error) -> ! {
+    eprintln!("Error: cannot {} {}: {}", action, path, err);
+    std::process::exit(1);
+}
 
-            program
-        }
+fn load_sources(ctx: &mut CompilerContext, input: &Input) -> Vec<SourceId> {
+    match input {
+        Input::Files(paths) => paths
+            .iter()
+            .map(|path| {
+                ctx.sources
+                    .add_from_file(path)
+                    .unwrap_or_else(|err| exit_with_io_error("read", path, err))
+            })
+            .collect(),
         Input::Stdin => {
             let mut buffer = String::new();
             std::io::stdin()
                 .read_to_string(&mut buffer)
-                .expect("Unable to read from stdin");
-            let source_id = ctx.sources.add_from_string(&buffer);
-            parse(ctx, source_id)?
+                .unwrap_or_else(|err| exit_with_io_error("read", "stdin", err));
+            vec![ctx.sources.add_from_string(&buffer)]
         }
-    };
+    }
+}
 
-    // Dump AST
-    if config.dump_ast {
-        println!("{:?}", ast.debug_with(&ctx));
+fn doc(ctx: &mut CompilerContext, config: Config) -> SourceResult<()> {
+    if config.doc_builtins {
+        print!("{}", builtins_markdown());
+        if let Input::Stdin = config.input {
+            return Ok(());
+        }
+        println!();
+    }
+    for (i, source_id) in load_sources(ctx, &config.input).into_iter().enumerate() {
+        let program = parse(ctx, source_id)?;
+        if i > 0 {
+            println!();
+        }
+        let title = ctx.sources.get_by_id(source_id).name();
+        print!("{}", to_markdown(ctx, title, &program));
+    }
+    Ok(())
+}
+
+// Compiles the sources like `run` without executing them and prints the resulting bytecode.
+fn inspect(ctx: &mut CompilerContext, config: Config) -> SourceResult<()> {
+    let source_ids = load_sources(ctx, &config.input);
+    let ast = parse_program(ctx, &source_ids)?;
+    let mut hir = analyze(ctx, &ast)?;
+    report_warnings(ctx);
+    config.passes.run(ctx, &mut hir)?;
+    report_warnings(ctx);
+    let bc = compile(ctx, &hir)?;
+    println!("sources:");
+    for (index, source) in ctx.sources.iter().enumerate() {
+        println!("{:5}  {}", index, source.name());
+    }
+    println!();
+    print!("{}", bc.inspect());
+    Ok(())
+}
+
+// Prints bytecode written by --compile or build, whose sources are not known
+fn inspect_bytecode(path: &str) -> NxResult<()> {
+    let bytes =
+        std::fs::read(path).map_err(|err| nx_error(format!("cannot read {}: {}", path, err)))?;
+    let bc = Bytecode::deserialize(&bytes)?;
+    print!("{}", bc.inspect());
+    Ok(())
+}
+
+/// The arguments of `main` (or the `--entry` function): the list of command line arguments, or
+/// nothing if it declares no parameter and reads them from `ARGS` instead. `param_count` is `None`
+/// if there is no such function.
+fn main_args(param_count: Option<usize>, args: Value) -> Vec<Value> {
+    if param_count == Some(0) {
+        Vec::new()
+    } else {
+        vec![args]
+    }
+}
+
+fn ast_param_count(ctx: &CompilerContext, ast: &Program, name: &str) -> Option<usize> {
+    ast.decls
+        .iter()
+        .find(|decl| ctx.interner.resolve(decl.name) == name)
+        .map(|decl| decl.params.len())
+}
+
+fn bc_param_count(bc: &Bytecode, name: &str) -> Option<usize> {
+    let index = bc.function_index(name)?;
+    Some(bc.globals[index].unwrap_function().param_count())
+}
+
+// Stops at the first instruction of each of the given lines of the main source and prints the
+// variables of the current function, or only the values of its frame without debug info
+fn debugger(
+    ctx: &CompilerContext,
+    bc: &Bytecode,
+    source_id: SourceId,
+    lines: &[usize],
+) -> Debugger {
+    let mut locations = HashMap::new();
+    let mut previous_line = None;
+    for (offset, code_span) in &bc.line_table {
+        let span = Span::from_code_span(&ctx.sources, *code_span);
+        let line = (span.source_id() == source_id).then(|| span.start_pos(&ctx.sources).0);
+        if let Some(line) = line
+            && lines.contains(&line)
+            && previous_line != Some(line)
+        {
+            locations.insert(*offset, line);
+        }
+        previous_line = line;
+    }
+    let offsets: Vec<usize> = locations.keys().copied().collect();
+    let name = ctx.sources.get_by_id(source_id).name().to_string();
+    let mut debugger = Debugger::new(move |bc, offset, frame| {
+        let values = match bc.locals_at(offset, frame) {
+            Some(locals) => locals
+                .iter()
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .collect::<Vec<String>>()
+                .join(", "),
+            None => {
+                let values: Vec<String> = frame.iter().map(|v| v.to_string()).collect();
+                format!("[{}]", values.join(", "))
+            }
+        };
+        eprintln!("break at {}:{}: {}", name, locations[&offset], values);
+    });
+    for offset in offsets {
+        debugger.add_breakpoint(offset);
     }
+    debugger
+}
+
+fn runtime_context(config: &Config, args: &Value) -> RuntimeContext {
+    let mut rt = RuntimeContext::new();
+    for &capability in &config.capabilities {
+        rt.grant(capability);
+    }
+    rt.set_interrupts(signal::handle_interrupts());
+    rt.set_strict(config.strict);
+    rt.set_warn_numeric_eq(config.warn_numeric_eq);
+    rt.set_log_level(config.log_level);
+    rt.set_args(args.clone());
+    for path in &config.plugins {
+        if let Err(err) = plugin::load(&mut rt, path) {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+    rt
+}
 
-    // Prepare arguments
-    let args = Value::from_list(Rc::new(RefCell::new(
+fn program_args(config: &Config) -> Value {
+    Value::from_list(Rc::new(
         config
             .args
             .iter()
             .map(|a| Value::from_string(a.as_str().into()))
             .collect(),
-    )));
+    ))
+}
+
+// Runs bytecode written by --compile. Without the sources, errors have no location.
+fn run_bytecode(config: &Config) -> NxResult<()> {
+    let path = config.bytecode_file.as_deref().unwrap();
+    let bytes =
+        std::fs::read(path).map_err(|err| nx_error(format!("cannot read {}: {}", path, err)))?;
+    let bc = Bytecode::deserialize(&bytes)?;
+    let args = program_args(config);
+    let mut rt = runtime_context(config, &args);
+    let mut interpreter = BcInterpreter::new(&mut rt);
+    let main_args = main_args(bc_param_count(&bc, "main"), args);
+    let result = interpreter.run_function(&bc, "main", main_args)?;
+    if !result.is_null() {
+        println!("{}", result);
+    }
+    Ok(())
+}
+
+fn run(ctx: &mut CompilerContext, config: Config) -> SourceResult<()> {
+    let mut timer = PhaseTimer::new();
+
+    // Parse sources, appending the declarations of all files and the modules they import to the
+    // first one
+    let source_ids = load_sources(ctx, &config.input);
+    if config.time {
+        // the parser pulls tokens on demand, so tokenizing is measured by a separate scan and is
+        // also part of the parse time
+        for source_id in &source_ids {
+            timer.time("tokenize", || tokenize(ctx, *source_id));
+        }
+    }
+    let ast = timer.time("parse", || parse_program(ctx, &source_ids))?;
+
+    // the runtime comes first, the analysis needs the natives of its plugins
+    let args = program_args(&config);
+    let mut rt = runtime_context(&config, &args);
+    ctx.declare_natives(&rt);
+
+    // Dump AST
+    if config.dump_ast {
+        println!("{:?}", ast.debug_with(&ctx));
+    }
+    if config.dump_ast_dot {
+        print!("{}", ast.to_dot(ctx));
+        return Ok(());
+    }
+    if config.explain_desugar {
+        let hir = analyze(ctx, &ast)?;
+        print!("{}", explain_desugaring(ctx, &hir));
+        return Ok(());
+    }
 
     // Execute
-    let mut rt = RuntimeContext::new();
+    let entry = config.entry.as_deref().unwrap_or("main");
     let result = match config.mode {
         Mode::Ast => {
+            // only for the diagnostics, like the definite assignment check, the AST interpreter
+            // does not use the HIR
+            timer.time("analyze", || analyze(&ctx, &ast))?;
             let mut interpreter = AstInterpreter::new(&ctx, &mut rt);
-            interpreter.run(ast, vec![args])?
+            interpreter.skip_decl_checks();
+            if config.profile_alloc {
+                #[cfg(feature = "profile-alloc")]
+                natrix_runtime::profile::reset();
+            }
+            let main_args = main_args(ast_param_count(ctx, &ast, entry), args);
+            let result = timer.time("execute", || {
+                interpreter.run_function(ast, entry, main_args)
+            });
+            report_warnings(ctx);
+            result
         }
         Mode::Bytecode => {
-            let mut hir = analyze(&ctx, &ast)?;
-            fold_constants(&mut hir)?;
-            if config.dump_hir {
-                println!("{:?}", hir.debug_with(&ctx));
-            }
+            // the imported modules are part of the key as well as the given sources
+            let cache_entry = config.cache.then(|| {
+                let all_ids: Vec<_> = ctx.sources.iter().map(|source| source.id()).collect();
+                cache::entry_path(ctx, &config.passes, config.entry.as_deref(), &all_ids)
+            });
+            let cached = cache_entry
+                .as_deref()
+                .and_then(|path| timer.time("load cache", || cache::load(path)));
+            let bc = match cached {
+                Some(bc) => bc,
+                None => {
+                    let mut hir = timer.time("analyze", || analyze(&ctx, &ast))?;
+                    report_warnings(ctx);
+                    for (name, duration) in config.passes.run_timed(ctx, &mut hir)? {
+                        timer.add(name, duration);
+                    }
+                    report_warnings(ctx);
+                    if config.dump_hir {
+                        println!("{:?}", hir.debug_with(&ctx));
+                    }
+                    if config.emit_hir_json {
+                        println!("{}", hir_json::to_json(ctx, &hir));
+                    }
+                    if config.dump_cfg_dot {
+                        print!("{}", cfg::to_dot(ctx, &hir));
+                        return Ok(());
+                    }
+                    if config.dump_callgraph {
+                        print!("{}", CallGraph::build(&hir).to_dot(ctx, &hir));
+                        return Ok(());
+                    }
 
-            let bc = compile(ctx, &hir)?;
+                    // a script run from another entry point does not need `main`
+                    let bc = timer.time("compile", || match config.entry {
+                        Some(_) => Ok(compile_functions(ctx, &hir)),
+                        None => compile(ctx, &hir),
+                    })?;
+                    // the key only covers the sources, not the files they embed
+                    if let Some(path) = &cache_entry
+                        && ctx.sources.embedded_files().is_empty()
+                    {
+                        cache::store(path, &bc);
+                    }
+                    bc
+                }
+            };
+            if let Some(path) = &config.compile_output {
+                let bytes = bc.serialize().err_at(ast.span)?;
+                std::fs::write(path, bytes)
+                    .unwrap_or_else(|err| exit_with_io_error("write", path, err));
+                return Ok(());
+            }
+            if config.dump_bc {
+                print!("{}", bc.disassemble_functions());
+            }
+            if config.stats {
+                eprintln!("{}", bc.stats());
+            }
+            let warning_format =
+                |message: &str, code_span| format_bc_warning(&ctx.sources, message, code_span);
             let mut interpreter = BcInterpreter::new(&mut rt);
-            interpreter.run(&bc, vec![args]).err_at(hir.span)?
+            interpreter.set_warning_format(&warning_format);
+            if config.coverage_file.is_some() || config.annotate_coverage {
+                interpreter.collect_coverage();
+            }
+            let profiler = Rc::new(RefCell::new(Profiler::new()));
+            if config.profile {
+                interpreter.add_hook(profiler.clone());
+            }
+            if let Some(functions) = &config.trace {
+                let mut tracer = Tracer::new(Box::new(std::io::stderr()));
+                if !functions.is_empty() {
+                    let functions = functions.clone();
+                    tracer = tracer
+                        .with_filter(move |function, _| functions.iter().any(|f| f == function));
+                }
+                interpreter.add_hook(Rc::new(RefCell::new(tracer)));
+            }
+            if !config.breakpoints.is_empty() {
+                let debugger = debugger(ctx, &bc, source_ids[0], &config.breakpoints);
+                interpreter.add_hook(Rc::new(RefCell::new(debugger)));
+            }
+            if config.profile_alloc {
+                #[cfg(feature = "profile-alloc")]
+                natrix_runtime::profile::reset();
+            }
+            let main_args = main_args(bc_param_count(&bc, entry), args);
+            let result = timer.time("execute", || {
+                interpreter.run_function(&bc, entry, main_args)
+            });
+            if config.profile {
+                eprintln!("{}", profiler.borrow().report());
+            }
+            if let Some(coverage) = interpreter.take_coverage() {
+                if let Some(path) = &config.coverage_file {
+                    std::fs::write(path, lcov_report(ctx, &bc, &coverage))
+                        .unwrap_or_else(|err| exit_with_io_error("write", path, err));
+                }
+                if config.annotate_coverage {
+                    print!("{}", annotate(ctx, &bc, &coverage));
+                }
+            }
+            result.err_at_bc(&ctx.sources, ast.span)
         }
     };
+    if config.mem_stats {
+        eprintln!("{}", rt.mem_stats());
+    }
+    if config.profile_alloc {
+        #[cfg(feature = "profile-alloc")]
+        {
+            natrix_runtime::profile::leave_opcode();
+            eprintln!("{}", natrix_runtime::profile::report());
+        }
+    }
+    if config.time {
+        eprintln!("{}", timer.report());
+    }
+    let result = result?;
     if !result.is_null() {
         println!("{}", result);
     }
     Ok(())
 }
 
+// Compiles the package in `package.dir` with the packages it depends on into `build/NAME.nxc`,
+// which `--run-bc` runs, or with --lib into `build/NAME.nxlib`, which other programs import. The
+// edition is the one of the manifest.
+fn build(ctx: &mut CompilerContext, config: &Config, package: &Package) -> SourceResult<()> {
+    ctx.edition = package.manifest.edition;
+    for (name, entry) in &package.dependencies {
+        ctx.modules.add_package(name, entry.clone());
+    }
+    for dir in &package.manifest.source_dirs {
+        ctx.modules.add_search_dir(package.dir.join(dir));
+    }
+    let entry = package.dir.join(&package.manifest.entry);
+    let source_id = ctx
+        .sources
+        .add_from_file(&entry)
+        .unwrap_or_else(|err| exit_with_io_error("read", entry.display(), err));
+    let ast = parse_program(ctx, &[source_id])?;
+    let mut hir = analyze(ctx, &ast)?;
+    report_warnings(ctx);
+    config.passes.run(ctx, &mut hir)?;
+    report_warnings(ctx);
+    let (bytes, extension) = if config.library {
+        let library = compile_library(ctx, &hir);
+        (library.serialize().err_at(ast.span)?, LIBRARY_EXTENSION)
+    } else {
+        let bc = compile(ctx, &hir)?;
+        (bc.serialize().err_at(ast.span)?, "nxc")
+    };
+    let path = package
+        .dir
+        .join("build")
+        .join(format!("{}.{}", package.manifest.name, extension));
+    std::fs::create_dir_all(path.parent().unwrap())
+        .and_then(|()| std::fs::write(&path, bytes))
+        .unwrap_or_else(|err| exit_with_io_error("write", path.display(), err));
+    println!("built {}", path.display());
+    Ok(())
+}
+
+// Reads all tokens of a source without parsing them, errors are left for the parser to report
+fn tokenize(ctx: &mut CompilerContext, source_id: SourceId) {
+    let mut tokenizer = Tokenizer::new(ctx, source_id);
+    while let Ok(token) = tokenizer.next_token() {
+        if token.tt == TokenType::Eof {
+            break;
+        }
+    }
+}
+
+fn report_warnings(ctx: &CompilerContext) {
+    for warning in ctx.take_warnings() {
+        eprintln!("{}", warning.display_as_warning(&ctx.sources));
+    }
+}
+
+// History is kept in $NATRIX_HISTORY, or ~/.natrix_history by default.
+fn history_file() -> Option<PathBuf> {
+    match std::env::var_os("NATRIX_HISTORY") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".natrix_history")),
+    }
+}
+
+fn repl(edition: Edition, capabilities: &[Capability]) {
+    let mut repl = Repl::with_edition(edition);
+    let mut editor = LineEditor::new(history_file());
+    let mut rt = RuntimeContext::new();
+    for &capability in capabilities {
+        rt.grant(capability);
+    }
+    let mut entry = String::new();
+    loop {
+        let prompt = if entry.is_empty() { "> " } else { "... " };
+        let line = match editor.read_line(prompt, |prefix| repl.completions(prefix)) {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                break;
+            }
+        };
+        editor.add_history(&line);
+        entry.push_str(&line);
+        entry.push('\n');
+        if is_incomplete(&entry) {
+            continue;
+        }
+        match repl.eval(&mut rt, &entry) {
+            Ok(value) if !value.is_null() => println!("{}", value),
+            Ok(_) => {}
+            Err(err) => println!("{}", err.display_with(&repl.ctx.sources)),
+        }
+        entry.clear();
+    }
+}
+
 fn main() {
     let config = match parse_args() {
         Ok(config) => config,
@@ -155,20 +885,198 @@ fn main() {
             eprintln!("Error: {}", msg);
             eprintln!();
             eprintln!("Usage: natrix [OPTIONS] [FILE...] [-- args]");
+            eprintln!("       natrix doc [--builtins] [FILE...]");
+            eprintln!("       natrix repl");
+            eprintln!("       natrix test [--bless] [OPTIONS] PATH...");
+            eprintln!("       natrix inspect [OPTIONS] [FILE...|FILE.nxc]");
+            eprintln!("       natrix build [OPTIONS] [DIR]");
+            eprintln!();
+            eprintln!("Commands:");
+            eprintln!("  doc          Print Markdown documentation of functions and doc comments,");
+            eprintln!("               --builtins adds a page about every builtin");
+            eprintln!("  repl         Start an interactive session (history in $NATRIX_HISTORY)");
+            eprintln!("  test         Compare the output of each .nx script in PATH with its");
+            eprintln!("               .expected file, --bless updates mismatching files");
+            eprintln!(
+                "  inspect      Compile without running and print the constant pool, globals,"
+            );
+            eprintln!("               disassembly and line table of the bytecode, or those of");
+            eprintln!("               a .nxc file");
+            eprintln!("  build        Compile the package described by DIR/natrix.toml (default:");
+            eprintln!("               the current directory) and its dependencies to");
+            eprintln!("               DIR/build/NAME.nxc, fetching git dependencies first; --lib");
+            eprintln!("               writes a library importable without the sources instead,");
+            eprintln!("               DIR/build/NAME.nxlib, whose entry needs no main");
             eprintln!();
             eprintln!("Options:");
             eprintln!("  --ast        Use AST interpreter (default: bytecode)");
             eprintln!("  --bc         Use bytecode interpreter");
             eprintln!("  --dump-ast   Print AST after parsing");
+            eprintln!("  --dump-ast=dot       Print AST in DOT format instead of running");
             eprintln!("  --dump-hir   Print HIR after analysis (bytecode mode only)");
+            eprintln!(
+                "  --dump-cfg=dot       Print the control-flow graph of each function in DOT format"
+            );
+            eprintln!("                       instead of running (bytecode mode only)");
+            eprintln!("  --dump-bc    Print the disassembly of each function before running (bytecode mode only)");
+            eprintln!(
+                "  --dump-callgraph     Print the calls between functions in DOT format instead of"
+            );
+            eprintln!("                       running (bytecode mode only)");
+            eprintln!(
+                "  --explain-desugar    Print the HIR that for, unless, guard, with and multiple"
+            );
+            eprintln!(
+                "                       assignments are lowered to, next to their source, instead of"
+            );
+            eprintln!("                       running");
+            eprintln!(
+                "  --emit=hir-json      Print HIR as JSON after the passes (bytecode mode only)"
+            );
+            eprintln!(
+                "  --coverage=FILE      Write an lcov coverage report to FILE (bytecode mode only)"
+            );
+            eprintln!("  --coverage-annotate  Print sources annotated with execution counts");
+            eprintln!("  --mem-stats  Print string and list allocation statistics after the run");
+            eprintln!("  --profile    Print calls and instructions per function and opcode counts");
+            eprintln!(
+                "  --trace[=FUN,...]    Print each executed instruction, or only those of the"
+            );
+            eprintln!("                       given functions, on stderr");
+            eprintln!(
+                "  --break=LINE Print the variables of the current function whenever LINE of"
+            );
+            eprintln!("               the first file is reached (bytecode mode only)");
+            eprintln!("  --debug      Keep the names of local variables for locals()");
+            eprintln!("  --keep-all   Keep functions that main never calls in the bytecode");
+            eprintln!(
+                "  --jobs=N     Parse the files of the program on N threads (default: one per core)"
+            );
+            eprintln!(
+                "  --cache      Reuse the bytecode of a previous run of the same sources, kept in"
+            );
+            eprintln!("               .natrix-cache next to the first file (skips the warnings)");
+            eprintln!(
+                "  --profile-alloc      Print allocations and value clones per opcode (needs"
+            );
+            eprintln!("                       natrix built with --features profile-alloc)");
+            eprintln!("  --stats      Print the size of the bytecode and its constant pool");
+            eprintln!("  --time       Print the time spent in each phase and the peak memory");
+            eprintln!("  --edition=N  Language edition, 1 (default) or 2 (adds defer, for, guard, import, try,");
+            eprintln!("                       unless and with, reserves class and match)");
+            eprintln!("  --define NAME[=VALUE]");
+            eprintln!("                       Set a flag tested by @if, VALUE defaults to true");
+            eprintln!("  --entry NAME Call the function NAME instead of main");
+            eprintln!(
+                "  --compile FILE       Write the bytecode to FILE (.nxc) instead of running it"
+            );
+            eprintln!("  --run-bc FILE        Run bytecode written by --compile");
+            eprintln!(
+                "  --plugin LIB         Load a shared library adding native functions (Unix only)"
+            );
+            eprintln!("  --allow-read Let the script read files and list directories");
+            eprintln!("  --allow-write        Let the script create, write and remove files and");
+            eprintln!("                       directories");
+            eprintln!("  --strict     Reject shadowed builtins, uses of implicit null returns,");
+            eprintln!("               comparisons of different types and integer overflow");
+            eprintln!("  --warn-numeric-eq    Warn about == and != between an int and a float");
+            eprintln!(
+                "  --log-level=LEVEL    Show log_* messages from LEVEL up on stderr: debug, info"
+            );
+            eprintln!("                       (default), warn, error or off");
+            eprintln!("  --print-passes       List the optimization passes and exit");
+            eprintln!("  --disable-pass=NAME  Skip an optimization pass (bytecode mode only)");
+            eprintln!("  --kernel     Evaluate cells sent as JSON lines on stdin (for notebooks)");
             eprintln!();
             eprintln!("If no FILE is not provided, reads from stdin.");
             std::process::exit(1);
         }
     };
 
-    let mut ctx = CompilerContext::default();
-    if let Err(err) = run(&mut ctx, config) {
+    let mut ctx = CompilerContext::with_edition(config.edition);
+    ctx.strict = config.strict;
+    ctx.defines = config.defines.clone();
+    ctx.debug_info = config.debug;
+    ctx.prune = !config.keep_all;
+    ctx.jobs = config.jobs;
+    let result = match config.command {
+        Command::Run => run(&mut ctx, config),
+        Command::Doc => doc(&mut ctx, config),
+        Command::Inspect => match &config.input {
+            Input::Files(paths) if paths.len() == 1 && paths[0].ends_with(".nxc") => {
+                if let Err(err) = inspect_bytecode(&paths[0]) {
+                    eprintln!("Error: {}", err.message);
+                    std::process::exit(1);
+                }
+                Ok(())
+            }
+            _ => inspect(&mut ctx, config),
+        },
+        Command::Build => {
+            let dir = match &config.input {
+                Input::Files(paths) => Path::new(&paths[0]),
+                Input::Stdin => Path::new("."),
+            };
+            match Package::resolve(dir) {
+                Ok(package) => build(&mut ctx, &config, &package),
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Repl => {
+            repl(config.edition, &config.capabilities);
+            Ok(())
+        }
+        Command::Test => {
+            let Input::Files(paths) = &config.input else {
+                unreachable!("checked by parse_args")
+            };
+            let options = TestOptions {
+                ast: matches!(config.mode, Mode::Ast),
+                bless: config.bless,
+                edition: config.edition,
+                capabilities: config.capabilities,
+                strict: config.strict,
+                passes: config.passes,
+            };
+            match test_runner::run(paths, &options) {
+                Ok(true) => {}
+                Ok(false) => std::process::exit(1),
+                Err(err) => {
+                    eprintln!("Error: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            Ok(())
+        }
+        Command::RunBytecode => {
+            if let Err(err) = run_bytecode(&config) {
+                println!("error: {}", err.message);
+                for (function, _) in &err.trace {
+                    println!("  in {}", function);
+                }
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Command::PrintPasses => {
+            for (pass, enabled) in config.passes.passes() {
+                let state = if enabled { "" } else { " (disabled)" };
+                println!("{:<16}{}{}", pass.name(), pass.description(), state);
+            }
+            Ok(())
+        }
+        Command::Kernel => {
+            if let Err(err) = kernel::run(config.edition, &config.capabilities) {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    };
+    if let Err(err) = result {
         println!("{}", err.display_with(&ctx.sources));
         std::process::exit(1);
     }
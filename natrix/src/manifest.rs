@@ -0,0 +1,450 @@
+// Packages of natrix scripts, described by a `natrix.toml` in their directory:
+//
+//     [package]
+//     name = "plots"
+//     entry = "src/main.nx"      # main.nx by default
+//     source-dirs = ["src/lib"]  # searched for imports not found next to the importing file
+//     edition = 2                # the default, imports need edition 2
+//
+//     [dependencies]
+//     shapes = { path = "../shapes" }
+//     colors = { git = "https://example.com/colors.git", rev = "v1.2" }
+//
+// A dependency is itself a package, and `import shapes;` reads its entry. Dependencies of
+// dependencies are visible to the whole program as well, so their names must be distinct.
+// Git dependencies are cloned into `.natrix-deps` in the directory of the package being built,
+// once: a clone that already exists is reused, and only cloned again when the url or the rev of
+// the dependency changed.
+
+use natrix_compiler::token_type::{is_identifier, Edition};
+use natrix_runtime::value::toml::Toml;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub const FILE_NAME: &str = "natrix.toml";
+pub const DEPS_DIR: &str = ".natrix-deps";
+
+#[derive(Debug, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    pub entry: PathBuf,            // relative to the directory of the package
+    pub source_dirs: Vec<PathBuf>, // relative to the directory of the package
+    pub edition: Edition,
+    pub dependencies: Vec<Dependency>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    pub source: DependencySource,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DependencySource {
+    Path(PathBuf), // relative to the directory of the package depending on it
+    Git { url: String, rev: Option<String> },
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Manifest, String> {
+        let toml = Toml::parse(text).map_err(|err| err.message.to_string())?;
+        check_keys(&toml, "the manifest", &["package", "dependencies"])?;
+        let Some(package) = toml.get("package") else {
+            return Err("missing [package]".to_string());
+        };
+        check_keys(
+            package,
+            "[package]",
+            &["name", "entry", "source-dirs", "edition"],
+        )?;
+        let name = match package.get("name") {
+            Some(name) => module_name(string(name, "package.name")?)?,
+            None => return Err("missing package.name".to_string()),
+        };
+        let entry = match package.get("entry") {
+            Some(entry) => PathBuf::from(string(entry, "package.entry")?),
+            None => PathBuf::from("main.nx"),
+        };
+        let source_dirs = match package.get("source-dirs").map(Toml::as_array) {
+            Some(Some(dirs)) => dirs
+                .iter()
+                .map(|dir| string(dir, "package.source-dirs").map(PathBuf::from))
+                .collect::<Result<_, _>>()?,
+            Some(None) => return Err("package.source-dirs must be an array".to_string()),
+            None => Vec::new(),
+        };
+        let edition = match package.get("edition") {
+            Some(edition) => match edition.as_integer() {
+                Some(n) => n.to_string().parse()?,
+                None => return Err("package.edition must be an integer".to_string()),
+            },
+            None => Edition::E2,
+        };
+        let mut dependencies = Vec::new();
+        for (name, value) in toml
+            .get("dependencies")
+            .and_then(Toml::as_table)
+            .unwrap_or_default()
+        {
+            dependencies.push(Dependency {
+                name: module_name(name)?,
+                source: dependency_source(name, value)?,
+            });
+        }
+        Ok(Manifest {
+            name,
+            entry,
+            source_dirs,
+            edition,
+            dependencies,
+        })
+    }
+
+    /// Reads the manifest in `dir`.
+    pub fn load(dir: &Path) -> Result<Manifest, String> {
+        let path = dir.join(FILE_NAME);
+        let text =
+            fs::read_to_string(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Manifest::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+}
+
+fn dependency_source(name: &str, value: &Toml) -> Result<DependencySource, String> {
+    let key = format!("dependencies.{}", name);
+    check_keys(value, &key, &["path", "git", "rev"])?;
+    match (value.get("path"), value.get("git"), value.get("rev")) {
+        (Some(path), None, None) => Ok(DependencySource::Path(PathBuf::from(string(
+            path,
+            &format!("{}.path", key),
+        )?))),
+        (None, Some(url), rev) => Ok(DependencySource::Git {
+            url: git_arg(url, &format!("{}.git", key))?,
+            rev: match rev {
+                Some(rev) => Some(git_arg(rev, &format!("{}.rev", key))?),
+                None => None,
+            },
+        }),
+        (Some(_), _, _) => Err(format!("{} takes either path or git", key)),
+        (None, None, _) => Err(format!("{} needs a path or git", key)),
+    }
+}
+
+// Reports keys of a table that the manifest does not know, which are most likely misspelled
+fn check_keys(table: &Toml, what: &str, known: &[&str]) -> Result<(), String> {
+    let Some(entries) = table.as_table() else {
+        return Err(format!("{} must be a table", what));
+    };
+    match entries
+        .iter()
+        .find(|(key, _)| !known.contains(&key.as_str()))
+    {
+        Some((key, _)) => Err(format!("unknown key {:?} in {}", key, what)),
+        None => Ok(()),
+    }
+}
+
+// A string passed to git, which would take it for an option if it started with a dash
+fn git_arg(value: &Toml, key: &str) -> Result<String, String> {
+    match string(value, key)? {
+        arg if arg.starts_with('-') => Err(format!("{} cannot start with -", key)),
+        arg => Ok(arg.to_string()),
+    }
+}
+
+fn string<'a>(value: &'a Toml, key: &str) -> Result<&'a str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("{} must be a string", key))
+}
+
+// Packages are imported by name, so the name must be an identifier
+fn module_name(name: &str) -> Result<String, String> {
+    if is_identifier(name, Edition::E2) {
+        Ok(name.to_string())
+    } else {
+        Err(format!("{:?} is not a valid package name", name))
+    }
+}
+
+/// A package ready to be built: its manifest and the entry of each package it depends on,
+/// directly or not.
+pub struct Package {
+    pub dir: PathBuf,
+    pub manifest: Manifest,
+    pub dependencies: Vec<(String, PathBuf)>,
+}
+
+impl Package {
+    /// Reads the manifest in `dir` and those of the dependencies, fetching the git ones.
+    pub fn resolve(dir: &Path) -> Result<Package, String> {
+        let manifest = Manifest::load(dir)?;
+        let entry = dir.join(&manifest.entry);
+        if !entry.is_file() {
+            return Err(format!("the entry {} does not exist", entry.display()));
+        }
+        let mut dependencies: Vec<(String, PathBuf)> = Vec::new();
+        let mut resolved_dirs: Vec<PathBuf> = Vec::new();
+        // the dependencies still to resolve and the directory of the package declaring each
+        let mut pending: VecDeque<(PathBuf, Dependency)> = manifest
+            .dependencies
+            .iter()
+            .map(|dep| (dir.to_path_buf(), dep.clone()))
+            .collect();
+        while let Some((from, dependency)) = pending.pop_front() {
+            let dep_dir = match &dependency.source {
+                DependencySource::Path(path) => from.join(path),
+                DependencySource::Git { url, rev } => fetch(
+                    &dir.join(DEPS_DIR).join(&dependency.name),
+                    url,
+                    rev.as_deref(),
+                )?,
+            };
+            let canonical = fs::canonicalize(&dep_dir).map_err(|err| {
+                format!(
+                    "dependency {} ({}): {}",
+                    dependency.name,
+                    dep_dir.display(),
+                    err
+                )
+            })?;
+            if let Some(index) = dependencies
+                .iter()
+                .position(|(name, _)| *name == dependency.name)
+            {
+                if resolved_dirs[index] != canonical {
+                    return Err(format!(
+                        "two different packages are named {}",
+                        dependency.name
+                    ));
+                }
+                continue;
+            }
+            let dep_manifest = Manifest::load(&dep_dir)?;
+            dependencies.push((dependency.name.clone(), dep_dir.join(&dep_manifest.entry)));
+            resolved_dirs.push(canonical);
+            for transitive in dep_manifest.dependencies {
+                pending.push_back((dep_dir.clone(), transitive));
+            }
+        }
+        Ok(Package {
+            dir: dir.to_path_buf(),
+            manifest,
+            dependencies,
+        })
+    }
+}
+
+// The file in a clone recording the url it was cloned from and the rev checked out, one per line,
+// the rev is empty for the default branch
+const REV_FILE: &str = ".git/natrix-rev";
+
+// Clones the repository of a git dependency into `dest` and checks out `rev`, unless it was already
+// cloned from `url` with that rev checked out, and returns `dest`. The manifest made sure that
+// neither `url` nor `rev` starts with a dash.
+fn fetch(dest: &Path, url: &str, rev: Option<&str>) -> Result<PathBuf, String> {
+    let rev_file = dest.join(REV_FILE);
+    let record = format!("{}\n{}", url, rev.unwrap_or(""));
+    if fs::read_to_string(&rev_file).is_ok_and(|recorded| recorded == record) {
+        return Ok(dest.to_path_buf());
+    }
+    let git = |args: &[&str]| {
+        let status = Command::new("git")
+            .args(args)
+            .status()
+            .map_err(|err| format!("cannot run git: {}", err))?;
+        match status.success() {
+            true => Ok(()),
+            false => Err(format!("git {} failed", args.join(" "))),
+        }
+    };
+    // a clone of another url or rev, or one whose checkout was interrupted
+    if dest.exists() {
+        fs::remove_dir_all(dest).map_err(|err| format!("{}: {}", dest.display(), err))?;
+    }
+    let dest_str = dest.to_string_lossy();
+    let result = git(&["clone", "--quiet", "--", url, &dest_str])
+        .and_then(|()| match rev {
+            Some(rev) => git(&["-C", &dest_str, "checkout", "--quiet", rev, "--"]),
+            None => Ok(()),
+        })
+        .and_then(|()| {
+            fs::write(&rev_file, &record).map_err(|err| format!("{}: {}", rev_file.display(), err))
+        });
+    if let Err(err) = result {
+        // a partial clone would be taken for a complete one by the next build
+        let _ = fs::remove_dir_all(dest);
+        return Err(err);
+    }
+    Ok(dest.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let manifest = Manifest::parse(
+            "[package]\n\
+             name = \"plots\"\n\
+             source-dirs = [\"lib\"]\n\
+             [dependencies]\n\
+             shapes = { path = \"../shapes\" }\n\
+             colors = { git = \"https://example.com/colors.git\", rev = \"v1\" }\n",
+        )
+        .unwrap();
+        assert_eq!(
+            manifest,
+            Manifest {
+                name: "plots".to_string(),
+                entry: PathBuf::from("main.nx"),
+                source_dirs: vec![PathBuf::from("lib")],
+                edition: Edition::E2,
+                // sorted by name, TOML tables have no order
+                dependencies: vec![
+                    Dependency {
+                        name: "colors".to_string(),
+                        source: DependencySource::Git {
+                            url: "https://example.com/colors.git".to_string(),
+                            rev: Some("v1".to_string()),
+                        },
+                    },
+                    Dependency {
+                        name: "shapes".to_string(),
+                        source: DependencySource::Path(PathBuf::from("../shapes")),
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text| Manifest::parse(text).unwrap_err();
+        assert_eq!(
+            error("name = \"a\""),
+            "unknown key \"name\" in the manifest"
+        );
+        assert_eq!(error("[package]\nentry = \"a.nx\""), "missing package.name");
+        assert_eq!(
+            error("[package]\nname = \"my-lib\""),
+            "\"my-lib\" is not a valid package name"
+        );
+        assert_eq!(
+            error("[package]\nname = \"a\"\nentyr = \"b.nx\""),
+            "unknown key \"entyr\" in [package]"
+        );
+        assert_eq!(
+            error("[package]\nname = \"a\"\n[dependencies]\nb = { path = \"b\", git = \"c\" }"),
+            "dependencies.b takes either path or git"
+        );
+        assert_eq!(
+            error("[package]\nname = \"a\"\n[dependencies]\nb = { git = \"--upload-pack=x\" }"),
+            "dependencies.b.git cannot start with -"
+        );
+        assert_eq!(
+            error("[package]\nname = \"a\"\n[dependencies]\nb = { git = \"c\", rev = \"-p\" }"),
+            "dependencies.b.rev cannot start with -"
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let root = std::env::temp_dir().join(format!("natrix-package-{}", std::process::id()));
+        let write = |dir: &str, manifest: &str| {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join(FILE_NAME), manifest).unwrap();
+        };
+        write(
+            "app",
+            "[package]\nname = \"app\"\n[dependencies]\n\
+             shapes = { path = \"../shapes\" }\ngeometry = { path = \"../geometry\" }",
+        );
+        write(
+            "geometry",
+            "[package]\nname = \"geometry\"\nentry = \"src/geometry.nx\"\n\
+             [dependencies]\nshapes = { path = \"../shapes\" }",
+        );
+        write("shapes", "[package]\nname = \"shapes\"");
+        fs::write(root.join("app").join("main.nx"), "fun main() {}").unwrap();
+        let package = Package::resolve(&root.join("app"));
+        write(
+            "app",
+            "[package]\nname = \"app\"\n[dependencies]\n\
+             shapes = { path = \"../geometry\" }\ngeometry = { path = \"../shapes\" }",
+        );
+        let conflict = Package::resolve(&root.join("app"));
+        fs::remove_dir_all(&root).unwrap();
+
+        let app = root.join("app");
+        assert_eq!(
+            package.unwrap().dependencies,
+            [
+                (
+                    "geometry".to_string(),
+                    app.join("../geometry").join("src/geometry.nx")
+                ),
+                ("shapes".to_string(), app.join("../shapes").join("main.nx")),
+            ]
+        );
+        assert_eq!(
+            conflict.err().unwrap(),
+            "two different packages are named shapes"
+        );
+    }
+
+    #[test]
+    fn test_fetch_rev() {
+        let root = std::env::temp_dir().join(format!("natrix-fetch-{}", std::process::id()));
+        // a repository whose tags v1 and v2 have a main.nx containing the name of the repository
+        // and the tag
+        let init = |name: &str| {
+            let repo = root.join(name);
+            fs::create_dir_all(&repo).unwrap();
+            let git = |args: &[&str]| {
+                let status = Command::new("git")
+                    .args([
+                        "-c",
+                        "user.name=natrix",
+                        "-c",
+                        "user.email=natrix@localhost",
+                    ])
+                    .arg("-C")
+                    .arg(&repo)
+                    .args(args)
+                    .output()
+                    .unwrap()
+                    .status;
+                assert!(status.success(), "git {:?}", args);
+            };
+            git(&["init", "--quiet"]);
+            for version in ["v1", "v2"] {
+                fs::write(repo.join("main.nx"), format!("{} {}", name, version)).unwrap();
+                git(&["add", "main.nx"]);
+                git(&["commit", "--quiet", "-m", version]);
+                git(&["tag", version]);
+            }
+            repo.to_string_lossy().into_owned()
+        };
+        let url = init("repo");
+        let moved_url = init("moved");
+        let dest = root.join(DEPS_DIR).join("dep");
+        let checked_out = |url, rev| {
+            fetch(&dest, url, rev).unwrap();
+            fs::read_to_string(dest.join("main.nx")).unwrap()
+        };
+        let first = checked_out(&url, Some("v1"));
+        let changed = checked_out(&url, Some("v2"));
+        let default = checked_out(&url, None);
+        let back = checked_out(&url, Some("v1"));
+        let moved = checked_out(&moved_url, Some("v1"));
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            [first, changed, default, back, moved],
+            ["repo v1", "repo v2", "repo v2", "repo v1", "moved v1"]
+        );
+    }
+}
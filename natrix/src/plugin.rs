@@ -0,0 +1,88 @@
+use natrix_runtime::ctx::RuntimeContext;
+
+/// Loads the plugin at `path`, a shared library exporting the `NxPlugin` of
+/// `natrix_runtime::plugin` (see `natrix_runtime::export_plugin!`), and registers its native
+/// functions with `rt`.
+///
+/// The library stays loaded until the process exits, since `rt` holds its functions. Only Unix
+/// platforms can load plugins.
+pub fn load(rt: &mut RuntimeContext, path: &str) -> Result<(), String> {
+    #[cfg(unix)]
+    return unix::load(rt, path).map_err(|err| format!("cannot load plugin {}: {}", path, err));
+    #[cfg(not(unix))]
+    {
+        let _ = rt;
+        Err(format!("cannot load plugin {}: plugins need Unix", path))
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use natrix_runtime::ctx::RuntimeContext;
+    use natrix_runtime::plugin::{register_plugin, NxPlugin, PLUGIN_SYMBOL};
+    use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+    const RTLD_NOW: c_int = 2;
+
+    unsafe extern "C" {
+        fn dlopen(filename: *const c_char, flags: c_int) -> *mut c_void;
+        fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+        fn dlerror() -> *mut c_char;
+    }
+
+    // The message of the last failure of dlopen or dlsym
+    fn last_error() -> String {
+        // SAFETY: dlerror returns null or a nul-terminated string valid until the next call
+        unsafe {
+            let message = dlerror();
+            if message.is_null() {
+                "unknown error".to_string()
+            } else {
+                CStr::from_ptr(message).to_string_lossy().into_owned()
+            }
+        }
+    }
+
+    pub fn load(rt: &mut RuntimeContext, path: &str) -> Result<(), String> {
+        // a name without a slash would be searched in the library path instead of the directory
+        let path = if path.contains('/') {
+            path.to_string()
+        } else {
+            format!("./{}", path)
+        };
+        let path = CString::new(path).map_err(|err| err.to_string())?;
+        // SAFETY: loading a library runs its initializers, which the user trusts by loading it
+        let handle = unsafe { dlopen(path.as_ptr(), RTLD_NOW) };
+        if handle.is_null() {
+            return Err(last_error());
+        }
+        // SAFETY: `handle` is a library that is never closed
+        let plugin = unsafe { dlsym(handle, PLUGIN_SYMBOL.as_ptr()) };
+        if plugin.is_null() {
+            return Err(format!(
+                "not a natrix plugin, {} is missing",
+                PLUGIN_SYMBOL.to_string_lossy()
+            ));
+        }
+        // SAFETY: the symbol is the `NxPlugin` of `export_plugin!` or of a plugin written in
+        // another language, whose version is checked before anything else is read
+        unsafe { register_plugin(rt, plugin as *const NxPlugin) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_errors() {
+        let mut rt = RuntimeContext::new();
+        let err = load(&mut rt, "no/such/libplugin.so").unwrap_err();
+        assert!(
+            err.starts_with("cannot load plugin no/such/libplugin.so: "),
+            "{}",
+            err
+        );
+        assert!(rt.natives().is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+use natrix_runtime::ctx::Interrupts;
+use std::sync::{Arc, OnceLock};
+
+// Shared with the signal handler, which cannot be given any state
+static INTERRUPTS: OnceLock<Arc<Interrupts>> = OnceLock::new();
+
+/// Makes Ctrl-C raise an interrupt that the script can handle with `on_interrupt`, and returns
+/// the interrupts to give to its runtime context.
+///
+/// The process still stops with status 130 if the script has no handler, or if Ctrl-C is pressed
+/// again before the handler ran. Other platforms than Unix keep the default behavior.
+pub fn handle_interrupts() -> Arc<Interrupts> {
+    let interrupts = INTERRUPTS.get_or_init(|| Arc::new(Interrupts::new()));
+    #[cfg(unix)]
+    unix::install();
+    interrupts.clone()
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::INTERRUPTS;
+
+    const SIGINT: i32 = 2;
+
+    unsafe extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+
+    extern "C" fn on_sigint(_signum: i32) {
+        // only atomics and _exit, which are safe to use in a signal handler
+        if !INTERRUPTS
+            .get()
+            .is_some_and(|interrupts| interrupts.raise())
+        {
+            // SAFETY: _exit does not run any Rust code
+            unsafe { _exit(128 + SIGINT) }
+        }
+    }
+
+    pub fn install() {
+        // SAFETY: the handler only uses async-signal-safe operations
+        unsafe {
+            signal(SIGINT, on_sigint);
+        }
+    }
+}
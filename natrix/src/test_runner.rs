@@ -0,0 +1,190 @@
+use crate::{ast_param_count, bc_param_count, main_args};
+use natrix_compiler::analyze::analyze;
+use natrix_compiler::ast::Interpreter as AstInterpreter;
+use natrix_compiler::bc::compiler::compile;
+use natrix_compiler::ctx::CompilerContext;
+use natrix_compiler::error::{AttachErrSpan, SourceResult};
+use natrix_compiler::hir::pass::PassManager;
+use natrix_compiler::parser::parse;
+use natrix_compiler::src::SourceId;
+use natrix_compiler::token_type::Edition;
+use natrix_runtime::bc::Interpreter as BcInterpreter;
+use natrix_runtime::ctx::{Capability, RuntimeContext};
+use natrix_runtime::value::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use test_utils::{check_golden, GoldenOutcome};
+
+pub struct TestOptions {
+    pub ast: bool,
+    pub bless: bool,
+    pub edition: Edition,
+    pub capabilities: Vec<Capability>,
+    pub strict: bool,
+    pub passes: PassManager,
+}
+
+/// Runs every `.nx` script in `paths` (directories are searched recursively) and compares what it
+/// prints with the `.expected` file next to it, like `natrix SCRIPT` would print it.
+///
+/// Scripts see a clock starting at zero and advancing by a millisecond every time it is read, and
+/// an empty environment and input.
+///
+/// Returns true if all scripts passed. Missing expected files are created, and with `bless`
+/// mismatching ones are updated instead of failing.
+pub fn run(paths: &[String], options: &TestOptions) -> io::Result<bool> {
+    let mut scripts = Vec::new();
+    for path in paths {
+        collect_scripts(Path::new(path), &mut scripts)?;
+    }
+    let mut failures = Vec::new();
+    let mut created = 0;
+    let mut blessed = 0;
+    for script in &scripts {
+        let source = fs::read_to_string(script)?;
+        let actual = run_script(script, &source, options);
+        let outcome = check_golden(script, &actual, "", options.bless)?;
+        let status = match &outcome {
+            GoldenOutcome::Passed => "ok",
+            GoldenOutcome::Created => "created",
+            GoldenOutcome::Blessed => "blessed",
+            GoldenOutcome::Mismatch(_) => "FAILED",
+        };
+        println!("test {} ... {}", script.display(), status);
+        match outcome {
+            GoldenOutcome::Passed => {}
+            GoldenOutcome::Created => created += 1,
+            GoldenOutcome::Blessed => blessed += 1,
+            GoldenOutcome::Mismatch(message) => failures.push((script, message)),
+        }
+    }
+    for (script, message) in &failures {
+        println!("\n---- {} ----\n{}", script.display(), message);
+    }
+    println!(
+        "\n{} passed, {} failed, {} created, {} blessed",
+        scripts.len() - failures.len() - created - blessed,
+        failures.len(),
+        created,
+        blessed
+    );
+    Ok(failures.is_empty())
+}
+
+fn collect_scripts(path: &Path, scripts: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !path.is_dir() {
+        scripts.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            collect_scripts(&entry, scripts)?;
+        } else if entry.extension().is_some_and(|ext| ext == "nx") {
+            scripts.push(entry);
+        }
+    }
+    Ok(())
+}
+
+// Returns the output of the script followed by its result or error, main gets no arguments.
+fn run_script(script: &Path, source: &str, options: &TestOptions) -> String {
+    let mut ctx = CompilerContext::with_edition(options.edition);
+    ctx.strict = options.strict;
+    // Errors name only the file, so that the output does not depend on the working directory,
+    // but `embed` reads files relative to it
+    let name = script.file_name().unwrap().to_string_lossy();
+    let source_id = ctx.sources.add_from_named_string_at(&name, source, script);
+    // The clock, random numbers, environment and input are fixed, so that scripts using them
+    // produce the same output on every run
+    let mut rt = RuntimeContext::with_capture();
+    rt.set_fixed_clock(0.0, 0.001);
+    rt.set_seed(1);
+    rt.set_env(HashMap::new());
+    rt.set_input(Box::new(io::empty()));
+    rt.set_strict(options.strict);
+    for &capability in &options.capabilities {
+        rt.grant(capability);
+    }
+    let result = execute(&mut ctx, &mut rt, source_id, options);
+    let mut output = rt.take_output();
+    match result {
+        Ok(value) if value.is_null() => {}
+        Ok(value) => output.push_str(&format!("{}\n", value)),
+        Err(err) => output.push_str(&format!("{}\n", err.display_with(&ctx.sources))),
+    }
+    output
+}
+
+fn execute(
+    ctx: &mut CompilerContext,
+    rt: &mut RuntimeContext,
+    source_id: SourceId,
+    options: &TestOptions,
+) -> SourceResult<Value> {
+    let ast = parse(ctx, source_id)?;
+    let args = Value::from_list(Rc::new(Vec::new()));
+    if options.ast {
+        if ctx.strict {
+            analyze(ctx, &ast)?;
+        }
+        let main_args = main_args(ast_param_count(ctx, &ast, "main"), args);
+        return AstInterpreter::new(ctx, rt).run(ast, main_args);
+    }
+    let mut hir = analyze(ctx, &ast)?;
+    options.passes.run(ctx, &mut hir)?;
+    let bc = compile(ctx, &hir)?;
+    let main_args = main_args(bc_param_count(&bc, "main"), args);
+    BcInterpreter::new(rt)
+        .run(&bc, main_args)
+        .err_at_bc(&ctx.sources, hir.span)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(ast: bool) -> TestOptions {
+        TestOptions {
+            ast,
+            bless: false,
+            edition: Edition::default(),
+            capabilities: Vec::new(),
+            strict: false,
+            passes: PassManager::with_default_passes(),
+        }
+    }
+
+    #[test]
+    fn test_run_script() {
+        let source = "fun main(args) {\n    print(len(args));\n    return 1 + 2;\n}\n";
+        for ast in [false, true] {
+            assert_eq!(
+                run_script(Path::new("sum.nx"), source, &options(ast)),
+                "0\n3\n"
+            );
+        }
+        let source = "fun main(args) {\n    print(\"start\");\n    print(x);\n}\n";
+        assert_eq!(
+            run_script(Path::new("error.nx"), source, &options(true)),
+            "start\nerror.nx:3:11: error: undeclared variable \"x\"\n    print(x);\n          ^\n"
+        );
+    }
+
+    #[test]
+    fn test_embed_relative_to_script() {
+        let dir = std::env::temp_dir().join(format!("natrix-test-embed-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data.txt"), "hello").unwrap();
+        let source = "fun main() {\n    print(embed(\"data.txt\"));\n}\n";
+        let output = run_script(&dir.join("embed.nx"), source, &options(false));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(output, "hello\n");
+    }
+}
@@ -0,0 +1,96 @@
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Wall-clock time spent in each phase of a run, for `--time`.
+///
+/// Phases are reported in the order they first ran. A phase that runs several times (e.g.
+/// parsing one file after another) accumulates into a single entry.
+pub struct PhaseTimer {
+    start: Instant,
+    phases: Vec<(String, Duration)>,
+}
+
+impl PhaseTimer {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            phases: Vec::new(),
+        }
+    }
+
+    /// Runs `f` and adds the time it takes to `phase`.
+    pub fn time<T>(&mut self, phase: &str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(phase, start.elapsed());
+        result
+    }
+
+    pub fn add(&mut self, phase: &str, duration: Duration) {
+        match self.phases.iter_mut().find(|(name, _)| name == phase) {
+            Some((_, total)) => *total += duration,
+            None => self.phases.push((phase.to_string(), duration)),
+        }
+    }
+
+    /// One line per phase, then the total time since the timer was created (which also covers
+    /// loading the sources and anything between the phases) and the peak memory if known.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let width = self
+            .phases
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0)
+            .max("total".len());
+        for (name, duration) in &self.phases {
+            writeln!(out, "{:width$}  {}", name, format_duration(*duration)).unwrap();
+        }
+        write!(
+            out,
+            "{:width$}  {}",
+            "total",
+            format_duration(self.start.elapsed())
+        )
+        .unwrap();
+        if let Some(kb) = peak_memory_kb() {
+            write!(out, "\npeak memory: {} kB", kb).unwrap();
+        }
+        out
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:10.3} ms", duration.as_secs_f64() * 1000.0)
+}
+
+// Peak resident set size of the process, only available where /proc reports it (Linux)
+fn peak_memory_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    line["VmHWM:".len()..]
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut timer = PhaseTimer::new();
+        timer.add("parse", Duration::from_micros(1500));
+        assert_eq!(timer.time("execute", || 42), 42);
+        timer.add("parse", Duration::from_micros(500));
+        let report = timer.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "parse         2.000 ms");
+        assert!(lines[1].starts_with("execute "), "{}", report);
+        assert!(lines[2].starts_with("total   "), "{}", report);
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn natrix(args: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_natrix"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("natrix-cli-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_inspect_nxc() {
+    let dir = temp_dir("inspect");
+    let script = dir.join("sum.nx");
+    let compiled = dir.join("sum.nxc");
+    let invalid = dir.join("invalid.nxc");
+    fs::write(&script, "fun main() {\n    print(1 + 2);\n}\n").unwrap();
+    fs::write(&invalid, "fun main() {}\n").unwrap();
+    let compile = natrix(&[Path::new("--compile"), &compiled, &script]);
+    let from_source = natrix(&[Path::new("inspect"), &script]);
+    let from_bytecode = natrix(&[Path::new("inspect"), &compiled]);
+    let from_invalid = natrix(&[Path::new("inspect"), &invalid]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(compile.status.success());
+    assert!(from_bytecode.status.success());
+    let from_source = String::from_utf8(from_source.stdout).unwrap();
+    let from_bytecode = String::from_utf8(from_bytecode.stdout).unwrap();
+    assert!(from_bytecode.contains("fun main:\n"), "{}", from_bytecode);
+    // the same bytecode, without the names of the sources
+    assert!(from_source.starts_with("sources:\n"), "{}", from_source);
+    assert!(from_source.ends_with(&from_bytecode), "{}", from_source);
+    assert!(!from_invalid.status.success());
+    assert!(String::from_utf8(from_invalid.stderr)
+        .unwrap()
+        .starts_with("Error: "));
+}
+
+#[test]
+fn test_unwritable_output() {
+    let dir = temp_dir("unwritable");
+    let script = dir.join("main.nx");
+    fs::write(&script, "fun main() {}\n").unwrap();
+    let output = dir.join("missing").join("main.nxc");
+    let compile = natrix(&[Path::new("--compile"), &output, &script]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(compile.status.code(), Some(1));
+    let stderr = String::from_utf8(compile.stderr).unwrap();
+    assert!(
+        stderr.starts_with(&format!("Error: cannot write {}: ", output.display())),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_parallel_parsing() {
+    let dir = temp_dir("jobs");
+    let files = [
+        (
+            "main.nx",
+            "import \"a.nx\";\nimport \"b.nx\";\n\
+             fun main() {\n    print(a.f(\"x\") + b.g());\n}\n",
+        ),
+        (
+            "other.nx",
+            "fun helper(y) {\n    return [\"x\", y, \"other\"];\n}\n",
+        ),
+        (
+            "a.nx",
+            "import \"c.nx\";\nfun f(x) {\n    return x + c.h(\"shared\");\n}\n",
+        ),
+        (
+            "b.nx",
+            "import \"c.nx\";\n\
+             fun g() {\n    var shared = \"b\";\n    return shared + c.h(shared);\n}\n",
+        ),
+        ("c.nx", "fun h(z) {\n    return z + \"c\";\n}\n"),
+    ];
+    for (name, source) in files {
+        fs::write(dir.join(name), source).unwrap();
+    }
+    let compile = |jobs: &str| {
+        let output = dir.join(format!("main-{}.nxc", jobs));
+        let result = natrix(&[
+            Path::new("--edition=2"),
+            Path::new("--keep-all"),
+            Path::new(&format!("--jobs={}", jobs)),
+            Path::new("--compile"),
+            &output,
+            &dir.join("main.nx"),
+            &dir.join("other.nx"),
+        ]);
+        assert!(result.status.success(), "{:?}", result);
+        fs::read(output).unwrap()
+    };
+    let sequential = compile("1");
+    let parallel = compile("4");
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(sequential, parallel);
+}
+
+// The AST interpreter runs the analysis too, which rejects a read of a variable that a path does
+// not assign even if the program never takes that path.
+#[test]
+fn test_ast_definite_assignment() {
+    let dir = temp_dir("assign");
+    let script = dir.join("script.nx");
+    fs::write(
+        &script,
+        "fun main() {\n    var x;\n    while (false) {\n        x = 1;\n    }\n    \
+         print(1);\n    print(x);\n}\n",
+    )
+    .unwrap();
+    let run = natrix(&[Path::new("--ast"), &script]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!run.status.success());
+    let stdout = String::from_utf8(run.stdout).unwrap();
+    assert!(
+        stdout.contains("error: variable x may be used before it is assigned"),
+        "{}",
+        stdout
+    );
+    assert!(!stdout.starts_with("1\n"), "{}", stdout);
+}
+
+// The longest chain the parser accepts fits the stack of the main thread in every pass of both
+// backends, even in an unoptimized build.
+#[test]
+fn test_long_operator_chain() {
+    let dir = temp_dir("chain");
+    let script = dir.join("script.nx");
+    fs::write(
+        &script,
+        format!(
+            "fun main() {{\n    var x = 1;\n    print(x{});\n}}\n",
+            " + x".repeat(998)
+        ),
+    )
+    .unwrap();
+    let bc = natrix(&[&script]);
+    let ast = natrix(&[Path::new("--ast"), &script]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    for run in [bc, ast] {
+        assert!(run.status.success(), "{:?}", run);
+        assert_eq!(String::from_utf8(run.stdout).unwrap(), "999\n");
+    }
+}
+
+// Without --debug the bytecode keeps no names of locals, and the AST interpreter refuses locals()
+// as well so that a script does not depend on the backend.
+#[test]
+fn test_locals_needs_debug() {
+    let dir = temp_dir("locals");
+    let script = dir.join("script.nx");
+    fs::write(
+        &script,
+        "fun main() {\n    var x = 1;\n    print(locals());\n}\n",
+    )
+    .unwrap();
+    let bc = natrix(&[&script]);
+    let ast = natrix(&[Path::new("--ast"), &script]);
+    let debug = natrix(&[Path::new("--debug"), Path::new("--ast"), &script]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(debug.status.success(), "{:?}", debug);
+    assert!(!bc.status.success());
+    assert!(!ast.status.success());
+    let bc = String::from_utf8(bc.stdout).unwrap();
+    assert!(bc.contains("error: locals needs debug info"), "{}", bc);
+    assert_eq!(String::from_utf8(ast.stdout).unwrap(), bc);
+}
+
+// A plugin written in C against natrix_plugin.h, so that it shares nothing with the host but the
+// ABI. The test is skipped where no C compiler is installed.
+#[cfg(unix)]
+#[test]
+fn test_c_plugin() {
+    let dir = temp_dir("plugin");
+    let source = dir.join("plugin.c");
+    let library = dir.join("libplugin.so");
+    let script = dir.join("script.nx");
+    fs::write(
+        &source,
+        r#"#include "natrix_plugin.h"
+#include <stdio.h>
+
+static void repeat(NxCall *call, const NxValue *args, size_t arg_count) {
+    char buffer[64];
+    if (args[0].tag != NX_STRING || args[1].tag != NX_INT) {
+        NxStr message = {(const uint8_t *)"expected a string and an int", 28};
+        call->set_error(call, message);
+        return;
+    }
+    int n = snprintf(buffer, sizeof buffer, "%.*s x%lld", (int)args[0].payload.string.len,
+                     (const char *)args[0].payload.string.ptr, (long long)args[1].payload.int_);
+    NxValue result = {NX_STRING, {.string = {(const uint8_t *)buffer, (size_t)n}}};
+    call->set_result(call, &result);
+}
+
+static void register_natives(NxRegistry *registry) {
+    NxStr name = {(const uint8_t *)"repeat", 6};
+    registry->register_native(registry, name, 2, repeat);
+}
+
+const NxPlugin natrix_plugin = {NX_PLUGIN_ABI_VERSION, register_natives};
+"#,
+    )
+    .unwrap();
+    fs::write(
+        &script,
+        "fun main() {\n    print(repeat(\"abc\", 3));\n    print(repeat(1, 2));\n}\n",
+    )
+    .unwrap();
+    let include = Path::new(env!("CARGO_MANIFEST_DIR")).join("../natrix-runtime/include");
+    let Ok(compiled) = Command::new("cc")
+        .args(["-shared", "-fPIC", "-I"])
+        .arg(&include)
+        .arg("-o")
+        .arg(&library)
+        .arg(&source)
+        .status()
+    else {
+        fs::remove_dir_all(&dir).unwrap();
+        return;
+    };
+    assert!(compiled.success());
+    let run = natrix(&[Path::new("--plugin"), &library, &script]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!run.status.success());
+    let stdout = String::from_utf8(run.stdout).unwrap();
+    assert!(
+        stdout.starts_with("abc x3\n") && stdout.contains("error: expected a string and an int"),
+        "{}",
+        stdout
+    );
+}
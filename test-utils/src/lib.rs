@@ -1,9 +1,32 @@
+#[cfg(feature = "datatest")]
 pub use datatest_stable;
 use std::path::Path;
 
 pub type TestResult = Result<(), Box<dyn std::error::Error>>;
 
-pub fn assert_golden(test_file: &Path, actual: &str, variant: &str) -> TestResult {
+/// Result of comparing an output with its golden file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum GoldenOutcome {
+    Passed,
+    /// The expected file did not exist and was created from the output.
+    Created,
+    /// The expected file did not match and was overwritten with the output.
+    Blessed,
+    /// The expected file did not match, the message shows both versions.
+    Mismatch(String),
+}
+
+/// Compares `actual` with the `.expected` file next to `test_file`.
+///
+/// On a mismatch, the output is written to a `.{variant}actual` file for inspection, unless
+/// `bless` is set, in which case the expected file is updated instead. A missing expected file
+/// is always created.
+pub fn check_golden(
+    test_file: &Path,
+    actual: &str,
+    variant: &str,
+    bless: bool,
+) -> std::io::Result<GoldenOutcome> {
     let expected_path = test_file.with_extension("expected");
     let actual_path = test_file.with_extension(&format!("{}actual", variant));
 
@@ -13,17 +36,20 @@ pub fn assert_golden(test_file: &Path, actual: &str, variant: &str) -> TestResul
             if actual.trim() == expected.trim() {
                 // Test passed - clean up any leftover .actual file
                 let _ = std::fs::remove_file(&actual_path);
-                Ok(())
+                Ok(GoldenOutcome::Passed)
+            } else if bless {
+                std::fs::write(&expected_path, actual)?;
+                let _ = std::fs::remove_file(&actual_path);
+                Ok(GoldenOutcome::Blessed)
             } else {
                 // Test failed - write .actual file
                 std::fs::write(&actual_path, actual)?;
-                Err(format!(
+                Ok(GoldenOutcome::Mismatch(format!(
                     "Golden file mismatch\nExpected: {}\nActual: {}\n\n{}",
                     expected_path.display(),
                     actual_path.display(),
                     diff(&expected, actual)
-                )
-                .into())
+                )))
             }
         }
         Err(_) => {
@@ -31,9 +57,20 @@ pub fn assert_golden(test_file: &Path, actual: &str, variant: &str) -> TestResul
             std::fs::write(&expected_path, actual)?;
             // Clean up any leftover .actual file
             let _ = std::fs::remove_file(&actual_path);
+            Ok(GoldenOutcome::Created)
+        }
+    }
+}
+
+pub fn assert_golden(test_file: &Path, actual: &str, variant: &str) -> TestResult {
+    match check_golden(test_file, actual, variant, false)? {
+        GoldenOutcome::Mismatch(message) => Err(message.into()),
+        GoldenOutcome::Created => {
+            let expected_path = test_file.with_extension("expected");
             println!("Created golden file: {}", expected_path.display());
             Ok(())
         }
+        GoldenOutcome::Passed | GoldenOutcome::Blessed => Ok(()),
     }
 }
 
@@ -42,7 +79,7 @@ fn diff(expected: &str, actual: &str) -> String {
     format!("Expected:\n{}\n\nActual:\n{}", expected, actual)
 }
 
-pub fn run_golden_test(path: &Path, transform: fn(&str) -> String) -> TestResult {
+pub fn run_golden_test(path: &Path, transform: impl FnOnce(&str) -> String) -> TestResult {
     let input = std::fs::read_to_string(path)?;
     let actual = transform(&input);
     assert_golden(path, &actual, "")?;
@@ -52,7 +89,7 @@ pub fn run_golden_test(path: &Path, transform: fn(&str) -> String) -> TestResult
 pub fn run_golden_test_variant(
     path: &Path,
     extension: &str,
-    transform: fn(&str) -> String,
+    transform: impl FnOnce(&str) -> String,
 ) -> TestResult {
     let input = std::fs::read_to_string(path)?;
     let actual = transform(&input);